@@ -0,0 +1,209 @@
+//! Expansion of command line arguments.
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::path::Path;
+use std::str;
+
+use {env_with_context, full_with_context, LazyHomeDir, LookupError};
+
+/// Performs both tilde and environment expansions in every argument of a command line in place,
+/// using the provided contexts.
+///
+/// Each argument is expanded separately with `full_with_context()`, so a tilde is only expanded
+/// at the beginning of an argument, and references never span several arguments. Arguments do
+/// not have to be valid UTF-8: every valid UTF-8 piece of an argument is expanded on its own and
+/// the rest of the argument is preserved as it is, so a reference ends before any invalid
+/// sequence. Arguments which do not change are left untouched, without any reallocation, which
+/// makes this function cheap for arguments without any expandable content.
+///
+/// The `home_dir` context function is called at most once. If a lookup fails, the offset of the
+/// error is relative to the argument which contains the reference; the arguments before it are
+/// already expanded at that point, while this argument and the ones after it are unchanged.
+///
+/// # Examples
+///
+/// ```
+/// use std::ffi::OsString;
+/// use std::path::{Path, PathBuf};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "PROFILE" => Ok(Some("release")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// let mut args: Vec<OsString> = vec!["build".into(), "--profile=$PROFILE".into(), "~/a".into()];
+/// shellexpand::expand_args(&mut args, home_dir, context).unwrap();
+/// assert_eq!(args, ["build", "--profile=release", "/home/user/a"]);
+/// ```
+pub fn expand_args<CO, C, E, P, HD>(
+    args: &mut [OsString],
+    home_dir: HD,
+    mut context: C,
+) -> Result<(), LookupError<E>>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let mut home_dir = LazyHomeDir::new(home_dir);
+
+    for arg in args {
+        let home_dir = || home_dir.get().map(|hd| hd.as_ref().to_path_buf());
+        let expanded = match arg.to_str() {
+            Some(s) => match full_with_context(s, home_dir, &mut context)? {
+                // references to unknown variables produce an owned copy of the same string
+                Cow::Owned(ref expanded) if expanded == s => continue,
+                Cow::Owned(expanded) => OsString::from(expanded),
+                Cow::Borrowed(_) => continue,
+            },
+            None => match expand_bytes(arg.as_encoded_bytes(), home_dir, &mut context)? {
+                // SAFETY: `from_encoded_bytes_unchecked()` accepts bytes of `as_encoded_bytes()`
+                // split at the boundaries of valid UTF-8 and joined with valid UTF-8; the bytes
+                // are split only around valid UTF-8 pieces, which are replaced with other valid
+                // UTF-8 strings, while the rest of the bytes is kept as it is, in the same order
+                Some(bytes) => unsafe { OsString::from_encoded_bytes_unchecked(bytes) },
+                None => continue,
+            },
+        };
+        *arg = expanded;
+    }
+    Ok(())
+}
+
+/// Expands every valid UTF-8 piece of `input` separately, returning `None` if nothing changes.
+fn expand_bytes<CO, C, E, P, HD>(
+    input: &[u8],
+    home_dir: HD,
+    mut context: C,
+) -> Result<Option<Vec<u8>>, LookupError<E>>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let mut home_dir = Some(home_dir);
+    let mut result = Vec::with_capacity(input.len());
+    let mut changed = false;
+    let mut pos = 0;
+    while pos < input.len() {
+        let (valid, invalid_len) = match str::from_utf8(&input[pos..]) {
+            Ok(s) => (s, 0),
+            Err(e) => {
+                let valid = str::from_utf8(&input[pos..pos + e.valid_up_to()]).unwrap();
+                let rest = input.len() - pos - valid.len();
+                (valid, e.error_len().unwrap_or(rest))
+            }
+        };
+
+        let add_offset = |mut e: LookupError<E>| {
+            e.offset += pos;
+            e
+        };
+        // the tilde is only expanded at the beginning of the argument
+        let expanded = match home_dir.take() {
+            Some(home_dir) => full_with_context(valid, home_dir, &mut context),
+            None => env_with_context(valid, &mut context),
+        }
+        .map_err(add_offset)?;
+        changed |= expanded != valid;
+        result.extend_from_slice(expanded.as_bytes());
+
+        pos += valid.len();
+        result.extend_from_slice(&input[pos..pos + invalid_len]);
+        pos += invalid_len;
+    }
+    Ok(if changed { Some(result) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use super::expand_args;
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "A" => Ok(Some("a")),
+            "LONG" => Ok(Some("a rather long value")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        }
+    }
+
+    #[test]
+    fn test_expand_args() {
+        let mut args: Vec<OsString> = ["plain", "$A/x", "~", "~/$LONG", "a~", "$UNKNOWN", ""]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        let plain_ptr = args[0].as_encoded_bytes().as_ptr();
+        let unknown_ptr = args[5].as_encoded_bytes().as_ptr();
+        expand_args(&mut args, || Some("/home/user"), context).unwrap();
+        assert_eq!(
+            args,
+            [
+                "plain",
+                "a/x",
+                "/home/user",
+                "/home/user/a rather long value",
+                "a~",
+                "$UNKNOWN",
+                ""
+            ]
+        );
+        // unchanged arguments are not reallocated
+        assert_eq!(args[0].as_encoded_bytes().as_ptr(), plain_ptr);
+        assert_eq!(args[5].as_encoded_bytes().as_ptr(), unknown_ptr);
+
+        // the home directory is looked up once for all arguments
+        let mut home_dir_calls = 0;
+        let mut args = vec![OsString::from("~/a"), OsString::from("~/b")];
+        let home_dir = || {
+            home_dir_calls += 1;
+            Some("/home/user")
+        };
+        expand_args(&mut args, home_dir, context).unwrap();
+        assert_eq!(args, ["/home/user/a", "/home/user/b"]);
+        assert_eq!(home_dir_calls, 1);
+
+        let mut args = vec![OsString::from("$A"), OsString::from("x $ERR")];
+        let err = expand_args(&mut args, || Some("/home/user"), context).unwrap_err();
+        assert_eq!(err.var_name, "ERR");
+        assert_eq!(err.offset, 2);
+        assert_eq!(args, ["a", "x $ERR"]);
+
+        expand_args(&mut [], || Some("/home/user"), context).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_args_non_utf8() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let mut args = vec![
+            OsString::from_vec(b"~/\xff/$A\xfe$LONG".to_vec()),
+            OsString::from_vec(b"\xff~/$A".to_vec()),
+            OsString::from_vec(b"plain\xff".to_vec()),
+            OsString::from_vec(b"\xff$ERR".to_vec()),
+        ];
+        let plain_ptr = args[2].as_bytes().as_ptr();
+        let err = expand_args(&mut args, || Some("/home/user"), context).unwrap_err();
+        assert_eq!(err.var_name, "ERR");
+        assert_eq!(err.offset, 1);
+        assert_eq!(
+            args[0].as_bytes(),
+            b"/home/user/\xff/a\xfea rather long value"
+        );
+        assert_eq!(args[1].as_bytes(), b"\xff~/a");
+        assert_eq!(args[2].as_bytes(), b"plain\xff");
+        assert_eq!(args[2].as_bytes().as_ptr(), plain_ptr);
+        assert_eq!(args[3].as_bytes(), b"\xff$ERR");
+    }
+}
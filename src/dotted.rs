@@ -0,0 +1,96 @@
+//! Lookup of values by dotted paths, shared by the contexts backed by structured documents.
+
+/// A value of a structured document which may contain other values.
+pub(crate) trait Nested {
+    /// Returns the member of a table-like value with the given key.
+    fn member(&self, key: &str) -> Option<&Self>;
+
+    /// Returns the element of an array-like value with the given index.
+    fn element(&self, index: usize) -> Option<&Self>;
+}
+
+/// Looks a value up by its name or by its dotted path, using `get` to look keys up in the root.
+///
+/// A key which contains the dots literally takes precedence over the path. Each segment of the
+/// path after the first one is a key of a table-like value or an index into an array-like one.
+pub(crate) fn lookup<'a, V, G>(get: G, name: &str) -> Option<&'a V>
+where
+    V: Nested,
+    G: Fn(&str) -> Option<&'a V>,
+{
+    if let Some(value) = get(name) {
+        return Some(value);
+    }
+
+    let mut segments = name.split('.');
+    let mut value = get(segments.next()?)?;
+    for segment in segments {
+        value = match value.member(segment) {
+            Some(member) => member,
+            None => value.element(segment.parse().ok()?)?,
+        };
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{lookup, Nested};
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Leaf(&'static str),
+        Table(HashMap<&'static str, Value>),
+        Array(Vec<Value>),
+    }
+
+    impl Nested for Value {
+        fn member(&self, key: &str) -> Option<&Value> {
+            match *self {
+                Value::Table(ref table) => table.get(key),
+                _ => None,
+            }
+        }
+
+        fn element(&self, index: usize) -> Option<&Value> {
+            match *self {
+                Value::Array(ref values) => values.get(index),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut nested = HashMap::new();
+        nested.insert("key", Value::Leaf("nested"));
+        nested.insert("0", Value::Leaf("zero key"));
+        let mut root = HashMap::new();
+        root.insert("dotted.key", Value::Leaf("literal"));
+        root.insert("dotted", Value::Table(nested));
+        root.insert(
+            "list",
+            Value::Array(vec![Value::Leaf("first"), Value::Leaf("second")]),
+        );
+        root.insert("leaf", Value::Leaf("leaf"));
+        let get = |key: &str| root.get(key);
+        let leaf = |name| match lookup(get, name) {
+            Some(&Value::Leaf(value)) => Some(value),
+            _ => None,
+        };
+
+        assert_eq!(leaf("leaf"), Some("leaf"));
+        assert_eq!(leaf("dotted.key"), Some("literal"));
+        assert_eq!(leaf("dotted.0"), Some("zero key"));
+        assert_eq!(leaf("list.1"), Some("second"));
+        assert_eq!(leaf("list.2"), None);
+        assert_eq!(leaf("list.x"), None);
+        assert_eq!(leaf("list.-1"), None);
+        assert_eq!(leaf("leaf.0"), None);
+        assert_eq!(leaf("missing.key"), None);
+        assert_eq!(leaf(""), None);
+        assert!(lookup(get, "dotted").is_some());
+    }
+}
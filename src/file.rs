@@ -0,0 +1,172 @@
+//! Expansion of whole files.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use {full_with_context, LookupError};
+
+/// An error which occurred while expanding a file with `expand_file()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExpandFileError<E> {
+    /// The source file could not be read, or the destination file could not be written.
+    Io(io::Error),
+    /// A variable lookup failed.
+    Lookup {
+        /// The number of the line containing the offending reference, starting from 1.
+        line: usize,
+        /// The lookup error; its offset is relative to the beginning of the line.
+        error: LookupError<E>,
+    },
+}
+
+impl<E> From<io::Error> for ExpandFileError<E> {
+    fn from(e: io::Error) -> ExpandFileError<E> {
+        ExpandFileError::Io(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for ExpandFileError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandFileError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ExpandFileError::Lookup { line, ref error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ExpandFileError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExpandFileError::Io(ref e) => Some(e),
+            ExpandFileError::Lookup { ref error, .. } => Some(error),
+        }
+    }
+}
+
+/// Reads the file `src`, expands every line of it and writes the result to the file `dst`.
+///
+/// Each line is expanded separately with `full_with_context()`, so, in particular, a tilde at
+/// the beginning of any line is expanded. Line endings, both `\n` and `\r\n`, are preserved as
+/// they are, including the presence or absence of the final one. The contexts may be called for
+/// each of the lines, which is why `home_dir` must be `FnMut` here.
+///
+/// The source file must be valid UTF-8. The destination file is only written if all lines have
+/// been expanded successfully; it is created if it does not exist and truncated if it does.
+/// If a lookup fails, the returned error contains the number of the offending line.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::env;
+///
+/// shellexpand::expand_file(
+///     "config.template",
+///     "config",
+///     dirs_next::home_dir,
+///     |s| env::var(s).map(Some),
+/// )
+/// .unwrap();
+/// ```
+pub fn expand_file<PS, PD, CO, C, E, P, HD>(
+    src: PS,
+    dst: PD,
+    mut home_dir: HD,
+    mut context: C,
+) -> Result<(), ExpandFileError<E>>
+where
+    PS: AsRef<Path>,
+    PD: AsRef<Path>,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnMut() -> Option<P>,
+{
+    let input = fs::read_to_string(src)?;
+    let mut output = String::with_capacity(input.len());
+    for (idx, line) in input.split_inclusive('\n').enumerate() {
+        let content = line.trim_end_matches('\n').trim_end_matches('\r');
+        let expanded =
+            full_with_context(content, &mut home_dir, &mut context).map_err(|error| {
+                ExpandFileError::Lookup {
+                    line: idx + 1,
+                    error,
+                }
+            })?;
+        output.push_str(&expanded);
+        output.push_str(&line[content.len()..]);
+    }
+    fs::write(dst, output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{expand_file, ExpandFileError};
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "VAR" => Ok(Some("value")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        }
+    }
+
+    fn home_dir() -> Option<&'static str> {
+        Some("/home/dir")
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("shellexpand-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_expand_file() {
+        let src = temp_path("expand-src");
+        let dst = temp_path("expand-dst");
+        for &(input, output) in &[
+            (
+                "a=${VAR}\n~/b\r\n$UNKNOWN ~\n",
+                "a=value\n/home/dir/b\r\n$UNKNOWN ~\n",
+            ),
+            ("${VAR}\r\n\n~", "value\r\n\n/home/dir"),
+            ("\r\n\r\n", "\r\n\r\n"),
+            ("", ""),
+        ] {
+            fs::write(&src, input).unwrap();
+            expand_file(&src, &dst, home_dir, context).unwrap();
+            assert_eq!(fs::read_to_string(&dst).unwrap(), output, "{:?}", input);
+        }
+        fs::remove_file(&src).unwrap();
+        fs::remove_file(&dst).unwrap();
+    }
+
+    #[test]
+    fn test_expand_file_errors() {
+        let src = temp_path("errors-src");
+        let dst = temp_path("errors-dst");
+        fs::write(&src, "first\nsecond $VAR\nthird ${ERR}\n").unwrap();
+        match expand_file(&src, &dst, home_dir, context) {
+            Err(ExpandFileError::Lookup { line, error }) => {
+                assert_eq!(line, 3);
+                assert_eq!(error.var_name, "ERR");
+                assert_eq!(error.offset, 6);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!dst.exists());
+        fs::remove_file(&src).unwrap();
+
+        match expand_file(&src, &dst, home_dir, context) {
+            Err(ExpandFileError::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
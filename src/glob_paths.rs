@@ -0,0 +1,164 @@
+//! Matching of expanded strings against the file system.
+
+use std::env::VarError;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use glob::{self, GlobError, PatternError};
+
+use {full_with_context, home, LookupError};
+
+/// An error which occurred while expanding and globbing a string with `full_glob_with_context()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExpandGlobError<E> {
+    /// A variable lookup failed.
+    Lookup(LookupError<E>),
+    /// The expanded string is not a valid glob pattern.
+    Pattern(PatternError),
+    /// A path matching the pattern could not be read, for example because of missing permissions.
+    Glob(GlobError),
+}
+
+impl<E> From<LookupError<E>> for ExpandGlobError<E> {
+    fn from(e: LookupError<E>) -> ExpandGlobError<E> {
+        ExpandGlobError::Lookup(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for ExpandGlobError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandGlobError::Lookup(ref e) => e.fmt(f),
+            ExpandGlobError::Pattern(ref e) => write!(f, "invalid glob pattern: {}", e),
+            ExpandGlobError::Glob(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ExpandGlobError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExpandGlobError::Lookup(ref e) => Some(e),
+            ExpandGlobError::Pattern(ref e) => Some(e),
+            ExpandGlobError::Glob(ref e) => Some(e),
+        }
+    }
+}
+
+/// Performs both tilde and environment expansions using the provided contexts, and then returns
+/// all paths in the file system which match the result.
+///
+/// The input is expanded with `full_with_context()` first, and the result is used as a glob
+/// pattern, like `~/projects/*/Cargo.toml`: `*`, `?` and `[...]` match any string, any character
+/// and any of the enclosed characters inside a single path component, and `**` matches any number
+/// of directories. Note that the values of variables become a part of the pattern too. The paths
+/// are returned in alphabetical order; if nothing matches the pattern, the result is empty.
+///
+/// If the expanded string is not a valid pattern, `ExpandGlobError::Pattern` is returned, and if
+/// some of the candidate paths cannot be read, `ExpandGlobError::Glob` is returned.
+///
+/// This function is only available with the `glob` feature.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::env;
+///
+/// let manifests = shellexpand::full_glob_with_context(
+///     "~/projects/*/Cargo.toml",
+///     dirs_next::home_dir,
+///     |s| env::var(s).map(Some),
+/// )
+/// .unwrap();
+/// for manifest in manifests {
+///     println!("{}", manifest.display());
+/// }
+/// ```
+pub fn full_glob_with_context<SI, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Vec<PathBuf>, ExpandGlobError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let pattern = full_with_context(input, home_dir, context)?;
+    glob::glob(&pattern)
+        .map_err(ExpandGlobError::Pattern)?
+        .collect::<Result<_, _>>()
+        .map_err(ExpandGlobError::Glob)
+}
+
+/// Performs both tilde and environment expansions in the default system context, and then
+/// returns all paths in the file system which match the result.
+///
+/// This function delegates to `full_glob_with_context()`, using the same contexts as `full()`.
+///
+/// This function is only available with the `glob` feature.
+#[inline]
+pub fn full_glob<SI>(input: &SI) -> Result<Vec<PathBuf>, ExpandGlobError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    full_glob_with_context(input, home::home_dir, |s| std::env::var(s).map(Some))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{full_glob_with_context, ExpandGlobError};
+
+    #[test]
+    fn test_full_glob_with_context() {
+        let root = env::temp_dir().join(format!("shellexpand-{}-glob", std::process::id()));
+        for dir in &["a", "b", "c"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in &["a/Cargo.toml", "b/Cargo.toml", "c/Cargo.lock", "Cargo.toml"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let root_str = root.to_str().unwrap().to_owned();
+        let glob = |input: &str| {
+            full_glob_with_context(
+                input,
+                || Some(&root),
+                |s| match s {
+                    "ROOT" => Ok(Some(root_str.clone())),
+                    "ERR" => Err(()),
+                    _ => Ok(None),
+                },
+            )
+        };
+
+        let manifests: Vec<PathBuf> = vec![root.join("a/Cargo.toml"), root.join("b/Cargo.toml")];
+        assert_eq!(glob("~/*/Cargo.toml").unwrap(), manifests);
+        assert_eq!(glob("$ROOT/*/Cargo.toml").unwrap(), manifests);
+        assert_eq!(
+            glob("${ROOT}/[bc]/Cargo.*").unwrap(),
+            [root.join("b/Cargo.toml"), root.join("c/Cargo.lock")]
+        );
+        assert_eq!(glob("~/Cargo.toml").unwrap(), [root.join("Cargo.toml")]);
+        assert_eq!(glob("~/*/missing").unwrap(), Vec::<PathBuf>::new());
+
+        match glob("~/[/*") {
+            Err(ExpandGlobError::Pattern(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match glob("$ERR/*") {
+            Err(ExpandGlobError::Lookup(e)) => assert_eq!(e.var_name, "ERR"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,574 @@
+//! Resolution of home directories in the default system context.
+
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+#[cfg(any(test, feature = "test-home-dir"))]
+use std::sync::Mutex;
+
+/// The kind of a `HomeDirError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HomeDirErrorKind {
+    /// The user or their home directory does not exist.
+    NotFound,
+    /// The operating system returned an error during the lookup.
+    Os,
+    /// Looking up home directories of other users is not supported on this platform or with
+    /// the enabled set of features.
+    Unimplemented,
+}
+
+/// Represents a home directory lookup error.
+///
+/// This error is returned by functions which resolve home directories, like `try_home_dir()` and
+/// `try_tilde_user()`, when the lookup cannot be performed. Its kind tells why, so callers may
+/// decide whether to retry the lookup, ask the user for the path, or give up.
+#[derive(Debug)]
+pub struct HomeDirError {
+    kind: HomeDirErrorKind,
+    user: Option<String>,
+    source: Option<io::Error>,
+}
+
+impl HomeDirError {
+    /// Creates a new error of the given kind.
+    ///
+    /// `user` is the name of the user whose home directory was looked up, or `None` for the
+    /// current user. This constructor is useful for custom home directory functions, like the ones
+    /// passed to `tilde_with_context_reporting()`.
+    pub fn new(kind: HomeDirErrorKind, user: Option<&str>) -> HomeDirError {
+        HomeDirError {
+            kind,
+            user: user.map(Into::into),
+            source: None,
+        }
+    }
+
+    /// Creates a new error of the `Os` kind caused by the given I/O error.
+    pub fn os(user: Option<&str>, source: io::Error) -> HomeDirError {
+        HomeDirError {
+            source: Some(source),
+            ..HomeDirError::new(HomeDirErrorKind::Os, user)
+        }
+    }
+
+    /// Returns the kind of this error.
+    pub fn kind(&self) -> HomeDirErrorKind {
+        self.kind
+    }
+
+    /// Returns the name of the user whose home directory was looked up, or `None` if it was the
+    /// current user.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Returns a hint on how the error may be fixed, suitable for showing to end users.
+    ///
+    /// The `Display` implementation of this error only describes what went wrong; this method
+    /// complements it with a short suggestion which takes the current platform into account, like
+    /// the name of the environment variable which defines the home directory. Returns `None` if
+    /// there is nothing useful to suggest.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::{HomeDirError, HomeDirErrorKind};
+    ///
+    /// let err = HomeDirError::new(HomeDirErrorKind::NotFound, Some("nobody-here"));
+    /// assert_eq!(
+    ///     err.to_string(),
+    ///     "error looking home directory of user 'nobody-here' up: not found"
+    /// );
+    /// assert_eq!(err.hint(), Some("check that the user exists on this system"));
+    /// ```
+    pub fn hint(&self) -> Option<&'static str> {
+        match (self.kind, self.user.is_some()) {
+            (HomeDirErrorKind::NotFound, true) => Some("check that the user exists on this system"),
+            (HomeDirErrorKind::NotFound, false) if cfg!(windows) => {
+                Some("set the USERPROFILE environment variable to the home directory")
+            }
+            (HomeDirErrorKind::NotFound, false) => {
+                Some("set the HOME environment variable to the home directory")
+            }
+            // profiles of other users may only be available while they are logged in
+            (HomeDirErrorKind::Os, true) if cfg!(windows) => Some(
+                "the user may need to be logged in, or the lookup may require elevated privileges",
+            ),
+            (HomeDirErrorKind::Os, _) => None,
+            (HomeDirErrorKind::Unimplemented, _) if cfg!(unix) => {
+                Some("enable the `libc` feature of shellexpand to look up other users")
+            }
+            (HomeDirErrorKind::Unimplemented, _) => None,
+        }
+    }
+}
+
+impl fmt::Display for HomeDirError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.user {
+            Some(ref user) => write!(f, "error looking home directory of user '{}' up: ", user)?,
+            None => write!(f, "error looking home directory of the current user up: ")?,
+        }
+        match self.kind {
+            HomeDirErrorKind::NotFound => write!(f, "not found"),
+            HomeDirErrorKind::Os => match self.source {
+                Some(ref e) => write!(f, "{}", e),
+                None => write!(f, "operating system error"),
+            },
+            HomeDirErrorKind::Unimplemented => write!(f, "not supported on this platform"),
+        }
+    }
+}
+
+impl Error for HomeDirError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_ref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+/// Held by tests which look the home directory of the current user up more than once, so that
+/// the test of `set_test_home_dir()` cannot change it in between.
+#[cfg(test)]
+pub(crate) static HOME_DIR_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(feature = "test-home-dir")]
+static TEST_HOME_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Overrides the home directory of the current user for the whole process.
+///
+/// This function is only available with the `test-home-dir` feature, and it is intended to be used
+/// only in tests of code which calls `tilde()`, `full()` or other functions using the default
+/// system context, to make their results deterministic. If an override is installed, these
+/// functions use it instead of looking the home directory up in the operating system. Passing
+/// `None` removes the override.
+///
+/// The override is global, so tests which rely on different overrides or on the actual home
+/// directory must not run concurrently with each other.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::PathBuf;
+///
+/// shellexpand::set_test_home_dir(Some(PathBuf::from("/test/home")));
+/// assert_eq!(shellexpand::tilde("~"), "/test/home");
+/// assert_eq!(shellexpand::tilde("~/x"), "/test/home/x");
+///
+/// shellexpand::set_test_home_dir(None);
+/// assert_ne!(shellexpand::tilde("~/x"), "/test/home/x");
+/// ```
+#[cfg(feature = "test-home-dir")]
+pub fn set_test_home_dir(home_dir: Option<PathBuf>) {
+    // the override is a plain value, so a panic while it was locked cannot leave it inconsistent
+    *TEST_HOME_DIR.lock().unwrap_or_else(|e| e.into_inner()) = home_dir;
+}
+
+/// Returns the home directory of the current user.
+///
+/// This is the override installed with `set_test_home_dir()`, if any, or the home directory
+/// returned by the operating system, falling back to the environment variables which define it.
+pub fn home_dir() -> Option<PathBuf> {
+    #[cfg(feature = "test-home-dir")]
+    {
+        let test_home_dir = TEST_HOME_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(ref home_dir) = *test_home_dir {
+            return Some(home_dir.clone());
+        }
+    }
+    home_dir_with_fallback(system_home_dir, |name| env::var_os(name))
+}
+
+/// Returns the home directory of the current user if `user` is `None`, or of the named user
+/// otherwise, along with the reason why it cannot be determined.
+///
+/// The home directory of the current user is looked up just like in `tilde()`, and if there is
+/// none, the error kind is `NotFound` on every platform. The sources of this directory, the
+/// operating system and the environment variables, only tell whether they know it, so even a
+/// failure of the operating system is reported as `NotFound` rather than `Os`.
+///
+/// Named users are looked up like in `tilde_user()`: a user who does not exist gives a `NotFound`
+/// error, an error of the operating system during the lookup gives an `Os` error, and if named
+/// users cannot be resolved on the current platform or with the enabled set of features, the
+/// error kind is `Unimplemented`.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::HomeDirErrorKind;
+///
+/// match shellexpand::try_home_dir(Some("surely-nonexisting-user")) {
+///     Ok(hd) => println!("found {}", hd.display()),
+///     Err(ref e) if e.kind() == HomeDirErrorKind::NotFound => println!("no such user"),
+///     Err(ref e) if e.kind() == HomeDirErrorKind::Unimplemented => println!("not supported"),
+///     Err(e) => println!("lookup failed: {}", e),
+/// }
+/// ```
+pub fn try_home_dir(user: Option<&str>) -> Result<PathBuf, HomeDirError> {
+    match user {
+        None => current_home_dir(home_dir()),
+        Some(user) => user_home_dir(user),
+    }
+}
+
+/// Turns the result of a lookup of the current user's home directory into an error if it failed.
+fn current_home_dir(home_dir: Option<PathBuf>) -> Result<PathBuf, HomeDirError> {
+    home_dir.ok_or_else(|| HomeDirError::new(HomeDirErrorKind::NotFound, None))
+}
+
+/// Returns the home directory given by `system_home_dir`, or, if there is none, the one defined
+/// by the environment variables read with `var_os`.
+///
+/// On unusual platforms the system sources may know nothing about the home directory even though
+/// it is set explicitly, so `HOME` is consulted then, preceded by `USERPROFILE` on Windows. Empty
+/// variables are ignored.
+fn home_dir_with_fallback<S, V>(system_home_dir: S, var_os: V) -> Option<PathBuf>
+where
+    S: FnOnce() -> Option<PathBuf>,
+    V: Fn(&str) -> Option<OsString>,
+{
+    let names: &[&str] = if cfg!(windows) {
+        &["USERPROFILE", "HOME"]
+    } else {
+        &["HOME"]
+    };
+    system_home_dir().or_else(|| {
+        names
+            .iter()
+            .filter_map(|name| var_os(name))
+            .find(|value| !value.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+/// Returns the home directory of the current user.
+///
+/// With the `home` feature enabled, this is `home::home_dir()`.
+#[cfg(feature = "home")]
+fn system_home_dir() -> Option<PathBuf> {
+    ::home_crate::home_dir()
+}
+
+/// Returns the home directory of the current user.
+///
+/// With the `dirs-next` feature enabled, which is the default, this is `dirs::home_dir()`.
+#[cfg(all(not(feature = "home"), feature = "dirs-next"))]
+fn system_home_dir() -> Option<PathBuf> {
+    ::dirs::home_dir()
+}
+
+/// Returns the home directory of the current user.
+///
+/// Without the `home` and `dirs-next` features, but with the `libc` feature on Unix, the `HOME`
+/// environment variable is consulted first, just like `dirs::home_dir()` does, and if it is not
+/// set or empty, the home directory is taken from the password database via `getpwuid_r()`.
+#[cfg(all(
+    not(any(feature = "home", feature = "dirs-next")),
+    unix,
+    feature = "libc"
+))]
+fn system_home_dir() -> Option<PathBuf> {
+    match std::env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Some(home.into()),
+        _ => unix::home_dir_from_passwd(),
+    }
+}
+
+#[cfg(not(any(feature = "home", feature = "dirs-next", all(unix, feature = "libc"))))]
+compile_error!(
+    "either the `home` or the `dirs-next` feature or, on Unix, the `libc` feature must be enabled"
+);
+
+/// Returns the home directory of the named user.
+///
+/// With the `libc` feature on Unix, the home directory is taken from the password database via
+/// `getpwnam_r()`.
+#[cfg(all(unix, feature = "libc"))]
+pub fn user_home_dir(user: &str) -> Result<PathBuf, HomeDirError> {
+    unix::user_home_dir_from_passwd(user)
+}
+
+/// Returns the home directory of the named user.
+///
+/// On this platform, or without the `libc` feature on Unix, home directories of other users
+/// cannot be looked up, so this function always returns an `Unimplemented` error.
+#[cfg(not(all(unix, feature = "libc")))]
+pub fn user_home_dir(user: &str) -> Result<PathBuf, HomeDirError> {
+    Err(HomeDirError::new(
+        HomeDirErrorKind::Unimplemented,
+        Some(user),
+    ))
+}
+
+/// Returns true if home directories of named users can be looked up on this platform with the
+/// enabled set of features.
+///
+/// Named users can only be resolved on Unix with the `libc` feature enabled; otherwise `~user`
+/// is always left as it is by `tilde_user()`, and `try_tilde_user()` returns an `Unimplemented`
+/// error for it. Applications may use this function to suggest enabling the `libc` feature.
+///
+/// # Examples
+///
+/// ```
+/// if !shellexpand::can_resolve_named_users() {
+///     assert_eq!(shellexpand::tilde_user("~root/x"), "~root/x");
+/// }
+/// ```
+pub fn can_resolve_named_users() -> bool {
+    cfg!(all(unix, feature = "libc"))
+}
+
+#[cfg(all(unix, feature = "libc"))]
+mod unix {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::{mem, ptr};
+
+    use libc;
+
+    use super::{HomeDirError, HomeDirErrorKind};
+
+    /// Looks up the home directory of the current user in the password database.
+    #[cfg_attr(any(feature = "home", feature = "dirs-next"), allow(dead_code))]
+    pub fn home_dir_from_passwd() -> Option<PathBuf> {
+        lookup(|passwd, buf, buf_len, result| unsafe {
+            libc::getpwuid_r(libc::getuid(), passwd, buf, buf_len, result)
+        })
+        .unwrap_or(None)
+    }
+
+    /// Looks up the home directory of the named user in the password database.
+    pub fn user_home_dir_from_passwd(user: &str) -> Result<PathBuf, HomeDirError> {
+        let not_found = || HomeDirError::new(HomeDirErrorKind::NotFound, Some(user));
+
+        // a name with a NUL byte inside cannot be present in the database
+        let name = CString::new(user).map_err(|_| not_found())?;
+        match lookup(|passwd, buf, buf_len, result| unsafe {
+            libc::getpwnam_r(name.as_ptr(), passwd, buf, buf_len, result)
+        }) {
+            Ok(Some(hd)) => Ok(hd),
+            Ok(None) => Err(not_found()),
+            Err(e) => Err(HomeDirError::os(Some(user), e)),
+        }
+    }
+
+    /// Calls one of the `getpw*_r()` functions with a large enough buffer and extracts the home
+    /// directory from its result.
+    fn lookup<F>(mut f: F) -> io::Result<Option<PathBuf>>
+    where
+        F: FnMut(
+            *mut libc::passwd,
+            *mut libc::c_char,
+            libc::size_t,
+            *mut *mut libc::passwd,
+        ) -> libc::c_int,
+    {
+        let initial_size = match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+            n if n > 0 => n as usize,
+            _ => 1024,
+        };
+        let mut buf: Vec<libc::c_char> = Vec::with_capacity(initial_size);
+
+        loop {
+            let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            let code = f(&mut passwd, buf.as_mut_ptr(), buf.capacity(), &mut result);
+
+            match code {
+                0 if !result.is_null() && !passwd.pw_dir.is_null() => {
+                    let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+                    return Ok(Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes()))));
+                }
+                // there is no entry for the user
+                0 => return Ok(None),
+                // the buffer is too small, try again with a bigger one
+                libc::ERANGE => buf.reserve(buf.capacity() * 2),
+                // these codes also mean that there is no entry for the user on some systems
+                libc::ENOENT | libc::ESRCH | libc::EBADF | libc::EPERM => return Ok(None),
+                code => return Err(io::Error::from_raw_os_error(code)),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{home_dir_from_passwd, user_home_dir_from_passwd};
+        use HomeDirErrorKind;
+
+        #[test]
+        fn test_home_dir_from_passwd() {
+            let hd = home_dir_from_passwd().expect("current user should have a home directory");
+            assert!(hd.is_absolute(), "{:?}", hd);
+        }
+
+        #[test]
+        fn test_user_home_dir_from_passwd() {
+            let hd = user_home_dir_from_passwd("root").expect("root should have a home directory");
+            assert!(hd.is_absolute(), "{:?}", hd);
+
+            let err = user_home_dir_from_passwd("surely-nonexisting-user").unwrap_err();
+            assert_eq!(err.kind(), HomeDirErrorKind::NotFound);
+            assert_eq!(err.user(), Some("surely-nonexisting-user"));
+
+            let err = user_home_dir_from_passwd("nul\0user").unwrap_err();
+            assert_eq!(err.kind(), HomeDirErrorKind::NotFound);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::{
+        current_home_dir, home_dir, home_dir_with_fallback, try_home_dir, HomeDirError,
+        HomeDirErrorKind, HOME_DIR_TEST_LOCK,
+    };
+
+    #[test]
+    fn test_messages() {
+        let err = HomeDirError::new(HomeDirErrorKind::NotFound, Some("user"));
+        assert_eq!(
+            err.to_string(),
+            "error looking home directory of user 'user' up: not found"
+        );
+        assert_eq!(
+            err.hint(),
+            Some("check that the user exists on this system")
+        );
+
+        let err = HomeDirError::new(HomeDirErrorKind::NotFound, None);
+        assert_eq!(
+            err.to_string(),
+            "error looking home directory of the current user up: not found"
+        );
+        assert!(err.hint().unwrap().contains("environment variable"));
+
+        let err = HomeDirError::os(Some("user"), io::Error::other("disk on fire"));
+        assert_eq!(
+            err.to_string(),
+            "error looking home directory of user 'user' up: disk on fire"
+        );
+        assert_eq!(err.hint().is_some(), cfg!(windows));
+
+        let err = HomeDirError::new(HomeDirErrorKind::Os, None);
+        assert_eq!(
+            err.to_string(),
+            "error looking home directory of the current user up: operating system error"
+        );
+        assert_eq!(err.hint(), None);
+
+        let err = HomeDirError::new(HomeDirErrorKind::Unimplemented, Some("user"));
+        assert_eq!(
+            err.to_string(),
+            "error looking home directory of user 'user' up: not supported on this platform"
+        );
+        assert_eq!(err.hint().is_some(), cfg!(unix));
+    }
+
+    #[test]
+    fn test_home_dir_with_fallback() {
+        let var_os = |name: &str| match name {
+            "HOME" => Some(OsString::from("/env/home")),
+            "USERPROFILE" => Some(OsString::from("C:\\Users\\env")),
+            _ => None,
+        };
+        let expected = if cfg!(windows) {
+            "C:\\Users\\env"
+        } else {
+            "/env/home"
+        };
+        assert_eq!(
+            home_dir_with_fallback(|| None, var_os),
+            Some(PathBuf::from(expected))
+        );
+        assert_eq!(
+            home_dir_with_fallback(|| Some(PathBuf::from("/system/home")), var_os),
+            Some(PathBuf::from("/system/home"))
+        );
+
+        let empty = |_: &str| Some(OsString::new());
+        assert_eq!(home_dir_with_fallback(|| None, empty), None);
+        assert_eq!(home_dir_with_fallback(|| None, |_| None), None);
+    }
+
+    #[test]
+    fn test_try_home_dir() {
+        let err = current_home_dir(home_dir_with_fallback(|| None, |_| None)).unwrap_err();
+        assert_eq!(err.kind(), HomeDirErrorKind::NotFound);
+        assert_eq!(err.user(), None);
+
+        let hd = home_dir_with_fallback(|| Some(PathBuf::from("/system/home")), |_| None);
+        assert_eq!(current_home_dir(hd).unwrap(), PathBuf::from("/system/home"));
+
+        let _lock = HOME_DIR_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(hd) = home_dir() {
+            assert_eq!(try_home_dir(None).unwrap(), hd);
+        }
+    }
+
+    #[cfg(feature = "test-home-dir")]
+    #[test]
+    fn test_set_test_home_dir() {
+        use super::{set_test_home_dir, system_home_dir};
+        use std::env;
+
+        // clears the override even if an assertion below fails
+        struct Reset;
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                set_test_home_dir(None);
+            }
+        }
+
+        let _lock = HOME_DIR_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let _reset = Reset;
+
+        set_test_home_dir(Some(PathBuf::from("/test/home")));
+        assert_eq!(home_dir(), Some(PathBuf::from("/test/home")));
+        assert_eq!(try_home_dir(None).unwrap(), PathBuf::from("/test/home"));
+        assert_eq!(::tilde("~"), "/test/home");
+        assert_eq!(::tilde("~/x"), "/test/home/x");
+
+        set_test_home_dir(None);
+        let system = home_dir_with_fallback(system_home_dir, |name| env::var_os(name));
+        assert_eq!(home_dir(), system);
+        match system {
+            Some(hd) => assert_eq!(::tilde("~/x"), format!("{}/x", hd.display())),
+            None => assert_eq!(::tilde("~/x"), "~/x"),
+        }
+    }
+
+    #[cfg(all(unix, feature = "libc"))]
+    #[test]
+    fn test_try_home_dir_libc() {
+        let hd = try_home_dir(Some("root")).unwrap();
+        assert!(hd.is_absolute(), "{:?}", hd);
+
+        let err = try_home_dir(Some("surely-nonexisting-user")).unwrap_err();
+        assert_eq!(err.kind(), HomeDirErrorKind::NotFound);
+        assert_eq!(err.user(), Some("surely-nonexisting-user"));
+    }
+
+    #[cfg(not(all(unix, feature = "libc")))]
+    #[test]
+    fn test_try_home_dir_unimplemented() {
+        let err = try_home_dir(Some("root")).unwrap_err();
+        assert_eq!(err.kind(), HomeDirErrorKind::Unimplemented);
+        assert_eq!(err.user(), Some("root"));
+    }
+
+    #[cfg(feature = "home")]
+    #[test]
+    fn test_system_home_dir_from_home_crate() {
+        assert_eq!(super::system_home_dir(), ::home_crate::home_dir());
+    }
+}
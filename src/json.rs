@@ -0,0 +1,161 @@
+//! Contexts backed by JSON objects.
+
+use std::borrow::Cow;
+
+use serde_json::{Map, Value};
+
+use dotted::{self, Nested};
+
+/// Creates a context which looks variables up in a JSON object.
+///
+/// Values of the returned context are taken from the members of `object` with the same names as
+/// the variables. String members are used as they are, while numbers and booleans are converted
+/// to strings with their JSON representation, like `42`, `1.5` or `true`. Members which are
+/// `null`, arrays or objects, as well as missing members, are treated as unset variables, so the
+/// references to them are left as they are. The context never fails, so it is meant to be used
+/// with `env_with_context_no_errors()` or `full_with_context_no_errors()`.
+///
+/// Names containing dots are paths into nested values: `${database.host}` refers to the `host`
+/// member of the `database` object, and `${servers.0}` to the first element of the `servers`
+/// array. A member whose name contains the dots literally takes precedence over the path. The
+/// same rules as above apply to the value at the end of the path, and if any part of the path is
+/// missing, the variable is treated as unset. Since bare references cannot contain dots, paths
+/// have to be written in braces.
+///
+/// This function is only available with the `serde_json` feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate serde_json;
+/// # extern crate shellexpand;
+///
+/// # fn main() {
+/// let config = serde_json::json!({
+///     "host": "localhost",
+///     "port": 8080,
+///     "tls": false,
+///     "paths": ["/a", "/b"],
+/// });
+///
+/// let context = shellexpand::json_context(config.as_object().unwrap());
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("$host:$port/?tls=$tls&$paths", &context),
+///     "localhost:8080/?tls=false&$paths"
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("${paths.1}/${paths.2}", &context),
+///     "/b/${paths.2}"
+/// );
+/// # }
+/// ```
+pub fn json_context<'a>(
+    object: &'a Map<String, Value>,
+) -> impl Fn(&str) -> Option<Cow<'a, str>> + 'a {
+    move |s| match dotted::lookup(|key| object.get(key), s)? {
+        Value::String(ref value) => Some(value.as_str().into()),
+        Value::Number(ref value) => Some(value.to_string().into()),
+        Value::Bool(value) => Some(value.to_string().into()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+impl Nested for Value {
+    fn member(&self, key: &str) -> Option<&Value> {
+        self.as_object()?.get(key)
+    }
+
+    fn element(&self, index: usize) -> Option<&Value> {
+        self.as_array()?.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value};
+
+    use super::json_context;
+    use env_with_context_no_errors;
+
+    #[test]
+    fn test_json_context() {
+        let value: Value = ::serde_json::from_str(
+            r#"{
+                "name": "value",
+                "empty": "",
+                "int": 42,
+                "negative": -7,
+                "float": 1.5,
+                "yes": true,
+                "nothing": null,
+                "list": [1, 2],
+                "nested": {"name": "inner"}
+            }"#,
+        )
+        .unwrap();
+        let object: &Map<String, Value> = value.as_object().unwrap();
+
+        let context = json_context(object);
+        assert_eq!(context("name").as_deref(), Some("value"));
+        assert_eq!(context("empty").as_deref(), Some(""));
+        assert_eq!(context("int").as_deref(), Some("42"));
+        assert_eq!(context("negative").as_deref(), Some("-7"));
+        assert_eq!(context("float").as_deref(), Some("1.5"));
+        assert_eq!(context("yes").as_deref(), Some("true"));
+        assert_eq!(context("nothing"), None);
+        assert_eq!(context("list"), None);
+        assert_eq!(context("nested"), None);
+        assert_eq!(context("missing"), None);
+
+        assert_eq!(
+            env_with_context_no_errors("$name/${int}/${missing:-default}/$nested", &context),
+            "value/42/default/$nested"
+        );
+    }
+
+    #[test]
+    fn test_dotted_paths() {
+        let value: Value = ::serde_json::from_str(
+            r#"{
+                "database": {"host": "db.local", "port": 5432, "auth": {"user": "admin"}},
+                "servers": ["alpha", {"name": "beta"}],
+                "dotted.key": "literal",
+                "dotted": {"key": "nested"},
+                "name": "value"
+            }"#,
+        )
+        .unwrap();
+        let context = json_context(value.as_object().unwrap());
+
+        assert_eq!(context("database.host").as_deref(), Some("db.local"));
+        assert_eq!(context("database.port").as_deref(), Some("5432"));
+        assert_eq!(context("database.auth.user").as_deref(), Some("admin"));
+        assert_eq!(context("servers.0").as_deref(), Some("alpha"));
+        assert_eq!(context("servers.1.name").as_deref(), Some("beta"));
+        assert_eq!(context("dotted.key").as_deref(), Some("literal"));
+
+        // missing intermediate keys
+        assert_eq!(context("missing.host"), None);
+        assert_eq!(context("database.missing.user"), None);
+        assert_eq!(context("servers.5"), None);
+        assert_eq!(context("servers.x"), None);
+        assert_eq!(context("name.inner"), None);
+        assert_eq!(context("database."), None);
+
+        // non-scalar leaves
+        assert_eq!(context("database.auth"), None);
+        assert_eq!(context("servers"), None);
+
+        assert_eq!(
+            env_with_context_no_errors(
+                "${database.host}:${database.port}/$database.host",
+                &context
+            ),
+            "db.local:5432/$database.host"
+        );
+        assert_eq!(
+            env_with_context_no_errors("${database.auth:-none}", &context),
+            "none"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Expansion with variables from both the environment and a configuration file.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use {full_with_context, home, LookupError};
+
+/// Which source of variables wins in `full_layered()` when a variable is defined in both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Precedence {
+    /// Variables of the process environment override the ones in the configuration file.
+    EnvOverFile,
+    /// Variables in the configuration file override the ones of the process environment.
+    FileOverEnv,
+}
+
+/// An error which occurred while expanding a string with `full_layered()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LayeredError {
+    /// The configuration file could not be read.
+    Io(io::Error),
+    /// A line of the configuration file is neither a `KEY=VALUE` pair, nor a comment, nor empty.
+    Syntax {
+        /// The number of the offending line, starting from 1.
+        line: usize,
+    },
+    /// A variable lookup failed.
+    Lookup(LookupError<VarError>),
+}
+
+impl From<io::Error> for LayeredError {
+    fn from(e: io::Error) -> LayeredError {
+        LayeredError::Io(e)
+    }
+}
+
+impl From<LookupError<VarError>> for LayeredError {
+    fn from(e: LookupError<VarError>) -> LayeredError {
+        LayeredError::Lookup(e)
+    }
+}
+
+impl fmt::Display for LayeredError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayeredError::Io(ref e) => write!(f, "I/O error: {}", e),
+            LayeredError::Syntax { line } => {
+                write!(f, "line {}: expected a `KEY=VALUE` pair", line)
+            }
+            LayeredError::Lookup(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for LayeredError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            LayeredError::Io(ref e) => Some(e),
+            LayeredError::Syntax { .. } => None,
+            LayeredError::Lookup(ref e) => Some(e),
+        }
+    }
+}
+
+/// Performs both tilde and environment expansions, looking variables up in both the process
+/// environment and a configuration file.
+///
+/// The configuration file at `config_path` consists of `KEY=VALUE` lines. Whitespace around
+/// keys is ignored, while values are taken as they are, up to the end of the line, and are not
+/// expanded themselves. Empty lines and lines starting with `#` are skipped; any other line
+/// without `=` makes the whole file invalid.
+///
+/// Variables which are defined in both sources are taken from the one which has precedence
+/// according to `precedence`, and variables which are defined in neither of them cause
+/// a `VarError::NotPresent` lookup error, like in `full()`. The tilde is expanded to the home
+/// directory of the current user, also like in `full()`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use shellexpand::Precedence;
+///
+/// let dir = shellexpand::full_layered("~/${APP_DIR}", "app.conf", Precedence::EnvOverFile)
+///     .unwrap();
+/// ```
+pub fn full_layered<SI, PC>(
+    input: &SI,
+    config_path: PC,
+    precedence: Precedence,
+) -> Result<Cow<'_, str>, LayeredError>
+where
+    SI: AsRef<str> + ?Sized,
+    PC: AsRef<Path>,
+{
+    let config = fs::read_to_string(config_path)?;
+    let file_vars = parse_config(&config)?;
+    let env_var = |name: &str| match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    let context = |name: &str| {
+        let file_var = || file_vars.get(name).map(|&value| value.to_owned());
+        // the other source is only consulted if the preferred one does not define the variable
+        let value = match precedence {
+            Precedence::EnvOverFile => env_var(name)?.or_else(file_var),
+            Precedence::FileOverEnv => match file_var() {
+                Some(value) => Some(value),
+                None => env_var(name)?,
+            },
+        };
+        value.map(Some).ok_or(VarError::NotPresent)
+    };
+    Ok(full_with_context(input, home::home_dir, context)?)
+}
+
+/// Parses the `KEY=VALUE` lines of a configuration file for `full_layered()`.
+fn parse_config(config: &str) -> Result<HashMap<&str, &str>, LayeredError> {
+    let mut vars = HashMap::new();
+    for (idx, line) in config.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        match line.find('=') {
+            Some(eq_idx) => vars.insert(line[..eq_idx].trim(), &line[eq_idx + 1..]),
+            None => return Err(LayeredError::Syntax { line: idx + 1 }),
+        };
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::{self, VarError};
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{full_layered, LayeredError, Precedence};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("shellexpand-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_full_layered() {
+        let config = temp_path("layered.conf");
+        fs::write(
+            &config,
+            "# application settings\n\
+             SHELLEXPAND_LAYERED_FILE=from file\n\
+             \n\
+             SHELLEXPAND_LAYERED_BOTH =file value\r\n\
+             SHELLEXPAND_LAYERED_EQ=a=b\n",
+        )
+        .unwrap();
+        env::set_var("SHELLEXPAND_LAYERED_ENV", "from env");
+        env::set_var("SHELLEXPAND_LAYERED_BOTH", "env value");
+        env::remove_var("SHELLEXPAND_LAYERED_FILE");
+        env::remove_var("SHELLEXPAND_LAYERED_NONE");
+
+        for &precedence in &[Precedence::EnvOverFile, Precedence::FileOverEnv] {
+            let expand = |s| full_layered(s, &config, precedence).unwrap();
+            assert_eq!(expand("$SHELLEXPAND_LAYERED_FILE"), "from file");
+            assert_eq!(expand("${SHELLEXPAND_LAYERED_ENV}/x"), "from env/x");
+            assert_eq!(expand("$SHELLEXPAND_LAYERED_EQ"), "a=b");
+            assert_eq!(expand("${SHELLEXPAND_LAYERED_NONE:-default}"), "default");
+
+            match full_layered("$SHELLEXPAND_LAYERED_NONE", &config, precedence) {
+                Err(LayeredError::Lookup(e)) => {
+                    assert_eq!(e.var_name, "SHELLEXPAND_LAYERED_NONE");
+                    assert_eq!(e.cause, VarError::NotPresent);
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        assert_eq!(
+            full_layered(
+                "$SHELLEXPAND_LAYERED_BOTH",
+                &config,
+                Precedence::EnvOverFile
+            )
+            .unwrap(),
+            "env value"
+        );
+        assert_eq!(
+            full_layered(
+                "$SHELLEXPAND_LAYERED_BOTH",
+                &config,
+                Precedence::FileOverEnv
+            )
+            .unwrap(),
+            "file value"
+        );
+
+        fs::remove_file(&config).unwrap();
+    }
+
+    #[test]
+    fn test_full_layered_errors() {
+        let config = temp_path("invalid.conf");
+        fs::write(&config, "A=a\n# comment\nnot a pair\n").unwrap();
+        match full_layered("$A", &config, Precedence::EnvOverFile) {
+            Err(LayeredError::Syntax { line: 3 }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&config).unwrap();
+
+        match full_layered("$A", temp_path("missing.conf"), Precedence::EnvOverFile) {
+            Err(LayeredError::Io(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -9,7 +9,11 @@
 //!
 //! Environment expansion also supports default values with the familiar shell syntax,
 //! so for example `${UNSET_ENV:-42}` will use the specified default value, i.e. `42`, if
-//! the `UNSET_ENV` variable is not set in the environment.
+//! the `UNSET_ENV` variable is not set in the environment. Similarly, `${SET_ENV:+42}` will
+//! expand to `42` only if `SET_ENV` is set, and to an empty string otherwise. The values of set
+//! variables may be transformed with the pattern-matching operators: `${FILE%.*}` removes the
+//! extension from the value of `FILE`, `${PATH##*/}` keeps only its last component and
+//! `${VAR//a/b}` replaces every `a` in it with `b`.
 //!
 //! The source of external information for these expansions (home directory and environment
 //! variables) is called their *context*. The context is provided to these functions as a closure
@@ -51,13 +55,11 @@
 //! // make sure that the variable indeed does not exist
 //! env::remove_var("MOST_LIKELY_NONEXISTING_VAR");
 //!
-//! assert_eq!(
-//!     shellexpand::env("$MOST_LIKELY_NONEXISTING_VAR"),
-//!     Err(shellexpand::LookupError {
-//!         var_name: "MOST_LIKELY_NONEXISTING_VAR".into(),
-//!         cause: env::VarError::NotPresent
-//!     })
-//! );
+//! let err = shellexpand::env("$MOST_LIKELY_NONEXISTING_VAR").unwrap_err();
+//! assert_eq!(err.var_name, "MOST_LIKELY_NONEXISTING_VAR");
+//! assert_eq!(err.offset(), 0);
+//! assert_eq!(err.kind(), shellexpand::ReferenceKind::Bare);
+//! assert_eq!(err.cause, env::VarError::NotPresent);
 //! ```
 //!
 //! The author thinks that this approach is more useful than just substituting an empty string
@@ -87,14 +89,127 @@
 //!
 //! The above example also demonstrates the flexibility of context function signatures: the context
 //! function may return anything which can be `AsRef`ed into a string slice.
+//!
+//! # Cargo features
+//!
+//! By default, the home directory of the current user is obtained with `dirs::home_dir()` from the
+//! [dirs-next](https://crates.io/crates/dirs-next) crate. On Unix, the `dirs-next` dependency may
+//! be dropped by disabling the default features and enabling the `libc` feature instead. In this
+//! case the home directory is taken from the `HOME` environment variable or, if it is not set,
+//! from the password database via `getpwuid_r()`. The `libc` feature also enables looking up home
+//! directories of other users for the `~user` syntax in `tilde_user()` and `full_user()`.
+//!
+//! Alternatively, the optional `home` feature makes the home directory of the current user be
+//! obtained with `home::home_dir()` from the [home](https://crates.io/crates/home) crate, which is
+//! what Cargo and rustup use. It takes precedence over `dirs-next` if both are enabled, and does
+//! not affect the `~user` syntax.
+//!
+//! Whichever source is used, if it does not know the home directory, the non-empty `HOME`
+//! environment variable is used as a fallback, preceded by `USERPROFILE` on Windows.
+//!
+//! The `test-home-dir` feature enables `set_test_home_dir()`, which overrides the home directory
+//! used by the functions with the default system context. It is meant only for tests.
+//!
+//! The optional `regex` feature allows using regular expressions instead of glob patterns in the
+//! pattern-matching operators, see `Expander::pattern_syntax()`.
+//!
+//! The default `dirs-next` feature also enables `with_user_dirs()`, a context which resolves
+//! pseudo-variables like `${CONFIG_DIR}` into common user directories.
+//!
+//! The optional `serde_json` feature enables `json_context()`, a context which looks variables up
+//! in a [serde_json](https://crates.io/crates/serde_json) object.
+//!
+//! The optional `glob` feature enables `full_glob()` and `full_glob_with_context()`, which match
+//! the result of the expansion against the file system with the
+//! [glob](https://crates.io/crates/glob) crate.
+//!
+//! The optional `toml` feature enables `full_toml()` and `full_toml_with_context()`, which expand
+//! all strings inside a [toml](https://crates.io/crates/toml) value, and `toml_context()`,
+//! a context which looks variables up in a TOML table.
+//!
+//! The optional `unicode-normalization` feature enables `full_nfc()` and
+//! `full_nfc_with_context()`, which normalize the result of the expansion to the Unicode
+//! Normalization Form C with the
+//! [unicode-normalization](https://crates.io/crates/unicode-normalization) crate.
 
+#[cfg(feature = "dirs-next")]
 extern crate dirs_next as dirs;
+#[cfg(feature = "glob")]
+extern crate glob;
+#[cfg(feature = "home")]
+extern crate home as home_crate;
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "unicode-normalization")]
+extern crate unicode_normalization;
+
+#[cfg(test)]
+extern crate proptest;
 
-use std::borrow::Cow;
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
 use std::env::VarError;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::path::Path;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+mod args;
+#[cfg(any(feature = "serde_json", feature = "toml"))]
+mod dotted;
+mod file;
+#[cfg(feature = "glob")]
+mod glob_paths;
+mod home;
+#[cfg(feature = "serde_json")]
+mod json;
+mod layered;
+#[cfg(feature = "unicode-normalization")]
+mod nfc;
+mod observer;
+mod path_list;
+mod pattern;
+mod resolve;
+mod source_map;
+mod stream;
+#[cfg(feature = "toml")]
+mod toml_value;
+mod write;
+
+pub use args::expand_args;
+pub use file::{expand_file, ExpandFileError};
+#[cfg(feature = "glob")]
+pub use glob_paths::{full_glob, full_glob_with_context, ExpandGlobError};
+#[cfg(feature = "test-home-dir")]
+pub use home::set_test_home_dir;
+pub use home::{can_resolve_named_users, try_home_dir, HomeDirError, HomeDirErrorKind};
+#[cfg(feature = "serde_json")]
+pub use json::json_context;
+pub use layered::{full_layered, LayeredError, Precedence};
+#[cfg(feature = "unicode-normalization")]
+pub use nfc::{full_nfc, full_nfc_with_context};
+pub use observer::ExpansionObserver;
+pub use path_list::{full_path_list, full_path_list_with_context};
+pub use pattern::PatternSyntax;
+pub use resolve::{resolve_map, CycleError};
+pub use source_map::{env_with_source_map, SourceKind, SourceMap};
+pub use stream::{expand_stream, ExpandStreamError};
+#[cfg(feature = "toml")]
+pub use toml_value::{full_toml, full_toml_with_context, toml_context, TomlError};
+pub use write::{env_write, ExpandWriteError};
+
+use observer::OnUnresolved;
+use pattern::Pattern;
 
 /// Performs both tilde and environment expansion using the provided contexts.
 ///
@@ -112,6 +227,16 @@ use std::path::Path;
 /// with tilde, the naive approach may result into expansion of this tilde. This function
 /// avoids this.
 ///
+/// Operators like `${VAR:-default}` work here in the same way as in `env_with_context()`, and
+/// the same rule applies to their words: tilde expansion is only performed for a `~` which is
+/// written literally at the beginning of the input. Therefore `~/${DIR:-default}/file` expands to
+/// the home directory followed by `/default/file` if `DIR` is not set, but `${HOMEISH:-~}/x`
+/// expands to just `~/x`, because the tilde comes from the default value. Note that this differs
+/// from shells, which perform tilde expansion inside such words.
+///
+/// The `home_dir` function is called at most once, and only if there is a tilde to expand, so it
+/// is safe for it to have side effects or to be expensive.
+///
 /// # Examples
 ///
 /// ```
@@ -137,13 +262,11 @@ use std::path::Path;
 /// );
 ///
 /// // Errors from environment expansion are propagated to the result
-/// assert_eq!(
-///     shellexpand::full_with_context("~/$E/something", home_dir, get_env),
-///     Err(shellexpand::LookupError {
-///         var_name: "E".into(),
-///         cause: "some error"
-///     })
-/// );
+/// let err = shellexpand::full_with_context("~/$E/something", home_dir, get_env).unwrap_err();
+/// assert_eq!(err.var_name, "E");
+/// assert_eq!(err.offset(), 2);
+/// assert_eq!(err.kind(), shellexpand::ReferenceKind::Bare);
+/// assert_eq!(err.cause, "some error");
 ///
 /// // Input without starting tilde and without variables does not cause allocations
 /// let s = shellexpand::full_with_context("some/path", home_dir, get_env);
@@ -159,37 +282,104 @@ use std::path::Path;
 ///     "~/a value/b value"
 /// );
 /// ```
-pub fn full_with_context<SI: ?Sized, CO, C, E, P, HD>(
+pub fn full_with_context<SI, CO, C, E, P, HD>(
     input: &SI,
     home_dir: HD,
     context: C,
-) -> Result<Cow<str>, LookupError<E>>
+) -> Result<Cow<'_, str>, LookupError<E>>
 where
-    SI: AsRef<str>,
+    SI: AsRef<str> + ?Sized,
     CO: AsRef<str>,
     C: FnMut(&str) -> Result<Option<CO>, E>,
     P: AsRef<Path>,
     HD: FnOnce() -> Option<P>,
 {
-    env_with_context(input, context).map(|r| match r {
-        // variable expansion did not modify the original string, so we can apply tilde expansion
-        // directly
-        Cow::Borrowed(s) => tilde_with_context(s, home_dir),
-        Cow::Owned(s) => {
-            // if the original string does not start with a tilde but the processed one does,
-            // then the tilde is contained in one of variables and should not be expanded
-            if !input.as_ref().starts_with("~") && s.starts_with("~") {
-                // return as is
-                s.into()
-            } else {
-                if let Cow::Owned(s) = tilde_with_context(&s, home_dir) {
-                    s.into()
-                } else {
-                    s.into()
-                }
-            }
+    Expander::new()
+        .full_with_context(input, home_dir, context)
+        .map_err(ExpandError::into_lookup_error)
+}
+
+/// Performs both tilde and environment expansion using the provided contexts, failing if the
+/// home directory cannot be looked up.
+///
+/// This function works like `full_with_context()`, except that the `home_dir` function may
+/// return an error, like the one passed to `tilde_with_context_reporting()`. The result is
+/// a `FullError`, which tells lookup errors of variables and of the home directory apart. As in
+/// `full_with_context()`, `home_dir` is only called if there is a tilde to expand, so its errors
+/// are only returned in this case, and if it returns `Ok(None)`, the tilde is left as it is.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::PathBuf;
+/// use shellexpand::{FullError, HomeDirError, HomeDirErrorKind};
+///
+/// fn home_dir() -> Result<Option<PathBuf>, HomeDirError> {
+///     Err(HomeDirError::new(HomeDirErrorKind::NotFound, None))
+/// }
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         _ => Err("unknown variable"),
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_with_context_reporting("$A/x", home_dir, context).unwrap(),
+///     "a value/x"
+/// );
+/// match shellexpand::full_with_context_reporting("~/$A", home_dir, context) {
+///     Err(FullError::HomeDir(e)) => assert_eq!(e.kind(), HomeDirErrorKind::NotFound),
+///     _ => unreachable!(),
+/// }
+/// match shellexpand::full_with_context_reporting("~/$B", home_dir, context) {
+///     Err(FullError::Lookup(e)) => assert_eq!(e.var_name, "B"),
+///     _ => unreachable!(),
+/// }
+/// ```
+pub fn full_with_context_reporting<SI, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Cow<'_, str>, FullError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Result<Option<P>, HomeDirError>,
+{
+    let mut home_dir_error = None;
+    let home_dir = || match home_dir() {
+        Ok(hd) => hd,
+        Err(e) => {
+            home_dir_error = Some(e);
+            None
         }
-    })
+    };
+    let result = full_with_context(input, home_dir, context)?;
+    match home_dir_error {
+        Some(e) => Err(FullError::HomeDir(e)),
+        None => Ok(result),
+    }
+}
+
+/// Performs both tilde and environment expansions in the default system context, failing if the
+/// home directory cannot be looked up.
+///
+/// This function delegates to `full_with_context_reporting()`, using the same contexts as
+/// `full()`, except that a missing home directory of the current user is an error.
+#[inline]
+pub fn try_full<SI>(input: &SI) -> Result<Cow<'_, str>, FullError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    full_with_context_reporting(
+        input,
+        || home::try_home_dir(None).map(Some),
+        |s| std::env::var(s).map(Some),
+    )
 }
 
 /// Same as `full_with_context()`, but forbids the variable lookup function to return errors.
@@ -198,7 +388,8 @@ where
 /// `env_with_context_no_errors()` for environment expansion whose context lookup function returns
 /// just `Option<CO>` instead of `Result<Option<CO>, E>`. Therefore, the function itself also
 /// returns just `Cow<str>` instead of `Result<Cow<str>, LookupError<E>>`. Otherwise it is
-/// identical to `full_with_context()`.
+/// identical to `full_with_context()`; in particular, all operators are supported in the same way
+/// as in `env_with_context_no_errors()`.
 ///
 /// # Examples
 ///
@@ -238,22 +429,19 @@ where
 /// );
 /// ```
 #[inline]
-pub fn full_with_context_no_errors<SI: ?Sized, CO, C, P, HD>(
+pub fn full_with_context_no_errors<SI, CO, C, P, HD>(
     input: &SI,
     home_dir: HD,
-    mut context: C,
-) -> Cow<str>
+    context: C,
+) -> Cow<'_, str>
 where
-    SI: AsRef<str>,
+    SI: AsRef<str> + ?Sized,
     CO: AsRef<str>,
     C: FnMut(&str) -> Option<CO>,
     P: AsRef<Path>,
     HD: FnOnce() -> Option<P>,
 {
-    match full_with_context(input, home_dir, move |s| Ok::<Option<CO>, ()>(context(s))) {
-        Ok(result) => result,
-        Err(_) => unreachable!(),
-    }
+    Expander::new().full_with_context_no_errors(input, home_dir, context)
 }
 
 /// Performs both tilde and environment expansions in the default system context.
@@ -289,515 +477,5131 @@ where
 /// );
 ///
 /// // Unknown variables cause expansion errors
-/// assert_eq!(
-///     shellexpand::full("~/$UNKNOWN/$B"),
-///     Err(shellexpand::LookupError {
-///         var_name: "UNKNOWN".into(),
-///         cause: env::VarError::NotPresent
-///     })
-/// );
+/// let err = shellexpand::full("~/$UNKNOWN/$B").unwrap_err();
+/// assert_eq!(err.var_name, "UNKNOWN");
+/// assert_eq!(err.offset(), 2);
+/// assert_eq!(err.kind(), shellexpand::ReferenceKind::Bare);
+/// assert_eq!(err.cause, env::VarError::NotPresent);
 /// ```
 #[inline]
-pub fn full<SI: ?Sized>(input: &SI) -> Result<Cow<str>, LookupError<VarError>>
+pub fn full<SI>(input: &SI) -> Result<Cow<'_, str>, LookupError<VarError>>
 where
-    SI: AsRef<str>,
+    SI: AsRef<str> + ?Sized,
 {
-    full_with_context(input, dirs::home_dir, |s| std::env::var(s).map(Some))
-}
-
-/// Represents a variable lookup error.
-///
-/// This error is returned by `env_with_context()` function (and, therefore, also by `env()`,
-/// `full_with_context()` and `full()`) when the provided context function returns an error. The
-/// original error is provided in the `cause` field, while `name` contains the name of a variable
-/// whose expansion caused the error.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct LookupError<E> {
-    /// The name of the problematic variable inside the input string.
-    pub var_name: String,
-    /// The original error returned by the context function.
-    pub cause: E,
-}
-
-impl<E: fmt::Display> fmt::Display for LookupError<E> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "error looking key '{}' up: {}",
-            self.var_name, self.cause
-        )
-    }
-}
-
-impl<E: Error + 'static> Error for LookupError<E> {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        Some(&self.cause)
-    }
-}
-
-macro_rules! try_lookup {
-    ($name:expr, $e:expr) => {
-        match $e {
-            Ok(s) => s,
-            Err(e) => {
-                return Err(LookupError {
-                    var_name: $name.into(),
-                    cause: e,
-                })
-            }
-        }
-    };
-}
-
-fn is_valid_var_name_char(c: char) -> bool {
-    c.is_alphanumeric() || c == '_'
+    full_with_context(input, home::home_dir, |s| std::env::var(s).map(Some))
 }
 
-/// Performs the environment expansion using the provided context.
-///
-/// This function walks through the input string `input` and attempts to construct a new string by
-/// replacing all shell-like variable sequences with the corresponding values obtained via the
-/// `context` function. The latter may return an error; in this case the error will be returned
-/// immediately, along with the name of the offending variable. Also the context function may
-/// return `Ok(None)`, indicating that the given variable is not available; in this case the
-/// variable sequence is left as it is in the output string.
-///
-/// The syntax of variables resembles the one of bash-like shells: all of `$VAR`, `${VAR}`,
-/// `$NAME_WITH_UNDERSCORES` are valid variable references, and the form with braces may be used to
-/// separate the reference from the surrounding alphanumeric text: `before${VAR}after`. Note,
-/// however, that for simplicity names like `$123` or `$1AB` are also valid, as opposed to shells
-/// where `$<number>` has special meaning of positional arguments. Also note that "alphanumericity"
-/// of variable names is checked with `char::is_alphanumeric()`, therefore lots of characters which
-/// are considered alphanumeric by the Unicode standard are also valid names for variables. When
-/// unsure, use braces to separate variables from the surrounding text.
-///
-/// This function has four generic type parameters: `SI` represents the input string, `CO` is the
-/// output of context lookups, `C` is the context closure and `E` is the type of errors returned by
-/// the context function. `SI` and `CO` must be types, a references to which can be converted to
-/// a string slice. For example, it is fine for the context function to return `&str`s, `String`s or
-/// `Cow<str>`s, which gives the user a lot of flexibility.
+/// Performs both tilde and environment expansions in the default system context, never failing.
 ///
-/// If the context function returns an error, it will be wrapped into `LookupError` and returned
-/// immediately. `LookupError`, besides the original error, also contains a string with the name of
-/// the variable whose expansion caused the error. `LookupError` implements `Error`, `Clone` and
-/// `Eq` traits for further convenience and interoperability.
+/// This function uses the same default system sources as `full()`, namely `dirs::home_dir()` and
+/// `std::env::var()`, but instead of returning an error it leaves references to variables which
+/// cannot be looked up as they are. This includes both variables which are not set and variables
+/// whose values are not valid Unicode. Default values, like in `${UNSET:-default}`, are still
+/// applied in both cases.
 ///
-/// If you need to expand system environment variables, you can use `env()` or `full()` functions.
-/// If your context does not have errors, you may use `env_with_context_no_errors()` instead of
-/// this function because it provides a simpler API.
+/// Use this function when all you need is "expand whatever can be expanded".
 ///
 /// # Examples
 ///
 /// ```
-/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
-///     match s {
-///         "A" => Ok(Some("a value")),
-///         "B" => Ok(Some("b value")),
-///         "E" => Err("something went wrong"),
-///         _ => Ok(None)
-///     }
-/// }
-///
-/// // Regular variables are expanded
-/// assert_eq!(
-///     shellexpand::env_with_context("begin/$A/${B}s/end", context).unwrap(),
-///     "begin/a value/b values/end"
-/// );
+/// extern crate dirs_next as dirs;
+/// use std::env;
 ///
-/// // Expand to a default value if the variable is not defined
-/// assert_eq!(
-///     shellexpand::env_with_context("begin/${UNSET_ENV:-42}/end", context).unwrap(),
-///     "begin/42/end"
-/// );
+/// env::set_var("A", "a value");
+/// env::remove_var("MOST_LIKELY_NONEXISTING_VAR");
 ///
-/// // Unknown variables are left as is
-/// assert_eq!(
-///     shellexpand::env_with_context("begin/$UNKNOWN/end", context).unwrap(),
-///     "begin/$UNKNOWN/end"
-/// );
+/// let home_dir = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
 ///
-/// // Errors are propagated
 /// assert_eq!(
-///     shellexpand::env_with_context("begin${E}end", context),
-///     Err(shellexpand::LookupError {
-///         var_name: "E".into(),
-///         cause: "something went wrong"
-///     })
+///     shellexpand::full_lossy("~/$A/$MOST_LIKELY_NONEXISTING_VAR"),
+///     format!("{}/a value/$MOST_LIKELY_NONEXISTING_VAR", home_dir)
 /// );
 /// ```
-pub fn env_with_context<SI: ?Sized, CO, C, E>(
-    input: &SI,
-    mut context: C,
-) -> Result<Cow<str>, LookupError<E>>
+#[inline]
+pub fn full_lossy<SI>(input: &SI) -> Cow<'_, str>
 where
-    SI: AsRef<str>,
-    CO: AsRef<str>,
-    C: FnMut(&str) -> Result<Option<CO>, E>,
+    SI: AsRef<str> + ?Sized,
 {
-    let input_str = input.as_ref();
-    if let Some(idx) = input_str.find('$') {
-        let mut result = String::with_capacity(input_str.len());
-
-        let mut input_str = input_str;
-        let mut next_dollar_idx = idx;
-        loop {
-            result.push_str(&input_str[..next_dollar_idx]);
-
-            input_str = &input_str[next_dollar_idx..];
-            if input_str.is_empty() {
-                break;
-            }
-
-            fn find_dollar(s: &str) -> usize {
-                s.find('$').unwrap_or(s.len())
-            }
-
-            let next_char = input_str[1..].chars().next();
-            if next_char == Some('{') {
-                match input_str.find('}') {
-                    Some(closing_brace_idx) => {
-                        let mut default_value = None;
-
-                        // Search for the default split
-                        let var_name_end_idx = match input_str[..closing_brace_idx].find(":-") {
-                            // Only match if there's a variable name, ie. this is not valid ${:-value}
-                            Some(default_split_idx) if default_split_idx != 2 => {
-                                default_value =
-                                    Some(&input_str[default_split_idx + 2..closing_brace_idx]);
-                                default_split_idx
-                            }
-                            _ => closing_brace_idx,
-                        };
-
-                        let var_name = &input_str[2..var_name_end_idx];
-                        match context(var_name) {
-                            // if we have the variable set to some value
-                            Ok(Some(var_value)) => {
-                                result.push_str(var_value.as_ref());
-                                input_str = &input_str[closing_brace_idx + 1..];
-                                next_dollar_idx = find_dollar(input_str);
-                            }
-
-                            // if the variable is set and empty or unset
-                            not_found_or_empty => {
-                                let value = match (not_found_or_empty, default_value) {
-                                    // return an error if we don't have a default and the variable is unset
-                                    (Err(err), None) => {
-                                        return Err(LookupError {
-                                            var_name: var_name.into(),
-                                            cause: err,
-                                        });
-                                    }
-                                    // use the default value if set
-                                    (_, Some(default)) => default,
-                                    // leave the variable as it is if the environment is empty
-                                    (_, None) => &input_str[..closing_brace_idx + 1],
-                                };
-
-                                result.push_str(value);
-                                input_str = &input_str[closing_brace_idx + 1..];
-                                next_dollar_idx = find_dollar(input_str);
-                            }
-                        }
-                    }
-                    // unbalanced braces
-                    None => {
-                        result.push_str(&input_str[..2]);
-                        input_str = &input_str[2..];
-                        next_dollar_idx = find_dollar(input_str);
-                    }
-                }
-            } else if next_char.map(is_valid_var_name_char) == Some(true) {
-                let end_idx = 2 + input_str[2..]
-                    .find(|c: char| !is_valid_var_name_char(c))
-                    .unwrap_or(input_str.len() - 2);
-
-                let var_name = &input_str[1..end_idx];
-                match try_lookup!(var_name, context(var_name)) {
-                    Some(var_value) => {
-                        result.push_str(var_value.as_ref());
-                        input_str = &input_str[end_idx..];
-                        next_dollar_idx = find_dollar(input_str);
-                    }
-                    None => {
-                        result.push_str(&input_str[..end_idx]);
-                        input_str = &input_str[end_idx..];
-                        next_dollar_idx = find_dollar(input_str);
-                    }
-                }
-            } else {
-                result.push_str("$");
-                input_str = if next_char == Some('$') {
-                    &input_str[2..] // skip the next dollar for escaping
-                } else {
-                    &input_str[1..]
-                };
-                next_dollar_idx = find_dollar(input_str);
-            };
-        }
-        Ok(result.into())
-    } else {
-        Ok(input_str.into())
-    }
+    full_with_context_no_errors(input, home::home_dir, |s| std::env::var(s).ok())
 }
 
-/// Same as `env_with_context()`, but forbids the variable lookup function to return errors.
-///
-/// This function also performs environment expansion, but it requires context function of type
-/// `FnMut(&str) -> Option<CO>` instead of `FnMut(&str) -> Result<Option<CO>, E>`. This simplifies
-/// the API when you know in advance that the context lookups may not fail.
-///
-/// Because of the above, instead of `Result<Cow<str>, LookupError<E>>` this function returns just
-/// `Cow<str>`.
+/// Performs both tilde and environment expansions in the default system context, returning the
+/// result as a shared string.
 ///
-/// Note that if the context function returns `None`, the behavior remains the same as that of
-/// `env_with_context()`: the variable reference will remain in the output string unexpanded.
+/// This function works exactly like `full()`, but the result is an `Arc<str>`, which can be
+/// cloned cheaply and shared between threads, like a template expanded once when a server starts
+/// and then used by many tasks. It is only a convenience wrapper: the result of `full()` is
+/// copied into the `Arc`, so when the input is expanded this costs one more allocation and copy
+/// than using `full()` directly.
 ///
 /// # Examples
 ///
 /// ```
-/// fn context(s: &str) -> Option<&'static str> {
-///     match s {
-///         "A" => Some("a value"),
-///         "B" => Some("b value"),
-///         _ => None
-///     }
-/// }
+/// use std::env;
+/// use std::sync::Arc;
 ///
-/// // Known variables are expanded
-/// assert_eq!(
-///     shellexpand::env_with_context_no_errors("begin/$A/${B}s/end", context),
-///     "begin/a value/b values/end"
-/// );
+/// env::set_var("SHELLEXPAND_FULL_ARC_DOC", "a value");
 ///
-/// // Unknown variables are left as is
-/// assert_eq!(
-///     shellexpand::env_with_context_no_errors("begin/$U/end", context),
-///     "begin/$U/end"
-/// );
+/// let shared: Arc<str> = shellexpand::full_arc("$SHELLEXPAND_FULL_ARC_DOC/x").unwrap();
+/// assert_eq!(&*shared, "a value/x");
 /// ```
 #[inline]
-pub fn env_with_context_no_errors<SI: ?Sized, CO, C>(input: &SI, mut context: C) -> Cow<str>
+pub fn full_arc<SI>(input: &SI) -> Result<Arc<str>, LookupError<VarError>>
 where
-    SI: AsRef<str>,
-    CO: AsRef<str>,
-    C: FnMut(&str) -> Option<CO>,
+    SI: AsRef<str> + ?Sized,
 {
-    match env_with_context(input, move |s| Ok::<Option<CO>, ()>(context(s))) {
-        Ok(value) => value,
-        Err(_) => unreachable!(),
-    }
+    full(input).map(|s| Arc::from(&*s))
 }
 
-/// Performs the environment expansion using the default system context.
-///
-/// This function delegates to `env_with_context()`, using the default system source for
-/// environment variables, namely the `std::env::var()` function.
+/// Performs both tilde and environment expansions using the given home directory and the system
+/// environment.
 ///
-/// Note that variable lookup of unknown variables will fail with an error instead of, for example,
-/// replacing the offending variables with an empty string. The author thinks that such behavior is
-/// more useful than the other ones. If you need something else, use `env_with_context()` or
-/// `env_with_context_no_errors()` with an appropriate context function.
+/// This function is the same as `full()`, except that `~` is expanded into `home` instead of the
+/// home directory of the current user. Use `full_with_context()` if you need to customize the
+/// variable lookup as well.
 ///
 /// # Examples
 ///
 /// ```
 /// use std::env;
+/// use std::path::Path;
 ///
-/// // make sure that some environment variables are set
-/// env::set_var("X", "x value");
-/// env::set_var("Y", "y value");
+/// env::set_var("A", "a value");
 ///
-/// // Known variables are expanded
 /// assert_eq!(
-///     shellexpand::env("begin/$X/${Y}s/end").unwrap(),
-///     "begin/x value/y values/end"
+///     shellexpand::full_with_home("~/$A", Path::new("/home/user")).unwrap(),
+///     "/home/user/a value"
 /// );
+/// ```
+#[inline]
+pub fn full_with_home<'a, SI>(
+    input: &'a SI,
+    home: &Path,
+) -> Result<Cow<'a, str>, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    full_with_context(input, || Some(home), |s| std::env::var(s).map(Some))
+}
+
+/// Performs both tilde and environment expansions using the home directory of the current user
+/// and the given map of variables.
+///
+/// This function expands `~` like `full()` does, but looks variables up in `map` instead of the
+/// system environment. Variables which are not present in the map are left as they are, so this
+/// function never fails. Use `full_with_context_no_errors()` if you need to customize the home
+/// directory as well.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("A", "a value");
+///
+/// assert_eq!(shellexpand::full_with_map("x/$A/$B", &map), "x/a value/$B");
+/// ```
+pub fn full_with_map<'a, SI, K, V, S>(input: &'a SI, map: &HashMap<K, V, S>) -> Cow<'a, str>
+where
+    SI: AsRef<str> + ?Sized,
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<str>,
+    S: BuildHasher,
+{
+    full_with_context_no_errors(input, home::home_dir, |s| map.get(s))
+}
+
+/// Performs both tilde and environment expansions using the provided contexts, including the
+/// `~user` form.
+///
+/// This function works like `full_with_context()`, but the tilde is expanded with
+/// `tilde_user_with_context()`, so its `home_dir` context accepts `None` for the current user and
+/// `Some(user)` for named users. Since the environment expansion is performed first, the user name
+/// may come from a variable: `~$OWNER/data` expands to the `data` directory in the home of the
+/// user named by `OWNER`. As in `full_with_context()`, a tilde which itself comes from a variable
+/// is not expanded.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// fn home_dir(user: Option<&str>) -> Option<PathBuf> {
+///     match user {
+///         None => Some(Path::new("/home/user").into()),
+///         Some("alice") => Some(Path::new("/home/alice").into()),
+///         Some(_) => None,
+///     }
+/// }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, ()> {
+///     match name {
+///         "OWNER" => Ok(Some("alice")),
+///         _ => Ok(None),
+///     }
+/// }
 ///
-/// // Unknown variables result in an error
 /// assert_eq!(
-///     shellexpand::env("begin/$Z/end"),
-///     Err(shellexpand::LookupError {
-///         var_name: "Z".into(),
-///         cause: env::VarError::NotPresent
-///     })
+///     shellexpand::full_user_with_context("~${OWNER}/data", home_dir, get_env).unwrap(),
+///     "/home/alice/data"
+/// );
+/// assert_eq!(
+///     shellexpand::full_user_with_context("~/data", home_dir, get_env).unwrap(),
+///     "/home/user/data"
 /// );
 /// ```
-#[inline]
-pub fn env<SI: ?Sized>(input: &SI) -> Result<Cow<str>, LookupError<VarError>>
+pub fn full_user_with_context<SI, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Cow<'_, str>, LookupError<E>>
 where
-    SI: AsRef<str>,
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce(Option<&str>) -> Option<P>,
 {
-    env_with_context(input, |s| std::env::var(s).map(Some))
+    let input = input.as_ref();
+    let expanded = env_with_context(input, context)?;
+    Ok(match expanded {
+        Cow::Borrowed(s) => tilde_user_with_context(s, home_dir),
+        // a tilde which comes from a variable is not expanded
+        Cow::Owned(s) if !input.starts_with('~') && s.starts_with('~') => s.into(),
+        Cow::Owned(s) => match tilde_user_with_context(&s, home_dir) {
+            Cow::Owned(s) => s.into(),
+            Cow::Borrowed(_) => s.into(),
+        },
+    })
 }
 
-/// Performs the tilde expansion using the provided context.
-///
-/// This function expands tilde (`~`) character in the beginning of the input string into contents
-/// of the path returned by `home_dir` function. If the input string does not contain a tilde, or
-/// if it is not followed either by a slash (`/`) or by the end of string, then it is also left as
-/// is. This means, in particular, that expansions like `~anotheruser/directory` are not supported.
-/// The context function may also return a `None`, in that case even if the tilde is present in the
-/// input in the correct place, it won't be replaced (there is nothing to replace it with, after
-/// all).
+/// Performs both tilde and environment expansions, including the `~user` form, in the default
+/// system context.
 ///
-/// This function has three generic type parameters: `SI` represents the input string, `P` is the
-/// output of a context lookup, and `HD` is the context closure. `SI` must be a type, a reference
-/// to which can be converted to a string slice via `AsRef<str>`, and `P` must be a type, a
-/// reference to which can be converted to a `Path` via `AsRef<Path>`. For example, `P` may be
-/// `Path`, `PathBuf` or `Cow<Path>`, which gives a lot of flexibility.
+/// This function delegates to `full_user_with_context()`, using the system environment for
+/// variables and the same sources of home directories as `tilde_user()`.
+#[inline]
+pub fn full_user<SI>(input: &SI) -> Result<Cow<'_, str>, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    full_user_with_context(
+        input,
+        |user| match user {
+            None => home::home_dir(),
+            Some(user) => home::user_home_dir(user).ok(),
+        },
+        |s| std::env::var(s).map(Some),
+    )
+}
+
+/// Performs both tilde and environment expansion using the provided contexts, returning a path.
 ///
-/// If you need to expand the tilde into the actual user home directory, you can use `tilde()` or
-/// `full()` functions.
+/// This function works like `full_with_context()`, except for the way the tilde is expanded. The
+/// environment expansion is performed first, just like in `full_with_context()`; then, if the
+/// result starts with a tilde followed by a path separator or by the end of the string, the rest
+/// of it is joined to the home directory with `PathBuf::push()` component by component, so the
+/// separators in the rest are normalized to the native ones. On Windows both slashes and
+/// backslashes are recognized as separators, so `~/dir/file` becomes something like
+/// `C:\Users\user\dir\file` there. As in `full_with_context()`, a tilde which comes from
+/// a variable is not expanded, and if the home directory is not available, the tilde is left as
+/// it is.
 ///
 /// # Examples
 ///
 /// ```
-/// use std::path::{PathBuf, Path};
+/// use std::path::{Path, PathBuf};
 ///
 /// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
 ///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "SUB" => Ok(Some("sub")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// # #[cfg(unix)]
 /// assert_eq!(
-///    shellexpand::tilde_with_context("~/some/dir", home_dir),
-///    "/home/user/some/dir"
+///     shellexpand::full_path_with_context("~/$SUB/file", home_dir, context).unwrap(),
+///     Path::new("/home/user/sub/file")
 /// );
 /// ```
-pub fn tilde_with_context<SI: ?Sized, P, HD>(input: &SI, home_dir: HD) -> Cow<str>
+pub fn full_path_with_context<SI, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<PathBuf, LookupError<E>>
 where
-    SI: AsRef<str>,
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
     P: AsRef<Path>,
     HD: FnOnce() -> Option<P>,
 {
-    let input_str = input.as_ref();
-    if input_str.starts_with("~") {
-        let input_after_tilde = &input_str[1..];
-        if input_after_tilde.is_empty() || input_after_tilde.starts_with("/") {
-            if let Some(hd) = home_dir() {
-                let result = format!("{}{}", hd.as_ref().display(), input_after_tilde);
-                result.into()
-            } else {
-                // home dir is not available
-                input_str.into()
-            }
-        } else {
-            // we cannot handle `~otheruser/` paths yet
-            input_str.into()
-        }
-    } else {
-        // input doesn't start with tilde
-        input_str.into()
-    }
+    full_path_with_base(input, "", home_dir, context)
 }
 
-/// Performs the tilde expansion using the default system context.
+/// Performs both tilde and environment expansion using the provided contexts, returning a path
+/// and resolving a relative home directory against `base`.
 ///
-/// This function delegates to `tilde_with_context()`, using the default system source of home
-/// directory path, namely `dirs::home_dir()` function.
+/// This function works like `full_path_with_context()`, but if the home directory returned by
+/// `home_dir` is a relative path, like `home/user`, it is joined to `base` before the rest of the
+/// path is appended. An absolute home directory is used as it is, ignoring `base`. This is useful
+/// for virtual file system roots, like sandboxes or chroots, where home directories are stored
+/// relative to the root. Note that only the home directory is resolved against `base`: a result
+/// which does not start with an expanded tilde is returned as it is, even if it is relative.
 ///
 /// # Examples
 ///
 /// ```
-/// extern crate dirs_next as dirs;
+/// use std::path::{Path, PathBuf};
 ///
-/// let hds = dirs::home_dir()
-///     .map(|p| p.display().to_string())
-///     .unwrap_or_else(|| "~".to_owned());
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("home/user").into()) }
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "SUB" => Ok(Some("sub")),
+///         _ => Ok(None)
+///     }
+/// }
 ///
+/// # #[cfg(unix)]
 /// assert_eq!(
-///     shellexpand::tilde("~/some/dir"),
-///     format!("{}/some/dir", hds)
+///     shellexpand::full_path_with_base("~/$SUB", "/srv/root", home_dir, context).unwrap(),
+///     Path::new("/srv/root/home/user/sub")
 /// );
 /// ```
+pub fn full_path_with_base<SI, B, CO, C, E, P, HD>(
+    input: &SI,
+    base: B,
+    home_dir: HD,
+    context: C,
+) -> Result<PathBuf, LookupError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    B: AsRef<Path>,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input = input.as_ref();
+    let expanded = env_with_context(input, context)?;
+
+    // a tilde which comes from a variable is never expanded
+    let rest = match expanded.strip_prefix('~') {
+        Some(rest) if input.starts_with('~') => rest,
+        _ => return Ok(expanded.into_owned().into()),
+    };
+    if !rest.is_empty() && !rest.starts_with(std::path::is_separator) {
+        return Ok(expanded.into_owned().into());
+    }
+    let mut path = match home_dir() {
+        // joining keeps an absolute home directory as it is
+        Some(hd) => base.as_ref().join(hd),
+        None => return Ok(expanded.into_owned().into()),
+    };
+    path.extend(Path::new(rest.trim_start_matches(std::path::is_separator)).components());
+    Ok(path)
+}
+
+/// Performs both tilde and environment expansion using the system contexts, returning a path.
+///
+/// This function delegates to `full_path_with_context()`, using the same contexts as `full()`.
 #[inline]
-pub fn tilde<SI: ?Sized>(input: &SI) -> Cow<str>
+pub fn full_path<SI>(input: &SI) -> Result<PathBuf, LookupError<VarError>>
 where
-    SI: AsRef<str>,
+    SI: AsRef<str> + ?Sized,
 {
-    tilde_with_context(input, dirs::home_dir)
+    full_path_with_context(input, home::home_dir, |s| std::env::var(s).map(Some))
 }
 
-#[cfg(test)]
-mod tilde_tests {
-    use std::path::{Path, PathBuf};
+/// Performs both tilde and environment expansion of a template which is checked at compile time.
+///
+/// `expand!(template, home_dir, context)` is the same as
+/// `full_with_context(template, home_dir, context)`, and `expand!(template, context)` uses the
+/// home directory of the current user, like `full()`. The template must be a string literal,
+/// and the macro checks at compile time that it is well-formed: every `${` must have a matching
+/// closing brace, and every braced reference must contain a variable name. Templates which the
+/// expansion would otherwise silently leave as they are, like `${VAR` or `${:-default}`, fail to
+/// compile. Only the syntax is checked; the values are still looked up at runtime.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate shellexpand;
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "APP" => Ok(Some("my-app")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// fn main() {
+///     assert_eq!(
+///         expand!("~/config/${APP}.toml", || Some("/home/user"), context).unwrap(),
+///         "/home/user/config/my-app.toml"
+///     );
+///     assert!(expand!("~/$APP", context).unwrap().ends_with("/my-app"));
+/// }
+/// ```
+///
+/// The macro can also be called by its path, without importing it:
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "APP" => Ok(Some("my-app")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// assert!(shellexpand::expand!("~/$APP", context).unwrap().ends_with("/my-app"));
+/// ```
+///
+/// A malformed template is a compile error:
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate shellexpand;
+///
+/// fn main() {
+///     let _ = expand!("~/config/${APP.toml", |_: &str| Ok::<Option<&str>, ()>(None));
+/// }
+/// ```
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate shellexpand;
+///
+/// fn main() {
+///     let _ = expand!("${:-default}", |_: &str| Ok::<Option<&str>, ()>(None));
+/// }
+/// ```
+#[macro_export]
+macro_rules! expand {
+    ($template:literal, $home_dir:expr, $context:expr $(,)?) => {{
+        const _: () = assert!(
+            $crate::__is_well_formed_template($template),
+            "malformed template"
+        );
+        $crate::full_with_context($template, $home_dir, $context)
+    }};
+    ($template:literal, $context:expr $(,)?) => {
+        $crate::expand!($template, $crate::__home_dir, $context)
+    };
+}
 
-    use super::{tilde, tilde_with_context};
+#[doc(hidden)]
+pub use home::home_dir as __home_dir;
 
-    #[test]
-    fn test_with_tilde_no_hd() {
-        fn hd() -> Option<PathBuf> {
-            None
+/// Checks that all braced references in `template` are terminated and have names.
+///
+/// This is used by the `expand!()` macro, so it must be a `const fn`.
+#[doc(hidden)]
+pub const fn __is_well_formed_template(template: &str) -> bool {
+    let bytes = template.as_bytes();
+    let mut idx = 0;
+    while idx + 1 < bytes.len() {
+        match (bytes[idx], bytes[idx + 1]) {
+            (b'$', b'$') => idx += 2,
+            (b'$', b'{') => {
+                let start = idx + 2;
+                let mut end = start;
+                while end < bytes.len() && bytes[end] != b'}' {
+                    end += if bytes[end] == b'\\' && end + 1 < bytes.len() && bytes[end + 1] == b'}'
+                    {
+                        2
+                    } else {
+                        1
+                    };
+                }
+                if end >= bytes.len() {
+                    return false;
+                }
+                if end == start
+                    || matches!(bytes[start], b':' | b'#' | b'%' | b'/' | b'^' | b',' | b'@')
+                {
+                    return false;
+                }
+                idx = end + 1;
+            }
+            _ => idx += 1,
         }
+    }
+    true
+}
 
-        assert_eq!(tilde_with_context("whatever", hd), "whatever");
-        assert_eq!(tilde_with_context("whatever/~", hd), "whatever/~");
-        assert_eq!(tilde_with_context("~/whatever", hd), "~/whatever");
-        assert_eq!(tilde_with_context("~", hd), "~");
-        assert_eq!(tilde_with_context("~something", hd), "~something");
+/// Represents a variable lookup error.
+///
+/// This error is returned by `env_with_context()` function (and, therefore, also by `env()`,
+/// `full_with_context()` and `full()`) when the provided context function returns an error. The
+/// original error is provided in the `cause` field, while `var_name` contains the name of
+/// a variable whose expansion caused the error, and `offset()` and `kind()` return the position
+/// and the form of the reference to this variable in the input string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupError<E> {
+    /// The name of the problematic variable inside the input string.
+    pub var_name: String,
+    offset: usize,
+    kind: ReferenceKind,
+    /// The original error returned by the context function.
+    pub cause: E,
+}
+
+impl<E> LookupError<E> {
+    /// Returns the byte offset of the problematic variable reference (that is, of its `$`
+    /// character) inside the input string.
+    pub fn offset(&self) -> usize {
+        self.offset
     }
 
-    #[test]
-    fn test_with_tilde() {
-        fn hd() -> Option<PathBuf> {
-            Some(Path::new("/home/dir").into())
-        }
+    /// Returns the syntactic form of the problematic variable reference.
+    pub fn kind(&self) -> ReferenceKind {
+        self.kind
+    }
 
-        assert_eq!(tilde_with_context("whatever/path", hd), "whatever/path");
-        assert_eq!(tilde_with_context("whatever/~/path", hd), "whatever/~/path");
-        assert_eq!(tilde_with_context("~", hd), "/home/dir");
-        assert_eq!(tilde_with_context("~/path", hd), "/home/dir/path");
-        assert_eq!(tilde_with_context("~whatever/path", hd), "~whatever/path");
+    /// Computes the human-readable position of the problematic variable reference.
+    ///
+    /// `input` must be the string whose expansion caused this error. The returned position is
+    /// 1-based and counts characters, not bytes, so it can be used directly in messages pointing to
+    /// the exact place in a possibly multi-line input. This method has to walk through the input up
+    /// to the error offset, so it is only computed on request.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// fn context(_: &str) -> Result<Option<String>, &'static str> {
+    ///     Err("something went wrong")
+    /// }
+    ///
+    /// let input = "first line\nsecond ünïcode line with $VAR";
+    /// let err = shellexpand::env_with_context(input, context).unwrap_err();
+    /// assert_eq!(err.offset(), 38);
+    /// assert_eq!(err.position(input), shellexpand::Position { line: 2, column: 26 });
+    /// ```
+    pub fn position(&self, input: &str) -> Position {
+        Position::at(input, self.offset)
     }
+}
 
-    #[test]
-    fn test_global_tilde() {
-        match dirs::home_dir() {
-            Some(hd) => assert_eq!(tilde("~/something"), format!("{}/something", hd.display())),
-            None => assert_eq!(tilde("~/something"), "~/something"),
-        }
+impl<E: Error + Send + Sync + 'static> LookupError<E> {
+    /// Converts this error into a boxed error object.
+    ///
+    /// This is what the `?` operator does in functions returning `Box<dyn Error + Send + Sync>`,
+    /// but it does not require the type to be spelled out. The variable name is not lost in the
+    /// conversion: the box can be downcast back to `LookupError<E>`, and its `source()` is the
+    /// original error returned by the context function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env::VarError;
+    /// use shellexpand::LookupError;
+    ///
+    /// fn context(_: &str) -> Result<Option<String>, VarError> {
+    ///     Err(VarError::NotPresent)
+    /// }
+    ///
+    /// let err = shellexpand::env_with_context("$BROKEN", context).unwrap_err().into_boxed();
+    /// assert!(err.source().unwrap().is::<VarError>());
+    /// let err = err.downcast::<LookupError<VarError>>().unwrap();
+    /// assert_eq!(err.var_name, "BROKEN");
+    /// ```
+    pub fn into_boxed(self) -> Box<dyn Error + Send + Sync> {
+        Box::new(self)
     }
 }
 
-#[cfg(test)]
-mod env_test {
-    use std;
+/// The syntactic form of a variable reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReferenceKind {
+    /// A reference without braces, like `$VAR`.
+    Bare,
+    /// A reference with braces, like `${VAR}` or `${VAR:-default}`.
+    Braced,
+}
 
-    use super::{env, env_with_context, LookupError};
+/// A valid variable name.
+///
+/// A valid name is a non-empty string which consists of alphanumeric characters (as defined by
+/// `char::is_alphanumeric()`) and underscores, and which does not start with a digit. This is what
+/// is usually considered a valid identifier, and `VarName::parse()` can be used to validate names,
+/// for example, before inserting them into a map which is then used as a context.
+///
+/// The expansion itself is a bit more lenient: for simplicity, bare references like `$123` or
+/// `$1AB` are accepted too, and braced references may contain arbitrary names. It always stops
+/// a bare reference at the first character which cannot be a part of a valid name, though.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::VarName;
+///
+/// assert_eq!(VarName::parse("HOME").map(VarName::as_str), Some("HOME"));
+/// assert_eq!(VarName::parse("_private1").map(VarName::as_str), Some("_private1"));
+/// assert_eq!(VarName::parse("1ST"), None);
+/// assert_eq!(VarName::parse("A-B"), None);
+/// assert_eq!(VarName::parse(""), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarName<'a>(&'a str);
 
-    macro_rules! table {
-        ($env:expr, unwrap, $($source:expr => $target:expr),+) => {
-            $(
-                assert_eq!(env_with_context($source, $env).unwrap(), $target);
-            )+
-        };
-        ($env:expr, error, $($source:expr => $name:expr),+) => {
-            $(
-                assert_eq!(env_with_context($source, $env), Err(LookupError {
-                    var_name: $name.into(),
-                    cause: ()
-                }));
-            )+
+impl<'a> VarName<'a> {
+    /// Returns `name` as a `VarName` if it is a valid variable name.
+    pub fn parse(name: &'a str) -> Option<VarName<'a>> {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if VarName::is_name_char(c) && !c.is_numeric() => {}
+            _ => return None,
+        }
+        if chars.all(VarName::is_name_char) {
+            Some(VarName(name))
+        } else {
+            None
         }
     }
 
-    #[test]
-    fn test_empty_env() {
-        fn e(_: &str) -> Result<Option<String>, ()> {
-            Ok(None)
+    /// Returns the name as a string slice.
+    pub fn as_str(self) -> &'a str {
+        self.0
+    }
+
+    /// Returns true if `c` may be a part of a variable name.
+    fn is_name_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+}
+
+impl<'a> AsRef<str> for VarName<'a> {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl<'a> fmt::Display for VarName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// A human-readable position inside an input string.
+///
+/// See `LookupError::position()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Position {
+    /// The 1-based line number.
+    pub line: usize,
+    /// The 1-based column number, counted in characters.
+    pub column: usize,
+}
+
+impl Position {
+    fn at(input: &str, offset: usize) -> Position {
+        let before = input.get(..offset).unwrap_or(input);
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        Position {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
         }
+    }
+}
 
-        table! { e, unwrap,
-            "whatever/path"        => "whatever/path",
-            "$VAR/whatever/path"   => "$VAR/whatever/path",
-            "whatever/$VAR/path"   => "whatever/$VAR/path",
-            "whatever/path/$VAR"   => "whatever/path/$VAR",
-            "${VAR}/whatever/path" => "${VAR}/whatever/path",
-            "whatever/${VAR}path"  => "whatever/${VAR}path",
-            "whatever/path/${VAR}" => "whatever/path/${VAR}",
-            "${}/whatever/path"    => "${}/whatever/path",
-            "whatever/${}path"     => "whatever/${}path",
-            "whatever/path/${}"    => "whatever/path/${}",
-            "$/whatever/path"      => "$/whatever/path",
-            "whatever/$path"       => "whatever/$path",
+/// A span of an input string, as returned by `parse_spans()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span<'a> {
+    /// What this span contains.
+    pub kind: SpanKind<'a>,
+    /// The byte range of the span inside the input string.
+    pub range: Range<usize>,
+}
+
+/// The contents of a `Span`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpanKind<'a> {
+    /// Literal text, which is left as it is by the environment expansion. This includes dollars
+    /// which do not start a variable reference, like in `$/` or in an unterminated `${VAR`.
+    Literal,
+    /// An escaped dollar, like `$$`, which is expanded into a single `$`.
+    Escape,
+    /// A variable reference, including its operator and word, if any.
+    Var {
+        /// The name of the referenced variable.
+        name: &'a str,
+        /// The syntactic form of the reference.
+        kind: ReferenceKind,
+    },
+}
+
+impl<E: fmt::Display> fmt::Display for LookupError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error looking key '{}' up: {}",
+            self.var_name, self.cause
+        )
+    }
+}
+
+impl<E: Error + 'static> Error for LookupError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+/// Represents an expansion error.
+///
+/// This error is returned by the methods of `Expander`. Besides failed variable lookups, which are
+/// the only errors possible with the default settings and which are what the free functions of
+/// this crate return, it covers the errors which may only happen with some of the `Expander`
+/// settings enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExpandError<E> {
+    /// The context function returned an error.
+    Lookup(LookupError<E>),
+    /// The pattern of a pattern-matching operator, like `pat` in `${VAR#pat}`, is invalid.
+    InvalidPattern {
+        /// The text of the invalid pattern.
+        pattern: String,
+        /// The byte offset of the variable reference containing the pattern inside the input
+        /// string.
+        offset: usize,
+        /// The description of the problem.
+        message: String,
+    },
+    /// A variable referenced with the `${VAR:?message}` operator is not set.
+    ///
+    /// This error is only possible if `Expander::error_operator()` is enabled.
+    Unset {
+        /// The name of the variable.
+        var_name: String,
+        /// The byte offset of the variable reference inside the input string.
+        offset: usize,
+        /// The message from the reference, or a default one if the message is empty.
+        message: String,
+    },
+    /// The expansion would perform more substitutions than allowed.
+    ///
+    /// This error is only possible if `Expander::max_substitutions()` is set.
+    TooManySubstitutions {
+        /// The maximum number of substitutions.
+        limit: usize,
+        /// The byte offset of the first variable reference over the limit inside the input string.
+        offset: usize,
+    },
+    /// The value of a variable contains a character forbidden with
+    /// `Expander::forbidden_value_chars()`.
+    ForbiddenChar {
+        /// The name of the variable.
+        var_name: String,
+        /// The byte offset of the variable reference inside the input string.
+        offset: usize,
+        /// The first forbidden character of the value.
+        character: char,
+    },
+    /// A variable unknown to the context is referenced, and the callback passed to
+    /// `Expander::env_with_context_on_unknown()` returned `UnknownAction::Error` for it.
+    Unknown {
+        /// The name of the variable.
+        var_name: String,
+        /// The byte offset of the variable reference inside the input string.
+        offset: usize,
+    },
+}
+
+impl<E> ExpandError<E> {
+    /// Returns the byte offset of the problematic variable reference inside the input string.
+    pub fn offset(&self) -> usize {
+        match *self {
+            ExpandError::Lookup(ref e) => e.offset,
+            ExpandError::InvalidPattern { offset, .. }
+            | ExpandError::Unset { offset, .. }
+            | ExpandError::TooManySubstitutions { offset, .. }
+            | ExpandError::ForbiddenChar { offset, .. }
+            | ExpandError::Unknown { offset, .. } => offset,
+        }
+    }
+
+    /// Computes the human-readable position of the problematic variable reference.
+    ///
+    /// See `LookupError::position()` for more details.
+    pub fn position(&self, input: &str) -> Position {
+        Position::at(input, self.offset())
+    }
+
+    fn offset_mut(&mut self) -> &mut usize {
+        match *self {
+            ExpandError::Lookup(ref mut e) => &mut e.offset,
+            ExpandError::InvalidPattern { ref mut offset, .. }
+            | ExpandError::Unset { ref mut offset, .. }
+            | ExpandError::TooManySubstitutions { ref mut offset, .. }
+            | ExpandError::ForbiddenChar { ref mut offset, .. }
+            | ExpandError::Unknown { ref mut offset, .. } => offset,
+        }
+    }
+
+    // Only lookup errors are possible with the default settings, which the free functions use.
+    fn into_lookup_error(self) -> LookupError<E> {
+        match self {
+            ExpandError::Lookup(e) => e,
+            _ => unreachable!("only lookup errors are possible with the default settings"),
+        }
+    }
+}
+
+impl<E> From<LookupError<E>> for ExpandError<E> {
+    fn from(e: LookupError<E>) -> ExpandError<E> {
+        ExpandError::Lookup(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for ExpandError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandError::Lookup(ref e) => e.fmt(f),
+            ExpandError::InvalidPattern {
+                ref pattern,
+                ref message,
+                ..
+            } => write!(f, "invalid pattern '{}': {}", pattern, message),
+            ExpandError::Unset {
+                ref var_name,
+                ref message,
+                ..
+            } => write!(f, "{}: {}", var_name, message),
+            ExpandError::TooManySubstitutions { limit, .. } => {
+                write!(f, "more than {} substitutions", limit)
+            }
+            ExpandError::ForbiddenChar {
+                ref var_name,
+                character,
+                ..
+            } => write!(
+                f,
+                "the value of '{}' contains a forbidden character {:?}",
+                var_name, character
+            ),
+            ExpandError::Unknown { ref var_name, .. } => {
+                write!(f, "unknown variable '{}'", var_name)
+            }
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ExpandError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExpandError::Lookup(ref e) => Some(e),
+            ExpandError::InvalidPattern { .. }
+            | ExpandError::Unset { .. }
+            | ExpandError::TooManySubstitutions { .. }
+            | ExpandError::ForbiddenChar { .. }
+            | ExpandError::Unknown { .. } => None,
+        }
+    }
+}
+
+/// What to do with a reference to a variable which is unknown to the context.
+///
+/// Values of this type are returned by the callback passed to
+/// `Expander::env_with_context_on_unknown()`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UnknownAction {
+    /// Leave the reference in the output as it is, which is what happens by default.
+    Leave,
+    /// Replace the reference with an empty string.
+    Empty,
+    /// Fail the expansion with `ExpandError::Unknown`.
+    Error,
+    /// Replace the reference with the given string, which is inserted literally.
+    Replace(String),
+}
+
+/// An error returned by `full_with_context_reporting()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum FullError<E> {
+    /// Looking up a variable failed.
+    Lookup(LookupError<E>),
+    /// Looking up the home directory failed.
+    HomeDir(HomeDirError),
+}
+
+impl<E> From<LookupError<E>> for FullError<E> {
+    fn from(e: LookupError<E>) -> FullError<E> {
+        FullError::Lookup(e)
+    }
+}
+
+impl<E> From<HomeDirError> for FullError<E> {
+    fn from(e: HomeDirError) -> FullError<E> {
+        FullError::HomeDir(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for FullError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FullError::Lookup(ref e) => e.fmt(f),
+            FullError::HomeDir(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for FullError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            FullError::Lookup(ref e) => Some(e),
+            FullError::HomeDir(ref e) => Some(e),
+        }
+    }
+}
+
+macro_rules! try_lookup {
+    ($name:expr, $offset:expr, $kind:expr, $e:expr) => {
+        match $e {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(ExpandError::Lookup(LookupError {
+                    var_name: $name.into(),
+                    offset: $offset,
+                    kind: $kind,
+                    cause: e,
+                })
+                .into())
+            }
+        }
+    };
+}
+
+/// Returns the index of the first closing delimiter in `s` which is not escaped with a backslash.
+///
+/// If `nested` is true, then `${`-`}` pairs (with the given delimiters) inside `s` are skipped,
+/// so the returned delimiter is the one which matches the opening one located right before `s`.
+fn find_closing_brace(s: &str, (open, close): (char, char), nested: bool) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = s.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        let next = chars.as_str().chars().next();
+        match c {
+            '\\' if next == Some(close) => {
+                chars.next();
+            }
+            '$' if nested && next == Some('$') => {
+                chars.next();
+            }
+            '$' if nested && next == Some(open) => {
+                depth += 1;
+                chars.next();
+            }
+            c if c == close && depth == 0 => return Some(idx),
+            c if c == close => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// An operator inside a braced variable reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    /// `${VAR:-word}`
+    Default,
+    /// `${VAR:+word}`
+    Alternate,
+    /// `${VAR:?word}`
+    Error,
+    /// `${VAR#pattern}` and `${VAR##pattern}`
+    RemovePrefix { longest: bool },
+    /// `${VAR%pattern}` and `${VAR%%pattern}`
+    RemoveSuffix { longest: bool },
+    /// `${VAR/pattern/replacement}` and `${VAR//pattern/replacement}`
+    Replace { all: bool },
+    /// `${VAR^pattern}`, `${VAR^^pattern}`, `${VAR,pattern}` and `${VAR,,pattern}`, as well as
+    /// `${VAR@U}`, `${VAR@u}` and `${VAR@L}`
+    ChangeCase { upper: bool, all: bool },
+}
+
+/// Removes the comment starting with ` # ` from the contents of braces, if there is one.
+fn strip_brace_comment(contents: &str) -> &str {
+    contents
+        .find(" # ")
+        .map_or(contents, |idx| &contents[..idx])
+}
+
+/// Splits the contents of braces into the variable name and an optional operator with its word.
+fn parse_braced(contents: &str) -> (&str, Option<(Operator, &str)>) {
+    for (idx, c) in contents.char_indices() {
+        let rest = &contents[idx + c.len_utf8()..];
+        let (operator, len) = match c {
+            ':' if rest.starts_with('-') => (Operator::Default, 2),
+            ':' if rest.starts_with('+') => (Operator::Alternate, 2),
+            ':' if rest.starts_with('?') => (Operator::Error, 2),
+            '#' | '%' | '/' | '^' | ',' => {
+                let doubled = rest.starts_with(c);
+                let operator = match c {
+                    '#' => Operator::RemovePrefix { longest: doubled },
+                    '%' => Operator::RemoveSuffix { longest: doubled },
+                    '/' => Operator::Replace { all: doubled },
+                    _ => Operator::ChangeCase {
+                        upper: c == '^',
+                        all: doubled,
+                    },
+                };
+                (operator, if doubled { 2 } else { 1 })
+            }
+            '@' if rest == "U" || rest == "u" || rest == "L" => {
+                let operator = Operator::ChangeCase {
+                    upper: rest != "L",
+                    all: rest != "u",
+                };
+                (operator, 2)
+            }
+            _ => continue,
+        };
+        // the name may be empty here, like in `${:-value}`; such references are left as they are
+        return (&contents[..idx], Some((operator, &contents[idx + len..])));
+    }
+    (contents, None)
+}
+
+/// Splits the word of the replacement operator at the first `/` which is not escaped with
+/// a backslash.
+fn split_replacement(word: &str) -> (&str, &str) {
+    let bytes = word.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'\\' => idx += 2,
+            b'/' => return (&word[..idx], &word[idx + 1..]),
+            _ => idx += 1,
+        }
+    }
+    (word, "")
+}
+
+/// Converts `\n`, `\t` and `\r` escape sequences in `s` into the respective control characters.
+///
+/// Other backslashes, including doubled ones, are left as they are.
+fn unescape_control_chars(s: Cow<'_, str>) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return s;
+    }
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(next) => {
+                result.push('\\');
+                result.push(next);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result.into()
+}
+
+/// Converts the characters of `value` matching `matches` to upper or lower case.
+///
+/// If `all` is false, only the first character is converted. Full Unicode case mappings are used,
+/// so a single character may be converted into several ones, like `ß` into `SS`.
+fn change_case<F>(value: &str, upper: bool, all: bool, matches: F) -> String
+where
+    F: Fn(char) -> bool,
+{
+    let mut result = String::with_capacity(value.len());
+    for (idx, c) in value.char_indices() {
+        if idx > 0 && !all {
+            result.push_str(&value[idx..]);
+            break;
+        }
+        match (matches(c), upper) {
+            (true, true) => result.extend(c.to_uppercase()),
+            (true, false) => result.extend(c.to_lowercase()),
+            (false, _) => result.push(c),
+        }
+    }
+    result
+}
+
+/// Converts an offset inside `unescape_closing_braces(s, close)` into the corresponding offset
+/// inside `s`.
+fn escaped_offset(s: &str, close: char, unescaped_offset: usize) -> usize {
+    let mut removed = 0;
+    for (idx, _) in s.match_indices(&format!("\\{}", close)) {
+        if idx - removed >= unescaped_offset {
+            break;
+        }
+        removed += 1;
+    }
+    unescaped_offset + removed
+}
+
+fn unescape_closing_braces(s: &str, close: char) -> Cow<'_, str> {
+    let escaped = format!("\\{}", close);
+    if s.contains(&escaped) {
+        s.replace(&escaped, close.encode_utf8(&mut [0; 4])).into()
+    } else {
+        s.into()
+    }
+}
+
+/// A piece of the input string, as split by `Tokens`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token<'a> {
+    kind: TokenKind<'a>,
+    /// The byte range of the token inside the input string.
+    range: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind<'a> {
+    /// Text which is not a part of any variable reference.
+    Literal,
+    /// An escaped dollar, `$$` (or `\$` with shell escaping), which expands to a single `$`.
+    Escape,
+    /// A reference without braces, like `$VAR`, with the variable name.
+    Bare(&'a str),
+    /// A reference with braces, like `${VAR}` or `${VAR:-word}`, with the variable name and the
+    /// operator with its raw word.
+    Braced(&'a str, Option<(Operator, &'a str)>),
+}
+
+/// Splits an input string into literal text, escapes and variable references.
+///
+/// This is the parser used by the expansion itself, so everything it considers a reference is
+/// looked up in the context during the expansion. Adjacent pieces of literal text, including
+/// dollars which do not start a reference, are always merged into a single token.
+struct Tokens<'a, 'e> {
+    input: &'a str,
+    pos: usize,
+    /// A reference which was found right after the literal text which has just been returned.
+    pending: Option<Token<'a>>,
+    /// The opening and closing delimiters of braced references.
+    delimiters: (char, char),
+    /// Whether `${`-`}` pairs inside braces are balanced, see `find_closing_brace()`.
+    nested: bool,
+    shell_escaping: bool,
+    /// The characters which are added to and removed from the default set of name characters.
+    extra_name_chars: &'e str,
+    excluded_name_chars: &'e str,
+    /// Whether whitespace around the names in braced references is ignored.
+    trim_braced_names: bool,
+    /// Whether ` # ` starts a comment inside braced references.
+    brace_comments: bool,
+    /// The introducer and the terminator of placeholders, which replace the dollar syntax.
+    placeholders: Option<(&'e str, &'e str)>,
+}
+
+impl<'a, 'e> Tokens<'a, 'e> {
+    /// Returns true if `c` may be a part of a bare variable name, where `first` indicates
+    /// whether `c` would be the first character of the name.
+    fn is_name_char(&self, c: char, first: bool) -> bool {
+        !self.excluded_name_chars.contains(c)
+            && (VarName::is_name_char(c) || !first && self.extra_name_chars.contains(c))
+    }
+
+    /// Parses the reference starting with the dollar at `idx`, if it is a reference.
+    ///
+    /// `literal_start` is the start of the current piece of literal text, which is where a `\$`
+    /// escape may begin. If there is no reference at `idx`, returns the position where the search
+    /// for the next dollar should continue.
+    fn reference_at(&self, idx: usize, literal_start: usize) -> Result<Token<'a>, usize> {
+        let token = |kind, range| Ok(Token { kind, range });
+
+        if let Some((introducer, terminator)) = self.placeholders {
+            let start = idx + introducer.len();
+            let rest = &self.input[start..];
+            if rest.starts_with(introducer) {
+                // a doubled introducer is an escaped introducer
+                return token(TokenKind::Escape, idx..start + introducer.len());
+            }
+            let name = match rest.find(terminator) {
+                Some(len) => &rest[..len],
+                None => return Err(start),
+            };
+            let end = start + name.len() + terminator.len();
+            let name = if self.trim_braced_names {
+                name.trim()
+            } else {
+                name
+            };
+            // placeholders without a name are left as they are
+            return match name {
+                "" => Err(end),
+                name => token(TokenKind::Braced(name, None), idx..end),
+            };
+        }
+
+        if self.shell_escaping && idx > literal_start && self.input[..idx].ends_with('\\') {
+            // `\$` is an escaped dollar in shells
+            return token(TokenKind::Escape, idx - 1..idx + 1);
+        }
+
+        let (open, close) = self.delimiters;
+        let rest = &self.input[idx + 1..];
+        match rest.chars().next() {
+            Some(c) if c == open => {
+                match find_closing_brace(&rest[open.len_utf8()..], self.delimiters, self.nested) {
+                    Some(closing_brace_idx) => {
+                        let contents = &rest[open.len_utf8()..open.len_utf8() + closing_brace_idx];
+                        let contents = if self.brace_comments {
+                            strip_brace_comment(contents)
+                        } else {
+                            contents
+                        };
+                        let end = idx + 1 + open.len_utf8() + closing_brace_idx + close.len_utf8();
+                        let (name, operator) = parse_braced(contents);
+                        let name = if self.trim_braced_names {
+                            name.trim()
+                        } else {
+                            name
+                        };
+                        match (name, operator) {
+                            // references without a name, like `${}` or `${:-value}`, are left
+                            // as they are
+                            ("", _) => Err(end),
+                            (name, operator) => token(TokenKind::Braced(name, operator), idx..end),
+                        }
+                    }
+                    // unbalanced braces
+                    None => Err(idx + 1 + open.len_utf8()),
+                }
+            }
+            // `$$` is a reference to the special `$` variable in shells
+            Some('$') if self.shell_escaping => token(TokenKind::Bare("$"), idx..idx + 2),
+            Some('$') => token(TokenKind::Escape, idx..idx + 2),
+            Some(c) if self.is_name_char(c, true) => {
+                let len = rest[c.len_utf8()..]
+                    .find(|c: char| !self.is_name_char(c, false))
+                    .map_or(rest.len(), |len| c.len_utf8() + len);
+                token(TokenKind::Bare(&rest[..len]), idx..idx + 1 + len)
+            }
+            _ => Err(idx + 1),
+        }
+    }
+}
+
+impl<'a, 'e> Iterator for Tokens<'a, 'e> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        if let Some(token) = self.pending.take() {
+            return Some(token);
+        }
+
+        let start = self.pos;
+        let mut search_pos = start;
+        let introducer = self.placeholders.map_or("$", |(introducer, _)| introducer);
+        while let Some(idx) = self.input[search_pos..].find(introducer) {
+            match self.reference_at(search_pos + idx, start) {
+                Ok(token) => {
+                    self.pos = token.range.end;
+                    if token.range.start == start {
+                        return Some(token);
+                    }
+                    let literal_end = token.range.start;
+                    self.pending = Some(token);
+                    return Some(Token {
+                        kind: TokenKind::Literal,
+                        range: start..literal_end,
+                    });
+                }
+                Err(next_search_pos) => search_pos = next_search_pos,
+            }
+        }
+
+        self.pos = self.input.len();
+        if start == self.input.len() {
+            None
+        } else {
+            Some(Token {
+                kind: TokenKind::Literal,
+                range: start..self.input.len(),
+            })
+        }
+    }
+}
+
+/// A configurable expander.
+///
+/// The functions in this crate, like `env_with_context()` or `full_with_context()`, perform
+/// expansion with the default settings. `Expander` allows changing these settings; its methods
+/// have the same signatures and semantics as the respective free functions, except for the
+/// differences enabled by the settings. `Expander::new()` returns an expander with the default
+/// settings, which behaves exactly like the free functions.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::Expander;
+///
+/// fn context(s: &str) -> Option<&'static str> {
+///     match s {
+///         "PATH" => Some("/usr/bin"),
+///         _ => None
+///     }
+/// }
+///
+/// let expander = Expander::new().recursive(true);
+///
+/// assert_eq!(
+///     expander.env_with_context_no_errors("/bin${PATH:+:$PATH}", context),
+///     "/bin:/usr/bin"
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Expander {
+    recursive: bool,
+    shell_escaping: bool,
+    pattern_syntax: PatternSyntax,
+    strip_double_quotes: bool,
+    tilde: bool,
+    existing_home_only: bool,
+    tilde_escape: Option<char>,
+    variables: bool,
+    delimiters: (char, char),
+    placeholders: Option<(String, String)>,
+    escape_sequences: bool,
+    error_operator: bool,
+    unset_on_error: bool,
+    extra_name_chars: String,
+    excluded_name_chars: String,
+    trim_braced_names: bool,
+    brace_comments: bool,
+    max_substitutions: Option<usize>,
+    forbidden_value_chars: String,
+}
+
+impl Default for Expander {
+    fn default() -> Expander {
+        Expander::new()
+    }
+}
+
+impl Expander {
+    /// Creates an expander with the default settings.
+    pub fn new() -> Expander {
+        Expander {
+            recursive: false,
+            shell_escaping: false,
+            pattern_syntax: PatternSyntax::default(),
+            strip_double_quotes: false,
+            tilde: true,
+            existing_home_only: false,
+            tilde_escape: None,
+            variables: true,
+            delimiters: ('{', '}'),
+            placeholders: None,
+            escape_sequences: false,
+            error_operator: false,
+            unset_on_error: true,
+            extra_name_chars: String::new(),
+            excluded_name_chars: String::new(),
+            trim_braced_names: false,
+            brace_comments: false,
+            max_substitutions: None,
+            forbidden_value_chars: String::new(),
+        }
+    }
+
+    /// Sets whether words of operators inside braced references are expanded themselves.
+    ///
+    /// By default the word of an operator, like `default` in `${VAR:-default}` or `alt` in
+    /// `${VAR:+alt}`, is inserted into the output literally. If this setting is enabled, variable
+    /// references in the word are expanded using the same context, and the word is only expanded
+    /// when it is actually used: the context is never called for references inside the word of
+    /// `${VAR:-word}` if `VAR` is set, or inside the word of `${VAR:+word}` if it is not. Braces
+    /// inside words are balanced in this mode, so `${A:-${B}}` works as expected. The values of
+    /// variables are never expanded.
+    ///
+    /// Escapes inside words are processed only in this mode as well, consistently with the rest
+    /// of the word: `${UNSET:-a$$b}` expands to `a$b` here, but to `a$$b` by default, where the
+    /// word is inserted exactly as it is written. With shell escaping, `\$` in a word works the
+    /// same way.
+    ///
+    /// In this mode the value of each variable is also looked up in the context at most once per
+    /// call, and reused for all the other references to the same variable, even if they are in
+    /// different words. This means that the context is not called repeatedly for the same name,
+    /// and that all references to a variable are guaranteed to expand to the same value. Lookup
+    /// errors are not cached.
+    ///
+    /// References inside the names of braced references are expanded in this mode as well, before
+    /// the name itself is looked up, so `${CONFIG_$PROFILE}` expands to the value of `CONFIG_dev`
+    /// if `PROFILE` is `dev`. Such inner references may be written as `$NAME` or `${NAME}`, but
+    /// must not contain operators. If the inner reference is not set, the outer name contains it
+    /// literally, like `CONFIG_$PROFILE`, which most likely is not set either.
+    pub fn recursive(mut self, recursive: bool) -> Expander {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets whether dollars are escaped like in shells.
+    ///
+    /// By default `$$` is an escape sequence which expands to a single literal `$`. In shells,
+    /// however, `$$` is a special variable holding the process ID, and a literal dollar is written
+    /// as `\$`. If this setting is enabled, the expander follows the shell rules: `$$` is a
+    /// reference to the variable named `$`, whose value is obtained from the context function just
+    /// like for any other variable (and which is left as it is if the context does not know it),
+    /// while `\$` expands to a literal `$` and the backslash is consumed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "$" => Some("1234"),
+    ///         "A" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(
+    ///     Expander::new().shell_escaping(true).env_with_context_no_errors("$$ \\$A $A", context),
+    ///     "1234 $A a value"
+    /// );
+    /// assert_eq!(
+    ///     Expander::new().env_with_context_no_errors("$$ \\$A $A", context),
+    ///     "$ \\a value a value"
+    /// );
+    /// ```
+    pub fn shell_escaping(mut self, shell_escaping: bool) -> Expander {
+        self.shell_escaping = shell_escaping;
+        self
+    }
+
+    /// Sets the syntax of patterns in the pattern-matching operators.
+    ///
+    /// By default the patterns in `${VAR#pattern}`, `${VAR%pattern}`, `${VAR/pattern/replacement}`
+    /// and their doubled forms are shell globs. If the `regex` feature is enabled, they may be
+    /// regular expressions instead. The semantics of the operators stay the same: the pattern must
+    /// match the whole removed prefix, suffix or replaced substring, and the shortest or the
+    /// longest match is chosen as the operator prescribes. The only difference is that regular
+    /// expressions follow the leftmost-first semantics of the regex crate where the longest match
+    /// of a glob would be taken: `${VAR##pattern}` removes the prefix which the regular expression
+    /// prefers, which is the longest one for greedy repetitions, and `${VAR/pattern/replacement}`
+    /// replaces the leftmost match.
+    ///
+    /// Glob patterns are always valid, while an invalid regular expression makes the expansion fail
+    /// with `ExpandError::InvalidPattern` holding the pattern text. The `*_no_errors` methods leave
+    /// references with invalid patterns as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[cfg(feature = "regex")]
+    /// # fn main() {
+    /// use shellexpand::{Expander, PatternSyntax};
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "VERSION" => Some("release-1.21.3"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().pattern_syntax(PatternSyntax::Regex);
+    /// assert_eq!(
+    ///     expander.env_with_context_no_errors("${VERSION/\\d+/N}", context),
+    ///     "release-N.21.3"
+    /// );
+    /// # }
+    /// # #[cfg(not(feature = "regex"))]
+    /// # fn main() {}
+    /// ```
+    pub fn pattern_syntax(mut self, pattern_syntax: PatternSyntax) -> Expander {
+        self.pattern_syntax = pattern_syntax;
+        self
+    }
+
+    /// Sets whether `\n`, `\t` and `\r` in patterns and replacements denote control characters.
+    ///
+    /// By default a pattern has to contain an actual newline character to match newlines, which
+    /// is awkward to write in many sources. If this setting is enabled, the escape sequences `\n`,
+    /// `\t` and `\r` in the patterns of the pattern-matching and case operators, as well as in
+    /// the replacements of `${VAR/pattern/replacement}`, are converted into a newline, a tab and
+    /// a carriage return, respectively, before the operator is applied. A doubled backslash stays
+    /// an escaped backslash, so `\\n` still matches a backslash followed by `n`; all other
+    /// backslashes are left as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "LINES" => Some("first\nsecond\nthird"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().escape_sequences(true);
+    /// assert_eq!(
+    ///     expander.env_with_context_no_errors("${LINES//\\n/ }", context),
+    ///     "first second third"
+    /// );
+    /// ```
+    pub fn escape_sequences(mut self, escape_sequences: bool) -> Expander {
+        self.escape_sequences = escape_sequences;
+        self
+    }
+
+    /// Sets whether `${VAR:?message}` fails the expansion if `VAR` is not set.
+    ///
+    /// By default a reference with the error operator behaves just like a plain `${VAR}`: it
+    /// expands to the value of the variable if it is set, and is left as it is otherwise. If this
+    /// setting is enabled, a reference to a variable which is not set makes the expansion fail with
+    /// `ExpandError::Unset` holding the message, like shells do. If the message is empty, as in
+    /// `${VAR:?}`, the error holds the default message "parameter null or not set" instead, and
+    /// the name of the variable is included into the text of the error in any case. The message
+    /// is expanded in recursive mode, just like the words of the other operators. The
+    /// `*_no_errors` methods leave such references as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "A" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().error_operator(true);
+    /// assert_eq!(expander.env_with_context_no_errors("${A:?}", context), "a value");
+    ///
+    /// let result = expander.env_with_context("${B:?B is required}", |s| {
+    ///     Ok::<_, String>(context(s))
+    /// });
+    /// assert_eq!(result.unwrap_err().to_string(), "B: B is required");
+    /// ```
+    pub fn error_operator(mut self, error_operator: bool) -> Expander {
+        self.error_operator = error_operator;
+        self
+    }
+
+    /// Sets whether `${VAR:-default}` and `${VAR:+alternate}` treat lookup errors like unset
+    /// variables.
+    ///
+    /// By default, if the context function returns an error for `VAR`, these references behave
+    /// as if `VAR` was not set: the default value is used, and the alternate value is not. This
+    /// is how these operators have always worked, and it is kept as the default because the
+    /// system context of `env()` and `full()` is backed by `std::env::var()`, which reports unset
+    /// variables as errors; it is also handy for values which are not valid Unicode. If this
+    /// setting is disabled, the error is propagated instead, just like for references without
+    /// operators.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
+    ///     match s {
+    ///         "BROKEN" => Err("broken"),
+    ///         _ => Ok(None)
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(Expander::new().env_with_context("${BROKEN:-x}", context).unwrap(), "x");
+    ///
+    /// let expander = Expander::new().unset_on_error(false);
+    /// assert!(expander.env_with_context("${BROKEN:-x}", context).is_err());
+    /// ```
+    pub fn unset_on_error(mut self, unset_on_error: bool) -> Expander {
+        self.unset_on_error = unset_on_error;
+        self
+    }
+
+    /// Sets whether double quotes are removed from the output.
+    ///
+    /// Variable references are expanded everywhere in the input, including inside double-quoted
+    /// regions, so `"${HOME}/x"` always expands `HOME`. By default the quotes themselves are kept
+    /// in the output. If this setting is enabled, unescaped double quotes in the input are removed
+    /// like in shells, so that adjacent quoted and unquoted parts are joined together, and `\"`
+    /// expands to a literal `"`. Only the text of the input outside of variable references is
+    /// affected: quotes in the values of variables, as well as in the literal words of operators,
+    /// are kept as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "HOME" => Some("/home/me"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().strip_double_quotes(true);
+    /// assert_eq!(expander.env_with_context_no_errors("\"${HOME}/x\"", context), "/home/me/x");
+    /// assert_eq!(
+    ///     Expander::new().env_with_context_no_errors("\"${HOME}/x\"", context),
+    ///     "\"/home/me/x\""
+    /// );
+    /// ```
+    pub fn strip_double_quotes(mut self, strip_double_quotes: bool) -> Expander {
+        self.strip_double_quotes = strip_double_quotes;
+        self
+    }
+
+    /// Sets whether `full_with_context()` and `full_with_context_no_errors()` perform tilde
+    /// expansion.
+    ///
+    /// Tilde expansion is enabled by default. If it is disabled, these methods only perform
+    /// environment expansion, and a leading `~` is left in the output as it is; the home directory
+    /// function is not called at all then. This is useful when the same expander is used for
+    /// inputs which may legitimately start with a literal `~`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use shellexpand::Expander;
+    ///
+    /// fn home_dir() -> Option<PathBuf> { Some(PathBuf::from("/home/user")) }
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "A" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().tilde(false);
+    /// assert_eq!(expander.full_with_context_no_errors("~/$A", home_dir, context), "~/a value");
+    /// ```
+    pub fn tilde(mut self, tilde: bool) -> Expander {
+        self.tilde = tilde;
+        self
+    }
+
+    /// Sets whether the tilde is only expanded if the home directory exists.
+    ///
+    /// By default the tilde is expanded to whatever the home directory function returns. If this
+    /// setting is enabled, `full_with_context()` and `full_with_context_no_errors()` check that
+    /// the home directory exists and is a directory, with `Path::is_dir()`, and leave the tilde as
+    /// it is otherwise, just like when the home directory is not available. This is useful when a
+    /// path to a non-existent directory is worse than a literal `~`. Since this accesses the file
+    /// system, it is disabled by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use shellexpand::Expander;
+    ///
+    /// fn home_dir() -> Option<PathBuf> { Some(PathBuf::from("/non/existent/home")) }
+    ///
+    /// fn context(_: &str) -> Option<&'static str> { None }
+    ///
+    /// let expander = Expander::new().existing_home_only(true);
+    /// assert_eq!(expander.full_with_context_no_errors("~/x", home_dir, context), "~/x");
+    /// ```
+    pub fn existing_home_only(mut self, existing_home_only: bool) -> Expander {
+        self.existing_home_only = existing_home_only;
+        self
+    }
+
+    /// Sets the character which escapes a leading tilde.
+    ///
+    /// There is no way to write a literal leading `~` by default, unlike a literal dollar, which is
+    /// written as `$$`. If an escape character is set, like `\`, then `full_with_context()` and
+    /// `full_with_context_no_errors()` treat an input starting with this character followed by
+    /// a tilde, like `\~/file`, as an escaped tilde: the escape character is removed and the
+    /// tilde is left as it is, so the result is `~/file`. This allows file names which
+    /// legitimately start with a tilde. Only the escape at the very beginning of the input is
+    /// special; everywhere else the escape character is left as it is. Nothing is escaped by
+    /// default, and the escape has no effect if tilde expansion is disabled with
+    /// `Expander::tilde()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use shellexpand::Expander;
+    ///
+    /// fn home_dir() -> Option<PathBuf> { Some(PathBuf::from("/home/user")) }
+    ///
+    /// fn context(_: &str) -> Option<&'static str> { None }
+    ///
+    /// let expander = Expander::new().tilde_escape(Some('\\'));
+    /// assert_eq!(expander.full_with_context_no_errors("\\~/foo", home_dir, context), "~/foo");
+    /// assert_eq!(
+    ///     expander.full_with_context_no_errors("~/foo", home_dir, context),
+    ///     "/home/user/foo"
+    /// );
+    /// ```
+    pub fn tilde_escape(mut self, escape: Option<char>) -> Expander {
+        self.tilde_escape = escape;
+        self
+    }
+
+    /// Sets whether `full_with_context()` and `full_with_context_no_errors()` perform environment
+    /// expansion.
+    ///
+    /// Environment expansion is enabled by default. If it is disabled, these methods only perform
+    /// tilde expansion, exactly like `tilde_with_context()`, and the context function is not
+    /// called at all. Together with `Expander::tilde()` this allows choosing the phases of the
+    /// expansion at runtime, for example, when variables have already been expanded elsewhere,
+    /// without switching between different functions. The `env_*` methods are not affected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use shellexpand::Expander;
+    ///
+    /// fn home_dir() -> Option<PathBuf> { Some(PathBuf::from("/home/user")) }
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "A" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().variables(false);
+    /// assert_eq!(
+    ///     expander.full_with_context_no_errors("~/$A", home_dir, context),
+    ///     "/home/user/$A"
+    /// );
+    /// ```
+    pub fn variables(mut self, variables: bool) -> Expander {
+        self.variables = variables;
+        self
+    }
+
+    /// Sets the opening and closing delimiters of braced references.
+    ///
+    /// By default braced references are written as `${VAR}`. This setting allows using other
+    /// delimiters instead of the braces, for example, `$[VAR]` or `$(VAR)`, which is useful when
+    /// `${...}` is already used by another templating layer. Everything else stays the same:
+    /// operators work inside the new delimiters, `$$` is still an escaped dollar, bare references
+    /// like `$VAR` are still supported, and a closing delimiter inside an operator word can be
+    /// escaped with a backslash. Braces have no special meaning when other delimiters are set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `open` is a dollar or a character which is allowed in variable names, since such
+    /// references could not be told apart from escapes and bare references.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "A" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().delimiters('[', ']');
+    /// assert_eq!(
+    ///     expander.env_with_context_no_errors("$[A]/$[B:-b]/${A}/$A", context),
+    ///     "a value/b/${A}/a value"
+    /// );
+    /// ```
+    pub fn delimiters(mut self, open: char, close: char) -> Expander {
+        assert!(
+            open != '$' && !VarName::is_name_char(open),
+            "invalid opening delimiter: {:?}",
+            open
+        );
+        self.delimiters = (open, close);
+        self
+    }
+
+    /// Replaces the dollar syntax of references with placeholders delimited by arbitrary strings.
+    ///
+    /// With this setting a reference is written as `introducer`, the variable name and
+    /// `terminator`, so with `placeholders("$$", "$$")` the input `$$VAR$$` is a reference to
+    /// `VAR`, and with `placeholders("##", "##")` it is written as `##VAR##`. A doubled
+    /// introducer, like `$$$$` or `####`, is an escape which expands to a single introducer. The
+    /// name extends up to the first terminator, and an introducer without a terminator, or
+    /// without a name before it, is left as it is.
+    ///
+    /// Dollars have no special meaning in this mode, so `$VAR` and `${VAR}` are left as they are
+    /// unless the dollar is a part of the introducer. Placeholders do not support operators like
+    /// `:-`, and the settings which only concern the dollar syntax, like shell escaping, custom
+    /// delimiters and the name characters of bare references, have no effect. Whitespace around
+    /// the names is trimmed if `Expander::trim_braced_names()` is enabled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `introducer` or `terminator` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "A" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().placeholders("$$", "$$");
+    /// assert_eq!(
+    ///     expander.env_with_context_no_errors("$$A$$/$$$$A/$A/${A}", context),
+    ///     "a value/$$A/$A/${A}"
+    /// );
+    /// ```
+    pub fn placeholders(mut self, introducer: &str, terminator: &str) -> Expander {
+        assert!(
+            !introducer.is_empty() && !terminator.is_empty(),
+            "the introducer and the terminator of placeholders must not be empty"
+        );
+        self.placeholders = Some((introducer.into(), terminator.into()));
+        self
+    }
+
+    /// Sets additional characters which may continue the names in bare references.
+    ///
+    /// By default the name in a bare reference like `$VAR` consists of alphanumeric characters and
+    /// underscores, and it ends right before the first character which is not one of them. With
+    /// this setting the name also continues through each of the characters in `chars`, so, for
+    /// example, with `-` added `$MY-VAR` is a reference to `MY-VAR` rather than to `MY` followed by
+    /// `-VAR`. The additional characters cannot start a name, so `$-` is still not a reference.
+    /// Braced references are not affected, since their names always extend up to the closing
+    /// brace or the operator.
+    ///
+    /// If a character is both added with this setting and excluded with
+    /// `Expander::excluded_name_chars()`, the exclusion wins.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "MY-VAR" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().extra_name_chars("-");
+    /// assert_eq!(expander.env_with_context_no_errors("x/$MY-VAR/x", context), "x/a value/x");
+    /// ```
+    pub fn extra_name_chars(mut self, chars: &str) -> Expander {
+        self.extra_name_chars = chars.into();
+        self
+    }
+
+    /// Sets characters which end the names in bare references even though they are allowed in
+    /// names by default.
+    ///
+    /// For example, with `_` excluded `$PREFIX_suffix` is a reference to `PREFIX` followed by the
+    /// literal `_suffix`. An excluded character cannot start a name either, so `$_` is not a
+    /// reference then. Braced references are not affected. See also
+    /// `Expander::extra_name_chars()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "PREFIX" => Some("a value"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().excluded_name_chars("_");
+    /// assert_eq!(
+    ///     expander.env_with_context_no_errors("$PREFIX_suffix", context),
+    ///     "a value_suffix"
+    /// );
+    /// ```
+    pub fn excluded_name_chars(mut self, chars: &str) -> Expander {
+        self.excluded_name_chars = chars.into();
+        self
+    }
+
+    /// Sets whether whitespace around the names in braced references is ignored.
+    ///
+    /// By default everything between the opening brace and the closing brace or the operator is
+    /// the name, so `${ HOME }` is a reference to ` HOME `, which is most likely unset and left
+    /// as it is. If this setting is enabled, leading and trailing whitespace is trimmed from such
+    /// names, so `${ HOME }` and `${ HOME :-/tmp}` reference `HOME`, like in templating systems
+    /// which allow spaces for readability. Operator words and bare references are not affected,
+    /// and a reference with only whitespace inside the braces is left as it is, like `${}`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "HOME" => Some("/home/user"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().trim_braced_names(true);
+    /// assert_eq!(expander.env_with_context_no_errors("${ HOME }/x", context), "/home/user/x");
+    /// assert_eq!(
+    ///     Expander::new().env_with_context_no_errors("${ HOME }/x", context),
+    ///     "${ HOME }/x"
+    /// );
+    /// ```
+    pub fn trim_braced_names(mut self, trim_braced_names: bool) -> Expander {
+        self.trim_braced_names = trim_braced_names;
+        self
+    }
+
+    /// Sets whether ` # ` starts a comment inside braced references.
+    ///
+    /// By default everything inside the braces is a part of the reference. If this setting is
+    /// enabled, a `#` surrounded by spaces starts a comment which lasts until the closing brace,
+    /// so `${HOME # the home directory}` is a reference to `HOME`, and `${DIR:-/tmp # fallback}`
+    /// has `/tmp` as its default word. This allows annotating templates, like configuration files,
+    /// right where the variables are used. Only the spaced form starts a comment, so the `#` and
+    /// `##` operators keep working as usual in references like `${FILE#*/}` and `${FILE##*/}`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "FILE" => Some("dir/name.txt"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().brace_comments(true);
+    /// assert_eq!(
+    ///     expander.env_with_context_no_errors("${FILE # the input}", context),
+    ///     "dir/name.txt"
+    /// );
+    /// assert_eq!(expander.env_with_context_no_errors("${FILE#*/}", context), "name.txt");
+    /// ```
+    pub fn brace_comments(mut self, brace_comments: bool) -> Expander {
+        self.brace_comments = brace_comments;
+        self
+    }
+
+    /// Sets the maximum number of substitutions performed by a single expansion.
+    ///
+    /// A substitution is the replacement of a variable reference with anything, be it the value of
+    /// the variable, the word of an operator or an empty string, so references which are left as
+    /// they are do not count. In recursive mode the references inside operator words count too.
+    /// If the limit is exceeded, the expansion fails with `ExpandError::TooManySubstitutions`
+    /// pointing to the first reference over the limit, and the `*_no_errors` methods leave that
+    /// reference and all the following ones as they are. Together with a limit on the input length,
+    /// this bounds the work done for untrusted templates. There is no limit by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::{ExpandError, Expander};
+    ///
+    /// fn context(s: &str) -> Option<&'static str> {
+    ///     match s {
+    ///         "A" => Some("a"),
+    ///         _ => None
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().max_substitutions(Some(2));
+    /// assert_eq!(expander.env_with_context_no_errors("$A$A$B", context), "aa$B");
+    /// assert_eq!(expander.env_with_context_no_errors("$A$A$A$A", context), "aa$A$A");
+    ///
+    /// let result = expander.env_with_context("$A $A $A", |s| Ok::<_, ()>(context(s)));
+    /// assert_eq!(result, Err(ExpandError::TooManySubstitutions { limit: 2, offset: 6 }));
+    /// ```
+    pub fn max_substitutions(mut self, max_substitutions: Option<usize>) -> Expander {
+        self.max_substitutions = max_substitutions;
+        self
+    }
+
+    /// Sets characters which must not appear in the values of variables.
+    ///
+    /// If the value of a referenced variable contains any of the characters in `chars`, the
+    /// expansion fails with `ExpandError::ForbiddenChar`, and the `*_no_errors` methods leave the
+    /// reference as it is. This prevents values from injecting, for example, line breaks into
+    /// single-line outputs like `Environment=` directives of systemd units, where a newline inside
+    /// a value would start a new directive. Only the values which end up in the output are
+    /// checked, after any pattern-matching operators are applied; the rest of the input,
+    /// including the words of operators, is trusted. No characters are forbidden by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::{ExpandError, Expander};
+    ///
+    /// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+    ///     match s {
+    ///         "USER" => Ok(Some("alice")),
+    ///         "EVIL" => Ok(Some("x\nExecStart=/bin/sh")),
+    ///         _ => Ok(None)
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().forbidden_value_chars("\r\n");
+    /// assert_eq!(
+    ///     expander.env_with_context("Environment=USER=$USER", context).unwrap(),
+    ///     "Environment=USER=alice"
+    /// );
+    /// assert_eq!(
+    ///     expander.env_with_context("Environment=USER=$EVIL", context),
+    ///     Err(ExpandError::ForbiddenChar {
+    ///         var_name: "EVIL".into(),
+    ///         offset: 17,
+    ///         character: '\n'
+    ///     })
+    /// );
+    /// ```
+    pub fn forbidden_value_chars(mut self, chars: &str) -> Expander {
+        self.forbidden_value_chars = chars.into();
+        self
+    }
+
+    /// Performs both tilde and environment expansion using the provided contexts.
+    ///
+    /// See `full_with_context()` for more details.
+    pub fn full_with_context<'a, SI, CO, C, E, P, HD>(
+        &self,
+        input: &'a SI,
+        home_dir: HD,
+        mut context: C,
+    ) -> Result<Cow<'a, str>, ExpandError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+        P: AsRef<Path>,
+        HD: FnOnce() -> Option<P>,
+    {
+        let input = input.as_ref();
+        if !self.variables {
+            return Ok(self.tilde_after_env(input, input.into(), home_dir));
+        }
+        self.expand_env(input, &mut context, &mut State::default())
+            .map(|r| self.tilde_after_env(input, r, home_dir))
+    }
+
+    /// Same as `full_with_context()`, but forbids the variable lookup function to return errors.
+    ///
+    /// See `full_with_context_no_errors()` for more details.
+    pub fn full_with_context_no_errors<'a, SI, CO, C, P, HD>(
+        &self,
+        input: &'a SI,
+        home_dir: HD,
+        mut context: C,
+    ) -> Cow<'a, str>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Option<CO>,
+        P: AsRef<Path>,
+        HD: FnOnce() -> Option<P>,
+    {
+        let input = input.as_ref();
+        if !self.variables {
+            return self.tilde_after_env(input, input.into(), home_dir);
+        }
+        let mut context = move |s: &str| Ok::<Option<CO>, ()>(context(s));
+        match self.expand_env(input, &mut context, &mut State::lenient()) {
+            Ok(result) => self.tilde_after_env(input, result, home_dir),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Performs the environment expansion using the provided context.
+    ///
+    /// See `env_with_context()` for more details.
+    pub fn env_with_context<'a, SI, CO, C, E>(
+        &self,
+        input: &'a SI,
+        mut context: C,
+    ) -> Result<Cow<'a, str>, ExpandError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        self.expand_env(input.as_ref(), &mut context, &mut State::default())
+    }
+
+    /// Same as `env_with_context()`, but forbids the variable lookup function to return errors.
+    ///
+    /// See `env_with_context_no_errors()` for more details.
+    pub fn env_with_context_no_errors<'a, SI, CO, C>(
+        &self,
+        input: &'a SI,
+        mut context: C,
+    ) -> Cow<'a, str>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Option<CO>,
+    {
+        let mut context = move |s: &str| Ok::<Option<CO>, ()>(context(s));
+        match self.expand_env(input.as_ref(), &mut context, &mut State::lenient()) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Same as `env_with_context()`, but reports references to unknown variables.
+    ///
+    /// `on_unresolved` is called with the name of a variable each time a reference to it is left
+    /// in the output as it is because the context function returned `Ok(None)` for it. It is not
+    /// called when an operator like `${VAR:-default}` provides a replacement for the reference, and
+    /// it does not affect the expansion in any way, which makes it suitable for logging unknown
+    /// variables without failing on them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+    ///     match s {
+    ///         "A" => Ok(Some("a value")),
+    ///         _ => Ok(None)
+    ///     }
+    /// }
+    ///
+    /// let mut unresolved = Vec::new();
+    /// let result = Expander::new().env_with_context_on_unresolved(
+    ///     "$A $B ${C:-c} ${D}",
+    ///     context,
+    ///     |name| unresolved.push(name.to_owned()),
+    /// );
+    ///
+    /// assert_eq!(result.unwrap(), "a value $B c ${D}");
+    /// assert_eq!(unresolved, ["B", "D"]);
+    /// ```
+    pub fn env_with_context_on_unresolved<'a, SI, CO, C, E, U>(
+        &self,
+        input: &'a SI,
+        context: C,
+        on_unresolved: U,
+    ) -> Result<Cow<'a, str>, ExpandError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+        U: FnMut(&str),
+    {
+        self.env_with_context_observed(input, context, OnUnresolved(on_unresolved))
+    }
+
+    /// Same as `env_with_context()`, but asks `on_unknown` what to do with every reference to
+    /// a variable unknown to the context.
+    ///
+    /// By default such references are left in the output as they are. Here `on_unknown` is called
+    /// with the name of the variable instead, and the returned `UnknownAction` decides whether the
+    /// reference is left as it is, replaced with an empty string or with another string, or makes
+    /// the expansion fail with `ExpandError::Unknown`. Since the decision is made for each
+    /// reference separately, the policy may depend on the name of the variable, for example to
+    /// require some variables while leaving the others as they are.
+    ///
+    /// The callback is not called for references whose operator provides a replacement, like
+    /// `${VAR:-default}`, nor for variables whose lookup fails. Replacements count as
+    /// substitutions for `Expander::max_substitutions()`.
+    ///
+    /// Unlike the other settings, the callback is not a part of the `Expander`, which only holds
+    /// plain data so that it can be cloned, printed with `Debug` and shared between threads; like
+    /// the callbacks of `env_with_context_on_unresolved()` and `env_with_context_observed()`, it
+    /// is passed to a single expansion, so it may borrow and mutate local state. Because of this,
+    /// the other entry points, like `full_with_context()`, the `*_no_errors()` variants,
+    /// `env_write()` and `expand_stream()`, always leave references to unknown variables as they
+    /// are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::{ExpandError, Expander, UnknownAction};
+    ///
+    /// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+    ///     match s {
+    ///         "A" => Ok(Some("a value")),
+    ///         _ => Ok(None)
+    ///     }
+    /// }
+    ///
+    /// let on_unknown = |name: &str| {
+    ///     if name.starts_with("REQUIRED_") {
+    ///         UnknownAction::Error
+    ///     } else {
+    ///         UnknownAction::Leave
+    ///     }
+    /// };
+    /// let expander = Expander::new();
+    ///
+    /// assert_eq!(
+    ///     expander.env_with_context_on_unknown("$A $B", context, on_unknown).unwrap(),
+    ///     "a value $B"
+    /// );
+    /// assert_eq!(
+    ///     expander.env_with_context_on_unknown("$A $REQUIRED_B", context, on_unknown),
+    ///     Err(ExpandError::Unknown { var_name: "REQUIRED_B".into(), offset: 3 })
+    /// );
+    /// ```
+    pub fn env_with_context_on_unknown<'a, SI, CO, C, E, U>(
+        &self,
+        input: &'a SI,
+        mut context: C,
+        mut on_unknown: U,
+    ) -> Result<Cow<'a, str>, ExpandError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+        U: FnMut(&str) -> UnknownAction,
+    {
+        let mut state = State {
+            on_unknown: Some(&mut on_unknown),
+            ..State::default()
+        };
+        self.expand_env(input.as_ref(), &mut context, &mut state)
+    }
+
+    /// Same as `env_with_context()`, but reports the lookups to an observer.
+    ///
+    /// See `ExpansionObserver` for the details of what is reported. The observer does not affect
+    /// the expansion in any way. It is taken by value, but a mutable reference to an observer may
+    /// be passed instead, so that it can be inspected after the expansion.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::{Expander, ExpansionObserver};
+    ///
+    /// #[derive(Default)]
+    /// struct Metrics {
+    ///     lookups: usize,
+    ///     unresolved: usize,
+    /// }
+    ///
+    /// impl ExpansionObserver for Metrics {
+    ///     fn on_lookup(&mut self, _: &str) {
+    ///         self.lookups += 1;
+    ///     }
+    ///
+    ///     fn on_unresolved(&mut self, _: &str) {
+    ///         self.unresolved += 1;
+    ///     }
+    /// }
+    ///
+    /// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+    ///     match s {
+    ///         "A" => Ok(Some("a value")),
+    ///         _ => Ok(None)
+    ///     }
+    /// }
+    ///
+    /// let mut metrics = Metrics::default();
+    /// let expander = Expander::new();
+    /// let result = expander.env_with_context_observed("$A $B ${C:-c}", context, &mut metrics);
+    ///
+    /// assert_eq!(result.unwrap(), "a value $B c");
+    /// assert_eq!(metrics.lookups, 3);
+    /// assert_eq!(metrics.unresolved, 1);
+    /// ```
+    pub fn env_with_context_observed<'a, SI, CO, C, E, O>(
+        &self,
+        input: &'a SI,
+        mut context: C,
+        mut observer: O,
+    ) -> Result<Cow<'a, str>, ExpandError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+        O: ExpansionObserver,
+    {
+        let mut state = State {
+            observer: Some(&mut observer),
+            ..State::default()
+        };
+        self.expand_env(input.as_ref(), &mut context, &mut state)
+    }
+
+    /// Splits `input` into spans of literal text, escapes and variable references.
+    ///
+    /// The spans are found by the same parser which is used by the environment expansion with
+    /// this expander's settings, so a `Var` span is returned exactly for those references which
+    /// would be looked up in the context. The spans cover the whole input in order and without
+    /// gaps, and adjacent literal text is always merged into a single span. Operator words are a
+    /// part of the span of their reference; they are not split further.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::{Expander, ReferenceKind, Span, SpanKind};
+    ///
+    /// let spans = Expander::new().shell_escaping(true).parse_spans("\\$A $$");
+    /// assert_eq!(
+    ///     spans,
+    ///     [
+    ///         Span { kind: SpanKind::Escape, range: 0..2 },
+    ///         Span { kind: SpanKind::Literal, range: 2..4 },
+    ///         Span { kind: SpanKind::Var { name: "$", kind: ReferenceKind::Bare }, range: 4..6 },
+    ///     ]
+    /// );
+    /// ```
+    pub fn parse_spans<'a, SI>(&self, input: &'a SI) -> Vec<Span<'a>>
+    where
+        SI: AsRef<str> + ?Sized,
+    {
+        self.tokens(input.as_ref())
+            .map(|token| Span {
+                kind: match token.kind {
+                    TokenKind::Literal => SpanKind::Literal,
+                    TokenKind::Escape => SpanKind::Escape,
+                    TokenKind::Bare(name) => SpanKind::Var {
+                        name,
+                        kind: ReferenceKind::Bare,
+                    },
+                    TokenKind::Braced(name, _) => SpanKind::Var {
+                        name,
+                        kind: ReferenceKind::Braced,
+                    },
+                },
+                range: token.range,
+            })
+            .collect()
+    }
+
+    /// Applies tilde expansion to the result of environment expansion of `input`.
+    fn tilde_after_env<'a, P, HD>(
+        &self,
+        input: &str,
+        expanded: Cow<'a, str>,
+        home_dir: HD,
+    ) -> Cow<'a, str>
+    where
+        P: AsRef<Path>,
+        HD: FnOnce() -> Option<P>,
+    {
+        if !self.tilde {
+            return expanded;
+        }
+        if let Some(escape) = self.tilde_escape {
+            // an escaped tilde is not touched by the environment expansion, so it is still there
+            let is_escaped =
+                |s: &str| s.starts_with(escape) && s[escape.len_utf8()..].starts_with('~');
+            if is_escaped(input) && is_escaped(&expanded) {
+                return match expanded {
+                    Cow::Borrowed(s) => s[escape.len_utf8()..].into(),
+                    Cow::Owned(mut s) => {
+                        s.drain(..escape.len_utf8());
+                        s.into()
+                    }
+                };
+            }
+        }
+        let existing_home_only = self.existing_home_only;
+        let home_dir = move || home_dir().filter(|hd| !existing_home_only || hd.as_ref().is_dir());
+        // `home_dir` is consumed by exactly one of the branches below, and `tilde_with_context()`
+        // only calls it when there is a tilde to expand, so it is called at most once
+        match expanded {
+            // variable expansion did not modify the original string, so we can apply tilde
+            // expansion directly
+            Cow::Borrowed(s) => tilde_with_context(s, home_dir),
+            // if the original string does not start with a tilde but the processed one does,
+            // then the tilde is contained in one of variables and should not be expanded
+            Cow::Owned(s) if !input.starts_with('~') && s.starts_with('~') => s.into(),
+            Cow::Owned(s) => match tilde_with_context(&s, home_dir) {
+                Cow::Owned(s) => s.into(),
+                Cow::Borrowed(_) => s.into(),
+            },
+        }
+    }
+
+    /// Writes a piece of the input which is not a part of any variable reference to `out`.
+    fn write_literal<W: fmt::Write + ?Sized>(&self, out: &mut W, literal: &str) -> fmt::Result {
+        if !self.strip_double_quotes {
+            return out.write_str(literal);
+        }
+        let mut chars = literal.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'"') => out.write_char(chars.next().unwrap())?,
+                '"' => {}
+                c => out.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands the word of an operator, like `word` in `${VAR:-word}`.
+    fn expand_word<'w, CO, C, E>(
+        &self,
+        raw_word: &'w str,
+        word_offset: usize,
+        context: &mut C,
+        state: &mut State,
+    ) -> Result<Cow<'w, str>, ExpandError<E>>
+    where
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        let close = self.delimiters.1;
+        let word = unescape_closing_braces(raw_word, close);
+        if !self.recursive {
+            return Ok(word);
+        }
+        let value = self.expand_env(&word, context, state).map_err(|mut e| {
+            let offset = e.offset_mut();
+            *offset = word_offset + escaped_offset(raw_word, close, *offset);
+            e
+        })?;
+        Ok(value.into_owned().into())
+    }
+
+    /// Applies a pattern-matching operator to the value of a variable.
+    ///
+    /// Returns `None` if the pattern is invalid and the expansion is lenient.
+    #[allow(clippy::too_many_arguments)]
+    fn apply_pattern_operator<CO, C, E>(
+        &self,
+        value: &str,
+        operator: Operator,
+        raw_word: &str,
+        word_offset: usize,
+        offset: usize,
+        context: &mut C,
+        state: &mut State,
+    ) -> Result<Option<String>, ExpandError<E>>
+    where
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        let (raw_pattern, raw_replacement) = match operator {
+            Operator::Replace { .. } => split_replacement(raw_word),
+            _ => (raw_word, ""),
+        };
+
+        let mut pattern_text = self.expand_word(raw_pattern, word_offset, context, state)?;
+        if self.escape_sequences {
+            pattern_text = unescape_control_chars(pattern_text);
+        }
+        let pattern = match Pattern::new(&pattern_text, self.pattern_syntax) {
+            Ok(pattern) => pattern,
+            Err(_) if state.lenient => return Ok(None),
+            Err(message) => {
+                return Err(ExpandError::InvalidPattern {
+                    pattern: pattern_text.into_owned(),
+                    offset,
+                    message,
+                })
+            }
+        };
+
+        let result = match operator {
+            Operator::RemovePrefix { longest } => pattern.remove_prefix(value, longest).into(),
+            Operator::RemoveSuffix { longest } => pattern.remove_suffix(value, longest).into(),
+            Operator::Replace { all } => {
+                let replacement_offset = word_offset + raw_word.len() - raw_replacement.len();
+                let mut replacement =
+                    self.expand_word(raw_replacement, replacement_offset, context, state)?;
+                if self.escape_sequences {
+                    replacement = unescape_control_chars(replacement);
+                }
+                pattern.replace(value, &replacement, all)
+            }
+            Operator::ChangeCase { upper, all } => {
+                // an omitted pattern matches every character
+                change_case(value, upper, all, |c| {
+                    pattern_text.is_empty() || pattern.matches(c.encode_utf8(&mut [0; 4]))
+                })
+            }
+            Operator::Default | Operator::Alternate | Operator::Error => unreachable!(),
+        };
+        Ok(Some(result))
+    }
+
+    fn expand_env<'a, CO, C, E>(
+        &self,
+        input_str: &'a str,
+        context: &mut C,
+        state: &mut State,
+    ) -> Result<Cow<'a, str>, ExpandError<E>>
+    where
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        // the fast path for inputs without variables, like `~/path`: the input stays borrowed, so
+        // `tilde_after_env()` expands the tilde right in it, and the context is never called
+        if !input_str.contains(self.introducer())
+            && (!self.strip_double_quotes || !input_str.contains('"'))
+        {
+            return Ok(input_str.into());
+        }
+
+        let mut result = String::with_capacity(input_str.len());
+        match self.write_env(input_str, &mut result, context, state) {
+            Ok(()) => Ok(result.into()),
+            Err(ExpandWriteError::Expand(e)) => Err(e),
+            Err(ExpandWriteError::Write(_)) => unreachable!("writing to a string never fails"),
+        }
+    }
+
+    /// Writes the environment expansion of `input_str` to `out`.
+    ///
+    /// This is the core of all environment expansions; the ones producing strings simply write
+    /// to a `String`.
+    fn write_env<W, CO, C, E>(
+        &self,
+        input_str: &str,
+        out: &mut W,
+        context: &mut C,
+        state: &mut State,
+    ) -> Result<(), ExpandWriteError<E>>
+    where
+        W: fmt::Write + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        for token in self.tokens(input_str) {
+            self.write_token(input_str, token, out, context, state)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the expansion of a single token of `input_str` to `out`.
+    fn write_token<W, CO, C, E>(
+        &self,
+        input_str: &str,
+        token: Token,
+        out: &mut W,
+        context: &mut C,
+        state: &mut State,
+    ) -> Result<(), ExpandWriteError<E>>
+    where
+        W: fmt::Write + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        let text = &input_str[token.range.clone()];
+        let offset = token.range.start;
+        match token.kind {
+            TokenKind::Literal => self.write_literal(out, text)?,
+            TokenKind::Escape => out.write_str(self.introducer())?,
+            TokenKind::Bare(var_name) => {
+                let value = self.lookup(var_name, context, state);
+                match try_lookup!(var_name, offset, ReferenceKind::Bare, value) {
+                    Some(_) if !self.count_substitution(offset, state)? => out.write_str(text)?,
+                    Some(ref var_value)
+                        if !self.check_value(var_name, var_value.as_ref(), offset, state)? =>
+                    {
+                        out.write_str(text)?
+                    }
+                    Some(var_value) => out.write_str(var_value.as_ref())?,
+                    None => self.write_unknown(var_name, text, offset, out, state)?,
+                }
+            }
+            TokenKind::Braced(var_name, operator) => {
+                // operator words end right before the closing brace, or before the comment
+                let word_offset = |raw_word: &str| {
+                    let mut end = token.range.end - self.delimiters.1.len_utf8();
+                    if self.brace_comments {
+                        let contents = &input_str[offset..end];
+                        end = offset + strip_brace_comment(contents).len();
+                    }
+                    end - raw_word.len()
+                };
+                // references inside the name, like `$B` in `${A_$B}`, are expanded first
+                let expanded_name =
+                    if self.recursive && self.placeholders.is_none() && var_name.contains('$') {
+                        let name_start = 1 + self.delimiters.0.len_utf8();
+                        // the name may be preceded by whitespace if it is trimmed
+                        let name_offset =
+                            offset + name_start + text[name_start..].find(var_name).unwrap_or(0);
+                        self.expand_word(var_name, name_offset, context, state)?
+                    } else {
+                        Cow::Borrowed(var_name)
+                    };
+                let var_name = &*expanded_name;
+
+                // the limit of substitutions is checked before any operator word is expanded
+                let value = self.lookup(var_name, context, state);
+                let substitutes = match (&value, operator) {
+                    (&Ok(Some(_)), _) => true,
+                    (value, Some((Operator::Default, _)))
+                    | (value, Some((Operator::Alternate, _))) => {
+                        value.is_ok() || self.unset_on_error
+                    }
+                    _ => false,
+                };
+                if substitutes && !self.count_substitution(offset, state)? {
+                    out.write_str(text)?;
+                    return Ok(());
+                }
+
+                let word = match (value, operator) {
+                    // if we have the variable set to some value
+                    (Ok(Some(var_value)), None)
+                    | (Ok(Some(var_value)), Some((Operator::Default, _)))
+                    | (Ok(Some(var_value)), Some((Operator::Error, _))) => {
+                        if self.check_value(var_name, var_value.as_ref(), offset, state)? {
+                            out.write_str(var_value.as_ref())?;
+                        } else {
+                            out.write_str(text)?;
+                        }
+                        None
+                    }
+                    // use the alternate value if the variable is set
+                    (Ok(Some(_)), Some((Operator::Alternate, word))) => Some(word),
+                    // transform the value with a pattern-matching operator
+                    (Ok(Some(var_value)), Some((operator, word))) => {
+                        let value = self.apply_pattern_operator(
+                            var_value.as_ref(),
+                            operator,
+                            word,
+                            word_offset(word),
+                            offset,
+                            context,
+                            state,
+                        )?;
+                        match value {
+                            Some(ref value)
+                                if self.check_value(var_name, value, offset, state)? =>
+                            {
+                                out.write_str(value)?
+                            }
+                            _ => out.write_str(text)?,
+                        }
+                        None
+                    }
+                    // use the default value if the variable is unset
+                    (ref value, Some((Operator::Default, word)))
+                        if value.is_ok() || self.unset_on_error =>
+                    {
+                        Some(word)
+                    }
+                    // the alternate value is not used if the variable is unset
+                    (ref value, Some((Operator::Alternate, _)))
+                        if value.is_ok() || self.unset_on_error =>
+                    {
+                        None
+                    }
+                    // fail with the message of the error operator if the variable is unset
+                    (Ok(None), Some((Operator::Error, word)))
+                        if self.error_operator && !state.lenient =>
+                    {
+                        let message = self.expand_word(word, word_offset(word), context, state)?;
+                        return Err(ExpandError::Unset {
+                            var_name: var_name.into(),
+                            offset,
+                            message: if message.is_empty() {
+                                UNSET_MESSAGE.into()
+                            } else {
+                                message.into_owned()
+                            },
+                        }
+                        .into());
+                    }
+                    // return an error if we don't have a default and the variable is unset
+                    (Err(err), _) => {
+                        return Err(ExpandError::Lookup(LookupError {
+                            var_name: var_name.into(),
+                            offset,
+                            kind: ReferenceKind::Braced,
+                            cause: err,
+                        })
+                        .into());
+                    }
+                    // leave the variable as it is if the environment is empty
+                    (Ok(None), _) => {
+                        self.write_unknown(var_name, text, offset, out, state)?;
+                        None
+                    }
+                };
+
+                if let Some(raw_word) = word {
+                    let value =
+                        self.expand_word(raw_word, word_offset(raw_word), context, state)?;
+                    out.write_str(&value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the result of a reference to an unknown variable, as decided by the callback of
+    /// `env_with_context_on_unknown()`; `text` is the reference as it is written in the input.
+    fn write_unknown<W, E>(
+        &self,
+        var_name: &str,
+        text: &str,
+        offset: usize,
+        out: &mut W,
+        state: &mut State,
+    ) -> Result<(), ExpandWriteError<E>>
+    where
+        W: fmt::Write + ?Sized,
+    {
+        let replacement = match state.unknown_action(var_name) {
+            UnknownAction::Leave => None,
+            UnknownAction::Empty => Some(Cow::Borrowed("")),
+            UnknownAction::Replace(replacement) => Some(Cow::Owned(replacement)),
+            UnknownAction::Error if !state.lenient => {
+                return Err(ExpandError::Unknown {
+                    var_name: var_name.into(),
+                    offset,
+                }
+                .into());
+            }
+            UnknownAction::Error => None,
+        };
+        match replacement {
+            Some(replacement) if self.count_substitution(offset, state)? => {
+                out.write_str(&replacement)?
+            }
+            _ => {
+                state.unresolved(var_name);
+                out.write_str(text)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `value` of the variable `var_name` contains no forbidden characters.
+    ///
+    /// Returns false if it does and errors are ignored, in which case the reference should be
+    /// left as it is.
+    fn check_value<E>(
+        &self,
+        var_name: &str,
+        value: &str,
+        offset: usize,
+        state: &State,
+    ) -> Result<bool, ExpandError<E>> {
+        if self.forbidden_value_chars.is_empty() {
+            return Ok(true);
+        }
+        match value
+            .chars()
+            .find(|&c| self.forbidden_value_chars.contains(c))
+        {
+            None => Ok(true),
+            Some(_) if state.lenient => Ok(false),
+            Some(character) => Err(ExpandError::ForbiddenChar {
+                var_name: var_name.into(),
+                offset,
+                character,
+            }),
+        }
+    }
+
+    /// Counts a substitution of the reference at `offset`, checking the limit of substitutions.
+    ///
+    /// Returns false if the limit is exceeded and the expansion is lenient, which means that the
+    /// reference should be left as it is.
+    fn count_substitution<E>(
+        &self,
+        offset: usize,
+        state: &mut State,
+    ) -> Result<bool, ExpandError<E>> {
+        let limit = match self.max_substitutions {
+            Some(limit) => limit,
+            None => return Ok(true),
+        };
+        if state.substitutions < limit {
+            state.substitutions += 1;
+            Ok(true)
+        } else if state.lenient {
+            Ok(false)
+        } else {
+            Err(ExpandError::TooManySubstitutions { limit, offset })
+        }
+    }
+
+    /// Looks a variable up in the context.
+    ///
+    /// In recursive mode successful lookups are cached for the whole expansion, so the context
+    /// is called at most once for each variable even if it is referenced many times.
+    fn lookup<CO, C, E>(
+        &self,
+        var_name: &str,
+        context: &mut C,
+        state: &mut State,
+    ) -> Result<Option<Value<CO>>, E>
+    where
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        if !self.recursive {
+            return state
+                .call_context(var_name, context)
+                .map(|value| value.map(Value::Fresh));
+        }
+
+        if let Some(value) = state.memo.as_ref().and_then(|memo| memo.get(var_name)) {
+            return Ok(value.clone().map(Value::Cached));
+        }
+        let value = state
+            .call_context(var_name, context)?
+            .map(|value| Rc::from(value.as_ref()));
+        let memo = state.memo.get_or_insert_with(HashMap::new);
+        memo.insert(var_name.into(), value.clone());
+        Ok(value.map(Value::Cached))
+    }
+
+    /// Returns the string which starts references and escapes: the introducer of placeholders, if
+    /// they are used, or a dollar.
+    fn introducer(&self) -> &str {
+        self.placeholders
+            .as_ref()
+            .map_or("$", |(introducer, _)| introducer)
+    }
+
+    fn tokens<'a, 'e>(&'e self, input: &'a str) -> Tokens<'a, 'e> {
+        Tokens {
+            input,
+            pos: 0,
+            pending: None,
+            delimiters: self.delimiters,
+            nested: self.recursive,
+            shell_escaping: self.shell_escaping,
+            extra_name_chars: &self.extra_name_chars,
+            excluded_name_chars: &self.excluded_name_chars,
+            trim_braced_names: self.trim_braced_names,
+            brace_comments: self.brace_comments,
+            placeholders: self
+                .placeholders
+                .as_ref()
+                .map(|(introducer, terminator)| (&**introducer, &**terminator)),
+        }
+    }
+}
+
+/// A value of a variable, either returned by the context or cached by `Expander::lookup()`.
+enum Value<CO> {
+    Fresh(CO),
+    Cached(Rc<str>),
+}
+
+impl<CO: AsRef<str>> AsRef<str> for Value<CO> {
+    fn as_ref(&self) -> &str {
+        match *self {
+            Value::Fresh(ref value) => value.as_ref(),
+            Value::Cached(ref value) => value,
+        }
+    }
+}
+
+/// The message of `ExpandError::Unset` for `${VAR:?}` references without a message.
+const UNSET_MESSAGE: &str = "parameter null or not set";
+
+/// The state of a single expansion, shared with the nested expansions of operator words.
+#[derive(Default)]
+struct State<'s> {
+    /// Whether errors other than lookup errors leave the offending reference as it is.
+    lenient: bool,
+    observer: Option<&'s mut dyn ExpansionObserver>,
+    /// The callback which decides what to do with references to unknown variables.
+    on_unknown: Option<&'s mut dyn FnMut(&str) -> UnknownAction>,
+    /// The values of variables which have already been looked up, in recursive mode.
+    memo: Option<HashMap<String, Option<Rc<str>>>>,
+    /// The number of substitutions performed so far, if their number is limited.
+    substitutions: usize,
+}
+
+impl<'s> State<'s> {
+    fn lenient() -> State<'s> {
+        State {
+            lenient: true,
+            ..State::default()
+        }
+    }
+
+    fn unresolved(&mut self, var_name: &str) {
+        if let Some(ref mut observer) = self.observer {
+            observer.on_unresolved(var_name);
+        }
+    }
+
+    /// Asks the callback what to do with a reference to the unknown variable `var_name`.
+    fn unknown_action(&mut self, var_name: &str) -> UnknownAction {
+        match self.on_unknown {
+            Some(ref mut on_unknown) => on_unknown(var_name),
+            None => UnknownAction::Leave,
+        }
+    }
+
+    /// Calls the context, reporting the lookup to the observer.
+    fn call_context<CO, C, E>(&mut self, var_name: &str, context: &mut C) -> Result<Option<CO>, E>
+    where
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        let observer = match self.observer {
+            Some(ref mut observer) => observer,
+            None => return context(var_name),
+        };
+        observer.on_lookup(var_name);
+        let value = context(var_name);
+        match value {
+            Ok(Some(ref value)) => observer.on_resolved(var_name, value.as_ref().len()),
+            Ok(None) => {}
+            Err(_) => observer.on_error(var_name),
+        }
+        value
+    }
+}
+
+/// Performs the environment expansion using the provided context.
+///
+/// This function walks through the input string `input` and attempts to construct a new string by
+/// replacing all shell-like variable sequences with the corresponding values obtained via the
+/// `context` function. The latter may return an error; in this case the error will be returned
+/// immediately, along with the name of the offending variable. Also the context function may
+/// return `Ok(None)`, indicating that the given variable is not available; in this case the
+/// variable sequence is left as it is in the output string. Every reference which is not expanded
+/// for any reason is copied to the output exactly as it is written in the input, including its
+/// operator, its word and any escapes inside it.
+///
+/// The syntax of variables resembles the one of bash-like shells: all of `$VAR`, `${VAR}`,
+/// `$NAME_WITH_UNDERSCORES` are valid variable references, and the form with braces may be used to
+/// separate the reference from the surrounding alphanumeric text: `before${VAR}after`. Note,
+/// however, that for simplicity names like `$123` or `$1AB` are also valid, as opposed to shells
+/// where `$<number>` has special meaning of positional arguments. Also note that "alphanumericity"
+/// of variable names is checked with `char::is_alphanumeric()`, therefore lots of characters which
+/// are considered alphanumeric by the Unicode standard are also valid names for variables. When
+/// unsure, use braces to separate variables from the surrounding text. Adjacent references, like
+/// `${PREFIX}${SUFFIX}` or `$A$B`, are always resolved independently of each other, and their
+/// results are concatenated; if one of them is not set, it is left as it is, and the others are
+/// still expanded. Names are never built from several references. A `${` which is not
+/// followed by a closing brace anywhere in the rest of the string, including a `${` at the very end
+/// of it, is not considered a variable reference and is left in the output as it is. The same
+/// applies to braces without a variable name, like `${}` or `${:-default}`; the context function is
+/// not called for them. A dollar inside braces is a part of the name, so for `${A$B}` the context
+/// is asked for the variable named `A$B`, which is left as it is if the context does not know it;
+/// use `Expander::recursive()` to expand `$B` first and look up the resulting name instead.
+///
+/// A literal dollar is written as `$$`. Dollars are consumed from left to right, so in a run of
+/// several dollars every pair becomes a single literal dollar, and what remains after the pairs
+/// depends on the parity of the run: `$$$$` expands to `$$`, `$$$VAR` expands to a dollar followed
+/// by the value of `VAR`, and `$$$` expands to `$$`, because the last dollar is not followed by
+/// a name and is left as it is, like a lone `$`. Conversely, `$$VAR` is never a reference, since
+/// the escape consumes both dollars before the name. Use `Expander::shell_escaping()` to switch to
+/// the shell rules instead.
+///
+/// Braced references may specify a default value which is used when the variable is not set or its
+/// lookup fails: `${VAR:-default}`. Conversely, an alternate value may be specified which is used
+/// only when the variable is set, and otherwise the reference expands to an empty string:
+/// `${VAR:+alternate}`. A variable is considered set when the context function returns a value for
+/// it, even if this value is empty. Lookup errors are not returned for these references; use
+/// `Expander::unset_on_error()` if they should be. A closing brace may be included into these
+/// values by escaping it with a backslash, so `${VAR:-a\}b}` expands to `a}b` if `VAR` is not set.
+/// The backslash is only special in front of a closing brace inside braces; everywhere else it is
+/// left as it is. The values are inserted literally; use `Expander::recursive()` if you need
+/// variable references inside them to be expanded too. `${VAR:?message}` expands just like
+/// `${VAR}`; use `Expander::error_operator()` to make it fail when the variable is not set.
+///
+/// The value of a set variable may be transformed with the pattern-matching operators before it is
+/// inserted: `${VAR#pattern}` and `${VAR##pattern}` remove the shortest and the longest prefix of
+/// the value matching the glob `pattern`, `${VAR%pattern}` and `${VAR%%pattern}` do the same with
+/// suffixes, and `${VAR/pattern/replacement}` replaces the first longest match of `pattern` with
+/// `replacement` (or every match, if the operator is written as `//`; an omitted replacement
+/// removes the matches). If the variable is not set, the reference is left as it is, just like
+/// a reference without an operator.
+///
+/// The case of a set variable may be changed too: `${VAR^^}` and `${VAR,,}` convert the whole value
+/// to upper and lower case, respectively, while `${VAR^}` and `${VAR,}` only convert its first
+/// character. A pattern may follow these operators, like in `${VAR^^[aeiou]}`; then only the
+/// characters matching it are converted. `${VAR@U}`, `${VAR@L}` and `${VAR@u}` are the same as
+/// `${VAR^^}`, `${VAR,,}` and `${VAR^}`. Full Unicode case mappings are used, so one character may
+/// be converted into several ones: `ß` becomes `SS` in upper case. The mappings do not depend on
+/// the locale, which means, for example, that `i` always becomes `I`, even though in Turkish it
+/// would be the dotted `İ`.
+///
+/// Because of these operators, a braced variable name ends at the first `#`, `%`, `/`, `^` or `,`,
+/// and at `@U`, `@u` or `@L` at the end of the braces. Before version 3.0 such characters were
+/// a part of the name, so `${a/b}` looked up a variable named `a/b`, while now it replaces `b`
+/// in the value of `a`. Contexts which need such names have to use a different notation for them.
+///
+/// This function has four generic type parameters: `SI` represents the input string, `CO` is the
+/// output of context lookups, `C` is the context closure and `E` is the type of errors returned by
+/// the context function. `SI` and `CO` must be types, a references to which can be converted to
+/// a string slice. For example, it is fine for the context function to return `&str`s, `String`s or
+/// `Cow<str>`s, which gives the user a lot of flexibility.
+///
+/// If the context function returns an error, it will be wrapped into `LookupError` and returned
+/// immediately. `LookupError`, besides the original error, also contains a string with the name of
+/// the variable whose expansion caused the error. `LookupError` implements `Error`, `Clone` and
+/// `Eq` traits for further convenience and interoperability.
+///
+/// The context is taken by value, but since `&mut F` implements `FnMut` whenever `F` does, it is
+/// possible to pass a mutable reference to a context instead, including a `&mut dyn FnMut(&str) ->
+/// ...` trait object. This way a single stateful context, like a cache or a recorder of looked up
+/// names, can be reused across many expansions without being moved into them.
+///
+/// The context is called lazily, in the order of the references in the input, and at most once
+/// for each reference. It is never called for references which are not expanded, like the ones
+/// inside the word of an operator whose branch is not taken: `$B` is not looked up in
+/// `${A:+$B}` if `A` is unset, or in `${A:-$B}` if `A` is set. Since words are only expanded in
+/// recursive mode, by default the context is called exactly once for each reference outside of
+/// words. This makes it possible to obtain values which are expensive to get, like secrets from
+/// a remote store, only when they are actually used; with `Expander::recursive()` enabled, each
+/// variable is also looked up at most once per call.
+///
+/// If you need to expand system environment variables, you can use `env()` or `full()` functions.
+/// If your context does not have errors, you may use `env_with_context_no_errors()` instead of
+/// this function because it provides a simpler API.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         "B" => Ok(Some("b value")),
+///         "E" => Err("something went wrong"),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// // Regular variables are expanded
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/$A/${B}s/end", context).unwrap(),
+///     "begin/a value/b values/end"
+/// );
+///
+/// // Expand to a default value if the variable is not defined
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${UNSET_ENV:-42}/end", context).unwrap(),
+///     "begin/42/end"
+/// );
+///
+/// // Expand to an alternate value only if the variable is defined
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${A:+42}/${UNSET_ENV:+42}/end", context).unwrap(),
+///     "begin/42//end"
+/// );
+///
+/// // Unknown variables are left as is
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/$UNKNOWN/end", context).unwrap(),
+///     "begin/$UNKNOWN/end"
+/// );
+///
+/// // Errors are propagated
+/// let err = shellexpand::env_with_context("begin${E}end", context).unwrap_err();
+/// assert_eq!(err.var_name, "E");
+/// assert_eq!(err.offset(), 5);
+/// assert_eq!(err.kind(), shellexpand::ReferenceKind::Braced);
+/// assert_eq!(err.cause, "something went wrong");
+/// ```
+pub fn env_with_context<SI, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<Cow<'_, str>, LookupError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    Expander::new()
+        .env_with_context(input, context)
+        .map_err(ExpandError::into_lookup_error)
+}
+
+/// Same as `env_with_context()`, but forbids the variable lookup function to return errors.
+///
+/// This function also performs environment expansion, but it requires context function of type
+/// `FnMut(&str) -> Option<CO>` instead of `FnMut(&str) -> Result<Option<CO>, E>`. This simplifies
+/// the API when you know in advance that the context lookups may not fail.
+///
+/// Because of the above, instead of `Result<Cow<str>, LookupError<E>>` this function returns just
+/// `Cow<str>`.
+///
+/// Note that if the context function returns `None`, the behavior remains the same as that of
+/// `env_with_context()`: the variable reference will remain in the output string unexpanded.
+///
+/// All operators supported by `env_with_context()`, that is, default and alternate values and the
+/// pattern-matching operators, work in exactly the same way here, and this function never fails.
+/// The error operator of `${VAR:?message}` is parsed as well, but such a reference expands just
+/// like `${VAR}`: to the value of the variable if it is set, and it is left in the output as it
+/// is otherwise. `Expander::env_with_context_no_errors()` behaves the same way even if
+/// `Expander::error_operator()` is enabled.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Option<&'static str> {
+///     match s {
+///         "A" => Some("a value"),
+///         "B" => Some("b value"),
+///         _ => None
+///     }
+/// }
+///
+/// // Known variables are expanded
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("begin/$A/${B}s/end", context),
+///     "begin/a value/b values/end"
+/// );
+///
+/// // Unknown variables are left as is
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("begin/$U/end", context),
+///     "begin/$U/end"
+/// );
+///
+/// // Operators are supported
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("${U:-default}/${A:+alt}/${B#b }", context),
+///     "default/alt/value"
+/// );
+///
+/// // The error operator does not fail the expansion
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("${A:?required}/${U:?required}", context),
+///     "a value/${U:?required}"
+/// );
+/// ```
+#[inline]
+pub fn env_with_context_no_errors<SI, CO, C>(input: &SI, context: C) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Option<CO>,
+{
+    Expander::new().env_with_context_no_errors(input, context)
+}
+
+/// Performs the environment expansion, requiring all referenced variables to be known.
+///
+/// This function expands `input` like `env_with_context_no_errors()`, but if any reference is
+/// left in the output as it is because the context does not know the variable, the expanded
+/// string is discarded, and the sorted list of the names of all such variables, without
+/// duplicates, is returned instead. References which are replaced by an operator, like
+/// `${VAR:-default}`, do not count as missing. This is useful for validating that all required
+/// variables are present, for example, in command line tools.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Option<&'static str> {
+///     match s {
+///         "A" => Some("a value"),
+///         _ => None
+///     }
+/// }
+///
+/// assert_eq!(shellexpand::expand_checked("$A/${B:-b}", context).unwrap(), "a value/b");
+/// assert_eq!(
+///     shellexpand::expand_checked("$D/$A/$C/${D}", context).unwrap_err(),
+///     ["C", "D"]
+/// );
+/// ```
+pub fn expand_checked<SI, CO, C>(input: &SI, mut context: C) -> Result<String, Vec<String>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Option<CO>,
+{
+    let mut missing = Vec::new();
+    let result = match Expander::new().env_with_context_on_unresolved(
+        input,
+        |s| Ok::<_, ()>(context(s)),
+        |name| missing.push(name.to_owned()),
+    ) {
+        Ok(result) => result,
+        Err(_) => unreachable!(),
+    };
+    if missing.is_empty() {
+        Ok(result.into_owned())
+    } else {
+        missing.sort_unstable();
+        missing.dedup();
+        Err(missing)
+    }
+}
+
+/// Performs the environment expansion using the default system context.
+///
+/// This function delegates to `env_with_context()`, using the default system source for
+/// environment variables, namely the `std::env::var()` function.
+///
+/// Note that variable lookup of unknown variables will fail with an error instead of, for example,
+/// replacing the offending variables with an empty string. The author thinks that such behavior is
+/// more useful than the other ones. If you need something else, use `env_with_context()` or
+/// `env_with_context_no_errors()` with an appropriate context function.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// // make sure that some environment variables are set
+/// env::set_var("X", "x value");
+/// env::set_var("Y", "y value");
+///
+/// // Known variables are expanded
+/// assert_eq!(
+///     shellexpand::env("begin/$X/${Y}s/end").unwrap(),
+///     "begin/x value/y values/end"
+/// );
+///
+/// // Unknown variables result in an error
+/// let err = shellexpand::env("begin/$Z/end").unwrap_err();
+/// assert_eq!(err.var_name, "Z");
+/// assert_eq!(err.offset(), 6);
+/// assert_eq!(err.kind(), shellexpand::ReferenceKind::Bare);
+/// assert_eq!(err.cause, env::VarError::NotPresent);
+/// ```
+#[inline]
+pub fn env<SI>(input: &SI) -> Result<Cow<'_, str>, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    env_with_context(input, |s| std::env::var(s).map(Some))
+}
+
+/// Same as `env()`, but never fails.
+///
+/// Variables which are not present in the environment of the current process, as well as the
+/// ones whose values are not valid Unicode, are left as they are, just like the variables which
+/// are unknown to the context of `env_with_context_no_errors()`. Use this function when the input
+/// should be expanded on a best effort basis, without handling any errors.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("X", "x value");
+/// env::remove_var("MOST_LIKELY_NONEXISTING_VAR");
+///
+/// assert_eq!(
+///     shellexpand::env_or_empty("$X/$MOST_LIKELY_NONEXISTING_VAR"),
+///     "x value/$MOST_LIKELY_NONEXISTING_VAR"
+/// );
+/// ```
+#[inline]
+pub fn env_or_empty<SI>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    env_with_context_no_errors(input, |s| std::env::var(s).ok())
+}
+
+/// Performs the environment expansion using the system environment, returning the result as
+/// a shared string.
+///
+/// This function works exactly like `env()`, but the result is an `Arc<str>`; see `full_arc()`
+/// for more details.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("SHELLEXPAND_ENV_ARC_DOC", "a value");
+///
+/// assert_eq!(
+///     &*shellexpand::env_arc("~/$SHELLEXPAND_ENV_ARC_DOC").unwrap(),
+///     "~/a value"
+/// );
+/// ```
+#[inline]
+pub fn env_arc<SI>(input: &SI) -> Result<Arc<str>, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    env(input).map(|s| Arc::from(&*s))
+}
+
+/// Performs the environment expansion using a map of string slices as the context.
+///
+/// This function works like `env_with_context_no_errors()` with a context which looks variables up
+/// in `map`. The values are spliced into the result directly from the map, without being cloned
+/// into intermediate strings, so this function is the cheapest way to expand variables whose
+/// names and values are already available as string slices. Variables which are not present in
+/// the map are left as they are, so this function never fails. `full_with_map()` accepts such
+/// maps too, if the tilde should be expanded as well.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("A", "a value");
+///
+/// assert_eq!(shellexpand::env_with_map_ref("x/$A/$B", &map), "x/a value/$B");
+/// ```
+#[inline]
+pub fn env_with_map_ref<'a, SI, S>(input: &'a SI, map: &HashMap<&str, &str, S>) -> Cow<'a, str>
+where
+    SI: AsRef<str> + ?Sized,
+    S: BuildHasher,
+{
+    env_with_context_no_errors(input, |s| map.get(s).copied())
+}
+
+/// Creates a context which looks variables up in `map`, ignoring the ASCII case of the names.
+///
+/// This is useful for user-facing configuration on case-insensitive systems, where `${home}`
+/// should find a `HOME` key. Names are compared with ASCII case folding, so `${HOME}`, `${home}`
+/// and `${Home}` all reference the same variable, while non-ASCII letters must match exactly.
+///
+/// If several keys of the map differ only in case, like `PATH` and `Path`, the lookup is still
+/// deterministic: a key which matches the name exactly is preferred, and otherwise the key which
+/// is the smallest in the byte order is used, so `${path}` finds `PATH` rather than `Path`. The
+/// keys are indexed when the context is created, so each lookup is a single hash map access.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("HOME", "/home/user");
+/// map.insert("Editor", "vim");
+///
+/// let context = shellexpand::case_insensitive_context(&map);
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("${home}/$EDITOR/$Unknown", &context),
+///     "/home/user/vim/$Unknown"
+/// );
+/// ```
+pub fn case_insensitive_context<'a, K, V, S>(
+    map: &'a HashMap<K, V, S>,
+) -> impl Fn(&str) -> Option<&'a str> + 'a
+where
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<str>,
+    S: BuildHasher,
+{
+    let mut folded: HashMap<String, (&str, &str)> = HashMap::with_capacity(map.len());
+    for (key, value) in map {
+        let key = key.borrow();
+        let entry = folded
+            .entry(key.to_ascii_lowercase())
+            .or_insert((key, value.as_ref()));
+        if key < entry.0 {
+            *entry = (key, value.as_ref());
+        }
+    }
+    move |s| match map.get(s) {
+        Some(value) => Some(value.as_ref()),
+        None => folded.get(&s.to_ascii_lowercase()).map(|&(_, value)| value),
+    }
+}
+
+/// Same as `env()`, but looks variables up in a snapshot of the environment.
+///
+/// This function reads the whole environment of the current process once, before the expansion,
+/// and then looks all variables up in this snapshot, while `env()` calls `std::env::var()` for
+/// each reference separately. Therefore, all references are resolved consistently even if the
+/// environment is modified concurrently, and expanding strings with many references may be
+/// faster. The results and errors are otherwise the same as the ones of `env()`.
+///
+/// Note that on Windows the names of environment variables are case-insensitive for `env()`, but
+/// they are compared case-sensitively in the snapshot.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("X", "x value");
+///
+/// assert_eq!(shellexpand::env_snapshot("$X/${X}").unwrap(), "x value/x value");
+/// assert_eq!(
+///     shellexpand::env_snapshot("$X/$Z").unwrap_err().cause,
+///     env::VarError::NotPresent
+/// );
+/// ```
+pub fn env_snapshot<SI>(input: &SI) -> Result<Cow<'_, str>, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    let vars: HashMap<OsString, OsString> = std::env::vars_os().collect();
+    env_with_context(input, |s| match vars.get(OsStr::new(s)) {
+        Some(value) => match value.to_str() {
+            Some(value) => Ok(Some(value)),
+            None => Err(VarError::NotUnicode(value.clone())),
+        },
+        None => Err(VarError::NotPresent),
+    })
+}
+
+/// Wraps a context function so that it also resolves common user directories.
+///
+/// The returned context resolves the following pseudo-variables with the
+/// [dirs-next](https://crates.io/crates/dirs-next) crate, so templates may refer to these
+/// directories in an OS-agnostic way:
+///
+/// * `CONFIG_DIR`, like `~/.config` on Linux or `%APPDATA%` on Windows;
+/// * `CACHE_DIR`, like `~/.cache` on Linux or `%LOCALAPPDATA%` on Windows;
+/// * `DATA_DIR`, like `~/.local/share` on Linux or `%APPDATA%` on Windows;
+/// * `DATA_LOCAL_DIR`, like `~/.local/share` on Linux or `%LOCALAPPDATA%` on Windows;
+/// * `DOCUMENTS_DIR`, like `~/Documents`;
+/// * `DOWNLOAD_DIR`, like `~/Downloads`.
+///
+/// All other names, as well as the pseudo-variables which the current platform does not define,
+/// are looked up in `context`, so `with_user_dirs()` may be combined with any other context.
+/// Paths which are not valid UTF-8 are converted lossily.
+///
+/// This function is only available with the `dirs-next` feature, which is enabled by default.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "APP" => Ok(Some("my-app")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// let result = shellexpand::env_with_context(
+///     "${CACHE_DIR}/$APP",
+///     shellexpand::with_user_dirs(context),
+/// );
+/// # if cfg!(target_os = "linux") && std::env::var_os("HOME").is_some() {
+/// assert!(result.unwrap().ends_with("/my-app"));
+/// # }
+/// ```
+#[cfg(feature = "dirs-next")]
+pub fn with_user_dirs<CO, C, E>(mut context: C) -> impl FnMut(&str) -> Result<Option<String>, E>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    move |s: &str| {
+        let dir = match s {
+            "CONFIG_DIR" => ::dirs::config_dir(),
+            "CACHE_DIR" => ::dirs::cache_dir(),
+            "DATA_DIR" => ::dirs::data_dir(),
+            "DATA_LOCAL_DIR" => ::dirs::data_local_dir(),
+            "DOCUMENTS_DIR" => ::dirs::document_dir(),
+            "DOWNLOAD_DIR" => ::dirs::download_dir(),
+            _ => None,
+        };
+        match dir {
+            Some(dir) => Ok(Some(dir.to_string_lossy().into_owned())),
+            None => context(s).map(|value| value.map(|value| value.as_ref().to_owned())),
+        }
+    }
+}
+
+/// Escapes all regular expression metacharacters in `s` with backslashes.
+///
+/// The result matches `s` literally when used as a regular expression, or as a part of one, with
+/// the [regex](https://crates.io/crates/regex) crate and most other regular expression engines.
+/// The escaped characters are the same as the ones escaped by `regex::escape()`:
+/// `\ . + * ? ( ) | [ ] { } ^ $ # & - ~`. If there is nothing to escape, `s` is returned as it is
+/// without allocating.
+///
+/// # Examples
+///
+/// ```
+/// use std::borrow::Cow;
+///
+/// assert_eq!(shellexpand::regex_escape("v1.2 (beta)*"), r"v1\.2 \(beta\)\*");
+/// assert!(matches!(shellexpand::regex_escape("plain"), Cow::Borrowed("plain")));
+/// ```
+pub fn regex_escape(s: &str) -> Cow<'_, str> {
+    fn is_meta_char(c: char) -> bool {
+        "\\.+*?()|[]{}^$#&-~".contains(c)
+    }
+
+    let first_meta_idx = match s.find(is_meta_char) {
+        Some(idx) => idx,
+        None => return s.into(),
+    };
+    let mut result = String::with_capacity(s.len() + 8);
+    result.push_str(&s[..first_meta_idx]);
+    for c in s[first_meta_idx..].chars() {
+        if is_meta_char(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result.into()
+}
+
+/// Wraps a context function so that the values it returns are escaped for regular expressions.
+///
+/// Every value returned by `context` is escaped with `regex_escape()`, so the result of the
+/// expansion may be used as a regular expression in which the values of variables are matched
+/// literally, like in `^${PREFIX}-[0-9]+$` with `PREFIX` set to `v1.2`. Only the values of
+/// variables are escaped; the rest of the template, including the words of operators like
+/// `${VAR:-default}`, is a part of the regular expression as it is. Note that pattern-matching
+/// operators are applied to the escaped values.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "PREFIX" => Ok(Some("v1.2")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_with_context("^${PREFIX}-[0-9]+$", shellexpand::regex_escaped(context))
+///         .unwrap(),
+///     r"^v1\.2-[0-9]+$"
+/// );
+/// ```
+pub fn regex_escaped<CO, C, E>(mut context: C) -> impl FnMut(&str) -> Result<Option<String>, E>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    move |s: &str| {
+        context(s).map(|value| value.map(|value| regex_escape(value.as_ref()).into_owned()))
+    }
+}
+
+/// Checks whether the input string contains a variable reference which would be expanded.
+///
+/// This function returns `true` if `env_with_context()` would consult its context function for at
+/// least one variable reference in `input`, that is, if `input` contains a `$VAR` or a `${VAR}`
+/// sequence with a non-empty name which is not escaped with another `$`. It uses the same parser as
+/// the expansion itself, so, for example, `$$VAR` and a `${VAR` without a closing brace are not
+/// considered references. This function does not allocate.
+///
+/// # Examples
+///
+/// ```
+/// assert!(shellexpand::contains_variable("begin/$A/end"));
+/// assert!(shellexpand::contains_variable("begin/${A}/end"));
+/// assert!(!shellexpand::contains_variable("begin/$$A/end"));
+/// assert!(!shellexpand::contains_variable("begin/${A/end"));
+/// assert!(!shellexpand::contains_variable("begin/$/end"));
+/// ```
+pub fn contains_variable<SI>(input: &SI) -> bool
+where
+    SI: AsRef<str> + ?Sized,
+{
+    Expander::new()
+        .tokens(input.as_ref())
+        .any(|token| match token.kind {
+            TokenKind::Bare(_) | TokenKind::Braced(..) => true,
+            TokenKind::Literal | TokenKind::Escape => false,
+        })
+}
+
+/// Finds the next variable reference in the input string, starting from the byte offset `from`.
+///
+/// This function returns the byte offset of the `$` which starts the first `$VAR` or `${VAR}`
+/// reference beginning at or after `from`, or `None` if there are no more references. It uses
+/// the same parser as `env_with_context()`, so dollars which do not start a reference are skipped:
+/// `$$` is an escaped dollar, and a `${` without a closing brace, a `${}` or a lone `$` are just
+/// literal text. The input is always parsed from its beginning, so an escape is recognized even
+/// if `from` points right after its first dollar, and a reference which starts before `from` is
+/// skipped even if it ends after it. This allows processing the input incrementally, one
+/// reference at a time, with exactly the same semantics as the expansion itself.
+///
+/// # Examples
+///
+/// ```
+/// let s = "$$A/${B}/$";
+/// assert_eq!(shellexpand::next_expansion_point(s, 0), Some(4));
+/// assert_eq!(shellexpand::next_expansion_point(s, 1), Some(4));
+/// assert_eq!(shellexpand::next_expansion_point(s, 5), None);
+/// ```
+pub fn next_expansion_point<SI>(input: &SI, from: usize) -> Option<usize>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    Expander::new()
+        .tokens(input.as_ref())
+        .filter(|token| match token.kind {
+            TokenKind::Bare(_) | TokenKind::Braced(..) => true,
+            TokenKind::Literal | TokenKind::Escape => false,
+        })
+        .map(|token| token.range.start)
+        .find(|&start| start >= from)
+}
+
+/// Estimates the length of the result of the environment expansion without performing it.
+///
+/// This function is a best-effort helper for pre-sizing output buffers, for example before
+/// `env_write()`. It parses `input` just like `env_with_context()` does, but instead of the values
+/// of variables it asks `value_len` for their lengths in bytes: `Some(len)` means that the variable
+/// is set and its value has the given length, while `None` means that the length is unknown or
+/// expensive to compute, or that the variable may be unset. Like `Iterator::size_hint()`, the
+/// function returns the lower bound of the length of the result and its upper bound, if any.
+/// The bounds are equal, so the estimate is exact, if all referenced variables are set, their
+/// lengths are known, and no pattern-matching or case-changing operators are used.
+///
+/// # Examples
+///
+/// ```
+/// fn value_len(s: &str) -> Option<usize> {
+///     match s {
+///         "A" => Some(7),
+///         _ => None,
+///     }
+/// }
+///
+/// assert_eq!(shellexpand::env_len_bounds("x/$A/y", value_len), (11, Some(11)));
+/// assert_eq!(shellexpand::env_len_bounds("x/$A/$B", value_len), (10, None));
+/// assert_eq!(shellexpand::env_len_bounds("${B:+alt}", value_len), (0, Some(3)));
+/// ```
+pub fn env_len_bounds<SI, C>(input: &SI, mut value_len: C) -> (usize, Option<usize>)
+where
+    SI: AsRef<str> + ?Sized,
+    C: FnMut(&str) -> Option<usize>,
+{
+    let input = input.as_ref();
+    let (mut lower, mut upper) = (0, Some(0));
+    for token in Expander::new().tokens(input) {
+        let text = &input[token.range.clone()];
+        let (token_lower, token_upper) = match token.kind {
+            TokenKind::Literal => (text.len(), Some(text.len())),
+            TokenKind::Escape => (1, Some(1)),
+            TokenKind::Braced("", _) => (text.len(), Some(text.len())),
+            TokenKind::Bare(name) | TokenKind::Braced(name, None) => match value_len(name) {
+                Some(len) => (len, Some(len)),
+                None => (0, None),
+            },
+            TokenKind::Braced(name, Some((operator, word))) => {
+                let word_len = unescape_closing_braces(word, '}').len();
+                match (value_len(name), operator) {
+                    (Some(len), Operator::Default) | (Some(len), Operator::Error) => {
+                        (len, Some(len))
+                    }
+                    (Some(_), Operator::Alternate) => (word_len, Some(word_len)),
+                    (None, Operator::Alternate) => (0, Some(word_len)),
+                    (Some(len), Operator::RemovePrefix { .. })
+                    | (Some(len), Operator::RemoveSuffix { .. }) => (0, Some(len)),
+                    _ => (0, None),
+                }
+            }
+        };
+        lower += token_lower;
+        upper = match (upper, token_upper) {
+            (Some(upper), Some(token_upper)) => Some(upper + token_upper),
+            _ => None,
+        };
+    }
+    (lower, upper)
+}
+
+/// Splits the input string into spans of literal text, escapes and variable references.
+///
+/// This function uses the same parser as `env_with_context()` and the other expansion functions,
+/// and returns spans which cover the whole input in order, together with their byte ranges. It is
+/// useful, for example, for highlighting variable references in an editor. See
+/// `Expander::parse_spans()` for more details and for parsing with non-default settings.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::{ReferenceKind, Span, SpanKind};
+///
+/// assert_eq!(
+///     shellexpand::parse_spans("$A/${B}"),
+///     [
+///         Span { kind: SpanKind::Var { name: "A", kind: ReferenceKind::Bare }, range: 0..2 },
+///         Span { kind: SpanKind::Literal, range: 2..3 },
+///         Span { kind: SpanKind::Var { name: "B", kind: ReferenceKind::Braced }, range: 3..7 },
+///     ]
+/// );
+/// ```
+#[inline]
+pub fn parse_spans<SI>(input: &SI) -> Vec<Span<'_>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    Expander::new().parse_spans(input)
+}
+
+/// Performs the tilde expansion using the provided context.
+///
+/// This function expands tilde (`~`) character in the beginning of the input string into contents
+/// of the path returned by `home_dir` function. If the input string does not contain a tilde, or
+/// if it is not followed either by a slash (`/`) or by the end of string, then it is also left as
+/// is. This means, in particular, that expansions like `~anotheruser/directory` are not supported.
+/// The context function may also return a `None`, in that case even if the tilde is present in the
+/// input in the correct place, it won't be replaced (there is nothing to replace it with, after
+/// all).
+///
+/// The rest of the input after the tilde is appended to the home directory exactly as it is, so
+/// callers may rely on the separators in the result: a bare `~` expands to the home directory
+/// itself, without a trailing slash being added, and `~/` expands to the home directory followed
+/// by exactly one slash. If the home directory already ends with a slash, like the root
+/// directory `/` does, that slash is not doubled.
+///
+/// This function has three generic type parameters: `SI` represents the input string, `P` is the
+/// output of a context lookup, and `HD` is the context closure. `SI` must be a type, a reference
+/// to which can be converted to a string slice via `AsRef<str>`, and `P` must be a type, a
+/// reference to which can be converted to a `Path` via `AsRef<Path>`. For example, `P` may be
+/// `&Path`, `PathBuf` or `Cow<Path>`, which gives a lot of flexibility. In particular, a context
+/// which has the home directory cached somewhere may return a borrowed path without cloning it.
+///
+/// If you need to expand the tilde into the actual user home directory, you can use `tilde()` or
+/// `full()` functions.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///    shellexpand::tilde_with_context("~/some/dir", home_dir),
+///    "/home/user/some/dir"
+/// );
+/// ```
+pub fn tilde_with_context<SI, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    match expand_tilde(input.as_ref(), || Ok::<_, ()>(home_dir())) {
+        Ok(result) => result,
+        Err(_) => unreachable!(),
+    }
+}
+
+/// Performs the tilde expansion using the given home directory.
+///
+/// This function is the same as `tilde_with_context()`, but it takes the home directory itself
+/// instead of a function returning it, which is handy when the home directory is already known.
+/// If `home` is `None`, the tilde is left as it is.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+///
+/// assert_eq!(
+///     shellexpand::tilde_with_home_opt("~/some/dir", Some(Path::new("/home/user"))),
+///     "/home/user/some/dir"
+/// );
+/// assert_eq!(shellexpand::tilde_with_home_opt("~/some/dir", None::<&Path>), "~/some/dir");
+/// ```
+#[inline]
+pub fn tilde_with_home_opt<SI, P>(input: &SI, home: Option<P>) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+{
+    tilde_with_context(input, || home)
+}
+
+/// Same as `tilde_with_context()`, but allows the home directory lookup to fail.
+///
+/// The `home_dir` function of this variant returns `Result<Option<P>, HomeDirError>`. `Ok(None)`
+/// means that there is no home directory, and the tilde is left as it is, like when the context
+/// of `tilde_with_context()` returns `None`. An error, however, is returned from this function
+/// instead of being silently ignored, which allows distinguishing a missing home directory from
+/// a failed lookup. The `home_dir` function is only called if the input starts with an expandable
+/// tilde, so inputs without it never fail.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+/// use shellexpand::{HomeDirError, HomeDirErrorKind};
+///
+/// fn home_dir() -> Result<Option<PathBuf>, HomeDirError> {
+///     Ok(Some(Path::new("/home/user").into()))
+/// }
+/// fn no_home_dir() -> Result<Option<PathBuf>, HomeDirError> { Ok(None) }
+/// fn broken_home_dir() -> Result<Option<PathBuf>, HomeDirError> {
+///     Err(HomeDirError::new(HomeDirErrorKind::Os, None))
+/// }
+///
+/// assert_eq!(
+///     shellexpand::tilde_with_context_reporting("~/some/dir", home_dir).unwrap(),
+///     "/home/user/some/dir"
+/// );
+/// assert_eq!(
+///     shellexpand::tilde_with_context_reporting("~/some/dir", no_home_dir).unwrap(),
+///     "~/some/dir"
+/// );
+/// assert_eq!(
+///     shellexpand::tilde_with_context_reporting("~/some/dir", broken_home_dir)
+///         .unwrap_err()
+///         .kind(),
+///     HomeDirErrorKind::Os
+/// );
+/// assert_eq!(
+///     shellexpand::tilde_with_context_reporting("some/dir", broken_home_dir).unwrap(),
+///     "some/dir"
+/// );
+/// ```
+pub fn tilde_with_context_reporting<SI, P, HD>(
+    input: &SI,
+    home_dir: HD,
+) -> Result<Cow<'_, str>, HomeDirError>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Result<Option<P>, HomeDirError>,
+{
+    expand_tilde(input.as_ref(), home_dir)
+}
+
+/// A home directory context function which is called at most once, when the home directory is
+/// needed for the first time.
+pub(crate) struct LazyHomeDir<P, HD> {
+    home_dir: Option<HD>,
+    hd: Option<Option<P>>,
+}
+
+impl<P, HD> LazyHomeDir<P, HD>
+where
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    pub(crate) fn new(home_dir: HD) -> LazyHomeDir<P, HD> {
+        LazyHomeDir {
+            home_dir: Some(home_dir),
+            hd: None,
+        }
+    }
+
+    /// Returns the home directory, calling the context function if it has not been called yet.
+    pub(crate) fn get(&mut self) -> Option<&P> {
+        let home_dir = &mut self.home_dir;
+        self.hd
+            .get_or_insert_with(|| home_dir.take().and_then(|f| f()))
+            .as_ref()
+    }
+}
+
+/// Returns the part of the home directory `hd` which should precede `rest` in the expansion.
+///
+/// If the home directory ends with a slash, like the root directory `/` does, and `rest` starts
+/// with one, then the trailing slash of the home directory is dropped, so `~/foo` becomes `/foo`
+/// and not `//foo`.
+fn home_dir_prefix<'a>(hd: &'a str, rest: &str) -> &'a str {
+    if rest.starts_with('/') {
+        hd.strip_suffix('/').unwrap_or(hd)
+    } else {
+        hd
+    }
+}
+
+fn expand_tilde<P, HD, E>(input_str: &str, home_dir: HD) -> Result<Cow<'_, str>, E>
+where
+    P: AsRef<Path>,
+    HD: FnOnce() -> Result<Option<P>, E>,
+{
+    if let Some(input_after_tilde) = input_str.strip_prefix('~') {
+        if is_expandable_after_tilde(input_after_tilde) {
+            if let Some(hd) = home_dir()? {
+                let hd = hd.as_ref().to_string_lossy();
+                if input_after_tilde.is_empty() {
+                    // the input is just `~`, so the result is the home dir itself
+                    Ok(hd.into_owned().into())
+                } else {
+                    let hd = home_dir_prefix(&hd, input_after_tilde);
+                    let mut result = String::with_capacity(hd.len() + input_after_tilde.len());
+                    result.push_str(hd);
+                    result.push_str(input_after_tilde);
+                    Ok(result.into())
+                }
+            } else {
+                // home dir is not available
+                Ok(input_str.into())
+            }
+        } else {
+            // we cannot handle `~otheruser/` paths yet
+            Ok(input_str.into())
+        }
+    } else {
+        // input doesn't start with tilde
+        Ok(input_str.into())
+    }
+}
+
+/// Performs the tilde expansion using the default system context.
+///
+/// This function delegates to `tilde_with_context()`, using the default system source of home
+/// directory path, namely `dirs::home_dir()` function.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+///
+/// let hds = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
+///
+/// assert_eq!(
+///     shellexpand::tilde("~/some/dir"),
+///     format!("{}/some/dir", hds)
+/// );
+/// ```
+#[inline]
+pub fn tilde<SI>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    tilde_with_context(input, home::home_dir)
+}
+
+fn is_expandable_after_tilde(s: &str) -> bool {
+    s.is_empty() || s.starts_with('/')
+}
+
+/// Performs the tilde expansion on a byte string using the provided context.
+///
+/// This function works exactly like `tilde_with_context()`, but it operates on arbitrary bytes
+/// instead of a string, so neither the input nor the home directory have to be valid UTF-8, which
+/// is useful for paths obtained from the operating system. Non-UTF-8 content in both the input and
+/// the home directory path is preserved as it is. The home directory path is converted to bytes
+/// with `OsStr::as_encoded_bytes()`; on Unix these are just the raw bytes of the path.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///    &*shellexpand::tilde_bytes_with_context(b"~/some/\xff/dir", home_dir),
+///    &b"/home/user/some/\xff/dir"[..]
+/// );
+/// ```
+pub fn tilde_bytes_with_context<P, HD>(input: &[u8], home_dir: HD) -> Cow<'_, [u8]>
+where
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    match input.split_first() {
+        Some((&b'~', input_after_tilde))
+            if input_after_tilde.is_empty() || input_after_tilde[0] == b'/' =>
+        {
+            match home_dir() {
+                Some(hd) => {
+                    let mut hd = hd.as_ref().as_os_str().as_encoded_bytes();
+                    // avoid a doubled separator, see `home_dir_prefix()`
+                    if !input_after_tilde.is_empty() {
+                        hd = hd.strip_suffix(b"/").unwrap_or(hd);
+                    }
+                    let mut result = Vec::with_capacity(hd.len() + input_after_tilde.len());
+                    result.extend_from_slice(hd);
+                    result.extend_from_slice(input_after_tilde);
+                    result.into()
+                }
+                // home dir is not available
+                None => input.into(),
+            }
+        }
+        // input doesn't start with tilde, or we cannot handle `~otheruser/` paths
+        _ => input.into(),
+    }
+}
+
+/// Performs the tilde expansion on a byte string using the default system context.
+///
+/// This function delegates to `tilde_bytes_with_context()`, using the default system source of
+/// home directory path, namely `dirs::home_dir()` function.
+#[inline]
+pub fn tilde_bytes(input: &[u8]) -> Cow<'_, [u8]> {
+    tilde_bytes_with_context(input, home::home_dir)
+}
+
+/// Performs the tilde expansion in every word of the input string using the provided context.
+///
+/// Unlike `tilde_with_context()`, which only looks at the beginning of the input string, this
+/// function treats the input as a list of words separated by whitespace, like a shell command line,
+/// and expands a tilde in the beginning of each word, following the same rules as
+/// `tilde_with_context()`: the tilde must be followed either by a slash or by the end of the word.
+/// Tildes in the middle of a word, like in `a~b`, are left as they are. Whitespace inside single or
+/// double quotes does not separate words, and since quoted words do not start with a tilde, tildes
+/// inside quotes are not expanded either. The quotes themselves, as well as all whitespace, are
+/// preserved in the output.
+///
+/// The `home_dir` context function is called at most once, and only if there is a tilde which
+/// needs to be expanded. If it returns `None`, the input is returned as it is.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///    shellexpand::tilde_words_with_context("ls ~ ~/dir a~b '~/quoted'", home_dir),
+///    "ls /home/user /home/user/dir a~b '~/quoted'"
+/// );
+/// ```
+pub fn tilde_words_with_context<SI, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input_str = input.as_ref();
+    let mut home_dir = LazyHomeDir::new(home_dir);
+
+    let mut result = String::new();
+    let mut copied_idx = 0;
+    let mut quote = None;
+    let mut at_word_start = true;
+    for (idx, c) in input_str.char_indices() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        if c.is_whitespace() {
+            at_word_start = true;
+            continue;
+        }
+
+        if at_word_start && c == '~' {
+            let after_tilde = &input_str[idx + 1..];
+            let word_after_tilde = &after_tilde[..after_tilde
+                .find(char::is_whitespace)
+                .unwrap_or(after_tilde.len())];
+            if is_expandable_after_tilde(word_after_tilde) {
+                if let Some(hd) = home_dir.get() {
+                    result.push_str(&input_str[copied_idx..idx]);
+                    result.push_str(home_dir_prefix(&hd.as_ref().to_string_lossy(), after_tilde));
+                    copied_idx = idx + 1;
+                }
+            }
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        }
+        at_word_start = false;
+    }
+
+    if copied_idx == 0 {
+        input_str.into()
+    } else {
+        result.push_str(&input_str[copied_idx..]);
+        result.into()
+    }
+}
+
+/// Performs the tilde expansion in an assignment, like `PREFIX=~/opt`, using the provided context.
+///
+/// Shells expand a tilde not only at the beginning of a word, but also right after an `=` sign in
+/// variable assignments. This function does the same: it expands a tilde at the beginning of the
+/// input string, like `tilde_with_context()` does, and also every tilde which immediately follows
+/// an `=`. The usual rules apply to each of them: the tilde must be followed either by a slash or
+/// by the end of the string. All other tildes, like the one in `a~b`, are left as they are.
+///
+/// The `home_dir` context function is called at most once, and only if there is a tilde which
+/// needs to be expanded. If it returns `None`, the input is returned as it is.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///    shellexpand::tilde_assignment_with_context("PREFIX=~/opt", home_dir),
+///    "PREFIX=/home/user/opt"
+/// );
+/// assert_eq!(shellexpand::tilde_assignment_with_context("a~b=~c", home_dir), "a~b=~c");
+/// ```
+pub fn tilde_assignment_with_context<SI, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input_str = input.as_ref();
+    let mut home_dir = LazyHomeDir::new(home_dir);
+
+    let mut result = String::new();
+    let mut copied_idx = 0;
+    for (idx, _) in input_str.match_indices('~') {
+        let after_tilde = &input_str[idx + 1..];
+        if idx > 0 && !input_str[..idx].ends_with('=') || !is_expandable_after_tilde(after_tilde) {
+            continue;
+        }
+        match home_dir.get() {
+            Some(hd) => {
+                result.push_str(&input_str[copied_idx..idx]);
+                result.push_str(home_dir_prefix(&hd.as_ref().to_string_lossy(), after_tilde));
+                copied_idx = idx + 1;
+            }
+            // home dir is not available
+            None => break,
+        }
+    }
+
+    if copied_idx == 0 {
+        input_str.into()
+    } else {
+        result.push_str(&input_str[copied_idx..]);
+        result.into()
+    }
+}
+
+/// Performs the tilde expansion in an assignment using the default system context.
+///
+/// This function delegates to `tilde_assignment_with_context()`, using the default system source
+/// of home directory path, namely `dirs::home_dir()` function.
+#[inline]
+pub fn tilde_assignment<SI>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    tilde_assignment_with_context(input, home::home_dir)
+}
+
+/// Performs the tilde expansion in every word of the input string using the default system
+/// context.
+///
+/// This function delegates to `tilde_words_with_context()`, using the default system source of
+/// home directory path, namely `dirs::home_dir()` function.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+///
+/// let hds = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
+///
+/// assert_eq!(
+///     shellexpand::tilde_words("cp ~/a a~b"),
+///     format!("cp {}/a a~b", hds)
+/// );
+/// ```
+#[inline]
+pub fn tilde_words<SI>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    tilde_words_with_context(input, home::home_dir)
+}
+
+/// Performs the tilde expansion, including the `~user` form, using the provided context.
+///
+/// This function works like `tilde_with_context()`, but besides `~` and `~/...` it also expands
+/// `~user` and `~user/...` into the home directory of the user named `user`. The `home_dir`
+/// context function accepts `None` when the home directory of the current user is needed, and
+/// `Some(user)` when the home directory of the named user is needed. As usual, if the context
+/// returns `None`, the input is returned as it is.
+///
+/// The user name spans from the tilde until the first slash (or, on Windows, the first slash or
+/// backslash) or the end of the string. It must start with an alphanumeric character or an
+/// underscore, and may only contain alphanumeric characters, underscores, dots and hyphens,
+/// optionally followed by a single `$`, like the names of Samba machine accounts. Anything else
+/// after the tilde, like in `~.config` or in a drive specification like `~C:\x`, is left as it is
+/// without calling the context; this includes the shell forms `~+` and `~-`, which refer to the
+/// current and the previous working directories and are supported by `tilde_dirs_with_context()`.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir(user: Option<&str>) -> Option<PathBuf> {
+///     match user {
+///         None => Some(Path::new("/home/user").into()),
+///         Some("alice") => Some(Path::new("/home/alice").into()),
+///         Some(_) => None,
+///     }
+/// }
+///
+/// assert_eq!(shellexpand::tilde_user_with_context("~/dir", home_dir), "/home/user/dir");
+/// assert_eq!(shellexpand::tilde_user_with_context("~alice/dir", home_dir), "/home/alice/dir");
+/// assert_eq!(shellexpand::tilde_user_with_context("~bob/dir", home_dir), "~bob/dir");
+/// ```
+pub fn tilde_user_with_context<SI, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+    HD: FnOnce(Option<&str>) -> Option<P>,
+{
+    match expand_tilde_user(input.as_ref(), |user| Ok::<_, ()>(home_dir(user))) {
+        Ok(result) => result,
+        Err(_) => unreachable!(),
+    }
+}
+
+fn expand_tilde_user<P, HD, E>(input_str: &str, home_dir: HD) -> Result<Cow<'_, str>, E>
+where
+    P: AsRef<Path>,
+    HD: FnOnce(Option<&str>) -> Result<Option<P>, E>,
+{
+    let (user, input_after_user) = match tilde_target(input_str) {
+        TildeTarget::CurrentUser(rest) => (None, rest),
+        TildeTarget::User(user, rest) => (Some(user), rest),
+        TildeTarget::CurrentDir(_) | TildeTarget::PreviousDir(_) | TildeTarget::None => {
+            return Ok(input_str.into())
+        }
+    };
+
+    match home_dir(user)? {
+        Some(hd) => {
+            let hd = hd.as_ref().to_string_lossy();
+            let hd = home_dir_prefix(&hd, input_after_user);
+            let mut result = String::with_capacity(hd.len() + input_after_user.len());
+            result.push_str(hd);
+            result.push_str(input_after_user);
+            Ok(result.into())
+        }
+        // home dir is not available
+        None => Ok(input_str.into()),
+    }
+}
+
+/// What a tilde at the beginning of a string refers to, along with the rest of the string.
+enum TildeTarget<'a> {
+    /// `~` or `~/...`
+    CurrentUser(&'a str),
+    /// `~user` or `~user/...`
+    User(&'a str, &'a str),
+    /// `~+` or `~+/...`
+    CurrentDir(&'a str),
+    /// `~-` or `~-/...`
+    PreviousDir(&'a str),
+    /// No tilde, or a tilde which is not followed by a slash or a valid user name.
+    None,
+}
+
+fn tilde_target(input_str: &str) -> TildeTarget<'_> {
+    let input_after_tilde = match input_str.strip_prefix('~') {
+        Some(input_after_tilde) => input_after_tilde,
+        // input doesn't start with tilde
+        None => return TildeTarget::None,
+    };
+
+    // backslashes are path separators on Windows, so they end user names there too
+    let is_separator = |c: char| c == '/' || cfg!(windows) && c == '\\';
+    let name_end_idx = input_after_tilde
+        .find(is_separator)
+        .unwrap_or(input_after_tilde.len());
+    let (user, input_after_user) = input_after_tilde.split_at(name_end_idx);
+    match user {
+        "" => return TildeTarget::CurrentUser(input_after_user),
+        "+" => return TildeTarget::CurrentDir(input_after_user),
+        "-" => return TildeTarget::PreviousDir(input_after_user),
+        _ => {}
+    }
+
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '.' || c == '-';
+    let name = user.strip_suffix('$').unwrap_or(user);
+    let starts_well = name.starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    if starts_well && name.chars().all(is_name_char) {
+        TildeTarget::User(user, input_after_user)
+    } else {
+        TildeTarget::None
+    }
+}
+
+/// Performs the tilde expansion, including the `~user` form, using the default system context.
+///
+/// This function delegates to `tilde_user_with_context()`, using `dirs::home_dir()` for the
+/// current user, and the system user database for named users. Looking up named users requires
+/// the `libc` feature and is only supported on Unix; on other platforms, and whenever a named user
+/// cannot be found, `~user` is left as it is. Use `can_resolve_named_users()` to check whether
+/// named users can be resolved at all, or `try_tilde_user()` to get an error when they cannot.
+#[inline]
+pub fn tilde_user<SI>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    tilde_user_with_context(input, |user| match user {
+        None => home::home_dir(),
+        Some(user) => home::user_home_dir(user).ok(),
+    })
+}
+
+/// Same as `tilde_user()`, but returns an error if a named user cannot be looked up.
+///
+/// A `~user` reference to a user who does not exist is still left as it is, just like shells
+/// do, but if the lookup cannot be performed at all, because it is not supported on the current
+/// platform (the error kind is `HomeDirErrorKind::Unimplemented`) or because the operating system
+/// returned an error (`HomeDirErrorKind::Os`), the error is returned.
+pub fn try_tilde_user<SI>(input: &SI) -> Result<Cow<'_, str>, HomeDirError>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    expand_tilde_user(input.as_ref(), |user| match user {
+        None => Ok(home::home_dir()),
+        Some(user) => match home::user_home_dir(user) {
+            Ok(hd) => Ok(Some(hd)),
+            Err(ref e) if e.kind() == HomeDirErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        },
+    })
+}
+
+/// Same as `tilde_user()`, but looks named users up in `overrides` before the system user
+/// database.
+///
+/// The keys of `overrides` are user names and the values are their home directories. A `~user`
+/// reference to a user who is present in the map is expanded to the path from the map, even if
+/// the user does not exist in the system, while other users are looked up like in `tilde_user()`,
+/// that is, with `getpwnam_r()` on Unix with the `libc` feature. This allows tests and
+/// containerized applications to provide home directories without real entries in the password
+/// database. The home directory of the current user is not affected by the map.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut overrides = HashMap::new();
+/// overrides.insert("alice", "/srv/alice");
+///
+/// assert_eq!(
+///     shellexpand::tilde_user_with_overrides("~alice/data", &overrides),
+///     "/srv/alice/data"
+/// );
+/// ```
+pub fn tilde_user_with_overrides<'a, SI, K, V, S>(
+    input: &'a SI,
+    overrides: &HashMap<K, V, S>,
+) -> Cow<'a, str>
+where
+    SI: AsRef<str> + ?Sized,
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<Path>,
+    S: BuildHasher,
+{
+    tilde_user_with_context(input, |user| match user {
+        None => home::home_dir(),
+        Some(user) => match overrides.get(user) {
+            Some(hd) => Some(hd.as_ref().to_path_buf()),
+            None => home::user_home_dir(user).ok(),
+        },
+    })
+}
+
+/// Performs the tilde expansion, including the `~+` and `~-` forms, using the provided contexts.
+///
+/// This function works like `tilde_with_context()`, but besides `~` and `~/...` it also expands
+/// `~+` and `~+/...` into the path returned by `pwd`, which is meant to be the current working
+/// directory, and `~-` and `~-/...` into the path returned by `oldpwd`, which is meant to be the
+/// previous working directory, like shells do. At most one of the contexts is called, and if it
+/// returns `None`, the input is returned as it is. `~user` is left as it is.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+/// fn pwd() -> Option<PathBuf> { Some(Path::new("/srv/app").into()) }
+/// fn oldpwd() -> Option<PathBuf> { None }
+///
+/// let expand = |s| shellexpand::tilde_dirs_with_context(s, home_dir, pwd, oldpwd);
+/// assert_eq!(expand("~/dir"), "/home/user/dir");
+/// assert_eq!(expand("~+/dir"), "/srv/app/dir");
+/// assert_eq!(expand("~-/dir"), "~-/dir");
+/// ```
+pub fn tilde_dirs_with_context<SI, P, HD, PD, OD>(
+    input: &SI,
+    home_dir: HD,
+    pwd: PD,
+    oldpwd: OD,
+) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+    PD: FnOnce() -> Option<P>,
+    OD: FnOnce() -> Option<P>,
+{
+    let input_str = input.as_ref();
+    let (dir, input_after_prefix) = match tilde_target(input_str) {
+        TildeTarget::CurrentUser(rest) => (home_dir(), rest),
+        TildeTarget::CurrentDir(rest) => (pwd(), rest),
+        TildeTarget::PreviousDir(rest) => (oldpwd(), rest),
+        TildeTarget::User(..) | TildeTarget::None => return input_str.into(),
+    };
+
+    match dir {
+        Some(dir) => {
+            let dir = dir.as_ref().to_string_lossy();
+            let dir = home_dir_prefix(&dir, input_after_prefix);
+            let mut result = String::with_capacity(dir.len() + input_after_prefix.len());
+            result.push_str(dir);
+            result.push_str(input_after_prefix);
+            result.into()
+        }
+        // the directory is not available
+        None => input_str.into(),
+    }
+}
+
+/// Performs the tilde expansion, including the `~+` and `~-` forms, using the default system
+/// context.
+///
+/// This function delegates to `tilde_dirs_with_context()`, using `dirs::home_dir()`, `pwd()` and
+/// `oldpwd()` as the contexts.
+#[inline]
+pub fn tilde_dirs<SI>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    tilde_dirs_with_context(input, home::home_dir, pwd, oldpwd)
+}
+
+/// Returns the current working directory of the process, which `~+` refers to.
+///
+/// This is `std::env::current_dir()`, with errors mapped to `None`.
+pub fn pwd() -> Option<PathBuf> {
+    std::env::current_dir().ok()
+}
+
+/// Returns the previous working directory, which `~-` refers to.
+///
+/// Like shells, this function takes the directory from the `OLDPWD` environment variable. It
+/// returns `None` if the variable is not set or is empty.
+pub fn oldpwd() -> Option<PathBuf> {
+    std::env::var_os("OLDPWD")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The default value of the shell `IFS` variable: space, tab and newline.
+pub const DEFAULT_IFS: &str = " \t\n";
+
+/// Splits the input string into fields like shells do after expansion.
+///
+/// `ifs` is the set of field separator characters, just like the shell `IFS` variable; use
+/// `DEFAULT_IFS` to get the default shell behavior. The splitting follows the POSIX rules:
+///
+/// * space, tab and newline characters from `ifs` are "IFS whitespace": sequences of them
+///   separate fields, but never produce empty fields, and they are ignored in the beginning and in
+///   the end of the input;
+/// * every other character from `ifs` separates fields by itself, together with any adjacent IFS
+///   whitespace, so two such characters in a row produce an empty field between them, as does such
+///   a character in the beginning of the input; a single such character in the end of the input
+///   does not produce an empty field after it;
+/// * if `ifs` is empty, no splitting is performed.
+///
+/// This function is meant to be applied to the result of an expansion, for example, to emulate
+/// shell constructs like `for x in $list`.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::{split_fields, DEFAULT_IFS};
+///
+/// assert_eq!(split_fields("  a b\t\tc \n", DEFAULT_IFS), vec!["a", "b", "c"]);
+/// assert_eq!(split_fields("a,b,,c", ","), vec!["a", "b", "", "c"]);
+/// assert_eq!(split_fields("a , b", " ,"), vec!["a", "b"]);
+/// ```
+pub fn split_fields<'a>(input: &'a str, ifs: &str) -> Vec<&'a str> {
+    let is_ifs_whitespace = |c: char| (c == ' ' || c == '\t' || c == '\n') && ifs.contains(c);
+    let is_ifs_other = |c: char| !is_ifs_whitespace(c) && ifs.contains(c);
+
+    let input = input.trim_matches(is_ifs_whitespace);
+    let mut fields = Vec::new();
+    if input.is_empty() {
+        return fields;
+    }
+
+    let mut rest = input;
+    loop {
+        match rest.find(|c: char| ifs.contains(c)) {
+            Some(idx) => {
+                fields.push(&rest[..idx]);
+
+                // skip the separator: IFS whitespace, optionally followed by one other IFS
+                // character and more IFS whitespace
+                rest = rest[idx..].trim_start_matches(is_ifs_whitespace);
+                if let Some(c) = rest.chars().next().filter(|&c| is_ifs_other(c)) {
+                    rest = rest[c.len_utf8()..].trim_start_matches(is_ifs_whitespace);
+                }
+
+                if rest.is_empty() {
+                    break;
+                }
+            }
+            None => {
+                fields.push(rest);
+                break;
+            }
+        }
+    }
+    fields
+}
+
+/// Checks whether the input string starts with a tilde which would be expanded.
+///
+/// This function returns `true` if `tilde_with_context()` would replace the beginning of `input`
+/// with the home directory, provided that the home directory is available, that is, if `input` is
+/// either `~` or starts with `~/`. Tildes in other positions, like in `whatever/~`, and tildes
+/// followed by a user name, like in `~user/path`, are not expanded, therefore this function returns
+/// `false` for them. This function does not allocate.
+///
+/// # Examples
+///
+/// ```
+/// assert!(shellexpand::starts_with_expandable_tilde("~"));
+/// assert!(shellexpand::starts_with_expandable_tilde("~/some/dir"));
+/// assert!(!shellexpand::starts_with_expandable_tilde("~user/some/dir"));
+/// assert!(!shellexpand::starts_with_expandable_tilde("some/~/dir"));
+/// ```
+pub fn starts_with_expandable_tilde<SI>(input: &SI) -> bool
+where
+    SI: AsRef<str> + ?Sized,
+{
+    input
+        .as_ref()
+        .strip_prefix('~')
+        .is_some_and(is_expandable_after_tilde)
+}
+
+#[cfg(test)]
+mod tilde_tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        can_resolve_named_users, oldpwd, pwd, starts_with_expandable_tilde, tilde,
+        tilde_bytes_with_context, tilde_dirs, tilde_dirs_with_context, tilde_user,
+        tilde_user_with_context, tilde_user_with_overrides, tilde_with_context,
+        tilde_with_context_reporting, tilde_with_home_opt, tilde_words_with_context,
+        try_tilde_user, HomeDirError, HomeDirErrorKind,
+    };
+
+    #[test]
+    fn test_tilde_assignment() {
+        use tilde_assignment_with_context;
+
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/dir").into())
+        }
+
+        assert_eq!(
+            tilde_assignment_with_context("KEY=~/x", hd),
+            "KEY=/home/dir/x"
+        );
+        assert_eq!(tilde_assignment_with_context("KEY=~", hd), "KEY=/home/dir");
+        assert_eq!(tilde_assignment_with_context("~/x", hd), "/home/dir/x");
+        assert_eq!(
+            tilde_assignment_with_context("~/a=~/b", hd),
+            "/home/dir/a=/home/dir/b"
+        );
+        assert_eq!(tilde_assignment_with_context("a~b", hd), "a~b");
+        assert_eq!(tilde_assignment_with_context("KEY=a~b", hd), "KEY=a~b");
+        assert_eq!(tilde_assignment_with_context("KEY=~user", hd), "KEY=~user");
+        assert_eq!(tilde_assignment_with_context("KEY= ~/x", hd), "KEY= ~/x");
+        assert_eq!(tilde_assignment_with_context("KEY=x", hd), "KEY=x");
+        assert_eq!(
+            tilde_assignment_with_context("KEY=~/x", || None::<PathBuf>),
+            "KEY=~/x"
+        );
+
+        let calls = ::std::cell::Cell::new(0);
+        let counting_hd = || {
+            calls.set(calls.get() + 1);
+            hd()
+        };
+        assert_eq!(
+            tilde_assignment_with_context("A=~:B=~/x", counting_hd),
+            "A=~:B=/home/dir/x"
+        );
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_tilde_dirs_with_context() {
+        let hd = || Some("/home/dir");
+        let pwd = || Some("/current");
+        let oldpwd = || Some("/previous");
+        let expand = |s| tilde_dirs_with_context(s, hd, pwd, oldpwd);
+
+        assert_eq!(expand("~"), "/home/dir");
+        assert_eq!(expand("~/path"), "/home/dir/path");
+        assert_eq!(expand("~+"), "/current");
+        assert_eq!(expand("~+/path"), "/current/path");
+        assert_eq!(expand("~-"), "/previous");
+        assert_eq!(expand("~-/path"), "/previous/path");
+        for s in &["~alice/path", "~++", "~+x", "~--/path", "path/~+", "+/path"] {
+            assert_eq!(expand(s), *s);
+        }
+
+        let none = || None::<&str>;
+        assert_eq!(tilde_dirs_with_context("~+/x", hd, none, oldpwd), "~+/x");
+        assert_eq!(tilde_dirs_with_context("~-/x", hd, pwd, none), "~-/x");
+        assert_eq!(tilde_dirs_with_context("~/x", none, pwd, oldpwd), "~/x");
+        assert_eq!(
+            tilde_dirs_with_context("~+/x", hd, || Some("/"), oldpwd),
+            "/x"
+        );
+
+        // the other forms are left to tilde_user_with_context()
+        assert_eq!(
+            tilde_user_with_context("~+/x", |_| Some("/home/dir")),
+            "~+/x"
+        );
+    }
+
+    #[test]
+    fn test_tilde_dirs() {
+        let current_dir = std::env::current_dir().unwrap();
+        assert_eq!(pwd(), Some(current_dir.clone()));
+        assert_eq!(
+            tilde_dirs("~+/x"),
+            format!("{}/x", current_dir.to_string_lossy())
+        );
+
+        std::env::set_var("OLDPWD", "/some/previous/dir");
+        assert_eq!(oldpwd(), Some(PathBuf::from("/some/previous/dir")));
+        assert_eq!(tilde_dirs("~-/x"), "/some/previous/dir/x");
+
+        std::env::set_var("OLDPWD", "");
+        assert_eq!(oldpwd(), None);
+        assert_eq!(tilde_dirs("~-/x"), "~-/x");
+    }
+
+    #[test]
+    fn test_root_home_dir() {
+        fn root() -> Option<&'static str> {
+            Some("/")
+        }
+
+        assert_eq!(tilde_with_context("~", root), "/");
+        assert_eq!(tilde_with_context("~/", root), "/");
+        assert_eq!(tilde_with_context("~/foo", root), "/foo");
+        assert_eq!(tilde_with_context("~/foo/", root), "/foo/");
+        assert_eq!(tilde_with_context("~", || Some("/home/dir/")), "/home/dir/");
+        assert_eq!(
+            tilde_with_context("~/foo", || Some("/home/dir/")),
+            "/home/dir/foo"
+        );
+        assert_eq!(tilde_bytes_with_context(b"~/foo", root), &b"/foo"[..]);
+        assert_eq!(tilde_bytes_with_context(b"~", root), &b"/"[..]);
+        assert_eq!(tilde_words_with_context("~/a ~ ~/b", root), "/a / /b");
+        assert_eq!(
+            tilde_user_with_context("~root/foo", |_: Option<&str>| Some("/")),
+            "/foo"
+        );
+        assert_eq!(
+            ::full_with_context_no_errors("~/$A", root, |_: &str| Some("a")),
+            "/a"
+        );
+    }
+
+    #[test]
+    fn test_with_tilde_no_hd() {
+        fn hd() -> Option<PathBuf> {
+            None
+        }
+
+        assert_eq!(tilde_with_context("whatever", hd), "whatever");
+        assert_eq!(tilde_with_context("whatever/~", hd), "whatever/~");
+        assert_eq!(tilde_with_context("~/whatever", hd), "~/whatever");
+        assert_eq!(tilde_with_context("~", hd), "~");
+        assert_eq!(tilde_with_context("~something", hd), "~something");
+    }
+
+    #[test]
+    fn test_with_tilde() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/dir").into())
+        }
+
+        assert_eq!(tilde_with_context("whatever/path", hd), "whatever/path");
+        assert_eq!(tilde_with_context("whatever/~/path", hd), "whatever/~/path");
+        assert_eq!(tilde_with_context("~", hd), "/home/dir");
+        assert_eq!(tilde_with_context("~/path", hd), "/home/dir/path");
+        assert_eq!(tilde_with_context("~whatever/path", hd), "~whatever/path");
+    }
+
+    #[test]
+    fn test_bare_tilde() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/me").into())
+        }
+
+        // the home directory itself, without a trailing slash
+        assert_eq!(tilde_with_context("~", hd), "/home/me");
+        assert_eq!(
+            format!("{}/file", tilde_with_context("~", hd)),
+            "/home/me/file"
+        );
+        assert_eq!(tilde_with_context("~", || Some("/home/me/")), "/home/me/");
+        assert_eq!(tilde_with_context("~", || Some("/")), "/");
+    }
+
+    #[test]
+    fn test_tilde_slash() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/me").into())
+        }
+
+        // the home directory followed by exactly one slash
+        assert_eq!(tilde_with_context("~/", hd), "/home/me/");
+        assert_eq!(
+            format!("{}file", tilde_with_context("~/", hd)),
+            "/home/me/file"
+        );
+        assert_eq!(tilde_with_context("~/", || Some("/home/me/")), "/home/me/");
+        assert_eq!(tilde_with_context("~/", || Some("/")), "/");
+        assert_eq!(tilde_with_context("~//", hd), "/home/me//");
+    }
+
+    #[test]
+    fn test_tilde_with_home_opt() {
+        let home = PathBuf::from("/home/dir");
+        assert_eq!(tilde_with_home_opt("~/path", Some(&home)), "/home/dir/path");
+        assert_eq!(tilde_with_home_opt("~", Some("/home/dir")), "/home/dir");
+        assert_eq!(tilde_with_home_opt("x/~/path", Some(&home)), "x/~/path");
+        assert_eq!(tilde_with_home_opt("~/path", Some(home)), "/home/dir/path");
+        assert_eq!(tilde_with_home_opt("~/path", None::<PathBuf>), "~/path");
+    }
+
+    #[test]
+    fn test_tilde_with_context_reporting() {
+        fn hd() -> Result<Option<PathBuf>, HomeDirError> {
+            Ok(Some(Path::new("/home/dir").into()))
+        }
+        fn no_hd() -> Result<Option<PathBuf>, HomeDirError> {
+            Ok(None)
+        }
+        fn err_hd() -> Result<Option<PathBuf>, HomeDirError> {
+            Err(HomeDirError::os(
+                None,
+                io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+            ))
+        }
+
+        assert_eq!(
+            tilde_with_context_reporting("~/path", hd).unwrap(),
+            "/home/dir/path"
+        );
+        assert_eq!(tilde_with_context_reporting("~", hd).unwrap(), "/home/dir");
+        assert_eq!(
+            tilde_with_context_reporting("~/path", no_hd).unwrap(),
+            "~/path"
+        );
+
+        let err = tilde_with_context_reporting("~/path", err_hd).unwrap_err();
+        assert_eq!(err.kind(), HomeDirErrorKind::Os);
+        assert_eq!(err.user(), None);
+        assert!(err.source().is_some());
+
+        // the home directory is not looked up if it is not needed
+        assert_eq!(
+            tilde_with_context_reporting("path/~", err_hd).unwrap(),
+            "path/~"
+        );
+        assert_eq!(
+            tilde_with_context_reporting("~user/path", err_hd).unwrap(),
+            "~user/path"
+        );
+    }
+
+    #[test]
+    fn test_bare_tilde_with_borrowed_hd() {
+        let home = Path::new("/home/dir");
+
+        assert_eq!(tilde_with_context("~", || Some(home)), "/home/dir");
+        assert_eq!(tilde_with_context("~/", || Some(home)), "/home/dir/");
+        assert_eq!(
+            tilde_with_context("~", || Some(Cow::Borrowed(home))),
+            "/home/dir"
+        );
+        assert_eq!(
+            tilde_with_context("~/path", || Some(Cow::Borrowed(home))),
+            "/home/dir/path"
+        );
+    }
+
+    #[test]
+    fn test_tilde_bytes() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/dir").into())
+        }
+        fn no_hd() -> Option<PathBuf> {
+            None
+        }
+
+        assert_eq!(&*tilde_bytes_with_context(b"~", hd), b"/home/dir");
+        assert_eq!(
+            &*tilde_bytes_with_context(b"~/\xff\xfe/path", hd),
+            b"/home/dir/\xff\xfe/path"
+        );
+        assert_eq!(&*tilde_bytes_with_context(b"~\xff/path", hd), b"~\xff/path");
+        assert_eq!(&*tilde_bytes_with_context(b"~user/path", hd), b"~user/path");
+        assert_eq!(&*tilde_bytes_with_context(b"x/~/\xff", hd), b"x/~/\xff");
+        assert_eq!(&*tilde_bytes_with_context(b"", hd), b"");
+        assert_eq!(&*tilde_bytes_with_context(b"~/\xff", no_hd), b"~/\xff");
+
+        match tilde_bytes_with_context(b"some/\xff", hd) {
+            Cow::Borrowed(s) => assert_eq!(s, b"some/\xff"),
+            Cow::Owned(_) => panic!("input without tilde should not be copied"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tilde_bytes_non_utf8_home() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let hd = || Some(Path::new(OsStr::from_bytes(b"/home/\xe9")));
+        assert_eq!(
+            &*tilde_bytes_with_context(b"~/\xff", hd),
+            b"/home/\xe9/\xff"
+        );
+    }
+
+    #[test]
+    fn test_tilde_user_with_context() {
+        fn hd(user: Option<&str>) -> Option<PathBuf> {
+            match user {
+                None => Some(Path::new("/home/dir").into()),
+                Some("alice") => Some(Path::new("/home/alice").into()),
+                Some(_) => None,
+            }
+        }
+
+        assert_eq!(tilde_user_with_context("~", hd), "/home/dir");
+        assert_eq!(tilde_user_with_context("~/path", hd), "/home/dir/path");
+        assert_eq!(tilde_user_with_context("~alice", hd), "/home/alice");
+        assert_eq!(
+            tilde_user_with_context("~alice/path", hd),
+            "/home/alice/path"
+        );
+        assert_eq!(tilde_user_with_context("~bob/path", hd), "~bob/path");
+        assert_eq!(tilde_user_with_context("path/~alice", hd), "path/~alice");
+        assert_eq!(tilde_user_with_context("~~alice", hd), "~~alice");
+        assert_eq!(
+            tilde_user_with_context("~alice/path", |_| None::<PathBuf>),
+            "~alice/path"
+        );
+    }
+
+    #[test]
+    fn test_tilde_user_dispatch() {
+        // every name which reaches the context is accepted, to see which ones are passed to it
+        fn hd(user: Option<&str>) -> Option<String> {
+            match user {
+                None => Some("/home/dir".into()),
+                Some(user) => Some(format!("/users/{}", user)),
+            }
+        }
+
+        assert_eq!(tilde_user_with_context("~/path", hd), "/home/dir/path");
+        assert_eq!(tilde_user_with_context("~alice", hd), "/users/alice");
+        assert_eq!(
+            tilde_user_with_context("~alice/path", hd),
+            "/users/alice/path"
+        );
+        assert_eq!(
+            tilde_user_with_context("~_a.b-c/path", hd),
+            "/users/_a.b-c/path"
+        );
+        assert_eq!(
+            tilde_user_with_context("~host$/path", hd),
+            "/users/host$/path"
+        );
+        assert_eq!(
+            tilde_user_with_context("~jörg/path", hd),
+            "/users/jörg/path"
+        );
+
+        // special forms and invalid names are never looked up
+        for s in &[
+            "~+",
+            "~+/path",
+            "~-",
+            "~-/path",
+            "~.config",
+            "~.config/path",
+            "~-alice",
+            "~a b/path",
+            "~a$b",
+            "~$",
+            "~a:b",
+            "~C:/x",
+            "~C:\\x",
+            "~C:",
+        ] {
+            assert_eq!(tilde_user_with_context(s, hd), *s);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_tilde_user_windows_paths() {
+        fn hd(user: Option<&str>) -> Option<String> {
+            match user {
+                None => Some("C:\\Users\\me".into()),
+                Some("alice") => Some("C:\\Users\\alice".into()),
+                Some(_) => None,
+            }
+        }
+
+        assert_eq!(tilde_user_with_context("~\\x", hd), "C:\\Users\\me\\x");
+        assert_eq!(
+            tilde_user_with_context("~alice\\x", hd),
+            "C:\\Users\\alice\\x"
+        );
+        assert_eq!(tilde_user_with_context("~C:\\x", hd), "~C:\\x");
+        assert_eq!(tilde_user_with_context("~D:x", hd), "~D:x");
+    }
+
+    #[cfg(not(all(unix, feature = "libc")))]
+    #[test]
+    fn test_tilde_user_unimplemented() {
+        use super::HomeDirErrorKind;
+
+        let _lock = ::home::HOME_DIR_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        assert!(!can_resolve_named_users());
+        assert_eq!(tilde_user("~alice/path"), "~alice/path");
+        assert_eq!(tilde_user("~alice"), "~alice");
+
+        let err = try_tilde_user("~alice/path").unwrap_err();
+        assert_eq!(err.kind(), HomeDirErrorKind::Unimplemented);
+        assert_eq!(err.user(), Some("alice"));
+
+        match ::home::home_dir() {
+            Some(hd) => {
+                let expected = format!("{}/path", hd.display());
+                assert_eq!(tilde_user("~/path"), expected);
+                assert_eq!(try_tilde_user("~/path").unwrap(), expected);
+            }
+            None => {
+                assert_eq!(tilde_user("~/path"), "~/path");
+                assert_eq!(try_tilde_user("~/path").unwrap(), "~/path");
+            }
+        }
+    }
+
+    #[test]
+    fn test_tilde_user_with_overrides() {
+        let _lock = ::home::HOME_DIR_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut overrides = HashMap::new();
+        overrides.insert("alice".to_owned(), PathBuf::from("/srv/alice"));
+        overrides.insert("root".to_owned(), PathBuf::from("/srv/root"));
+
+        assert_eq!(
+            tilde_user_with_overrides("~alice/path", &overrides),
+            "/srv/alice/path"
+        );
+        assert_eq!(
+            tilde_user_with_overrides("~alice", &overrides),
+            "/srv/alice"
+        );
+        // overrides take precedence over the system user database
+        assert_eq!(
+            tilde_user_with_overrides("~root/path", &overrides),
+            "/srv/root/path"
+        );
+        assert_eq!(
+            tilde_user_with_overrides("~surely-nonexisting-user/path", &overrides),
+            "~surely-nonexisting-user/path"
+        );
+        assert_eq!(
+            tilde_user_with_overrides("~/path", &overrides),
+            tilde_user("~/path")
+        );
+    }
+
+    #[cfg(all(unix, feature = "libc"))]
+    #[test]
+    fn test_tilde_user_with_overrides_libc() {
+        let mut overrides = HashMap::new();
+        overrides.insert("alice", "/srv/alice");
+
+        // users without an override are looked up with `getpwnam_r()`
+        let root_hd = ::home::user_home_dir("root").unwrap();
+        assert_eq!(
+            tilde_user_with_overrides("~root/path", &overrides),
+            format!("{}/path", root_hd.display())
+        );
+        assert_eq!(
+            tilde_user_with_overrides("~alice/path", &overrides),
+            "/srv/alice/path"
+        );
+    }
+
+    #[cfg(all(unix, feature = "libc"))]
+    #[test]
+    fn test_tilde_user_libc() {
+        assert!(can_resolve_named_users());
+
+        let root_hd = ::home::user_home_dir("root").unwrap();
+        let expected = format!("{}/path", root_hd.display());
+        assert_eq!(tilde_user("~root/path"), expected);
+        assert_eq!(try_tilde_user("~root/path").unwrap(), expected);
+
+        assert_eq!(
+            tilde_user("~surely-nonexisting-user/path"),
+            "~surely-nonexisting-user/path"
+        );
+        assert_eq!(
+            try_tilde_user("~surely-nonexisting-user/path").unwrap(),
+            "~surely-nonexisting-user/path"
+        );
+    }
+
+    #[test]
+    fn test_starts_with_expandable_tilde() {
+        assert!(!starts_with_expandable_tilde("whatever"));
+        assert!(!starts_with_expandable_tilde("whatever/~"));
+        assert!(starts_with_expandable_tilde("~/whatever"));
+        assert!(starts_with_expandable_tilde("~"));
+        assert!(!starts_with_expandable_tilde("~something"));
+        assert!(!starts_with_expandable_tilde("whatever/~/path"));
+        assert!(!starts_with_expandable_tilde(""));
+    }
+
+    #[test]
+    fn test_tilde_words() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/dir").into())
+        }
+
+        assert_eq!(tilde_words_with_context("~", hd), "/home/dir");
+        assert_eq!(tilde_words_with_context("echo ~", hd), "echo /home/dir");
+        assert_eq!(tilde_words_with_context("foo ~", hd), "foo /home/dir");
+        assert_eq!(tilde_words_with_context("foo ~/x", hd), "foo /home/dir/x");
+        assert_eq!(tilde_words_with_context("echo a~b", hd), "echo a~b");
+        assert_eq!(tilde_words_with_context("a~", hd), "a~");
+        assert_eq!(tilde_words_with_context("~user/x", hd), "~user/x");
+        assert_eq!(
+            tilde_words_with_context("~ \t~/x\n~", hd),
+            "/home/dir \t/home/dir/x\n/home/dir"
+        );
+        assert_eq!(
+            tilde_words_with_context("echo '~ x' \"a ~\" ~", hd),
+            "echo '~ x' \"a ~\" /home/dir"
+        );
+    }
+
+    #[test]
+    fn test_tilde_words_no_hd() {
+        let mut calls = 0;
+        assert_eq!(
+            tilde_words_with_context("echo a~b", || {
+                calls += 1;
+                None::<PathBuf>
+            }),
+            "echo a~b"
+        );
+        assert_eq!(calls, 0);
+
+        assert_eq!(
+            tilde_words_with_context("~ ~/x", || {
+                calls += 1;
+                None::<PathBuf>
+            }),
+            "~ ~/x"
+        );
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_global_tilde() {
+        let _lock = ::home::HOME_DIR_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match ::home::home_dir() {
+            Some(hd) => assert_eq!(tilde("~/something"), format!("{}/something", hd.display())),
+            None => assert_eq!(tilde("~/something"), "~/something"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod env_test {
+    use std;
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use super::{
+        case_insensitive_context, contains_variable, env, env_arc, env_len_bounds, env_or_empty,
+        env_snapshot, env_with_context, env_with_context_no_errors, env_with_map_ref,
+        expand_checked, next_expansion_point, parse_spans, regex_escape, regex_escaped,
+        ExpandError, Expander, ExpansionObserver, LookupError, Position, ReferenceKind, Span,
+        SpanKind, UnknownAction, VarName,
+    };
+
+    macro_rules! table {
+        ($env:expr, unwrap, $($source:expr => $target:expr),+) => {
+            $(
+                assert_eq!(env_with_context($source, $env).unwrap(), $target);
+            )+
+        };
+        ($env:expr, error, $($source:expr => $name:expr),+) => {
+            $(
+                assert_eq!(
+                    env_with_context($source, $env).map_err(|e| (e.var_name, e.cause)),
+                    Err(($name.into(), ()))
+                );
+            )+
+        }
+    }
+
+    #[test]
+    fn test_empty_env() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Ok(None)
+        }
+
+        table! { e, unwrap,
+            "whatever/path"        => "whatever/path",
+            "$VAR/whatever/path"   => "$VAR/whatever/path",
+            "whatever/$VAR/path"   => "whatever/$VAR/path",
+            "whatever/path/$VAR"   => "whatever/path/$VAR",
+            "${VAR}/whatever/path" => "${VAR}/whatever/path",
+            "whatever/${VAR}path"  => "whatever/${VAR}path",
+            "whatever/path/${VAR}" => "whatever/path/${VAR}",
+            "${}/whatever/path"    => "${}/whatever/path",
+            "whatever/${}path"     => "whatever/${}path",
+            "whatever/path/${}"    => "whatever/path/${}",
+            "$/whatever/path"      => "$/whatever/path",
+            "whatever/$path"       => "whatever/$path",
             "whatever/path/$"      => "whatever/path/$",
             "$$/whatever/path"     => "$/whatever/path",
             "whatever/$$path"      => "whatever/$path",
@@ -808,128 +5612,2891 @@ mod env_test {
     }
 
     #[test]
-    fn test_error_env() {
-        fn e(_: &str) -> Result<Option<String>, ()> {
-            Err(())
+    fn test_dollar_runs() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "$" => "$",
+            "$$" => "$",
+            "$$$" => "$$",
+            "$$$$" => "$$",
+            "$$$$$" => "$$$",
+            "$VAR" => "value",
+            "$$VAR" => "$VAR",
+            "$$$VAR" => "$value",
+            "$$$$VAR" => "$$VAR",
+            "$$$$$VAR" => "$$value",
+            "$$${VAR}" => "$value",
+            "$$$${VAR}" => "$${VAR}",
+            "a$$$b$$$" => "a$$b$$",
+            "$$$VAR$$$$" => "$value$$"
+        };
+    }
+
+    #[test]
+    fn test_empty_names() {
+        fn e(s: &str) -> Result<Option<String>, ()> {
+            Ok(Some(format!("<{}>", s)))
+        }
+
+        table! { e, unwrap,
+            "${}" => "${}",
+            "a${}b" => "a${}b",
+            "${:-x}" => "${:-x}",
+            "${:+x}" => "${:+x}",
+            "${#}" => "${#}",
+            "${##*}" => "${##*}",
+            "${%x}" => "${%x}",
+            "${/a/b}" => "${/a/b}",
+            "${:-x}$A" => "${:-x}<A>",
+            "${:a}" => "<:a>"
+        };
+        assert_eq!(
+            Expander::new()
+                .recursive(true)
+                .env_with_context("${:-$A}", e)
+                .unwrap(),
+            "${:-$A}"
+        );
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        fn var(name: &str, kind: ReferenceKind) -> SpanKind<'_> {
+            SpanKind::Var { name, kind }
+        }
+        fn spans<'a>(input: &'a str, expander: &Expander) -> Vec<(SpanKind<'a>, &'a str)> {
+            expander
+                .parse_spans(input)
+                .into_iter()
+                .map(|span| (span.kind, &input[span.range]))
+                .collect()
+        }
+
+        let input = "a$$b/$X${Y:-$$}$/${Z$é$";
+        assert_eq!(
+            parse_spans(input),
+            [
+                Span {
+                    kind: SpanKind::Literal,
+                    range: 0..1
+                },
+                Span {
+                    kind: SpanKind::Escape,
+                    range: 1..3
+                },
+                Span {
+                    kind: SpanKind::Literal,
+                    range: 3..5
+                },
+                Span {
+                    kind: var("X", ReferenceKind::Bare),
+                    range: 5..7
+                },
+                Span {
+                    kind: var("Y", ReferenceKind::Braced),
+                    range: 7..15
+                },
+                Span {
+                    kind: SpanKind::Literal,
+                    range: 15..20
+                },
+                Span {
+                    kind: var("é", ReferenceKind::Bare),
+                    range: 20..23
+                },
+                Span {
+                    kind: SpanKind::Literal,
+                    range: 23..24
+                },
+            ]
+        );
+        assert!(parse_spans("").is_empty());
+
+        let shell = Expander::new().shell_escaping(true);
+        assert_eq!(
+            spans("\\$A$$${}\\", &shell),
+            [
+                (SpanKind::Escape, "\\$"),
+                (SpanKind::Literal, "A"),
+                (var("$", ReferenceKind::Bare), "$$"),
+                (SpanKind::Literal, "${}\\"),
+            ]
+        );
+
+        let nested = "${A:-${B}}}";
+        assert_eq!(
+            spans(nested, &Expander::new()),
+            [
+                (var("A", ReferenceKind::Braced), "${A:-${B}"),
+                (SpanKind::Literal, "}}"),
+            ]
+        );
+        assert_eq!(
+            spans(nested, &Expander::new().recursive(true)),
+            [
+                (var("A", ReferenceKind::Braced), "${A:-${B}}"),
+                (SpanKind::Literal, "}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_var_name() {
+        for s in &["A", "_", "HOME", "_A_1", "snake_case", "ünïcode", "A1B2"] {
+            assert_eq!(VarName::parse(s).map(VarName::as_str), Some(*s), "{:?}", s);
+        }
+        for s in &["", "1", "1AB", "9_", "A-B", "A B", "$A", "{A}", "A\n"] {
+            assert_eq!(VarName::parse(s), None, "{:?}", s);
+        }
+
+        let name = VarName::parse("PATH").unwrap();
+        assert_eq!(name.to_string(), "PATH");
+        assert_eq!(name.as_ref(), "PATH");
+    }
+
+    #[cfg(feature = "dirs-next")]
+    #[test]
+    fn test_with_user_dirs() {
+        use with_user_dirs;
+
+        fn context(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let mut user_dirs = with_user_dirs(context);
+        assert_eq!(user_dirs("A"), Ok(Some("a".into())));
+        assert_eq!(user_dirs("UNKNOWN"), Ok(None));
+        assert_eq!(user_dirs("ERR"), Err(()));
+
+        let expected = ::dirs::config_dir().map(|dir| dir.to_string_lossy().into_owned());
+        assert_eq!(user_dirs("CONFIG_DIR").unwrap(), expected);
+        if cfg!(any(target_os = "linux", target_os = "macos", windows)) {
+            for name in &["CONFIG_DIR", "CACHE_DIR", "DATA_DIR", "DATA_LOCAL_DIR"] {
+                assert!(user_dirs(name).unwrap().is_some(), "{}", name);
+            }
+        }
+
+        assert_eq!(
+            env_with_context("$CACHE_DIR/$A", with_user_dirs(context)).unwrap(),
+            format!("{}/a", ::dirs::cache_dir().unwrap().to_string_lossy())
+        );
+    }
+
+    #[test]
+    fn test_case_operators() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("hello World")),
+                "SHOUT" => Ok(Some("HELLO World")),
+                "SHARP" => Ok(Some("straße")),
+                "NAPOSTROPHE" => Ok(Some("\u{149}x")),
+                "DECOMPOSED" => Ok(Some("e\u{301}cole")),
+                "TURKISH" => Ok(Some("istanbul")),
+                "EMPTY" => Ok(Some("")),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${VAR^^}" => "HELLO WORLD",
+            "${VAR^}" => "Hello World",
+            "${SHOUT,,}" => "hello world",
+            "${SHOUT,}" => "hELLO World",
+            "${VAR^^[lo]}" => "heLLO WOrLd",
+            "${VAR^[!h]}" => "hello World",
+            "${SHOUT,,[A-K]}" => "heLLO World",
+            "${VAR@U}" => "HELLO WORLD",
+            "${SHOUT@L}" => "hello world",
+            "${VAR@u}" => "Hello World",
+            "${EMPTY^^}" => "",
+            "${UNKNOWN^^}" => "${UNKNOWN^^}",
+            "${VAR@Q}" => "${VAR@Q}",
+
+            // full Unicode case mappings
+            "${SHARP^^}" => "STRASSE",
+            "${SHARP^}" => "Straße",
+            "${NAPOSTROPHE^}" => "\u{2bc}Nx",
+            "${DECOMPOSED^}" => "E\u{301}cole",
+            "${TURKISH^}" => "Istanbul"
+        };
+    }
+
+    #[test]
+    fn test_env_snapshot() {
+        std::env::set_var("SHELLEXPAND_ENV_SNAPSHOT_A", "a value");
+        std::env::set_var("SHELLEXPAND_ENV_SNAPSHOT_B", "");
+        std::env::remove_var("SHELLEXPAND_ENV_SNAPSHOT_UNSET");
+
+        for s in &[
+            "x/$SHELLEXPAND_ENV_SNAPSHOT_A/${SHELLEXPAND_ENV_SNAPSHOT_B}/x",
+            "${SHELLEXPAND_ENV_SNAPSHOT_A}${SHELLEXPAND_ENV_SNAPSHOT_A}",
+            "${SHELLEXPAND_ENV_SNAPSHOT_UNSET:-default}",
+            "x/$SHELLEXPAND_ENV_SNAPSHOT_A/$SHELLEXPAND_ENV_SNAPSHOT_UNSET",
+            "$$SHELLEXPAND_ENV_SNAPSHOT_A",
+            "no variables",
+        ] {
+            assert_eq!(env_snapshot(s), env(s), "{:?}", s);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_env_snapshot_not_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        std::env::set_var(
+            "SHELLEXPAND_ENV_SNAPSHOT_NOT_UNICODE",
+            OsStr::from_bytes(b"\xff\xfe"),
+        );
+
+        let s = "x/$SHELLEXPAND_ENV_SNAPSHOT_NOT_UNICODE/x";
+        assert_eq!(env_snapshot(s), env(s));
+        assert!(env_snapshot(s).is_err());
+    }
+
+    #[test]
+    fn test_env_or_empty() {
+        std::env::set_var("SHELLEXPAND_ENV_OR_EMPTY_A", "a value");
+        std::env::remove_var("SHELLEXPAND_ENV_OR_EMPTY_UNSET");
+
+        assert_eq!(
+            env_or_empty("x/$SHELLEXPAND_ENV_OR_EMPTY_A/${SHELLEXPAND_ENV_OR_EMPTY_UNSET}"),
+            "x/a value/${SHELLEXPAND_ENV_OR_EMPTY_UNSET}"
+        );
+        assert_eq!(
+            env_or_empty("${SHELLEXPAND_ENV_OR_EMPTY_UNSET:-default}"),
+            "default"
+        );
+        assert!(matches!(env_or_empty("no variables"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_cow_context() {
+        let vars: HashMap<String, String> = vec![
+            ("NAME".to_owned(), "world".to_owned()),
+            ("GREETING".to_owned(), "hello".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        let calls = RefCell::new(Vec::new());
+        // stored values are lent without cloning, while computed ones are owned
+        let context = |s: &str| -> Result<Option<Cow<str>>, ()> {
+            let value = match s.strip_suffix("_UPPER") {
+                Some(name) => vars.get(name).map(|v| Cow::Owned(v.to_uppercase())),
+                None => vars.get(s).map(|v| Cow::Borrowed(v.as_str())),
+            };
+            calls.borrow_mut().push(match value {
+                Some(Cow::Borrowed(_)) => "borrowed",
+                Some(Cow::Owned(_)) => "owned",
+                None => "none",
+            });
+            Ok(value)
+        };
+
+        assert_eq!(
+            env_with_context("$GREETING, ${NAME_UPPER}! $OTHER", context).unwrap(),
+            "hello, WORLD! $OTHER"
+        );
+        assert_eq!(*calls.borrow(), ["borrowed", "owned", "none"]);
+
+        calls.borrow_mut().clear();
+        let expander = Expander::new().recursive(true);
+        assert_eq!(
+            expander
+                .env_with_context("${OTHER:-$NAME} ${GREETING_UPPER#H}", context)
+                .unwrap(),
+            "world ELLO"
+        );
+        assert_eq!(*calls.borrow(), ["none", "borrowed", "owned"]);
+        assert_eq!(
+            env_with_context_no_errors("$NAME_UPPER", |s| context(s).unwrap()),
+            "WORLD"
+        );
+    }
+
+    #[test]
+    fn test_env_arc() {
+        std::env::set_var("SHELLEXPAND_ENV_ARC_A", "a value");
+        std::env::remove_var("SHELLEXPAND_ENV_ARC_UNSET");
+
+        for input in &[
+            "x/$SHELLEXPAND_ENV_ARC_A/${SHELLEXPAND_ENV_ARC_UNSET:-default}",
+            "no variables",
+            "",
+        ] {
+            assert_eq!(&*env_arc(input).unwrap(), env(input).unwrap());
+        }
+        assert_eq!(
+            env_arc("$SHELLEXPAND_ENV_ARC_UNSET"),
+            Err(LookupError {
+                var_name: "SHELLEXPAND_ENV_ARC_UNSET".into(),
+                offset: 0,
+                kind: ReferenceKind::Bare,
+                cause: std::env::VarError::NotPresent,
+            })
+        );
+    }
+
+    #[test]
+    fn test_env_with_map_ref() {
+        let owned = String::from("a value");
+        let mut map = HashMap::new();
+        map.insert("A", owned.as_str());
+        map.insert("EMPTY", "");
+
+        assert_eq!(env_with_map_ref("x/$A/${A}s", &map), "x/a value/a values");
+        assert_eq!(env_with_map_ref("$EMPTY/${B}/$B", &map), "/${B}/$B");
+        assert_eq!(env_with_map_ref("${B:-$A}/${A:+alt}", &map), "$A/alt");
+
+        let input = "no variables";
+        match env_with_map_ref(input, &map) {
+            Cow::Borrowed(s) => assert!(std::ptr::eq(s, input)),
+            Cow::Owned(_) => panic!("the input without variables must be borrowed"),
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_context() {
+        let mut map = HashMap::new();
+        map.insert("HOME".to_owned(), "/home/user".to_owned());
+        map.insert("editor".to_owned(), "vim".to_owned());
+        map.insert("ÄRGER".to_owned(), "ärger".to_owned());
+
+        let context = case_insensitive_context(&map);
+        for &(name, value) in &[
+            ("HOME", Some("/home/user")),
+            ("home", Some("/home/user")),
+            ("hOmE", Some("/home/user")),
+            ("EDITOR", Some("vim")),
+            ("Editor", Some("vim")),
+            ("ÄRGER", Some("ärger")),
+            ("äRGER", None),
+            ("HOMES", None),
+        ] {
+            assert_eq!(context(name), value, "{}", name);
+        }
+        assert_eq!(
+            env_with_context_no_errors("${home}/$Editor/$UNKNOWN", &context),
+            "/home/user/vim/$UNKNOWN"
+        );
+
+        // exact matches win, and otherwise the smallest key does
+        let mut map = HashMap::new();
+        map.insert("Path", "mixed");
+        map.insert("PATH", "upper");
+        map.insert("path", "lower");
+        let context = case_insensitive_context(&map);
+        assert_eq!(context("Path"), Some("mixed"));
+        assert_eq!(context("path"), Some("lower"));
+        assert_eq!(context("PATH"), Some("upper"));
+        assert_eq!(context("pATH"), Some("upper"));
+        drop(context);
+        map.remove("PATH");
+        let context = case_insensitive_context(&map);
+        assert_eq!(context("PATH"), Some("mixed"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_env_or_empty_not_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        std::env::set_var(
+            "SHELLEXPAND_ENV_OR_EMPTY_NOT_UNICODE",
+            OsStr::from_bytes(b"\xff\xfe"),
+        );
+
+        let s = "x/$SHELLEXPAND_ENV_OR_EMPTY_NOT_UNICODE/x";
+        assert!(env(s).is_err());
+        assert_eq!(env_or_empty(s), s);
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "LINES" => Ok(Some("first\nsecond\r\nthird\n")),
+                "TABS" => Ok(Some("a\tb\\nc")),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().escape_sequences(true);
+        for &(source, target) in &[
+            ("${LINES//\\n/ }", "first second\r third "),
+            ("${LINES//[\\r\\n]/ }", "first second  third "),
+            ("${LINES%\\n}", "first\nsecond\r\nthird"),
+            ("${LINES#*\\n}", "second\r\nthird\n"),
+            ("${TABS/\\t/\\n}", "a\nb\\nc"),
+            ("${TABS/\\\\n/-}", "a\tb-c"),
+            ("${TABS/b/\\x}", "a\t\\x\\nc"),
+        ] {
+            assert_eq!(
+                expander.env_with_context(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        // escape sequences are not interpreted by default, so `\n` is just an escaped `n`
+        assert_eq!(
+            Expander::new()
+                .env_with_context("${LINES//\\n/ }", e)
+                .unwrap(),
+            "first\nseco d\r\nthird\n"
+        );
+        assert_eq!(
+            Expander::new()
+                .env_with_context("${TABS/\\\\n/-}", e)
+                .unwrap(),
+            "a\tb-c"
+        );
+    }
+
+    #[test]
+    fn test_error_operator() {
+        fn e(s: &str) -> Result<Option<&'static str>, String> {
+            match s {
+                "A" => Ok(Some("a")),
+                "MSG" => Ok(Some("from MSG")),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().error_operator(true);
+        assert_eq!(expander.env_with_context("x${A:?}y", e).unwrap(), "xay");
+        assert_eq!(expander.env_with_context("${A:?oops}", e).unwrap(), "a");
+
+        let err = expander.env_with_context("x ${VAR:?}", e).unwrap_err();
+        assert_eq!(
+            err,
+            ExpandError::Unset {
+                var_name: "VAR".into(),
+                offset: 2,
+                message: "parameter null or not set".into(),
+            }
+        );
+        assert_eq!(err.to_string(), "VAR: parameter null or not set");
+
+        let err = expander.env_with_context("${VAR:?custom}", e).unwrap_err();
+        assert_eq!(
+            err,
+            ExpandError::Unset {
+                var_name: "VAR".into(),
+                offset: 0,
+                message: "custom".into(),
+            }
+        );
+        assert_eq!(err.to_string(), "VAR: custom");
+
+        let err = expander
+            .clone()
+            .recursive(true)
+            .env_with_context("${VAR:?$MSG}", e)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "VAR: from MSG");
+
+        // the reference is left as it is without the setting or in lenient mode
+        assert_eq!(
+            expander.env_with_context_no_errors("${VAR:?}", |s| e(s).unwrap()),
+            "${VAR:?}"
+        );
+        assert_eq!(
+            Expander::new()
+                .env_with_context("${VAR:?}/${A:?}", e)
+                .unwrap(),
+            "${VAR:?}/a"
+        );
+        assert_eq!(
+            env_with_context("${VAR:?custom}", e).unwrap(),
+            "${VAR:?custom}"
+        );
+    }
+
+    #[test]
+    fn test_well_formed_template() {
+        use __is_well_formed_template;
+
+        for s in &[
+            "",
+            "$",
+            "$A ${B} ${C:-${D}",
+            "$${",
+            "${A:-a\\}b}",
+            "~/${A}.toml",
+        ] {
+            assert!(__is_well_formed_template(s), "{:?}", s);
+        }
+        for s in &[
+            "${",
+            "x${A",
+            "${}",
+            "${:-x}",
+            "${#}",
+            "${A:-a\\}",
+            "${A} ${B",
+        ] {
+            assert!(!__is_well_formed_template(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_contains_variable() {
+        for s in &[
+            "whatever/path",
+            "${}/whatever/path",
+            "whatever/${}path",
+            "whatever/path/${}",
+            "$/whatever/path",
+            "whatever/path/$",
+            "$$/whatever/path",
+            "whatever/$$path",
+            "whatever/path/$$",
+            "x${VAR",
+            "${:-x}",
+            "${#}",
+            "",
+        ] {
+            assert!(!contains_variable(s), "{:?}", s);
+        }
+
+        for s in &[
+            "$VAR/whatever/path",
+            "whatever/$VAR/path",
+            "whatever/path/$VAR",
+            "${VAR}/whatever/path",
+            "whatever/${VAR}path",
+            "whatever/path/${VAR}",
+            "whatever/$path",
+            "$A$B$C",
+            "$A_B_C",
+            "$$$A",
+            "${$A",
+        ] {
+            assert!(contains_variable(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_values_are_not_reparsed() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("x$")),
+                "OPEN" => Ok(Some("x${")),
+                "BS" => Ok(Some("x\\")),
+                "A" => Ok(Some("a")),
+                _ => Ok(None),
+            }
+        }
+
+        for expander in &[
+            Expander::new(),
+            Expander::new().recursive(true),
+            Expander::new().shell_escaping(true),
+            Expander::new().strip_double_quotes(true),
+        ] {
+            for &(source, target) in &[
+                ("${VAR}VAR", "x$VAR"),
+                ("$VAR{A}", "x${A}"),
+                ("${VAR}$A", "x$a"),
+                ("${OPEN}A}", "x${A}"),
+                ("$OPEN}", "x${}"),
+                ("${BS}$A", "x\\a"),
+            ] {
+                assert_eq!(
+                    expander.env_with_context(source, e).unwrap(),
+                    target,
+                    "{:?} with {:?}",
+                    source,
+                    expander
+                );
+            }
+        }
+
+        // a backslash from a value does not escape the following dollar or quote of the input
+        let expander = Expander::new()
+            .shell_escaping(true)
+            .strip_double_quotes(true);
+        assert_eq!(
+            expander.env_with_context("${BS}$A/${BS}\"A\"", e).unwrap(),
+            "x\\a/x\\A"
+        );
+    }
+
+    #[test]
+    fn test_references_inside_names() {
+        let lookups = RefCell::new(Vec::new());
+        let e = |s: &str| {
+            lookups.borrow_mut().push(s.to_owned());
+            Ok::<_, ()>(match s {
+                "B" => Some("b"),
+                "Ab" => Some("A and b"),
+                "EMPTY" => Some(""),
+                "A" => Some("just A"),
+                _ => None,
+            })
+        };
+
+        // the dollar is a part of the name by default
+        assert_eq!(env_with_context("${A$B}", e).unwrap(), "${A$B}");
+        assert_eq!(*lookups.borrow(), ["A$B"]);
+        assert_eq!(env_with_context("${A$B:-x}", e).unwrap(), "x");
+
+        let expander = Expander::new().recursive(true);
+        for &(source, target) in &[
+            ("${A$B}", "A and b"),
+            ("${A${B}}", "A and b"),
+            ("x/${A$B}/y", "x/A and b/y"),
+            ("${A$B:+set}", "set"),
+            ("${A$EMPTY}", "just A"),
+            ("${A$UNSET}", "${A$UNSET}"),
+            ("${A$B$B:-default}", "default"),
+        ] {
+            assert_eq!(
+                expander.env_with_context(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        lookups.borrow_mut().clear();
+        assert_eq!(expander.env_with_context("${A$B}", e).unwrap(), "A and b");
+        assert_eq!(*lookups.borrow(), ["B", "Ab"]);
+
+        // errors in the inner references point at them
+        let failing = |s: &str| if s == "B" { Err(()) } else { Ok(None::<&str>) };
+        let err = expander.env_with_context("x ${A$B}", failing).unwrap_err();
+        assert_eq!(err.offset(), 5);
+    }
+
+    #[test]
+    fn test_next_expansion_point() {
+        for &(s, from, expected) in &[
+            ("", 0, None),
+            ("whatever/path", 0, None),
+            ("$VAR/path", 0, Some(0)),
+            ("$VAR/path", 1, None),
+            ("a/$VAR/${B}", 0, Some(2)),
+            ("a/$VAR/${B}", 3, Some(7)),
+            ("a/$VAR/${B}", 7, Some(7)),
+            ("a/$VAR/${B}", 8, None),
+            ("$$VAR/$B", 0, Some(6)),
+            ("$$VAR/$B", 1, Some(6)),
+            ("$$$A", 0, Some(2)),
+            ("$ $/ ${} ${:-x} $", 0, None),
+            ("${$A", 0, Some(2)),
+            ("$A$B", 1, Some(2)),
+            ("x/$", 0, None),
+        ] {
+            assert_eq!(
+                next_expansion_point(s, from),
+                expected,
+                "{:?} from {}",
+                s,
+                from
+            );
+        }
+
+        // walking through all references
+        let s = "$A, ${B:-$$}, $$C and ${D}";
+        let mut points = Vec::new();
+        let mut from = 0;
+        while let Some(idx) = next_expansion_point(s, from) {
+            points.push(idx);
+            from = idx + 1;
+        }
+        assert_eq!(points, [0, 4, 22]);
+    }
+
+    #[test]
+    fn test_error_env() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Err(())
+        }
+
+        table! { e, unwrap,
+            "whatever/path" => "whatever/path",
+            // check that escaped $ does nothing
+            "whatever/$/path" => "whatever/$/path",
+            "whatever/path$" => "whatever/path$",
+            "whatever/$$path" => "whatever/$path"
+        };
+
+        table! { e, error,
+            "$VAR/something" => "VAR",
+            "${VAR}/something" => "VAR",
+            "whatever/${VAR}/something" => "VAR",
+            "whatever/${VAR}" => "VAR",
+            "whatever/$VAR/something" => "VAR",
+            "whatever/$VARsomething" => "VARsomething",
+            "whatever/$VAR" => "VAR",
+            "whatever/$VAR_VAR_VAR" => "VAR_VAR_VAR"
+        };
+    }
+
+    #[test]
+    fn test_unset_on_error() {
+        fn e(s: &str) -> Result<Option<&'static str>, &'static str> {
+            match s {
+                "BROKEN" => Err("broken"),
+                "VAR" => Ok(Some("value")),
+                _ => Ok(None),
+            }
+        }
+
+        for &(source, target) in &[
+            ("${BROKEN:-default}", "default"),
+            ("${BROKEN:+alt}", ""),
+            ("${VAR:-default}/${UNSET:-default}", "value/default"),
+            ("${VAR:+alt}/${UNSET:+alt}", "alt/"),
+        ] {
+            assert_eq!(env_with_context(source, e).unwrap(), target);
+            assert_eq!(
+                Expander::new()
+                    .unset_on_error(true)
+                    .env_with_context(source, e)
+                    .unwrap(),
+                target
+            );
+        }
+
+        let expander = Expander::new().unset_on_error(false);
+        for &(source, offset) in &[
+            ("${BROKEN:-default}", 0),
+            ("x/${BROKEN:+alt}", 2),
+            ("${UNSET:-$VAR}/${BROKEN:-default}", 15),
+        ] {
+            assert_eq!(
+                expander.env_with_context(source, e),
+                Err(ExpandError::Lookup(LookupError {
+                    var_name: "BROKEN".into(),
+                    offset,
+                    kind: ReferenceKind::Braced,
+                    cause: "broken",
+                }))
+            );
+        }
+        assert_eq!(
+            expander
+                .env_with_context("${VAR:-default}/${UNSET:-default}/${VAR:+alt}", e)
+                .unwrap(),
+            "value/default/alt"
+        );
+    }
+
+    #[test]
+    fn test_forbidden_value_chars() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "CLEAN" => Ok(Some("value")),
+                "MULTILINE" => Ok(Some("line 1\nline 2")),
+                "TABBED" => Ok(Some("a\tb")),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().forbidden_value_chars("\n");
+        for &(source, target) in &[
+            ("Environment=A=$CLEAN", "Environment=A=value"),
+            ("${CLEAN}/${TABBED}", "value/a\tb"),
+            ("${UNSET:-a\nb}", "a\nb"),
+            ("${MULTILINE:+x}", "x"),
+            ("${MULTILINE#*\n}", "line 2"),
+            ("$UNKNOWN", "$UNKNOWN"),
+        ] {
+            assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+        }
+
+        let forbidden = |var_name: &str, offset, character| {
+            Err(ExpandError::ForbiddenChar {
+                var_name: var_name.into(),
+                offset,
+                character,
+            })
+        };
+        assert_eq!(
+            expander.env_with_context("A=$MULTILINE", e),
+            forbidden("MULTILINE", 2, '\n')
+        );
+        assert_eq!(
+            expander.env_with_context("A=${MULTILINE:-x}", e),
+            forbidden("MULTILINE", 2, '\n')
+        );
+        assert_eq!(
+            expander.env_with_context("${MULTILINE%2}", e),
+            forbidden("MULTILINE", 0, '\n')
+        );
+        assert_eq!(
+            Expander::new()
+                .forbidden_value_chars("\t\n")
+                .env_with_context("$CLEAN $TABBED", e),
+            forbidden("TABBED", 7, '\t')
+        );
+        assert_eq!(
+            expander.env_with_context_no_errors("$CLEAN $MULTILINE", |s| e(s).unwrap()),
+            "value $MULTILINE"
+        );
+
+        // references inside words are checked in recursive mode
+        let expander = expander.recursive(true);
+        assert_eq!(
+            expander.env_with_context("${UNSET:-x$MULTILINE}", e),
+            forbidden("MULTILINE", 10, '\n')
+        );
+
+        assert_eq!(
+            Expander::new().env_with_context("$MULTILINE", e).unwrap(),
+            "line 1\nline 2"
+        );
+        let err = ExpandError::<String>::ForbiddenChar {
+            var_name: "A".into(),
+            offset: 0,
+            character: '\n',
+        };
+        assert_eq!(
+            err.to_string(),
+            "the value of 'A' contains a forbidden character '\\n'"
+        );
+    }
+
+    #[test]
+    fn test_max_substitutions() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                _ => Ok(None),
+            }
+        }
+        let too_many = |offset| Err(ExpandError::TooManySubstitutions { limit: 3, offset });
+
+        let expander = Expander::new().max_substitutions(Some(3));
+        assert_eq!(expander.env_with_context("$A${A}$A", e).unwrap(), "aaa");
+        assert_eq!(
+            expander.env_with_context("$A$B$C${A}$D$A", e).unwrap(),
+            "a$B$Ca$Da"
+        );
+        assert_eq!(expander.env_with_context("$A$A$A$A", e), too_many(6));
+        assert_eq!(
+            expander.env_with_context("${A:+x}${B:-y}${B:+z}${A}", e),
+            too_many(21)
+        );
+        assert_eq!(
+            expander.env_with_context_no_errors("$A${A:+x}${B:-y}${A}$A$B", |s| e(s).unwrap()),
+            "axy${A}$A$B"
+        );
+        assert_eq!(
+            Expander::new()
+                .max_substitutions(Some(0))
+                .env_with_context("no references, $B", e)
+                .unwrap(),
+            "no references, $B"
+        );
+
+        // references inside words count too
+        let expander = expander.recursive(true);
+        assert_eq!(expander.env_with_context("${B:-$A$A}", e).unwrap(), "aa");
+        assert_eq!(expander.env_with_context("${B:-$A$A}$A", e), too_many(10));
+
+        let input = "$A".repeat(10_000);
+        assert_eq!(
+            Expander::new().env_with_context(&input, e).unwrap(),
+            "a".repeat(10_000)
+        );
+        let err = ExpandError::<String>::TooManySubstitutions {
+            limit: 3,
+            offset: 6,
+        };
+        assert_eq!(err.to_string(), "more than 3 substitutions");
+    }
+
+    #[test]
+    fn test_env_len_bounds() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "A" => Some("a value"),
+                "EMPTY" => Some(""),
+                "LONG" => Some("a rather long value"),
+                _ => None,
+            }
+        }
+        let value_len = |s: &str| e(s).map(str::len);
+
+        // the estimate is exact if all lengths are known
+        for input in &[
+            "",
+            "no references",
+            "$A/${A}/$EMPTY/$$A",
+            "${A:-default}/${A:-x\\}y}/${A:+x\\}y}",
+            "${A:+alt}/${A:?message}/${}/${:-x}",
+        ] {
+            let len = env_with_context_no_errors(input, e).len();
+            assert_eq!(
+                env_len_bounds(input, value_len),
+                (len, Some(len)),
+                "{}",
+                input
+            );
+        }
+
+        // otherwise the actual length is between the bounds
+        let lengths_of_set = |s: &str| match s {
+            "A" | "EMPTY" | "LONG" => value_len(s),
+            _ => None,
+        };
+        for &(input, lower, upper) in &[
+            ("$A/$B", 8, None),
+            ("${A:-x}/${B:-default}", 8, None),
+            ("${B:+alt}/${LONG:+x}", 2, Some(5)),
+            ("${LONG#* }/${LONG%%a*}", 1, Some(39)),
+            ("${A^^}/${A/a/b}", 1, None),
+        ] {
+            let len = env_with_context_no_errors(input, e).len();
+            assert_eq!(
+                env_len_bounds(input, lengths_of_set),
+                (lower, upper),
+                "{}",
+                input
+            );
+            assert!(lower <= len && upper.is_none_or(|upper| len <= upper));
+        }
+    }
+
+    #[test]
+    fn test_lazy_lookups() {
+        // looking `SECRET` up fails, so the expansions only succeed if it is never looked up
+        fn lookups(expander: &Expander, input: &str) -> Vec<String> {
+            let mut calls = Vec::new();
+            let context = |s: &str| {
+                calls.push(s.to_owned());
+                match s {
+                    "SET" => Ok(Some("value")),
+                    "SECRET" => Err("the secret must not be fetched"),
+                    _ => Ok(None),
+                }
+            };
+            expander.env_with_context(input, context).unwrap();
+            calls
+        }
+
+        let expander = Expander::new();
+        assert_eq!(
+            lookups(&expander, "$SET/${SET}/$SET"),
+            ["SET", "SET", "SET"]
+        );
+        assert_eq!(
+            lookups(
+                &expander,
+                "${UNSET:+$SECRET}/${SET:-$SECRET}/${SET:+$SECRET}"
+            ),
+            ["UNSET", "SET", "SET"]
+        );
+
+        let expander = Expander::new().recursive(true).error_operator(true);
+        assert_eq!(lookups(&expander, "$SET/${SET}/$SET"), ["SET"]);
+        assert_eq!(
+            lookups(&expander, "${UNSET:+$SECRET}/${SET:-${SECRET}}"),
+            ["UNSET", "SET"]
+        );
+        assert_eq!(
+            lookups(&expander, "${UNSET:-$SET}/${SET:+${OTHER}}/${UNSET:-$SET}"),
+            ["UNSET", "SET", "OTHER"]
+        );
+        assert_eq!(
+            lookups(
+                &expander,
+                "${UNSET#$SECRET}/${UNSET/$SECRET/x}/${SET:?$SECRET}"
+            ),
+            ["UNSET", "SET"]
+        );
+    }
+
+    #[test]
+    fn test_regular_env() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "a_b" => Ok(Some("X_Y")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            // no variables
+            "whatever/path" => "whatever/path",
+
+            // empty string
+            "" => "",
+
+            // existing variable without braces in various positions
+            "$VAR/whatever/path" => "value/whatever/path",
+            "whatever/$VAR/path" => "whatever/value/path",
+            "whatever/path/$VAR" => "whatever/path/value",
+            "whatever/$VARpath" => "whatever/$VARpath",
+            "$VAR$VAR/whatever" => "valuevalue/whatever",
+            "/whatever$VAR$VAR" => "/whatevervaluevalue",
+            "$VAR $VAR" => "value value",
+            "$a_b" => "X_Y",
+            "$a_b$VAR" => "X_Yvalue",
+
+            // existing variable with braces in various positions
+            "${VAR}/whatever/path" => "value/whatever/path",
+            "whatever/${VAR}/path" => "whatever/value/path",
+            "whatever/path/${VAR}" => "whatever/path/value",
+            "whatever/${VAR}path" => "whatever/valuepath",
+            "${VAR}${VAR}/whatever" => "valuevalue/whatever",
+            "/whatever${VAR}${VAR}" => "/whatevervaluevalue",
+            "${VAR} ${VAR}" => "value value",
+            "${VAR}$VAR" => "valuevalue",
+
+            // adjacent references are resolved independently
+            "${VAR}${UNKNOWN}" => "value${UNKNOWN}",
+            "${UNKNOWN}${VAR}" => "${UNKNOWN}value",
+            "${VAR}${EMPTY}${VAR}" => "valuevalue",
+            "$VAR$UNKNOWN" => "value$UNKNOWN",
+            "$UNKNOWN$VAR" => "$UNKNOWNvalue",
+            "${UNKNOWN}$VAR${UNKNOWN}" => "${UNKNOWN}value${UNKNOWN}",
+
+            // default values
+            "/answer/${UNKNOWN:-42}" => "/answer/42",
+            "/answer/${:-42}" => "/answer/${:-42}",
+            "/whatever/${UNKNOWN:-other}$VAR" => "/whatever/othervalue",
+            "/whatever/${UNKNOWN:-other}/$VAR" => "/whatever/other/value",
+            ":-/whatever/${UNKNOWN:-other}/$VAR :-" => ":-/whatever/other/value :-",
+            "/whatever/${VAR:-other}" => "/whatever/value",
+            "/whatever/${VAR:-other}$VAR" => "/whatever/valuevalue",
+            "/whatever/${VAR} :-" => "/whatever/value :-",
+            "/whatever/${:-}" => "/whatever/${:-}",
+            "/whatever/${UNKNOWN:-}" => "/whatever/",
+
+            // escaped closing braces in default values
+            "${UNKNOWN:-a\\}b}" => "a}b",
+            "${UNKNOWN:-a\\}b\\}}/x" => "a}b}/x",
+            "${UNKNOWN:-a}b}" => "ab}",
+            "${UNKNOWN:-a\\b}" => "a\\b",
+            "${VAR:-a\\}b}" => "value",
+            "${UNKNOWN\\}}" => "${UNKNOWN\\}}",
+            "a\\}b" => "a\\}b",
+
+            // empty variable in various positions
+            "${EMPTY}/whatever/path" => "/whatever/path",
+            "whatever/${EMPTY}/path" => "whatever//path",
+            "whatever/path/${EMPTY}" => "whatever/path/"
+        };
+
+        table! { e, error,
+            "$ERR" => "ERR",
+            "${ERR}" => "ERR"
+        };
+    }
+
+    #[test]
+    fn test_unterminated_braces() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Err(())
+        }
+
+        table! { e, unwrap,
+            "${"      => "${",
+            "x${"     => "x${",
+            "x${VAR"  => "x${VAR",
+            "${VAR"   => "${VAR",
+            "x$${"    => "x${",
+            "x${$"    => "x${$"
+        };
+    }
+
+    #[test]
+    fn test_unexpanded_references_are_verbatim() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Ok(None)
+        }
+
+        // a simple xorshift generator keeps the test deterministic without extra dependencies
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as usize
+        };
+
+        let alphabet: Vec<char> = "AZ_a1${}:-+?#%/^,@!\\\" é}".chars().collect();
+        let expanders = [Expander::new(), Expander::new().recursive(true)];
+        for _ in 0..5000 {
+            let contents: String = (0..next(10))
+                .map(|_| alphabet[next(alphabet.len())])
+                .collect();
+            let input = format!("x${{{}}}y", contents);
+            // only these sequences make a reference to an unset variable expand to something else
+            if ["$$", ":-", ":+"].iter().any(|s| input.contains(s)) {
+                continue;
+            }
+            for expander in &expanders {
+                assert_eq!(
+                    expander.env_with_context(&input, e).unwrap(),
+                    input,
+                    "{:?}",
+                    expander
+                );
+            }
+        }
+
+        // references without a name are never expanded, whatever operator follows
+        fn known(s: &str) -> Result<Option<String>, ()> {
+            Ok(Some(format!("<{}>", s)))
+        }
+        let operators = [":-", ":+", ":?", "#", "##", "%", "%%", "/", "//", "^^", ","];
+        for _ in 0..5000 {
+            let contents: String = (0..next(10))
+                .map(|_| alphabet[next(alphabet.len())])
+                .filter(|&c| c != '$')
+                .collect();
+            let input = format!("${{{}{}}}", operators[next(operators.len())], contents);
+            for expander in &expanders {
+                assert_eq!(
+                    expander.env_with_context(&input, known).unwrap(),
+                    input,
+                    "{:?}",
+                    expander
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_name_chars() {
+        fn e(s: &str) -> Result<Option<String>, ()> {
+            Ok(Some(format!("<{}>", s)))
+        }
+
+        let expander = Expander::new().extra_name_chars("-");
+        for &(source, target) in &[
+            ("$MY-VAR", "<MY-VAR>"),
+            ("$MY-VAR-", "<MY-VAR->"),
+            ("$A-B.c", "<A-B>.c"),
+            ("x-$A-B/y", "x-<A-B>/y"),
+            ("$-A", "$-A"),
+            ("$A--", "<A-->"),
+            ("${A-B}", "<A-B>"),
+            ("$A_1", "<A_1>"),
+            ("$$A-B", "$A-B"),
+        ] {
+            assert_eq!(
+                expander.env_with_context(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+        assert_eq!(
+            expander.parse_spans("$A-B/$C"),
+            [
+                Span {
+                    kind: SpanKind::Var {
+                        name: "A-B",
+                        kind: ReferenceKind::Bare
+                    },
+                    range: 0..4
+                },
+                Span {
+                    kind: SpanKind::Literal,
+                    range: 4..5
+                },
+                Span {
+                    kind: SpanKind::Var {
+                        name: "C",
+                        kind: ReferenceKind::Bare
+                    },
+                    range: 5..7
+                },
+            ]
+        );
+
+        let expander = Expander::new().excluded_name_chars("_");
+        for &(source, target) in &[
+            ("$A_B", "<A>_B"),
+            ("$_A", "$_A"),
+            ("${A_B}", "<A_B>"),
+            ("$AB", "<AB>"),
+        ] {
+            assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+        }
+
+        // the exclusion wins
+        let expander = Expander::new()
+            .extra_name_chars("-.")
+            .excluded_name_chars("-");
+        assert_eq!(
+            expander.env_with_context("$A.B-C $D-E", e).unwrap(),
+            "<A.B>-C <D>-E"
+        );
+        assert_eq!(env_with_context("$A-B", e).unwrap(), "<A>-B");
+    }
+
+    #[test]
+    fn test_brace_comments() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "FILE" => Ok(Some("dir/name.tar.gz")),
+                "FILE " => Ok(Some("spaced")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().brace_comments(true);
+        for &(source, target) in &[
+            ("${FILE # the input}", "dir/name.tar.gz"),
+            ("${FILE # }", "dir/name.tar.gz"),
+            ("${FILE#*/}", "name.tar.gz"),
+            ("${FILE##*.}", "gz"),
+            ("${FILE#*/ # strip the directory}", "name.tar.gz"),
+            ("${FILE%.* # a # b}", "dir/name.tar"),
+            ("${UNSET:-/tmp # fallback}", "/tmp"),
+            ("${UNSET # note}", "${UNSET # note}"),
+            ("${ # note}", "${ # note}"),
+            ("$FILE # not a comment", "dir/name.tar.gz # not a comment"),
+        ] {
+            assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+        }
+
+        // without the setting, the comment is a part of the operator word
+        for &(source, target) in &[
+            ("${FILE # the input}", "spaced"),
+            ("${FILE#*/}", "name.tar.gz"),
+            ("${UNSET:-/tmp # fallback}", "/tmp # fallback"),
+        ] {
+            assert_eq!(env_with_context(source, e).unwrap(), target);
+        }
+
+        // offsets of errors inside operator words are not affected by comments
+        let expander = expander.recursive(true);
+        match expander.env_with_context("x ${UNSET:-$ERR # note}", e) {
+            Err(ExpandError::Lookup(err)) => {
+                assert_eq!(err.var_name, "ERR");
+                assert_eq!(err.offset, 11);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_trim_braced_names() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "HOME" => Ok(Some("/home/user")),
+                " HOME " => Ok(Some("spaced")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().trim_braced_names(true);
+        for &(source, target) in &[
+            ("${ HOME }/x", "/home/user/x"),
+            ("${\tHOME\n}", "/home/user"),
+            ("${HOME}", "/home/user"),
+            ("${ HOME :- default }", "/home/user"),
+            ("${ UNSET :- default }", " default "),
+            ("${ UNSET }", "${ UNSET }"),
+            ("${   }", "${   }"),
+            ("${ :-x}", "${ :-x}"),
+            ("$ HOME", "$ HOME"),
+        ] {
+            assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+        }
+        let expander = expander.recursive(true);
+        assert_eq!(
+            expander.env_with_context("${ HO$ME }", |s| match s {
+                "ME" => Ok(Some("ME")),
+                "HOME" => Ok(Some("/home/user")),
+                _ => Err(()),
+            }),
+            Ok("/home/user".into())
+        );
+        match expander.env_with_context("${  A_$ERR }", e) {
+            Err(ExpandError::Lookup(e)) => assert_eq!(e.offset, 6),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(
+            expander.parse_spans("${ HOME }")[0].kind,
+            SpanKind::Var {
+                name: "HOME",
+                kind: ReferenceKind::Braced
+            }
+        );
+
+        // without trimming the whitespace is a part of the name
+        assert_eq!(env_with_context("${ HOME }/x", e).unwrap(), "spaced/x");
+        assert_eq!(env_with_context("${ UNSET }", e).unwrap(), "${ UNSET }");
+    }
+
+    #[test]
+    fn test_placeholders() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                "B" => Ok(Some("b")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().placeholders("$$", "$$");
+        for &(source, target) in &[
+            ("$$A$$/$$B$$", "a/b"),
+            ("$$A$$$$B$$", "ab"),
+            ("$$$$A$$", "$$A$$"),
+            ("$$$$$$A$$", "$$a"),
+            ("$$UNKNOWN$$", "$$UNKNOWN$$"),
+            ("$$$$", "$$"),
+            ("$$", "$$"),
+            ("$$A", "$$A"),
+            ("$$$$$$", "$$$$"),
+            ("$A/${A}/$$${A}", "$A/${A}/$$${A}"),
+            ("$$A:-x$$", "$$A:-x$$"),
+        ] {
+            assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+        }
+
+        let expander = Expander::new().placeholders("##", "##");
+        for &(source, target) in &[
+            ("##A##/##B##", "a/b"),
+            ("####A##", "##A##"),
+            ("####", "##"),
+            ("# ## #A#", "# ## #A#"),
+            ("$A/$$", "$A/$$"),
+        ] {
+            assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+        }
+
+        let expander = Expander::new()
+            .placeholders("<%", "%>")
+            .trim_braced_names(true);
+        assert_eq!(
+            expander.env_with_context("<% A %>/<%B%>/<%<%", e).unwrap(),
+            "a/b/<%"
+        );
+        assert_eq!(
+            expander.parse_spans("x<% A %>"),
+            [
+                Span {
+                    kind: SpanKind::Literal,
+                    range: 0..1
+                },
+                Span {
+                    kind: SpanKind::Var {
+                        name: "A",
+                        kind: ReferenceKind::Braced
+                    },
+                    range: 1..8
+                },
+            ]
+        );
+        assert_eq!(
+            expander.env_with_context("ok <%ERR%>", e),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                offset: 3,
+                kind: ReferenceKind::Braced,
+                cause: ()
+            }))
+        );
+
+        // the dollar syntax is not affected by default
+        assert_eq!(env_with_context("$$A$$", e).unwrap(), "$A$");
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be empty")]
+    fn test_empty_placeholders() {
+        let _ = Expander::new().placeholders("", "}");
+    }
+
+    #[test]
+    fn test_custom_delimiters() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                "B" => Ok(Some("b")),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().delimiters('[', ']');
+        for &(source, target) in &[
+            ("$[A]/$A/$[B:-x]", "a/a/b"),
+            ("${A}/$[A]", "${A}/a"),
+            ("$$[A]", "$[A]"),
+            ("$[UNKNOWN]", "$[UNKNOWN]"),
+            ("$[UNKNOWN:-a\\]b]", "a]b"),
+            ("$[A", "$[A"),
+            ("x$[", "x$["),
+            ("$[]", "$[]"),
+            ("$[A:-$[B]]", "a]"),
+            ("$[UNKNOWN:-$[B]]", "$[B]"),
+        ] {
+            assert_eq!(
+                expander.env_with_context(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        let expander = Expander::new().delimiters('[', ']').recursive(true);
+        for &(source, target) in &[
+            ("$[A:-$[B]]", "a"),
+            ("$[UNKNOWN:-$[B]/${B}]", "b/${B}"),
+            ("$[UNKNOWN:-$[B:-$[A]]x]", "bx"),
+            ("$[UNKNOWN:-$[B]", "$[UNKNOWN:-b"),
+        ] {
+            assert_eq!(
+                expander.env_with_context(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        let expander = Expander::new().delimiters('(', ')');
+        assert_eq!(expander.env_with_context("$(A)$(B#?)$(", e).unwrap(), "a$(");
+        assert_eq!(
+            expander
+                .parse_spans("$(A)")
+                .first()
+                .map(|span| span.range.clone()),
+            Some(0..4)
+        );
+    }
+
+    #[test]
+    fn test_alternate_value() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "/x/${VAR:+alt}/y" => "/x/alt/y",
+            "/x/${EMPTY:+alt}/y" => "/x/alt/y",
+            "/x/${UNKNOWN:+alt}/y" => "/x//y",
+            "/x/${ERR:+alt}/y" => "/x//y",
+            "/x/${VAR:+}/y" => "/x//y",
+            "/x/${VAR:+a\\}b}/y" => "/x/a}b/y",
+            "/x/${VAR:+$VAR}/y" => "/x/$VAR/y",
+            "/x/${VAR:+a:-b}/y" => "/x/a:-b/y",
+            "/x/${UNKNOWN:-a:+b}/y" => "/x/a:+b/y",
+            "/x/${:+alt}/y" => "/x/${:+alt}/y"
+        };
+    }
+
+    #[test]
+    fn test_recursive() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "PATH" => Ok(Some("/usr/bin")),
+                "VAR" => Ok(Some("value")),
+                "REF" => Ok(Some("$VAR")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+        fn u(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "PATH" => Ok(None),
+                _ => e(s),
+            }
+        }
+
+        let expander = Expander::new().recursive(true);
+        let expand = |s| expander.env_with_context(s, e);
+
+        assert_eq!(expand("${PATH:+:$PATH}").unwrap(), ":/usr/bin");
+        assert_eq!(expander.env_with_context("${PATH:+:$PATH}", u).unwrap(), "");
+        assert_eq!(expand("${UNKNOWN:-$VAR}/x").unwrap(), "value/x");
+        assert_eq!(expand("${UNKNOWN:-${VAR}}/x").unwrap(), "value/x");
+        assert_eq!(
+            expand("${UNKNOWN:-${UNKNOWN2:-${VAR}}}/x").unwrap(),
+            "value/x"
+        );
+        assert_eq!(expand("${UNKNOWN:-$$VAR}/x").unwrap(), "$VAR/x");
+        assert_eq!(expand("${UNKNOWN:-$UNKNOWN2}/x").unwrap(), "$UNKNOWN2/x");
+        assert_eq!(expand("${VAR:-$ERR}/x").unwrap(), "value/x");
+        assert_eq!(expand("${REF:+$REF}").unwrap(), "$VAR");
+        assert_eq!(
+            expand("${UNKNOWN:-$ERR}/x"),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                offset: 11,
+                kind: ReferenceKind::Bare,
+                cause: ()
+            }))
+        );
+
+        // without recursion the words are used literally
+        assert_eq!(env_with_context("${PATH:+:$PATH}", e).unwrap(), ":$PATH");
+        assert_eq!(
+            env_with_context("${UNKNOWN:-${VAR}}/x", e).unwrap(),
+            "${VAR}/x"
+        );
+    }
+
+    #[test]
+    fn test_escapes_in_words() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                _ => Ok(None),
+            }
+        }
+
+        // escapes in the word are processed only if the word is expanded
+        let expander = Expander::new().recursive(true);
+        let expand = |s| expander.env_with_context(s, e).unwrap();
+        assert_eq!(expand("${UNSET:-a$$b}"), "a$b");
+        assert_eq!(expand("${VAR:+a$$b}"), "a$b");
+        assert_eq!(expand("${UNSET:-a$$$VAR}"), "a$value");
+        assert_eq!(expand("${VAR:-a$$b}"), "value");
+        assert_eq!(expand("${UNSET:+a$$b}"), "");
+
+        let expand = |s| env_with_context(s, e).unwrap();
+        assert_eq!(expand("${UNSET:-a$$b}"), "a$$b");
+        assert_eq!(expand("${VAR:+a$$b}"), "a$$b");
+        assert_eq!(expand("${VAR:-a$$b}"), "value");
+        assert_eq!(expand("${UNSET:+a$$b}"), "");
+
+        let expander = Expander::new().recursive(true).shell_escaping(true);
+        let expand = |s| expander.env_with_context(s, e).unwrap();
+        assert_eq!(expand("${UNSET:-a\\$b}"), "a$b");
+        assert_eq!(expand("${VAR:-a\\$b}"), "value");
+        let expander = Expander::new().shell_escaping(true);
+        let expand = |s| expander.env_with_context(s, e).unwrap();
+        assert_eq!(expand("${UNSET:-a\\$b}"), "a\\$b");
+    }
+
+    #[test]
+    fn test_recursive_words_are_lazy() {
+        let lookups = RefCell::new(Vec::new());
+        let mut e = |s: &str| -> Result<Option<&'static str>, ()> {
+            lookups.borrow_mut().push(s.to_owned());
+            match s {
+                "SET" => Ok(Some("value")),
+                "EXPENSIVE" => Ok(Some("expensive")),
+                _ => Ok(None),
+            }
+        };
+
+        let expander = Expander::new().recursive(true);
+        assert_eq!(
+            expander
+                .env_with_context("${SET:-$EXPENSIVE}", &mut e)
+                .unwrap(),
+            "value"
+        );
+        assert_eq!(
+            expander
+                .env_with_context("${UNSET:+${EXPENSIVE}}", &mut e)
+                .unwrap(),
+            ""
+        );
+        assert_eq!(*lookups.borrow(), ["SET", "UNSET"]);
+
+        lookups.borrow_mut().clear();
+        assert_eq!(
+            expander
+                .env_with_context("${UNSET:-$EXPENSIVE}", &mut e)
+                .unwrap(),
+            "expensive"
+        );
+        assert_eq!(
+            expander
+                .env_with_context("${SET:+${EXPENSIVE}}", &mut e)
+                .unwrap(),
+            "expensive"
+        );
+        assert_eq!(
+            *lookups.borrow(),
+            ["UNSET", "EXPENSIVE", "SET", "EXPENSIVE"]
+        );
+    }
+
+    #[test]
+    fn test_recursive_lookups_are_memoized() {
+        let lookups = RefCell::new(Vec::new());
+        let mut e = |s: &str| -> Result<Option<String>, ()> {
+            lookups.borrow_mut().push(s.to_owned());
+            match s {
+                "A" => Ok(Some(format!("a{}", lookups.borrow().len()))),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        };
+
+        let expander = Expander::new().recursive(true);
+        assert_eq!(
+            expander
+                .env_with_context("${A} ${A} ${B:-$A} $B ${B}", &mut e)
+                .unwrap(),
+            "a1 a1 a1 $B ${B}"
+        );
+        assert_eq!(*lookups.borrow(), ["A", "B"]);
+
+        // the cache is discarded after each call
+        assert_eq!(expander.env_with_context("$A", &mut e).unwrap(), "a3");
+
+        // errors are not cached
+        lookups.borrow_mut().clear();
+        assert_eq!(
+            expander
+                .env_with_context("${ERR:-x}${ERR:-y}", &mut e)
+                .unwrap(),
+            "xy"
+        );
+        assert_eq!(*lookups.borrow(), ["ERR", "ERR"]);
+
+        // lookups are not cached in non-recursive mode
+        lookups.borrow_mut().clear();
+        assert_eq!(
+            Expander::new().env_with_context("$A $A", &mut e).unwrap(),
+            "a1 a2"
+        );
+    }
+
+    #[test]
+    fn test_shell_escaping() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "$" => Ok(Some("4242")),
+                "VAR" => Ok(Some("value")),
+                _ => Ok(None),
+            }
+        }
+        fn u(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "$" => Ok(None),
+                _ => e(s),
+            }
+        }
+        fn err(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "$" => Err(()),
+                _ => e(s),
+            }
+        }
+
+        let expander = Expander::new().shell_escaping(true);
+        let expand = |s| expander.env_with_context(s, e).unwrap();
+
+        assert_eq!(expand("pid $$"), "pid 4242");
+        assert_eq!(expand("$$$VAR"), "4242value");
+        assert_eq!(expand("\\$VAR"), "$VAR");
+        assert_eq!(expand("\\${VAR}"), "${VAR}");
+        assert_eq!(expand("a\\$$VAR"), "a$value");
+        assert_eq!(expand("\\$$"), "$$");
+        assert_eq!(expand("$VAR\\$"), "value$");
+        assert_eq!(expand("a\\b$VAR"), "a\\bvalue");
+        assert_eq!(expander.env_with_context("pid $$", u).unwrap(), "pid $$");
+        assert_eq!(
+            expander.env_with_context("pid $$", err),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "$".into(),
+                offset: 4,
+                kind: ReferenceKind::Bare,
+                cause: ()
+            }))
+        );
+
+        // in the default mode `$$` is an escape and backslashes are not special
+        assert_eq!(env_with_context("pid $$", e).unwrap(), "pid $");
+        assert_eq!(env_with_context("\\$VAR", e).unwrap(), "\\value");
+    }
+
+    #[test]
+    fn test_pattern_operators_before_literals() {
+        use full_with_context;
+
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "PATH" => Ok(Some("/opt/tool/libexec")),
+                "FILE" => Ok(Some("/etc/app/config.toml")),
+                _ => Ok(None),
+            }
+        }
+
+        // the operator ends at the first closing brace, and the rest is literal text
+        for expander in &[Expander::new(), Expander::new().recursive(true)] {
+            for &(source, target) in &[
+                ("${PATH%/*}/bin", "/opt/tool/bin"),
+                ("${FILE##*/}.bak", "config.toml.bak"),
+                (
+                    "${PATH%/*}/bin/${FILE##*/}.bak",
+                    "/opt/tool/bin/config.toml.bak",
+                ),
+                ("${PATH%/*}}", "/opt/tool}"),
+                ("${PATH%%/*}/bin", "/bin"),
+                ("${FILE#*/}.bak", "etc/app/config.toml.bak"),
+            ] {
+                assert_eq!(expander.env_with_context(source, e).unwrap(), target);
+            }
+        }
+        assert_eq!(
+            full_with_context("${PATH%/*}/bin", || None::<&str>, e).unwrap(),
+            "/opt/tool/bin"
+        );
+    }
+
+    #[test]
+    fn test_operator_characters_in_names() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "a" => Ok(Some("abc")),
+                "x" => Ok(Some("xyz")),
+                "a/b" | "x,y" | "a#b" | "a%c" | "x^y" | "a@U" => Ok(Some("literal name")),
+                _ => Ok(None),
+            }
+        }
+
+        // these names were looked up literally before the pattern-matching and case operators
+        table! { e, unwrap,
+            "${a/b}" => "ac",
+            "${a//b/x}" => "axc",
+            "${x,y}" => "xyz",
+            "${x^y}" => "xyz",
+            "${x^^y}" => "xYz",
+            "${a#b}" => "abc",
+            "${a#a}" => "bc",
+            "${a%c}" => "ab",
+            "${a@U}" => "ABC",
+            "${a@X}" => "${a@X}"
+        }
+    }
+
+    #[test]
+    fn test_pattern_operators() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "FILE" => Ok(Some("/usr/src/archive.tar.gz")),
+                "EMPTY" => Ok(Some("")),
+                "SEP" => Ok(Some("/")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${FILE#*/}" => "usr/src/archive.tar.gz",
+            "${FILE##*/}" => "archive.tar.gz",
+            "${FILE%.*}" => "/usr/src/archive.tar",
+            "${FILE%%.*}" => "/usr/src/archive",
+            "${FILE/r/R}" => "/usR/src/archive.tar.gz",
+            "${FILE//r/R}" => "/usR/sRc/aRchive.taR.gz",
+            "${FILE//[aeiou]}" => "/sr/src/rchv.tr.gz",
+            "${FILE/a*e/x}" => "/usr/src/x.tar.gz",
+            "${FILE#nomatch}" => "/usr/src/archive.tar.gz",
+            "${FILE%\\}}" => "/usr/src/archive.tar.gz",
+            "${FILE//\\//:}" => ":usr:src:archive.tar.gz",
+            "${EMPTY#*}" => "",
+            "${UNKNOWN#*/}" => "${UNKNOWN#*/}",
+            "${FILE##*/}.bak" => "archive.tar.gz.bak",
+            "${FILE#$SEP}" => "/usr/src/archive.tar.gz"
+        }
+        assert_eq!(
+            env_with_context("${ERR#*}", e),
+            Err(LookupError {
+                var_name: "ERR".into(),
+                offset: 0,
+                kind: ReferenceKind::Braced,
+                cause: ()
+            })
+        );
+
+        // patterns and replacements are expanded in the recursive mode
+        let expander = Expander::new().recursive(true);
+        assert_eq!(
+            expander.env_with_context("${FILE#$SEP}", e).unwrap(),
+            "usr/src/archive.tar.gz"
+        );
+        assert_eq!(
+            expander
+                .env_with_context("${FILE//$SEP/$SEP$SEP}", e)
+                .unwrap(),
+            "//usr//src//archive.tar.gz"
+        );
+        assert_eq!(
+            expander.env_with_context("${FILE#$ERR}", e),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                offset: 7,
+                kind: ReferenceKind::Bare,
+                cause: ()
+            }))
+        );
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_pattern_syntax() {
+        use PatternSyntax;
+
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "VERSION" => Some("release-1.21.3"),
+                _ => None,
+            }
+        }
+
+        let glob = Expander::new();
+        let regex = Expander::new().pattern_syntax(PatternSyntax::Regex);
+
+        let input = "${VERSION%.*}|${VERSION%%.*}|${VERSION#*-}";
+        assert_eq!(
+            glob.env_with_context_no_errors(input, e),
+            "release-1.21|release-1|1.21.3"
+        );
+        let input = "${VERSION%\\..*}|${VERSION%%\\..*}|${VERSION#.*-}";
+        assert_eq!(
+            regex.env_with_context_no_errors(input, e),
+            "release-1.21|release-1|1.21.3"
+        );
+
+        let input = "${VERSION/\\d+/N}|${VERSION//\\d+/N}";
+        assert_eq!(
+            glob.env_with_context_no_errors(input, e),
+            "release-1.21.3|release-1.21.3"
+        );
+        assert_eq!(
+            regex.env_with_context_no_errors(input, e),
+            "release-N.21.3|release-N.N.N"
+        );
+
+        // the glob `*` is not a valid regular expression
+        assert_eq!(
+            glob.env_with_context_no_errors("${VERSION#*-}", e),
+            "1.21.3"
+        );
+        match regex.env_with_context("a ${VERSION#*-}", |s| Ok::<_, ()>(e(s))) {
+            Err(ExpandError::InvalidPattern {
+                pattern,
+                offset,
+                message,
+            }) => {
+                assert_eq!(pattern, "*-");
+                assert_eq!(offset, 2);
+                assert!(message.contains("repetition operator missing expression"));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            regex.env_with_context_no_errors("a ${VERSION#*-}", e),
+            "a ${VERSION#*-}"
+        );
+    }
+
+    #[test]
+    fn test_operators_without_errors() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "FILE" => Some("/tmp/file.txt"),
+                "EMPTY" => Some(""),
+                _ => None,
+            }
+        }
+
+        let expand = |s| env_with_context_no_errors(s, e);
+        assert_eq!(expand("${UNKNOWN:-default}"), "default");
+        assert_eq!(expand("${EMPTY:-default}"), "");
+        assert_eq!(expand("${FILE:+set}|${UNKNOWN:+set}"), "set|");
+        assert_eq!(expand("${FILE##*/}|${FILE#*.}"), "file.txt|txt");
+        assert_eq!(expand("${UNKNOWN#*/}"), "${UNKNOWN#*/}");
+        assert_eq!(expand("${UNKNOWN:?message}"), "${UNKNOWN:?message}");
+    }
+
+    #[test]
+    fn test_strip_double_quotes() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "HOME" => Some("/home/me"),
+                "QUOTED" => Some("\"q\""),
+                _ => None,
+            }
+        }
+
+        let expander = Expander::new().strip_double_quotes(true);
+        let expand = |s| expander.env_with_context_no_errors(s, e);
+
+        assert_eq!(expand("\"${HOME}/x\""), "/home/me/x");
+        assert_eq!(expand("\"$HOME\"/x/\"a b\""), "/home/me/x/a b");
+        assert_eq!(expand("say \"\\\"hi\\\"\""), "say \"hi\"");
+        assert_eq!(expand("no references \"here\""), "no references here");
+        assert_eq!(expand("\"\"$QUOTED"), "\"q\"");
+        assert_eq!(expand("a\\b"), "a\\b");
+
+        let expand = |s| Expander::new().env_with_context_no_errors(s, e);
+        assert_eq!(expand("\"${HOME}/x\""), "\"/home/me/x\"");
+        assert_eq!(expand("say \"\\\"hi\\\"\""), "say \"\\\"hi\\\"\"");
+    }
+
+    #[test]
+    fn test_expand_checked() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "A" => Some("a"),
+                "EMPTY" => Some(""),
+                _ => None,
+            }
+        }
+
+        assert_eq!(expand_checked("$A/${EMPTY}/x", e), Ok("a//x".to_owned()));
+        assert_eq!(
+            expand_checked("no variables", e),
+            Ok("no variables".to_owned())
+        );
+        assert_eq!(
+            expand_checked("${X:-x}/${A:+y}/${Y:+y}/$$Z", e),
+            Ok("x/y//$Z".to_owned())
+        );
+
+        assert_eq!(expand_checked("$A/$Z", e), Err(vec!["Z".to_owned()]));
+        assert_eq!(
+            expand_checked("$Z/$A/${B}/$Z/${C}/$B", e),
+            Err(vec!["B".to_owned(), "C".to_owned(), "Z".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_on_unresolved() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                _ => Ok(None),
+            }
+        }
+
+        let mut count = 0;
+        let result = Expander::new().env_with_context_on_unresolved(
+            "$VAR $UNKNOWN ${VAR} ${UNKNOWN2} ${UNKNOWN:-default} ${UNKNOWN:+alt} $$",
+            e,
+            |_| count += 1,
+        );
+        assert_eq!(
+            result.unwrap(),
+            "value $UNKNOWN value ${UNKNOWN2} default  $"
+        );
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_regex_escape() {
+        for &(value, escaped) in &[
+            ("1.2.3", r"1\.2\.3"),
+            ("*.rs", r"\*\.rs"),
+            ("f(x)", r"f\(x\)"),
+            ("a\\b", r"a\\b"),
+            ("[a-z]{2}^$", r"\[a\-z\]\{2\}\^\$"),
+            ("x+y?|#&~", r"x\+y\?\|\#\&\~"),
+            ("naïve.α", r"naïve\.α"),
+        ] {
+            assert_eq!(regex_escape(value), escaped);
+        }
+        for &value in &["plain", "with spaces/and_slashes", ""] {
+            match regex_escape(value) {
+                Cow::Borrowed(s) => assert_eq!(s, value),
+                Cow::Owned(s) => panic!("unexpected allocation: {:?}", s),
+            }
+        }
+
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VERSION" => Ok(Some("1.2*")),
+                "NAME" => Ok(Some("app")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+        assert_eq!(
+            env_with_context("^$NAME-${VERSION}(-.+)?$", regex_escaped(e)).unwrap(),
+            r"^app-1\.2\*(-.+)?$"
+        );
+        assert_eq!(
+            env_with_context("${UNSET:-a.b} $UNSET", regex_escaped(e)).unwrap(),
+            "a.b $UNSET"
+        );
+        assert!(env_with_context("$ERR", regex_escaped(e)).is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_escape_matches_literally() {
+        use regex::Regex;
+
+        for &value in &[
+            "1.2.3",
+            "*.rs",
+            "f(x)",
+            "a\\b",
+            "[a-z]{2}^$",
+            "x+y?|#&~",
+            "plain",
+        ] {
+            let regex = Regex::new(&format!("^{}$", regex_escape(value))).unwrap();
+            assert!(regex.is_match(value), "{:?}", value);
+            assert_eq!(&*regex_escape(value), regex::escape(value));
+        }
+    }
+
+    #[test]
+    fn test_on_unknown() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "BROKEN" => Err(()),
+                _ => Ok(None),
+            }
+        }
+        fn on_unknown(name: &str) -> UnknownAction {
+            if name.starts_with("REQUIRED_") {
+                UnknownAction::Error
+            } else if name.starts_with("EMPTY_") {
+                UnknownAction::Empty
+            } else if name.starts_with("REPLACED_") {
+                UnknownAction::Replace(format!("<{}>", name))
+            } else {
+                UnknownAction::Leave
+            }
+        }
+
+        let expander = Expander::new();
+        let expand = |s| expander.env_with_context_on_unknown(s, e, on_unknown);
+        for &(source, target) in &[
+            ("$VAR $OPTIONAL ${OPTIONAL}", "value $OPTIONAL ${OPTIONAL}"),
+            ("[$EMPTY_A] [${EMPTY_B}]", "[] []"),
+            ("$REPLACED_A ${REPLACED_B}/x", "<REPLACED_A> <REPLACED_B>/x"),
+            ("${OPTIONAL#x} ${EMPTY_A#x}", "${OPTIONAL#x} "),
+            ("${REQUIRED_A:-default} ${REQUIRED_B:+alt}.", "default ."),
+            ("${BROKEN:-y}", "y"),
+        ] {
+            assert_eq!(expand(source).unwrap(), target);
+        }
+
+        // one prefix fails the expansion while another one is left as it is
+        assert_eq!(
+            expand("$OPTIONAL ${REQUIRED_A} $VAR"),
+            Err(ExpandError::Unknown {
+                var_name: "REQUIRED_A".into(),
+                offset: 10,
+            })
+        );
+        let err = ExpandError::<String>::Unknown {
+            var_name: "REQUIRED_B".into(),
+            offset: 0,
+        };
+        assert_eq!(err.to_string(), "unknown variable 'REQUIRED_B'");
+        assert_eq!(
+            expand("$BROKEN"),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "BROKEN".into(),
+                offset: 0,
+                kind: ReferenceKind::Bare,
+                cause: (),
+            }))
+        );
+
+        // the callback is called for each reference, in order
+        let mut names = Vec::new();
+        let result = expander.env_with_context_on_unknown("$A ${B:-b} $VAR $C", e, |name: &str| {
+            names.push(name.to_owned());
+            UnknownAction::Leave
+        });
+        assert_eq!(result.unwrap(), "$A b value $C");
+        assert_eq!(names, ["A", "C"]);
+
+        // replacements count as substitutions
+        let expander = Expander::new().max_substitutions(Some(1));
+        assert_eq!(
+            expander.env_with_context_on_unknown("$EMPTY_A $EMPTY_B", e, on_unknown),
+            Err(ExpandError::TooManySubstitutions {
+                limit: 1,
+                offset: 9
+            })
+        );
+        assert_eq!(
+            expander
+                .env_with_context_on_unknown("$EMPTY_A $OPTIONAL", e, on_unknown)
+                .unwrap(),
+            " $OPTIONAL"
+        );
+    }
+
+    #[test]
+    fn test_observer() {
+        #[derive(Default)]
+        struct Recorder(Vec<String>);
+
+        impl ExpansionObserver for Recorder {
+            fn on_lookup(&mut self, name: &str) {
+                self.0.push(format!("lookup {}", name));
+            }
+
+            fn on_resolved(&mut self, name: &str, value_len: usize) {
+                self.0.push(format!("resolved {} {}", name, value_len));
+            }
+
+            fn on_unresolved(&mut self, name: &str) {
+                self.0.push(format!("unresolved {}", name));
+            }
+
+            fn on_error(&mut self, name: &str) {
+                self.0.push(format!("error {}", name));
+            }
+        }
+
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "BROKEN" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let mut recorder = Recorder::default();
+        let result = Expander::new().env_with_context_observed(
+            "$VAR $UNKNOWN ${UNKNOWN:-x} ${BROKEN:-y} ${VAR}",
+            e,
+            &mut recorder,
+        );
+        assert_eq!(result.unwrap(), "value $UNKNOWN x y value");
+        assert_eq!(
+            recorder.0,
+            [
+                "lookup VAR",
+                "resolved VAR 5",
+                "lookup UNKNOWN",
+                "unresolved UNKNOWN",
+                "lookup UNKNOWN",
+                "lookup BROKEN",
+                "error BROKEN",
+                "lookup VAR",
+                "resolved VAR 5",
+            ]
+        );
+
+        // in recursive mode each variable is looked up once
+        let mut recorder = Recorder::default();
+        let result = Expander::new().recursive(true).env_with_context_observed(
+            "$VAR ${VAR} $UNKNOWN ${UNKNOWN} $BROKEN",
+            e,
+            &mut recorder,
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            recorder.0,
+            [
+                "lookup VAR",
+                "resolved VAR 5",
+                "lookup UNKNOWN",
+                "unresolved UNKNOWN",
+                "unresolved UNKNOWN",
+                "lookup BROKEN",
+                "error BROKEN",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_context_by_mut_ref() {
+        let mut lookups = Vec::new();
+        let mut context = |s: &str| -> Result<Option<String>, ()> {
+            lookups.push(s.to_owned());
+            Ok(Some(format!("<{}>", s)))
+        };
+
+        assert_eq!(env_with_context("$A", &mut context).unwrap(), "<A>");
+        assert_eq!(env_with_context("${B}", &mut context).unwrap(), "<B>");
+        {
+            let dyn_context: &mut dyn FnMut(&str) -> Result<Option<String>, ()> = &mut context;
+            assert_eq!(
+                Expander::new()
+                    .env_with_context("$C$A", dyn_context)
+                    .unwrap(),
+                "<C><A>"
+            );
+        }
+
+        assert_eq!(lookups, vec!["A", "B", "C", "A"]);
+    }
+
+    #[test]
+    fn test_error_kind() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Err(())
+        }
+        fn kind(e: ExpandError<()>) -> ReferenceKind {
+            match e {
+                ExpandError::Lookup(e) => e.kind,
+                e => panic!("unexpected error: {:?}", e),
+            }
+        }
+
+        assert_eq!(
+            env_with_context("x/$VAR", e).unwrap_err().kind,
+            ReferenceKind::Bare
+        );
+        assert_eq!(
+            env_with_context("x/${VAR}", e).unwrap_err().kind,
+            ReferenceKind::Braced
+        );
+        assert_eq!(
+            Expander::new()
+                .recursive(true)
+                .env_with_context("x/${VAR:+}${UNKNOWN:-$VAR}", e)
+                .map_err(kind),
+            Err(ReferenceKind::Bare)
+        );
+        assert_eq!(
+            Expander::new()
+                .shell_escaping(true)
+                .env_with_context("x/$$", e)
+                .map_err(kind),
+            Err(ReferenceKind::Bare)
+        );
+        assert_eq!(
+            env_with_context("x/${VAR#pattern}", e).unwrap_err().kind,
+            ReferenceKind::Braced
+        );
+    }
+
+    #[test]
+    fn test_error_position() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let input = "first $VAR line\nsecond line\n  ${UNKNOWN} and ${ERR}";
+        let err = env_with_context(input, e).unwrap_err();
+        assert_eq!(err.var_name, "ERR");
+        assert_eq!(err.offset, 45);
+        assert_eq!(
+            err.position(input),
+            Position {
+                line: 3,
+                column: 18
+            }
+        );
+
+        let input = "ünïcödé\r\n\n\tä $ERR";
+        let err = env_with_context(input, e).unwrap_err();
+        assert_eq!(err.position(input), Position { line: 3, column: 4 });
+
+        let input = "$ERR";
+        let err = env_with_context(input, e).unwrap_err();
+        assert_eq!(err.position(input), Position { line: 1, column: 1 });
+
+        // errors in recursively expanded words point into the word
+        let expander = Expander::new().recursive(true);
+        let input = "a\n${UNKNOWN:-x\\}y$ERR}";
+        let err = expander.env_with_context(input, e).unwrap_err();
+        assert_eq!(err.offset(), 17);
+        assert_eq!(&input[err.offset()..], "$ERR}");
+        assert_eq!(
+            err.position(input),
+            Position {
+                line: 2,
+                column: 16
+            }
+        );
+    }
+
+    #[test]
+    fn test_lookup_error_into_boxed() {
+        #[derive(Debug)]
+        struct Cause;
+
+        impl std::fmt::Display for Cause {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "broken")
+            }
+        }
+
+        impl std::error::Error for Cause {}
+
+        let boxed = env_with_context("x/${VAR}", |_| Err::<Option<&str>, _>(Cause))
+            .unwrap_err()
+            .into_boxed();
+        assert_eq!(boxed.to_string(), "error looking key 'VAR' up: broken");
+        assert!(boxed.source().unwrap().downcast_ref::<Cause>().is_some());
+
+        let err = boxed.downcast::<LookupError<Cause>>().unwrap();
+        assert_eq!(err.var_name, "VAR");
+        assert_eq!(err.offset, 2);
+        assert_eq!(err.kind, ReferenceKind::Braced);
+    }
+
+    #[test]
+    fn test_global_env() {
+        match std::env::var("PATH") {
+            Ok(value) => assert_eq!(env("x/$PATH/x").unwrap(), format!("x/{}/x", value)),
+            Err(e) => assert_eq!(
+                env("x/$PATH/x"),
+                Err(LookupError {
+                    var_name: "PATH".into(),
+                    offset: 2,
+                    kind: ReferenceKind::Bare,
+                    cause: e
+                })
+            ),
+        }
+        match std::env::var("SOMETHING_DEFINITELY_NONEXISTING") {
+            Ok(value) => assert_eq!(
+                env("x/$SOMETHING_DEFINITELY_NONEXISTING/x").unwrap(),
+                format!("x/{}/x", value)
+            ),
+            Err(e) => assert_eq!(
+                env("x/$SOMETHING_DEFINITELY_NONEXISTING/x"),
+                Err(LookupError {
+                    var_name: "SOMETHING_DEFINITELY_NONEXISTING".into(),
+                    offset: 2,
+                    kind: ReferenceKind::Bare,
+                    cause: e
+                })
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod full_tests {
+    use std::borrow::Cow;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    use super::{
+        full, full_arc, full_lossy, full_path_with_base, full_path_with_context,
+        full_user_with_context, full_with_context, full_with_context_no_errors,
+        full_with_context_reporting, full_with_home, full_with_map, Expander, FullError,
+        HomeDirError, HomeDirErrorKind,
+    };
+
+    #[test]
+    fn test_operators_without_errors() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "DIR" => Some("a/b/c"),
+                _ => None,
+            }
+        }
+        let hd = || Some("/home/user");
+
+        assert_eq!(
+            full_with_context_no_errors("~/${UNKNOWN:-x}/${DIR:+y}/${DIR#*/}", hd, e),
+            "/home/user/x/y/b/c"
+        );
+        assert_eq!(
+            full_with_context_no_errors("${UNKNOWN:+~}/${DIR##*/}", hd, e),
+            "/c"
+        );
+    }
+
+    #[test]
+    fn test_operators_and_tilde() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "SET" => Some("value"),
+                _ => None,
+            }
+        }
+        let hd = || Some("/home/user");
+
+        assert_eq!(
+            full_with_context_no_errors("~/${DIR:-default}/file", hd, e),
+            "/home/user/default/file"
+        );
+        assert_eq!(full_with_context_no_errors("${HOMEISH:-~}/x", hd, e), "~/x");
+        assert_eq!(full_with_context_no_errors("${SET:+~}/x", hd, e), "~/x");
+        assert_eq!(
+            full_with_context_no_errors("~${HOMEISH:-/x}", hd, e),
+            "/home/user/x"
+        );
+        assert_eq!(full_with_context_no_errors("~${HOMEISH:-x}", hd, e), "~x");
+        assert_eq!(
+            Expander::new().recursive(true).full_with_context_no_errors(
+                "${HOMEISH:-~/$SET}",
+                hd,
+                e
+            ),
+            "~/value"
+        );
+    }
+
+    #[test]
+    fn test_home_dir_called_at_most_once() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "VAR" => Some("value"),
+                "T" => Some("~"),
+                _ => None,
+            }
+        }
+
+        let calls = Cell::new(0);
+        let hd = || {
+            calls.set(calls.get() + 1);
+            Some("/home/user")
+        };
+
+        assert_eq!(full_with_context_no_errors("~/x", hd, e), "/home/user/x");
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            full_with_context_no_errors("~/$VAR", hd, e),
+            "/home/user/value"
+        );
+        assert_eq!(calls.get(), 2);
+        assert_eq!(full_with_context_no_errors("x/$VAR", hd, e), "x/value");
+        assert_eq!(full_with_context_no_errors("$T/x", hd, e), "~/x");
+        assert_eq!(full_with_context_no_errors("~user/x", hd, e), "~user/x");
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_no_variables_fast_path() {
+        let calls = Cell::new(0);
+        let context = |_: &str| {
+            calls.set(calls.get() + 1);
+            Ok::<_, ()>(Some("value"))
+        };
+        let hd = || Some("/home/user");
+
+        assert_eq!(
+            full_with_context("~/x", hd, context).unwrap(),
+            "/home/user/x"
+        );
+        assert_eq!(full_with_context("~", hd, context).unwrap(), "/home/user");
+        assert_eq!(calls.get(), 0);
+
+        let input = String::from("x/~/y");
+        match full_with_context(&input, hd, context).unwrap() {
+            Cow::Borrowed(s) => assert!(ptr::eq(s, input.as_str())),
+            Cow::Owned(s) => panic!("the input was copied: {:?}", s),
+        }
+        assert_eq!(calls.get(), 0);
+
+        assert_eq!(
+            full_with_context("~/$X", hd, context).unwrap(),
+            "/home/user/value"
+        );
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_selectable_phases() {
+        let calls = Cell::new(0);
+        let context = |s: &str| {
+            calls.set(calls.get() + 1);
+            Ok::<_, ()>(if s == "VAR" { Some("value") } else { None })
+        };
+        let home_dir_calls = Cell::new(0);
+        let hd = || {
+            home_dir_calls.set(home_dir_calls.get() + 1);
+            Some("/home/user")
+        };
+
+        for &(tilde, variables, expected) in &[
+            (true, true, "/home/user/value"),
+            (true, false, "/home/user/$VAR"),
+            (false, true, "~/value"),
+            (false, false, "~/$VAR"),
+        ] {
+            calls.set(0);
+            home_dir_calls.set(0);
+            let expander = Expander::new().tilde(tilde).variables(variables);
+            assert_eq!(
+                expander.full_with_context("~/$VAR", hd, context).unwrap(),
+                expected
+            );
+            assert_eq!(calls.get(), variables as usize);
+            assert_eq!(home_dir_calls.get(), tilde as usize);
+
+            calls.set(0);
+            home_dir_calls.set(0);
+            let context = |s: &str| context(s).unwrap();
+            assert_eq!(
+                expander.full_with_context_no_errors("~/$VAR", hd, context),
+                expected
+            );
+            assert_eq!(calls.get(), variables as usize);
+            assert_eq!(home_dir_calls.get(), tilde as usize);
+        }
+
+        // without environment expansion neither the context nor the references can fail
+        let expander = Expander::new().variables(false).error_operator(true);
+        let failing = |_: &str| Err::<Option<&str>, _>(());
+        assert_eq!(
+            expander.full_with_context("~/${X:?}", hd, failing).unwrap(),
+            "/home/user/${X:?}"
+        );
+        let input = String::from("x/$VAR");
+        match expander.full_with_context(&input, hd, context).unwrap() {
+            Cow::Borrowed(s) => assert!(ptr::eq(s, input.as_str())),
+            Cow::Owned(s) => panic!("the input was copied: {:?}", s),
+        }
+
+        // the `env_*` methods are not affected
+        assert_eq!(
+            Expander::new()
+                .variables(false)
+                .env_with_context("$VAR", context)
+                .unwrap(),
+            "value"
+        );
+    }
+
+    #[test]
+    fn test_full_user_with_context() {
+        fn hd(user: Option<&str>) -> Option<PathBuf> {
+            match user {
+                None => Some("/home/user".into()),
+                Some("alice") => Some("/home/alice".into()),
+                Some(_) => None,
+            }
+        }
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "OWNER" => Ok(Some("alice")),
+                "NOBODY" => Ok(Some("bob")),
+                "T" => Ok(Some("~alice")),
+                _ => Ok(None),
+            }
+        }
+
+        for &(source, target) in &[
+            ("~${OWNER}/data", "/home/alice/data"),
+            ("~$OWNER/data", "/home/alice/data"),
+            ("~$OWNER", "/home/alice"),
+            ("~alice/$OWNER", "/home/alice/alice"),
+            ("~/$OWNER", "/home/user/alice"),
+            ("~${NOBODY}/data", "~bob/data"),
+            ("~${UNKNOWN}/data", "~${UNKNOWN}/data"),
+            ("$T/data", "~alice/data"),
+            ("x/~$OWNER", "x/~alice"),
+        ] {
+            assert_eq!(
+                full_user_with_context(source, hd, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+        match full_user_with_context("some/path", hd, e) {
+            Ok(Cow::Borrowed(s)) => assert_eq!(s, "some/path"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_full_path_with_context() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "SUB" => Ok(Some("sub")),
+                "T" => Ok(Some("~")),
+                "NESTED" => Ok(Some("a/b")),
+                _ => Ok(None),
+            }
+        }
+        let no_hd = || None::<PathBuf>;
+
+        #[cfg(unix)]
+        {
+            let hd = || Some("/home/user");
+            let path = |s| full_path_with_context(s, hd, e).unwrap();
+            assert_eq!(path("~/${SUB}/file"), Path::new("/home/user/sub/file"));
+            assert_eq!(path("~"), Path::new("/home/user"));
+            assert_eq!(path("~//$SUB//file/"), Path::new("/home/user/sub/file"));
+            assert_eq!(path("~/$NESTED"), Path::new("/home/user/a/b"));
+            assert_eq!(path("~\\$SUB"), Path::new("~\\sub"));
+            assert_eq!(
+                full_path_with_context("~/x", || Some("/"), e).unwrap(),
+                Path::new("/x")
+            );
+        }
+
+        #[cfg(windows)]
+        {
+            let hd = || Some("C:\\Users\\user");
+            let path = |s| full_path_with_context(s, hd, e).unwrap();
+            let expected = Path::new("C:\\Users\\user\\sub\\file");
+            assert_eq!(path("~/${SUB}/file").as_os_str(), expected.as_os_str());
+            assert_eq!(path("~\\${SUB}\\file").as_os_str(), expected.as_os_str());
+            assert_eq!(
+                path("~/$NESTED").as_os_str(),
+                Path::new("C:\\Users\\user\\a\\b").as_os_str()
+            );
+        }
+
+        let path = |s| full_path_with_context(s, || Some("/home/user"), e).unwrap();
+        assert_eq!(path("$T/x"), Path::new("~/x"));
+        assert_eq!(path("~user/x"), Path::new("~user/x"));
+        assert_eq!(path("x/~/$SUB"), Path::new("x/~/sub"));
+        assert_eq!(
+            full_path_with_context("~/$SUB", no_hd, e).unwrap(),
+            Path::new("~/sub")
+        );
+        assert_eq!(
+            full_path_with_context("~/$X", no_hd, |_| Err::<Option<&str>, _>(()))
+                .unwrap_err()
+                .var_name,
+            "X"
+        );
+    }
+
+    #[test]
+    fn test_full_with_context_reporting() {
+        fn e(s: &str) -> Result<Option<&'static str>, &'static str> {
+            match s {
+                "A" => Ok(Some("a")),
+                "T" => Ok(Some("~")),
+                "ERR" => Err("broken"),
+                _ => Ok(None),
+            }
+        }
+        fn hd() -> Result<Option<PathBuf>, HomeDirError> {
+            Ok(Some(PathBuf::from("/home/user")))
+        }
+        fn no_hd() -> Result<Option<PathBuf>, HomeDirError> {
+            Ok(None)
+        }
+        fn err_hd() -> Result<Option<PathBuf>, HomeDirError> {
+            Err(HomeDirError::new(HomeDirErrorKind::Os, None))
         }
 
-        table! { e, unwrap,
-            "whatever/path" => "whatever/path",
-            // check that escaped $ does nothing
-            "whatever/$/path" => "whatever/$/path",
-            "whatever/path$" => "whatever/path$",
-            "whatever/$$path" => "whatever/$path"
-        };
+        assert_eq!(
+            full_with_context_reporting("~/$A", hd, e).unwrap(),
+            "/home/user/a"
+        );
+        assert_eq!(
+            full_with_context_reporting("~/$A", no_hd, e).unwrap(),
+            "~/a"
+        );
+        // the home directory is not looked up without a tilde to expand
+        assert_eq!(
+            full_with_context_reporting("$A/~", err_hd, e).unwrap(),
+            "a/~"
+        );
+        assert_eq!(
+            full_with_context_reporting("$T/$A", err_hd, e).unwrap(),
+            "~/a"
+        );
 
-        table! { e, error,
-            "$VAR/something" => "VAR",
-            "${VAR}/something" => "VAR",
-            "whatever/${VAR}/something" => "VAR",
-            "whatever/${VAR}" => "VAR",
-            "whatever/$VAR/something" => "VAR",
-            "whatever/$VARsomething" => "VARsomething",
-            "whatever/$VAR" => "VAR",
-            "whatever/$VAR_VAR_VAR" => "VAR_VAR_VAR"
-        };
+        match full_with_context_reporting("~/$A", err_hd, e) {
+            Err(FullError::HomeDir(err)) => {
+                assert_eq!(err.kind(), HomeDirErrorKind::Os);
+                assert_eq!(
+                    FullError::<&str>::HomeDir(err).to_string(),
+                    "error looking home directory of the current user up: operating system error"
+                );
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match full_with_context_reporting("~/$ERR", hd, e) {
+            Err(FullError::Lookup(err)) => {
+                assert_eq!(err.var_name, "ERR");
+                assert_eq!(err.offset, 2);
+                assert_eq!(
+                    FullError::Lookup(err).to_string(),
+                    "error looking key 'ERR' up: broken"
+                );
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // variables are expanded before the tilde
+        match full_with_context_reporting("~/$ERR", err_hd, e) {
+            Err(FullError::Lookup(err)) => assert_eq!(err.var_name, "ERR"),
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 
     #[test]
-    fn test_regular_env() {
+    fn test_full_path_with_base() {
         fn e(s: &str) -> Result<Option<&'static str>, ()> {
             match s {
-                "VAR" => Ok(Some("value")),
-                "a_b" => Ok(Some("X_Y")),
-                "EMPTY" => Ok(Some("")),
-                "ERR" => Err(()),
+                "SUB" => Ok(Some("sub")),
+                "REL" => Ok(Some("rel/path")),
                 _ => Ok(None),
             }
         }
+        let base = Path::new("/srv/root");
 
-        table! { e, unwrap,
-            // no variables
-            "whatever/path" => "whatever/path",
+        // a relative home directory is resolved against the base
+        let path = |s| full_path_with_base(s, base, || Some("home/me"), e).unwrap();
+        assert_eq!(path("~/$SUB/file"), base.join("home/me/sub/file"));
+        assert_eq!(path("~"), base.join("home/me"));
+        // other paths are returned as they are
+        assert_eq!(path("$REL/~/x"), Path::new("rel/path/~/x"));
+        assert_eq!(
+            full_path_with_base("~/x", base, || None::<PathBuf>, e).unwrap(),
+            Path::new("~/x")
+        );
 
-            // empty string
-            "" => "",
+        // an absolute home directory ignores the base
+        let home = std::env::temp_dir().join("home");
+        assert!(home.is_absolute());
+        let path = |s| full_path_with_base(s, base, || Some(&home), e).unwrap();
+        assert_eq!(path("~/$SUB"), home.join("sub"));
+        assert_eq!(path("~"), home);
 
-            // existing variable without braces in various positions
-            "$VAR/whatever/path" => "value/whatever/path",
-            "whatever/$VAR/path" => "whatever/value/path",
-            "whatever/path/$VAR" => "whatever/path/value",
-            "whatever/$VARpath" => "whatever/$VARpath",
-            "$VAR$VAR/whatever" => "valuevalue/whatever",
-            "/whatever$VAR$VAR" => "/whatevervaluevalue",
-            "$VAR $VAR" => "value value",
-            "$a_b" => "X_Y",
-            "$a_b$VAR" => "X_Yvalue",
+        // an empty base keeps relative home directories relative
+        assert_eq!(
+            full_path_with_base("~/$SUB", "", || Some("home/me"), e).unwrap(),
+            Path::new("home/me/sub")
+        );
+    }
 
-            // existing variable with braces in various positions
-            "${VAR}/whatever/path" => "value/whatever/path",
-            "whatever/${VAR}/path" => "whatever/value/path",
-            "whatever/path/${VAR}" => "whatever/path/value",
-            "whatever/${VAR}path" => "whatever/valuepath",
-            "${VAR}${VAR}/whatever" => "valuevalue/whatever",
-            "/whatever${VAR}${VAR}" => "/whatevervaluevalue",
-            "${VAR} ${VAR}" => "value value",
-            "${VAR}$VAR" => "valuevalue",
+    #[test]
+    fn test_disabled_tilde() {
+        let mut home_dir_calls = 0;
+        let home_dir = || {
+            home_dir_calls += 1;
+            Some(PathBuf::from("/home/user"))
+        };
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "VAR" => Some("value"),
+                _ => None,
+            }
+        }
 
-            // default values
-            "/answer/${UNKNOWN:-42}" => "/answer/42",
-            "/answer/${:-42}" => "/answer/${:-42}",
-            "/whatever/${UNKNOWN:-other}$VAR" => "/whatever/othervalue",
-            "/whatever/${UNKNOWN:-other}/$VAR" => "/whatever/other/value",
-            ":-/whatever/${UNKNOWN:-other}/$VAR :-" => ":-/whatever/other/value :-",
-            "/whatever/${VAR:-other}" => "/whatever/value",
-            "/whatever/${VAR:-other}$VAR" => "/whatever/valuevalue",
-            "/whatever/${VAR} :-" => "/whatever/value :-",
-            "/whatever/${:-}" => "/whatever/${:-}",
-            "/whatever/${UNKNOWN:-}" => "/whatever/",
+        let expander = Expander::new().tilde(false);
+        assert_eq!(
+            expander.full_with_context_no_errors("~/x/$VAR", home_dir, e),
+            "~/x/value"
+        );
+        assert_eq!(
+            expander.full_with_context_no_errors("~", || Some("/home/user"), e),
+            "~"
+        );
+        assert_eq!(home_dir_calls, 0);
 
-            // empty variable in various positions
-            "${EMPTY}/whatever/path" => "/whatever/path",
-            "whatever/${EMPTY}/path" => "whatever//path",
-            "whatever/path/${EMPTY}" => "whatever/path/"
-        };
+        assert_eq!(
+            Expander::new().full_with_context_no_errors("~/x/$VAR", || Some("/home/user"), e),
+            "/home/user/x/value"
+        );
+    }
 
-        table! { e, error,
-            "$ERR" => "ERR",
-            "${ERR}" => "ERR"
-        };
+    #[test]
+    fn test_existing_home_only() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "VAR" => Some("value"),
+                _ => None,
+            }
+        }
+        let existing = std::env::temp_dir();
+        let missing = existing.join(format!("shellexpand-{}-missing", std::process::id()));
+        assert!(!missing.exists());
+
+        let expander = Expander::new().existing_home_only(true);
+        assert_eq!(
+            expander.full_with_context_no_errors("~/$VAR", || Some(&existing), e),
+            format!("{}/value", existing.display())
+        );
+        assert_eq!(
+            expander.full_with_context_no_errors("~/$VAR", || Some(&missing), e),
+            "~/value"
+        );
+        assert_eq!(
+            expander.full_with_context_no_errors("~", || Some(&missing), e),
+            "~"
+        );
+
+        // a file is not a home directory
+        let file = existing.join(format!("shellexpand-{}-file", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        assert_eq!(
+            expander.full_with_context_no_errors("~", || Some(&file), e),
+            "~"
+        );
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(
+            Expander::new().full_with_context_no_errors("~", || Some(&missing), e),
+            missing.to_str().unwrap()
+        );
     }
 
     #[test]
-    fn test_global_env() {
-        match std::env::var("PATH") {
-            Ok(value) => assert_eq!(env("x/$PATH/x").unwrap(), format!("x/{}/x", value)),
-            Err(e) => assert_eq!(
-                env("x/$PATH/x"),
-                Err(LookupError {
-                    var_name: "PATH".into(),
-                    cause: e
-                })
-            ),
+    fn test_tilde_escape() {
+        fn e(s: &str) -> Option<&'static str> {
+            match s {
+                "VAR" => Some("value"),
+                "ESCAPED" => Some("\\~/x"),
+                _ => None,
+            }
         }
-        match std::env::var("SOMETHING_DEFINITELY_NONEXISTING") {
-            Ok(value) => assert_eq!(
-                env("x/$SOMETHING_DEFINITELY_NONEXISTING/x").unwrap(),
-                format!("x/{}/x", value)
-            ),
-            Err(e) => assert_eq!(
-                env("x/$SOMETHING_DEFINITELY_NONEXISTING/x"),
-                Err(LookupError {
-                    var_name: "SOMETHING_DEFINITELY_NONEXISTING".into(),
-                    cause: e
-                })
-            ),
+        let hd = || Some("/home/user");
+
+        let expander = Expander::new().tilde_escape(Some('\\'));
+        for &(source, target) in &[
+            ("\\~/foo", "~/foo"),
+            ("\\~", "~"),
+            ("\\~/$VAR", "~/value"),
+            ("\\~user/x", "~user/x"),
+            ("~/foo", "/home/user/foo"),
+            ("~", "/home/user"),
+            ("\\\\~/foo", "\\\\~/foo"),
+            ("\\x/~", "\\x/~"),
+            ("a/\\~", "a/\\~"),
+            ("$ESCAPED", "\\~/x"),
+        ] {
+            assert_eq!(expander.full_with_context_no_errors(source, hd, e), target);
         }
-    }
-}
 
-#[cfg(test)]
-mod full_tests {
-    use std::path::{Path, PathBuf};
+        // the home directory is not looked up for an escaped tilde
+        let expander = Expander::new().tilde_escape(Some('^'));
+        assert_eq!(
+            expander.full_with_context_no_errors("^~/foo", || -> Option<&str> { panic!() }, e),
+            "~/foo"
+        );
+        assert_eq!(
+            expander.full_with_context_no_errors("\\~/foo", hd, e),
+            "\\~/foo"
+        );
 
-    use super::full_with_context;
+        // the escape is kept without tilde expansion and by default
+        let expander = Expander::new().tilde_escape(Some('\\')).tilde(false);
+        assert_eq!(
+            expander.full_with_context_no_errors("\\~/foo", hd, e),
+            "\\~/foo"
+        );
+        assert_eq!(
+            Expander::new().full_with_context_no_errors("\\~/foo", hd, e),
+            "\\~/foo"
+        );
+    }
 
     #[test]
     fn test_quirks() {
@@ -967,4 +8534,265 @@ mod full_tests {
         );
         assert_eq!(full_with_context("$TILDE", hd, env).unwrap(), "~");
     }
+
+    #[test]
+    fn test_full_lossy() {
+        let _lock = ::home::HOME_DIR_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("SHELLEXPAND_FULL_LOSSY_SET", "value");
+        std::env::remove_var("SHELLEXPAND_FULL_LOSSY_UNSET");
+
+        assert_eq!(
+            full_lossy("x/$SHELLEXPAND_FULL_LOSSY_SET/$SHELLEXPAND_FULL_LOSSY_UNSET"),
+            "x/value/$SHELLEXPAND_FULL_LOSSY_UNSET"
+        );
+        assert_eq!(
+            full_lossy("x/${SHELLEXPAND_FULL_LOSSY_UNSET:-default}"),
+            "x/default"
+        );
+        match ::home::home_dir() {
+            Some(hd) => assert_eq!(
+                full_lossy("~/$SHELLEXPAND_FULL_LOSSY_UNSET"),
+                format!("{}/$SHELLEXPAND_FULL_LOSSY_UNSET", hd.display())
+            ),
+            None => assert_eq!(
+                full_lossy("~/$SHELLEXPAND_FULL_LOSSY_UNSET"),
+                "~/$SHELLEXPAND_FULL_LOSSY_UNSET"
+            ),
+        }
+    }
+
+    #[test]
+    fn test_full_arc() {
+        std::env::set_var("SHELLEXPAND_FULL_ARC_SET", "value");
+        std::env::remove_var("SHELLEXPAND_FULL_ARC_UNSET");
+
+        for input in &["~/$SHELLEXPAND_FULL_ARC_SET", "~", "plain/path", ""] {
+            assert_eq!(&*full_arc(input).unwrap(), full(input).unwrap());
+        }
+        assert_eq!(
+            full_arc("~/$SHELLEXPAND_FULL_ARC_UNSET")
+                .unwrap_err()
+                .var_name,
+            "SHELLEXPAND_FULL_ARC_UNSET"
+        );
+    }
+
+    #[test]
+    fn test_full_with_home() {
+        std::env::set_var("SHELLEXPAND_FULL_WITH_HOME_SET", "value");
+        std::env::remove_var("SHELLEXPAND_FULL_WITH_HOME_UNSET");
+
+        let home = Path::new("/home/dir");
+        assert_eq!(
+            full_with_home("~/$SHELLEXPAND_FULL_WITH_HOME_SET/x", home).unwrap(),
+            "/home/dir/value/x"
+        );
+        assert_eq!(full_with_home("~", home).unwrap(), "/home/dir");
+        assert_eq!(full_with_home("x/~/y", home).unwrap(), "x/~/y");
+        assert_eq!(
+            full_with_home("~/$SHELLEXPAND_FULL_WITH_HOME_UNSET", home)
+                .unwrap_err()
+                .var_name,
+            "SHELLEXPAND_FULL_WITH_HOME_UNSET"
+        );
+    }
+
+    #[test]
+    fn test_full_with_map() {
+        let _lock = ::home::HOME_DIR_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut map = HashMap::new();
+        map.insert("A".to_owned(), "a value".to_owned());
+        map.insert("EMPTY".to_owned(), String::new());
+
+        assert_eq!(full_with_map("$A/${EMPTY}/$B", &map), "a value//$B");
+        assert_eq!(full_with_map("${B:-$A}", &map), "$A");
+        assert_eq!(full_with_map("x/~", &map), "x/~");
+        match ::home::home_dir() {
+            Some(hd) => assert_eq!(
+                full_with_map("~/$A", &map),
+                format!("{}/a value", hd.display())
+            ),
+            None => assert_eq!(full_with_map("~/$A", &map), "~/a value"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_full_lossy_not_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        std::env::set_var(
+            "SHELLEXPAND_FULL_LOSSY_NOT_UNICODE",
+            OsStr::from_bytes(b"\xff\xfe"),
+        );
+
+        assert_eq!(
+            full_lossy("x/$SHELLEXPAND_FULL_LOSSY_NOT_UNICODE/x"),
+            "x/$SHELLEXPAND_FULL_LOSSY_NOT_UNICODE/x"
+        );
+        assert_eq!(
+            full_lossy("x/${SHELLEXPAND_FULL_LOSSY_NOT_UNICODE:-default}/x"),
+            "x/default/x"
+        );
+    }
+}
+
+#[cfg(test)]
+mod split_tests {
+    use super::{split_fields, DEFAULT_IFS};
+
+    #[test]
+    fn test_default_ifs() {
+        assert_eq!(split_fields("a b c", DEFAULT_IFS), vec!["a", "b", "c"]);
+        assert_eq!(
+            split_fields("  a \t b\n\nc  ", DEFAULT_IFS),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(split_fields("a,b", DEFAULT_IFS), vec!["a,b"]);
+        assert_eq!(split_fields("", DEFAULT_IFS), Vec::<&str>::new());
+        assert_eq!(split_fields(" \t\n ", DEFAULT_IFS), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_custom_ifs() {
+        assert_eq!(split_fields("a,b,c", ","), vec!["a", "b", "c"]);
+        assert_eq!(split_fields("a b,c", ","), vec!["a b", "c"]);
+        assert_eq!(split_fields("a:b;c", ":;"), vec!["a", "b", "c"]);
+        assert_eq!(split_fields("aéb", "é"), vec!["a", "b"]);
+        assert_eq!(split_fields(" a , b ,c", " ,"), vec!["a", "b", "c"]);
+        assert_eq!(split_fields("a b", ""), vec!["a b"]);
+    }
+
+    #[test]
+    fn test_empty_fields() {
+        assert_eq!(split_fields("a,,b", ","), vec!["a", "", "b"]);
+        assert_eq!(split_fields(",a", ","), vec!["", "a"]);
+        assert_eq!(split_fields("a,", ","), vec!["a"]);
+        assert_eq!(split_fields("a,,", ","), vec!["a", ""]);
+        assert_eq!(split_fields(",", ","), vec![""]);
+        assert_eq!(split_fields("a , , b", " ,"), vec!["a", "", "b"]);
+        assert_eq!(split_fields("  , a", " ,"), vec!["", "a"]);
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use std::borrow::Cow;
+
+    use proptest::prelude::*;
+
+    use super::{
+        env_with_context, next_expansion_point, parse_spans, tilde_assignment_with_context,
+        tilde_bytes_with_context, tilde_dirs_with_context, tilde_user_with_context,
+        tilde_with_context, tilde_words_with_context, Expander,
+    };
+
+    /// Checks that the result is the input itself if it is borrowed.
+    fn check_borrowed(input: &str, output: Cow<'_, str>) {
+        if let Cow::Borrowed(s) = output {
+            assert_eq!(s, input);
+        }
+    }
+
+    fn expanders() -> Vec<Expander> {
+        vec![
+            Expander::new(),
+            Expander::new().recursive(true),
+            Expander::new().shell_escaping(true),
+            Expander::new().strip_double_quotes(true),
+            Expander::new().delimiters('(', ')'),
+            Expander::new().recursive(true).delimiters('«', '😀'),
+            Expander::new()
+                .recursive(true)
+                .error_operator(true)
+                .escape_sequences(true)
+                .unset_on_error(false),
+            Expander::new()
+                .extra_name_chars("-é")
+                .excluded_name_chars("_"),
+        ]
+    }
+
+    /// Arbitrary strings, and strings made mostly of the characters which are special somewhere.
+    fn input() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<String>(),
+            r#"[${}()«~:+#%/^,@?=\\"aZ_1é😀 \n-]{0,32}"#
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn env_never_panics(input in input(), value in input()) {
+            for expander in expanders() {
+                let unknown = |_: &str| Ok::<Option<String>, ()>(None);
+                let constant = |_: &str| Ok::<_, ()>(Some(value.clone()));
+                let echo = |s: &str| Ok::<_, ()>(Some(s.to_owned()));
+                let failing = |_: &str| Err::<Option<String>, _>(());
+
+                if let Ok(output) = expander.env_with_context(&input, unknown) {
+                    check_borrowed(&input, output);
+                }
+                if let Ok(output) = expander.env_with_context(&input, constant) {
+                    check_borrowed(&input, output);
+                }
+                if let Ok(output) = expander.env_with_context(&input, echo) {
+                    check_borrowed(&input, output);
+                }
+                if let Ok(output) = expander.env_with_context(&input, failing) {
+                    check_borrowed(&input, output);
+                }
+                let output = expander.env_with_context_no_errors(&input, |_| Some(&value));
+                check_borrowed(&input, output);
+                let output =
+                    expander.full_with_context_no_errors(&input, || Some(&value), |_| Some(&value));
+                check_borrowed(&input, output);
+
+                let spans = expander.parse_spans(&input);
+                let mut end = 0;
+                for span in &spans {
+                    prop_assert_eq!(span.range.start, end);
+                    end = span.range.end;
+                }
+                prop_assert_eq!(end, input.len());
+            }
+
+            let unknown = |_: &str| Ok::<Option<String>, ()>(None);
+            if input.contains('$') {
+                prop_assert!(env_with_context(&input, unknown).is_ok());
+            } else {
+                prop_assert_eq!(env_with_context(&input, unknown), Ok(Cow::Borrowed(&*input)));
+            }
+            for (idx, _) in input.char_indices() {
+                if let Some(point) = next_expansion_point(&input, idx) {
+                    prop_assert!(point >= idx && input[point..].starts_with('$'));
+                }
+            }
+            let spans = parse_spans(&input);
+            prop_assert_eq!(spans.last().map_or(0, |s| s.range.end), input.len());
+        }
+
+        #[test]
+        fn tilde_never_panics(input in input(), home in input()) {
+            check_borrowed(&input, tilde_with_context(&input, || Some(&home)));
+            check_borrowed(&input, tilde_with_context(&input, || None::<&str>));
+            check_borrowed(&input, tilde_words_with_context(&input, || Some(&home)));
+            check_borrowed(&input, tilde_assignment_with_context(&input, || Some(&home)));
+            check_borrowed(&input, tilde_user_with_context(&input, |_| Some(&home)));
+            check_borrowed(
+                &input,
+                tilde_dirs_with_context(&input, || Some(&home), || Some(&home), || None),
+            );
+
+            let output = tilde_bytes_with_context(input.as_bytes(), || Some(&home));
+            if let Cow::Borrowed(bytes) = output {
+                prop_assert_eq!(bytes, input.as_bytes());
+            }
+        }
+    }
 }
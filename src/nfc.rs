@@ -0,0 +1,116 @@
+//! Expansion with Unicode normalization of the result.
+
+use std::borrow::Cow;
+use std::env::VarError;
+use std::path::Path;
+
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+use {full_with_context, home, LookupError};
+
+/// Performs both tilde and environment expansions using the provided contexts, and then
+/// normalizes the result to the Unicode Normalization Form C.
+///
+/// The input is expanded with `full_with_context()` first, and then the whole result, including
+/// the literal text of the input, the values of variables and the home directory, is converted
+/// to NFC, the composed form which most systems produce. Values which come from different
+/// sources may use different normalization forms, like file names on macOS, which are usually
+/// decomposed, and names typed by users, which are usually composed; after the normalization,
+/// equal strings are equal byte for byte, regardless of the forms of their parts. If the result
+/// is already normalized, it is returned as it is.
+///
+/// This function is only available with the `unicode-normalization` feature.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         // "é" written as "e" followed by a combining acute accent
+///         "NAME" => Ok(Some("Cafe\u{301}")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_nfc_with_context("~/$NAME", home_dir, context).unwrap(),
+///     "/home/user/Caf\u{e9}"
+/// );
+/// ```
+pub fn full_nfc_with_context<SI, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Cow<'_, str>, LookupError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let expanded = full_with_context(input, home_dir, context)?;
+    Ok(match is_nfc_quick(expanded.chars()) {
+        IsNormalized::Yes => expanded,
+        IsNormalized::No | IsNormalized::Maybe => expanded.nfc().collect::<String>().into(),
+    })
+}
+
+/// Performs both tilde and environment expansions in the default system context, and then
+/// normalizes the result to the Unicode Normalization Form C.
+///
+/// This function delegates to `full_nfc_with_context()`, using the same contexts as `full()`.
+///
+/// This function is only available with the `unicode-normalization` feature.
+#[inline]
+pub fn full_nfc<SI>(input: &SI) -> Result<Cow<'_, str>, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    full_nfc_with_context(input, home::home_dir, |s| std::env::var(s).map(Some))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::full_nfc_with_context;
+
+    #[test]
+    fn test_full_nfc_with_context() {
+        let context = |s: &str| match s {
+            "NFD" => Ok(Some("re\u{301}sume\u{301}")),
+            "NFC" => Ok(Some("r\u{e9}sum\u{e9}")),
+            "HANGUL" => Ok(Some("\u{1100}\u{1161}")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        };
+        let expand = |input| full_nfc_with_context(input, || Some("/home/u\u{308}ser"), context);
+
+        assert_eq!(expand("$NFD").unwrap(), "r\u{e9}sum\u{e9}");
+        assert_eq!(expand("$NFD").unwrap(), expand("$NFC").unwrap());
+        assert_eq!(expand("${NFD}.txt").unwrap(), "r\u{e9}sum\u{e9}.txt");
+        assert_eq!(expand("$HANGUL").unwrap(), "\u{ac00}");
+        // the literal text and the home directory are normalized too
+        assert_eq!(
+            expand("~/cafe\u{301}/$NFC").unwrap(),
+            "/home/\u{fc}ser/caf\u{e9}/r\u{e9}sum\u{e9}"
+        );
+        // a combining character in a value may compose with the preceding literal text
+        assert_eq!(
+            full_nfc_with_context("e$ACUTE", || None::<&str>, |_| Ok::<_, ()>(Some("\u{301}")))
+                .unwrap(),
+            "\u{e9}"
+        );
+
+        match expand("plain/r\u{e9}sum\u{e9}") {
+            Ok(Cow::Borrowed(s)) => assert_eq!(s, "plain/r\u{e9}sum\u{e9}"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(expand("$ERR").unwrap_err().var_name, "ERR");
+    }
+}
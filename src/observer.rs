@@ -0,0 +1,66 @@
+//! Observation of the progress of an expansion.
+
+/// An observer of the variable lookups performed during an expansion.
+///
+/// An observer is passed to `Expander::env_with_context_observed()` and is notified about every
+/// call to the context function and about every reference which is left in the output as it is.
+/// This allows collecting metrics, like the number of unresolved variables or of expensive
+/// lookups, without instrumenting the context itself. Observers never receive the values of
+/// variables, which may be secrets, but only their lengths. All methods do nothing by default, so
+/// an observer only needs to implement the ones it is interested in.
+pub trait ExpansionObserver {
+    /// Called right before the context function is called for the variable `name`.
+    ///
+    /// In recursive mode the context is called at most once for each variable, so repeated
+    /// references to a variable whose value has already been looked up are not reported.
+    fn on_lookup(&mut self, name: &str) {
+        let _ = name;
+    }
+
+    /// Called when the context function returns a value for the variable `name`, with the length
+    /// of the value in bytes.
+    fn on_resolved(&mut self, name: &str, value_len: usize) {
+        let _ = (name, value_len);
+    }
+
+    /// Called when a reference to the variable `name` is left in the output as it is because the
+    /// context function does not know it.
+    ///
+    /// It is not called when an operator like `${VAR:-default}` provides a replacement for the
+    /// reference.
+    fn on_unresolved(&mut self, name: &str) {
+        let _ = name;
+    }
+
+    /// Called when the context function returns an error for the variable `name`.
+    fn on_error(&mut self, name: &str) {
+        let _ = name;
+    }
+}
+
+impl<O: ExpansionObserver + ?Sized> ExpansionObserver for &mut O {
+    fn on_lookup(&mut self, name: &str) {
+        (**self).on_lookup(name)
+    }
+
+    fn on_resolved(&mut self, name: &str, value_len: usize) {
+        (**self).on_resolved(name, value_len)
+    }
+
+    fn on_unresolved(&mut self, name: &str) {
+        (**self).on_unresolved(name)
+    }
+
+    fn on_error(&mut self, name: &str) {
+        (**self).on_error(name)
+    }
+}
+
+/// An observer which only reports unresolved references to a closure.
+pub(crate) struct OnUnresolved<F>(pub(crate) F);
+
+impl<F: FnMut(&str)> ExpansionObserver for OnUnresolved<F> {
+    fn on_unresolved(&mut self, name: &str) {
+        (self.0)(name)
+    }
+}
@@ -0,0 +1,215 @@
+//! Expansion of `PATH`-like lists of paths.
+
+use std::collections::HashSet;
+use std::env::VarError;
+use std::path::Path;
+
+use {full_with_context, home, Expander, LazyHomeDir, LookupError, TokenKind};
+
+/// The separator of entries in `PATH`-like lists on the current platform.
+const SEPARATOR: char = if cfg!(windows) { ';' } else { ':' };
+
+/// Expands every entry of a `PATH`-like list using the provided contexts, and removes duplicate
+/// entries from the result.
+///
+/// The entries of the list are separated by `:`, or by `;` on Windows. Each entry of `input` is
+/// expanded with `full_with_context()`, so a tilde at the beginning of any entry is expanded,
+/// like in `PATH=~/bin:~/.local/bin` assignments in shells. Separators inside variable references
+/// do not split the list, so `${DIRS:-/a:/b}` is a single entry of the input. The expanded
+/// entries, including the entries of values like those of `$PATH` or `${DIRS:-/a:/b}` which
+/// contain whole lists, are then deduplicated: only the first occurrence of each entry is kept,
+/// and the order of the entries is preserved, as shells and tools like `path_helper` do. Entries
+/// are compared as strings, so `/bin` and `/bin/` are different entries. If `drop_empty` is true,
+/// empty entries, which usually mean the current directory, are removed too.
+///
+/// The `home_dir` context function is called at most once.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "PATH" => Ok(Some("/usr/bin:/home/user/bin::/bin")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// # #[cfg(unix)]
+/// # {
+/// assert_eq!(
+///     shellexpand::full_path_list_with_context("~/bin:$PATH:/usr/bin", home_dir, context, false)
+///         .unwrap(),
+///     "/home/user/bin:/usr/bin::/bin"
+/// );
+/// assert_eq!(
+///     shellexpand::full_path_list_with_context("~/bin:$PATH:/usr/bin", home_dir, context, true)
+///         .unwrap(),
+///     "/home/user/bin:/usr/bin:/bin"
+/// );
+/// # }
+/// ```
+pub fn full_path_list_with_context<SI, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    mut context: C,
+    drop_empty: bool,
+) -> Result<String, LookupError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let mut home_dir = LazyHomeDir::new(home_dir);
+
+    let mut result = String::new();
+    let mut seen = HashSet::new();
+    let mut entry_offset = 0;
+    for entry in split_entries(input.as_ref()) {
+        let home_dir = || home_dir.get().map(|hd| hd.as_ref().to_path_buf());
+        let expanded = full_with_context(entry, home_dir, &mut context).map_err(|mut e| {
+            e.offset += entry_offset;
+            e
+        })?;
+        entry_offset += entry.len() + SEPARATOR.len_utf8();
+
+        for path in expanded.split(SEPARATOR) {
+            if drop_empty && path.is_empty() || !seen.insert(path.to_owned()) {
+                continue;
+            }
+            if seen.len() > 1 {
+                result.push(SEPARATOR);
+            }
+            result.push_str(path);
+        }
+    }
+    Ok(result)
+}
+
+/// Splits `input` at the separators which are not a part of variable references.
+fn split_entries(input: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for token in Expander::new().tokens(input) {
+        if let TokenKind::Literal = token.kind {
+            for (idx, _) in input[token.range.clone()].match_indices(SEPARATOR) {
+                let idx = token.range.start + idx;
+                entries.push(&input[start..idx]);
+                start = idx + SEPARATOR.len_utf8();
+            }
+        }
+    }
+    entries.push(&input[start..]);
+    entries
+}
+
+/// Expands every entry of a `PATH`-like list using the system contexts, and removes duplicate
+/// entries from the result.
+///
+/// This function delegates to `full_path_list_with_context()`, using the same contexts as
+/// `full()`.
+#[inline]
+pub fn full_path_list<SI>(input: &SI, drop_empty: bool) -> Result<String, LookupError<VarError>>
+where
+    SI: AsRef<str> + ?Sized,
+{
+    full_path_list_with_context(
+        input,
+        home::home_dir,
+        |s| std::env::var(s).map(Some),
+        drop_empty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{full_path_list_with_context, SEPARATOR};
+
+    /// Joins the entries with the separator of the current platform.
+    fn list(entries: &[&str]) -> String {
+        entries.join(&SEPARATOR.to_string())
+    }
+
+    #[test]
+    fn test_full_path_list_with_context() {
+        let path = list(&["/usr/bin", "/bin", "/usr/bin", "", "/sbin", ""]);
+        let context = |s: &str| match s {
+            "PATH" => Ok(Some(path.clone())),
+            "EMPTY" => Ok(Some(String::new())),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        };
+        let expand = |input: &str, drop_empty| {
+            full_path_list_with_context(input, || Some("/home/user"), context, drop_empty)
+        };
+
+        // duplicates are removed, keeping the first occurrence
+        let input = list(&["~/bin", "$PATH", "/bin", "~/bin", "~"]);
+        assert_eq!(
+            expand(&input, false).unwrap(),
+            list(&[
+                "/home/user/bin",
+                "/usr/bin",
+                "/bin",
+                "",
+                "/sbin",
+                "/home/user"
+            ])
+        );
+        assert_eq!(
+            expand(&input, true).unwrap(),
+            list(&["/home/user/bin", "/usr/bin", "/bin", "/sbin", "/home/user"])
+        );
+
+        let input = list(&["/z", "/a", "", "/m", "/a", "", "$EMPTY", "/z"]);
+        assert_eq!(
+            expand(&input, false).unwrap(),
+            list(&["/z", "/a", "", "/m"])
+        );
+        assert_eq!(expand(&input, true).unwrap(), list(&["/z", "/a", "/m"]));
+
+        assert_eq!(expand("", false).unwrap(), "");
+        assert_eq!(expand("", true).unwrap(), "");
+        assert_eq!(expand("a~b", true).unwrap(), "a~b");
+        assert_eq!(expand("$UNKNOWN", true).unwrap(), "$UNKNOWN");
+
+        // separators inside references do not split the list
+        let input = list(&["${UNKNOWN:-/x:/y;/z}", "/y", "${PATH:+/p:/q;/r}", "/a"]);
+        let mut expected = if cfg!(windows) {
+            vec!["/x:/y", "/z", "/y", "/p:/q", "/r"]
+        } else {
+            vec!["/x", "/y;/z", "/y", "/p", "/q;/r"]
+        };
+        expected.push("/a");
+        assert_eq!(expand(&input, false).unwrap(), list(&expected));
+
+        let input = list(&["/bin", "$ERR"]);
+        let err = expand(&input, false).unwrap_err();
+        assert_eq!(err.var_name, "ERR");
+        assert_eq!(err.offset, 5);
+
+        // the home directory is looked up once for all entries
+        let mut home_dir_calls = 0;
+        let home_dir = || {
+            home_dir_calls += 1;
+            Some("/home/user")
+        };
+        let input = list(&["~/a", "~/b", "~"]);
+        assert_eq!(
+            full_path_list_with_context(&input, home_dir, context, false).unwrap(),
+            list(&["/home/user/a", "/home/user/b", "/home/user"])
+        );
+        assert_eq!(home_dir_calls, 1);
+        assert_eq!(
+            full_path_list_with_context("~/bin", || None::<PathBuf>, context, false).unwrap(),
+            "~/bin"
+        );
+    }
+}
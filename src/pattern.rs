@@ -0,0 +1,407 @@
+//! Pattern matching for the pattern-matching operators, like `${VAR#pattern}`.
+
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+/// The syntax of patterns in the pattern-matching operators.
+///
+/// See `Expander::pattern_syntax()` for more details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PatternSyntax {
+    /// Shell glob patterns: `*` matches any string, `?` matches any character, `[...]` matches
+    /// any of the enclosed characters or ranges (or any character except them, if the class
+    /// starts with `!` or `^`), and a backslash makes the next character literal.
+    #[default]
+    Glob,
+    /// Regular expressions as implemented by the [regex](https://crates.io/crates/regex) crate.
+    #[cfg(feature = "regex")]
+    Regex,
+}
+
+/// A compiled pattern which can be matched against whole strings.
+pub(crate) enum Pattern {
+    Glob(Vec<GlobToken>),
+    #[cfg(feature = "regex")]
+    Regex(Regexes),
+}
+
+/// A regular expression compiled with each of the anchors which the operators need.
+#[cfg(feature = "regex")]
+pub(crate) struct Regexes {
+    unanchored: Regex,
+    prefix: Regex,
+    suffix: Regex,
+    whole: Regex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GlobToken {
+    Literal(char),
+    AnyChar,
+    AnyString,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl GlobToken {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            GlobToken::Literal(l) => l == c,
+            GlobToken::AnyChar => true,
+            GlobToken::AnyString => false,
+            GlobToken::Class {
+                negated,
+                ref ranges,
+            } => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated,
+        }
+    }
+}
+
+impl Pattern {
+    /// Compiles `pattern` with the given syntax, returning an error message if it is invalid.
+    pub(crate) fn new(pattern: &str, syntax: PatternSyntax) -> Result<Pattern, String> {
+        match syntax {
+            PatternSyntax::Glob => Ok(Pattern::Glob(parse_glob(pattern))),
+            #[cfg(feature = "regex")]
+            PatternSyntax::Regex => {
+                // the pattern is checked on its own first, since wrapping an unbalanced pattern
+                // like `a)|(?:b` into a group could make it valid
+                let unanchored = Regex::new(pattern).map_err(|e| e.to_string())?;
+                let anchored = |prefix: &str, suffix: &str| {
+                    Regex::new(&format!("{}(?:{}){}", prefix, pattern, suffix))
+                        .map_err(|e| e.to_string())
+                };
+                Ok(Pattern::Regex(Regexes {
+                    unanchored,
+                    prefix: anchored("^", "")?,
+                    suffix: anchored("", "$")?,
+                    whole: anchored("^", "$")?,
+                }))
+            }
+        }
+    }
+
+    /// Returns true if the pattern matches the whole `s`.
+    pub(crate) fn matches(&self, s: &str) -> bool {
+        match *self {
+            Pattern::Glob(ref tokens) => glob_matches(tokens, s),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(ref regexes) => regexes.whole.is_match(s),
+        }
+    }
+
+    /// Returns the end of the shortest (or the longest) prefix of `s` which matches the pattern.
+    fn prefix_end(&self, s: &str, longest: bool) -> Option<usize> {
+        match *self {
+            Pattern::Glob(ref tokens) => glob_prefix_end(tokens, s, longest),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(ref regexes) if longest => regexes.prefix.find(s).map(|m| m.end()),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(ref regexes) => regexes.prefix.shortest_match(s),
+        }
+    }
+
+    /// Removes the shortest (or the longest) prefix of `s` which matches the pattern.
+    pub(crate) fn remove_prefix<'a>(&self, s: &'a str, longest: bool) -> &'a str {
+        self.prefix_end(s, longest).map_or(s, |idx| &s[idx..])
+    }
+
+    /// Removes the shortest (or the longest) suffix of `s` which matches the pattern.
+    pub(crate) fn remove_suffix<'a>(&self, s: &'a str, longest: bool) -> &'a str {
+        let found = match *self {
+            #[cfg(feature = "regex")]
+            Pattern::Regex(ref regexes) if longest => regexes.suffix.find(s).map(|m| m.start()),
+            _ if longest => char_boundaries(s).find(|&idx| self.matches(&s[idx..])),
+            _ => char_boundaries(s)
+                .rev()
+                .find(|&idx| self.matches(&s[idx..])),
+        };
+        found.map_or(s, |idx| &s[..idx])
+    }
+
+    /// Replaces the first (or every) longest non-empty match of the pattern in `s`.
+    pub(crate) fn replace(&self, s: &str, replacement: &str, all: bool) -> String {
+        let mut result = String::with_capacity(s.len());
+        // the end of the last match, and the position where the next match is looked for
+        let (mut last, mut pos) = (0, 0);
+        while let Some((start, end)) = self.find_non_empty(s, pos) {
+            result.push_str(&s[last..start]);
+            result.push_str(replacement);
+            last = end;
+            pos = end;
+            if !all {
+                break;
+            }
+        }
+        result.push_str(&s[last..]);
+        result
+    }
+
+    /// Finds the leftmost non-empty match of the pattern in `s` which starts at `from` or later.
+    fn find_non_empty(&self, s: &str, from: usize) -> Option<(usize, usize)> {
+        match *self {
+            Pattern::Glob(_) => char_boundaries(&s[from..])
+                .map(|idx| from + idx)
+                .take_while(|&start| start < s.len())
+                .find_map(|start| {
+                    let end = self.prefix_end(&s[start..], true)?;
+                    if end > 0 {
+                        Some((start, start + end))
+                    } else {
+                        None
+                    }
+                }),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(ref regexes) => {
+                let mut from = from;
+                loop {
+                    let m = regexes.unanchored.find_at(s, from)?;
+                    if m.end() > m.start() {
+                        return Some((m.start(), m.end()));
+                    }
+                    // an empty match is skipped, looking for the next match one character later
+                    from = m.start() + s[m.start()..].chars().next()?.len_utf8();
+                }
+            }
+        }
+    }
+}
+
+/// Returns the byte offsets of all characters in `s` and the offset of its end.
+fn char_boundaries(s: &str) -> impl DoubleEndedIterator<Item = usize> + '_ {
+    s.char_indices().map(|(idx, _)| idx).chain(Some(s.len()))
+}
+
+fn parse_glob(pattern: &str) -> Vec<GlobToken> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\\' => GlobToken::Literal(chars.next().unwrap_or('\\')),
+            '*' if tokens.last() == Some(&GlobToken::AnyString) => continue,
+            '*' => GlobToken::AnyString,
+            '?' => GlobToken::AnyChar,
+            '[' => match parse_class(chars.as_str()) {
+                Some((token, consumed)) => {
+                    chars = chars.as_str()[consumed..].chars();
+                    token
+                }
+                // an unterminated class is matched literally
+                None => GlobToken::Literal('['),
+            },
+            c => GlobToken::Literal(c),
+        };
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Parses a character class right after its opening bracket, returning the class and the number of
+/// bytes consumed, including the closing bracket.
+fn parse_class(s: &str) -> Option<(GlobToken, usize)> {
+    let mut chars = s.char_indices().peekable();
+    let negated = match chars.peek() {
+        Some(&(_, '!')) | Some(&(_, '^')) => {
+            chars.next();
+            true
+        }
+        _ => false,
+    };
+
+    let mut ranges = Vec::new();
+    let mut first = true;
+    while let Some((idx, c)) = chars.next() {
+        let lo = match c {
+            // a closing bracket right after the opening one is a part of the class
+            ']' if !first => {
+                let token = GlobToken::Class { negated, ranges };
+                return Some((token, idx + 1));
+            }
+            '\\' => chars.next()?.1,
+            c => c,
+        };
+        first = false;
+
+        let is_range = match chars.peek() {
+            Some(&(idx, '-')) => s[idx + 1..].chars().next().is_some_and(|c| c != ']'),
+            _ => false,
+        };
+        let hi = if is_range {
+            chars.next();
+            match chars.next()?.1 {
+                '\\' => chars.next()?.1,
+                c => c,
+            }
+        } else {
+            lo
+        };
+        ranges.push((lo, hi));
+    }
+    None
+}
+
+/// Matches `tokens`, which contain no `*`, at the beginning of `s`, returning the length of the
+/// match.
+fn match_fixed(tokens: &[GlobToken], s: &str) -> Option<usize> {
+    let mut chars = s.chars();
+    for token in tokens {
+        match chars.next() {
+            Some(c) if token.matches(c) => {}
+            _ => return None,
+        }
+    }
+    Some(s.len() - chars.as_str().len())
+}
+
+/// Returns true if `tokens`, which contain no `*`, match the end of `s`.
+fn matches_fixed_end(tokens: &[GlobToken], s: &str) -> bool {
+    let mut chars = s.chars().rev();
+    tokens
+        .iter()
+        .rev()
+        .all(|token| chars.next().is_some_and(|c| token.matches(c)))
+}
+
+/// Finds the leftmost match of `tokens`, which contain no `*`, in `s`, returning its end.
+fn find_fixed(tokens: &[GlobToken], s: &str) -> Option<usize> {
+    char_boundaries(s).find_map(|idx| match_fixed(tokens, &s[idx..]).map(|len| idx + len))
+}
+
+/// Returns the earliest end of a match of `tokens` at the beginning of `s`.
+///
+/// The parts of the pattern between its `*`s are simply matched at their leftmost positions, since
+/// a match which ends later can only leave less of `s` to the rest of the pattern.
+fn glob_head_end(tokens: &[GlobToken], s: &str) -> Option<usize> {
+    let mut parts = tokens.split(|t| *t == GlobToken::AnyString);
+    let mut end = match_fixed(parts.next().unwrap_or(&[]), s)?;
+    for part in parts {
+        end += find_fixed(part, &s[end..])?;
+    }
+    Some(end)
+}
+
+/// Returns the end of the shortest (or the longest) prefix of `s` which matches `tokens`.
+///
+/// The last `*` of the pattern may match anything, so the part before it only has to end as early
+/// as possible, and the part after it, which has a fixed length, has to match the end of the
+/// prefix. This makes a single pass over `s` enough.
+fn glob_prefix_end(tokens: &[GlobToken], s: &str, longest: bool) -> Option<usize> {
+    let star = match tokens.iter().rposition(|t| *t == GlobToken::AnyString) {
+        Some(star) => star,
+        None => return match_fixed(tokens, s),
+    };
+    let head_end = glob_head_end(&tokens[..star], s)?;
+    let tail = &tokens[star + 1..];
+    let mut ends = char_boundaries(&s[head_end..]).map(|idx| head_end + idx);
+    let matches_at = |end: &usize| matches_fixed_end(tail, &s[head_end..*end]);
+    if longest {
+        ends.rev().find(matches_at)
+    } else {
+        ends.find(matches_at)
+    }
+}
+
+fn glob_matches(tokens: &[GlobToken], s: &str) -> bool {
+    match tokens.iter().rposition(|t| *t == GlobToken::AnyString) {
+        Some(star) => glob_head_end(&tokens[..star], s)
+            .is_some_and(|head_end| matches_fixed_end(&tokens[star + 1..], &s[head_end..])),
+        None => match_fixed(tokens, s) == Some(s.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pattern, PatternSyntax};
+
+    fn glob(s: &str) -> Pattern {
+        Pattern::new(s, PatternSyntax::Glob).unwrap()
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob("").matches(""));
+        assert!(!glob("").matches("a"));
+        assert!(glob("*").matches(""));
+        assert!(glob("a*c").matches("abbbc"));
+        assert!(!glob("a*c").matches("abbcb"));
+        assert!(glob("*.tar.*").matches("x.tar.gz"));
+        assert!(glob("?é?").matches("aéb"));
+        assert!(!glob("??").matches("a"));
+        assert!(glob("[a-c]x").matches("bx"));
+        assert!(!glob("[!a-c]x").matches("bx"));
+        assert!(glob("[^a-c]x").matches("dx"));
+        assert!(glob("[]]").matches("]"));
+        assert!(glob("[a-]").matches("-"));
+        assert!(glob("\\*").matches("*"));
+        assert!(!glob("\\*").matches("a"));
+        assert!(glob("[ab").matches("[ab"));
+        assert!(glob("a\\").matches("a\\"));
+    }
+
+    #[test]
+    fn test_remove_and_replace() {
+        assert_eq!(glob("*/").remove_prefix("a/b/c", false), "b/c");
+        assert_eq!(glob("*/").remove_prefix("a/b/c", true), "c");
+        assert_eq!(glob("/*").remove_suffix("a/b/c", false), "a/b");
+        assert_eq!(glob("/*").remove_suffix("a/b/c", true), "a");
+        assert_eq!(glob("x").remove_prefix("abc", true), "abc");
+        assert_eq!(glob("b*").replace("abcabc", "X", false), "aX");
+        assert_eq!(glob("b").replace("abcabc", "X", false), "aXcabc");
+        assert_eq!(glob("b").replace("abcabc", "X", true), "aXcaXc");
+        assert_eq!(glob("").replace("abc", "X", true), "abc");
+
+        assert_eq!(glob("a*b?").remove_prefix("axbxbxbx", false), "bxbx");
+        assert_eq!(glob("a*b?").remove_prefix("axbxbxbx", true), "");
+        assert_eq!(glob("*b*x").remove_prefix("abcxbx", false), "bx");
+        assert_eq!(glob("?*b").remove_suffix("abab", false), "ab");
+        assert_eq!(glob("?*b").remove_suffix("abab", true), "");
+        assert_eq!(glob("a*c").replace("xabcabc-ac", "X", true), "xX");
+        assert_eq!(glob("a?").replace("éaéa", "X", true), "éXa");
+        assert_eq!(glob("*").replace("", "X", true), "");
+    }
+
+    #[test]
+    fn test_long_values() {
+        let value = "ab/".repeat(20_000);
+        assert_eq!(glob("*").replace(&value, "X", true), "X");
+        assert_eq!(
+            glob("a*c").replace(&value[..3_000], "X", true),
+            &value[..3_000]
+        );
+        assert_eq!(glob("*/").remove_prefix(&value, false), &value[3..]);
+        assert_eq!(glob("*/").remove_prefix(&value, true), "");
+        assert_eq!(glob("/*").remove_suffix(&value, true), "ab");
+        assert_eq!(
+            glob("b/*").remove_suffix(&value, false),
+            &value[..value.len() - 2]
+        );
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex() {
+        let regex = |s| Pattern::new(s, PatternSyntax::Regex);
+        assert!(regex("a)|(?:b").is_err());
+        assert!(regex("(a").is_err());
+
+        let pattern = regex("b+").unwrap();
+        assert!(pattern.matches("bb"));
+        assert!(!pattern.matches("abb"));
+        assert_eq!(pattern.replace("abbcb", "X", false), "aXcb");
+        assert_eq!(pattern.replace("abbcb", "X", true), "aXcX");
+
+        let pattern = regex("a.*/").unwrap();
+        assert_eq!(pattern.remove_prefix("a/b/c", false), "b/c");
+        assert_eq!(pattern.remove_prefix("a/b/c", true), "c");
+        assert_eq!(pattern.remove_prefix("b/c", true), "b/c");
+
+        let pattern = regex("/.*").unwrap();
+        assert_eq!(pattern.remove_suffix("a/b/c", false), "a/b");
+        assert_eq!(pattern.remove_suffix("a/b/c", true), "a");
+
+        // empty matches are never replaced
+        assert_eq!(regex("x*").unwrap().replace("axb", "Y", true), "aYb");
+    }
+}
@@ -0,0 +1,197 @@
+//! Expansion of maps of variables whose values reference each other.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use Expander;
+
+/// An error returned by `resolve_map()` when values of the map reference each other in a cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleError {
+    /// The names of the variables forming the cycle, in the order of references.
+    ///
+    /// The first name is repeated at the end, so `A=$B` and `B=$A` make a cycle like
+    /// `["A", "B", "A"]`, and a variable which references itself makes a cycle like `["A", "A"]`.
+    pub cycle: Vec<String>,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "variables reference each other in a cycle: ")?;
+        for (idx, name) in self.cycle.iter().enumerate() {
+            if idx > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for CycleError {}
+
+/// Expands every value of the map using the other entries of the map as the context.
+///
+/// This function is useful for flattening layered configuration, where values are defined in
+/// terms of other values, before using it. Each value is expanded with `env_with_context()`, and
+/// every variable it references is looked up in the map itself; such variables are resolved
+/// first, so the order of the entries does not matter and chains like `A=$B`, `B=$C` are expanded
+/// completely. Variables which are not present in the map are left as they are.
+///
+/// If values reference each other in a cycle, including a value which references its own
+/// variable, a `CycleError` listing the variables of the cycle is returned. This also applies to
+/// references which have operators, so `A=${A:-default}` is a cycle too, rather than an unset
+/// variable.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("PREFIX", "/usr/local");
+/// map.insert("BIN", "$PREFIX/bin");
+/// map.insert("TOOL", "$BIN/tool --home=$HOME");
+///
+/// let resolved = shellexpand::resolve_map(&map).unwrap();
+/// assert_eq!(resolved["BIN"], "/usr/local/bin");
+/// assert_eq!(resolved["TOOL"], "/usr/local/bin/tool --home=$HOME");
+///
+/// map.insert("PREFIX", "$TOOL");
+/// assert!(shellexpand::resolve_map(&map).is_err());
+/// ```
+pub fn resolve_map<K, V, S>(map: &HashMap<K, V, S>) -> Result<HashMap<String, String>, CycleError>
+where
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<str>,
+    S: BuildHasher,
+{
+    let mut resolver = Resolver {
+        map,
+        resolved: HashMap::with_capacity(map.len()),
+        visiting: Vec::new(),
+    };
+
+    // the names are sorted to make the reported cycle independent of the order of the entries
+    let mut names: Vec<&str> = map.keys().map(Borrow::borrow).collect();
+    names.sort_unstable();
+    for name in names {
+        resolver.resolve(name)?;
+    }
+    Ok(resolver.resolved)
+}
+
+struct Resolver<'m, K, V, S> {
+    map: &'m HashMap<K, V, S>,
+    resolved: HashMap<String, String>,
+    /// The variables which are being resolved, each one referenced by the previous one.
+    visiting: Vec<String>,
+}
+
+impl<'m, K, V, S> Resolver<'m, K, V, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<str>,
+    S: BuildHasher,
+{
+    fn resolve(&mut self, name: &str) -> Result<Option<String>, CycleError> {
+        if let Some(value) = self.resolved.get(name) {
+            return Ok(Some(value.clone()));
+        }
+        let map = self.map;
+        let raw_value = match map.get(name) {
+            Some(raw_value) => raw_value.as_ref(),
+            None => return Ok(None),
+        };
+        if let Some(start) = self.visiting.iter().position(|n| n == name) {
+            let mut cycle = self.visiting[start..].to_vec();
+            cycle.push(name.into());
+            return Err(CycleError { cycle });
+        }
+
+        // a cycle must not be treated as an unset variable by operators like `${A:-default}`
+        self.visiting.push(name.into());
+        let value = Expander::new()
+            .unset_on_error(false)
+            .env_with_context(raw_value, |s| self.resolve(s))
+            .map_err(|e| e.into_lookup_error().cause);
+        self.visiting.pop();
+        let value = value?.into_owned();
+
+        self.resolved.insert(name.into(), value.clone());
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{resolve_map, CycleError};
+
+    #[test]
+    fn test_resolve_map() {
+        let mut map = HashMap::new();
+        map.insert("A", "a/$B");
+        map.insert("B", "b/${C}");
+        map.insert("C", "c");
+        map.insert("D", "d/$UNKNOWN/${C:+set}");
+        map.insert("E", "");
+
+        let resolved = resolve_map(&map).unwrap();
+        assert_eq!(resolved.len(), 5);
+        assert_eq!(resolved["A"], "a/b/c");
+        assert_eq!(resolved["B"], "b/c");
+        assert_eq!(resolved["C"], "c");
+        assert_eq!(resolved["D"], "d/$UNKNOWN/set");
+        assert_eq!(resolved["E"], "");
+
+        assert_eq!(
+            resolve_map(&HashMap::<String, String>::new()).unwrap(),
+            HashMap::new()
+        );
+    }
+
+    #[test]
+    fn test_resolve_map_cycles() {
+        let mut map = HashMap::new();
+        map.insert("A", "$B");
+        map.insert("B", "x/${C}");
+        map.insert("C", "$A/y");
+        map.insert("D", "d");
+
+        let cycle = |names: &[&str]| CycleError {
+            cycle: names.iter().map(|&n| n.into()).collect(),
+        };
+        let err = resolve_map(&map).unwrap_err();
+        assert_eq!(err, cycle(&["A", "B", "C", "A"]));
+        assert_eq!(
+            err.to_string(),
+            "variables reference each other in a cycle: A -> B -> C -> A"
+        );
+
+        let mut map = HashMap::new();
+        map.insert("PATH", "$PATH:/bin");
+        assert_eq!(resolve_map(&map).unwrap_err(), cycle(&["PATH", "PATH"]));
+
+        // the cycle is reported even if it is only reachable through another variable
+        let mut map = HashMap::new();
+        map.insert("A", "$X");
+        map.insert("X", "$Y");
+        map.insert("Y", "$X");
+        assert_eq!(resolve_map(&map).unwrap_err(), cycle(&["X", "Y", "X"]));
+
+        // references with operators which are part of a cycle are not treated as unset
+        let mut map = HashMap::new();
+        map.insert("A", "${B:-x}");
+        map.insert("B", "$A");
+        assert_eq!(resolve_map(&map).unwrap_err(), cycle(&["A", "B", "A"]));
+
+        let mut map = HashMap::new();
+        map.insert("A", "${A:-fallback}");
+        assert_eq!(resolve_map(&map).unwrap_err(), cycle(&["A", "A"]));
+    }
+}
@@ -0,0 +1,191 @@
+//! Environment expansion which records where each part of the output comes from.
+
+use std::ops::Range;
+
+use {ExpandError, ExpandWriteError, Expander, State, TokenKind};
+
+/// The source of a range of the output of `env_with_source_map()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SourceKind<'a> {
+    /// Literal text of the input, including escaped dollars and references which are left as
+    /// they are because the variable is unknown.
+    Literal,
+    /// The expansion of a reference to a variable, including the words of its operator.
+    Var(&'a str),
+}
+
+/// The sources of the ranges of the output of `env_with_source_map()`, in order.
+pub type SourceMap<'a> = Vec<(Range<usize>, SourceKind<'a>)>;
+
+impl Expander {
+    /// Performs the environment expansion of `input`, also returning the sources of the ranges of
+    /// the result.
+    ///
+    /// See `env_with_source_map()` for more details.
+    pub fn env_with_source_map<'a, SI, CO, C, E>(
+        &self,
+        input: &'a SI,
+        mut context: C,
+    ) -> Result<(String, SourceMap<'a>), ExpandError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        let input_str = input.as_ref();
+        let mut state = State::default();
+        let mut result = String::with_capacity(input_str.len());
+        let mut sources = SourceMap::new();
+        for token in self.tokens(input_str) {
+            let source = match token.kind {
+                TokenKind::Literal | TokenKind::Escape => SourceKind::Literal,
+                TokenKind::Bare(name) | TokenKind::Braced(name, _) => SourceKind::Var(name),
+            };
+            let start = result.len();
+            match self.write_token(input_str, token, &mut result, &mut context, &mut state) {
+                Ok(()) => {}
+                Err(ExpandWriteError::Expand(e)) => return Err(e),
+                Err(ExpandWriteError::Write(_)) => unreachable!("writing to a string never fails"),
+            }
+            let range = start..result.len();
+
+            match (sources.last_mut(), source) {
+                // adjacent literal text is merged into a single range
+                (Some(&mut (ref mut last, SourceKind::Literal)), SourceKind::Literal) => {
+                    last.end = range.end
+                }
+                (_, SourceKind::Literal) if range.is_empty() => {}
+                _ => sources.push((range, source)),
+            }
+        }
+        Ok((result, sources))
+    }
+}
+
+/// Performs the environment expansion of `input`, also returning the sources of the ranges of
+/// the result.
+///
+/// This function works like `env_with_context()`, but it also returns a source map: a list of
+/// byte ranges of the result, each one with the piece of the input it was produced from. This
+/// is the counterpart of `parse_spans()`, which describes the ranges of the input instead, and
+/// allows tools like editors to show which parts of an expanded string come from which
+/// variables.
+///
+/// The ranges cover the whole result in order and without gaps. Every variable reference gets a
+/// range of its own, which is empty if the reference expands to an empty string, while adjacent
+/// literal text is merged into a single range. A reference which is left as it is because the
+/// variable is unknown still gets a `Var` range. The name of a `Var` is the name as written in
+/// the input, so it may contain references itself, like `A_$B` in `${A_$B}`. Use
+/// `Expander::env_with_source_map()` to expand with non-default settings.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::SourceKind;
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "HOME" => Ok(Some("/home/user")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// let (result, sources) = shellexpand::env_with_source_map("$HOME/bin", context).unwrap();
+/// assert_eq!(result, "/home/user/bin");
+/// assert_eq!(
+///     sources,
+///     [(0..10, SourceKind::Var("HOME")), (10..14, SourceKind::Literal)]
+/// );
+/// ```
+pub fn env_with_source_map<SI, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<(String, SourceMap<'_>), ExpandError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    Expander::new().env_with_source_map(input, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{env_with_source_map, SourceKind};
+    use {ExpandError, Expander};
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "SHORT" => Ok(Some("ab")),
+            "LONG" => Ok(Some("a rather long value")),
+            "EMPTY" => Ok(Some("")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        }
+    }
+
+    #[test]
+    fn test_env_with_source_map() {
+        let (result, sources) = env_with_source_map("x=$SHORT, y=${LONG}!", context).unwrap();
+        assert_eq!(result, "x=ab, y=a rather long value!");
+        assert_eq!(
+            sources,
+            [
+                (0..2, SourceKind::Literal),
+                (2..4, SourceKind::Var("SHORT")),
+                (4..8, SourceKind::Literal),
+                (8..27, SourceKind::Var("LONG")),
+                (27..28, SourceKind::Literal),
+            ]
+        );
+        for &(ref range, source) in &sources {
+            if let SourceKind::Var(name) = source {
+                assert_eq!(&result[range.clone()], context(name).unwrap().unwrap());
+            }
+        }
+
+        // escapes are literal text, and unknown and empty variables get ranges of their own
+        let (result, sources) =
+            env_with_source_map("$LONG$$a$EMPTY${NONE}${X:-b}", context).unwrap();
+        assert_eq!(result, "a rather long value$a${NONE}b");
+        assert_eq!(
+            sources,
+            [
+                (0..19, SourceKind::Var("LONG")),
+                (19..21, SourceKind::Literal),
+                (21..21, SourceKind::Var("EMPTY")),
+                (21..28, SourceKind::Var("NONE")),
+                (28..29, SourceKind::Var("X")),
+            ]
+        );
+
+        assert_eq!(
+            env_with_source_map("", context).unwrap(),
+            (String::new(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_env_with_source_map_settings() {
+        let expander = Expander::new().strip_double_quotes(true);
+        let (result, sources) = expander
+            .env_with_source_map("\"$SHORT\"\"\"$LONG", context)
+            .unwrap();
+        assert_eq!(result, "aba rather long value");
+        assert_eq!(
+            sources,
+            [
+                (0..2, SourceKind::Var("SHORT")),
+                (2..21, SourceKind::Var("LONG")),
+            ]
+        );
+
+        match env_with_source_map("ok $ERR", context) {
+            Err(ExpandError::Lookup(e)) => {
+                assert_eq!(e.var_name, "ERR");
+                assert_eq!(e.offset, 3);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Streaming environment expansion.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str;
+
+use {find_closing_brace, ExpandError, Expander, State, Token, TokenKind};
+
+/// An error which occurred while expanding a stream with `expand_stream()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExpandStreamError<E> {
+    /// Reading from the reader or writing to the writer failed, or the input is not valid UTF-8.
+    Io(io::Error),
+    /// The expansion failed; the offset of the error is relative to the beginning of the stream.
+    Expand(ExpandError<E>),
+}
+
+impl<E> From<io::Error> for ExpandStreamError<E> {
+    fn from(e: io::Error) -> ExpandStreamError<E> {
+        ExpandStreamError::Io(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for ExpandStreamError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandStreamError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ExpandStreamError::Expand(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ExpandStreamError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExpandStreamError::Io(ref e) => Some(e),
+            ExpandStreamError::Expand(ref e) => Some(e),
+        }
+    }
+}
+
+impl Expander {
+    /// Performs the environment expansion of everything read from `reader`, writing the result
+    /// to `writer`.
+    ///
+    /// See `expand_stream()` for more details.
+    pub fn expand_stream<R, W, CO, C, E>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        mut context: C,
+    ) -> Result<(), ExpandStreamError<E>>
+    where
+        R: Read,
+        W: Write,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        let mut state = State::default();
+        let mut pending = Vec::new();
+        let mut chunk = [0; 8192];
+        // the offset of the beginning of `pending` inside the whole stream
+        let mut offset = 0;
+        // the length of the beginning of `pending` which is already known to be valid UTF-8
+        let mut valid_len = 0;
+        // whether `pending` starts with an unterminated reference, which needs a closing delimiter
+        let mut unterminated = false;
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            pending.extend_from_slice(&chunk[..read]);
+            let eof = read == 0;
+
+            let checked_len = valid_len;
+            valid_len += match str::from_utf8(&pending[valid_len..]) {
+                Ok(s) => s.len(),
+                // the last character may be completed by the next read
+                Err(ref e) if !eof && e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e).into()),
+            };
+            let text = str::from_utf8(&pending[..valid_len]).unwrap();
+            // nothing can become stable before the closing delimiter of the reference arrives, so
+            // the whole buffer is not scanned again on every read
+            if unterminated && !eof && !text[checked_len..].contains(self.delimiters.1) {
+                continue;
+            }
+            let len = if eof {
+                text.len()
+            } else {
+                let (len, waits_for_close) = self.stable_len(text);
+                unterminated = waits_for_close;
+                len
+            };
+
+            let expanded = self
+                .expand_env(&text[..len], &mut context, &mut state)
+                .map_err(|mut e| {
+                    *e.offset_mut() += offset;
+                    ExpandStreamError::Expand(e)
+                })?;
+            writer.write_all(expanded.as_bytes())?;
+            drop(expanded);
+
+            pending.drain(..len);
+            offset += len;
+            valid_len -= len;
+            if eof {
+                writer.flush()?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns the length of the longest prefix of `text` whose expansion cannot be changed by
+    /// the text which follows it, and whether the rest of `text` starts with a reference which
+    /// is unstable only because its closing delimiter has not arrived yet.
+    fn stable_len(&self, text: &str) -> (usize, bool) {
+        // a trailing backslash may escape a dollar or a quote in the next piece
+        let len = if (self.shell_escaping || self.strip_double_quotes) && text.ends_with('\\') {
+            text.len() - 1
+        } else {
+            text.len()
+        };
+        let text = &text[..len];
+
+        if let Some((ref introducer, _)) = self.placeholders {
+            return (
+                placeholders_stable_len(text, introducer, self.tokens(text)),
+                false,
+            );
+        }
+
+        let (open, _) = self.delimiters;
+        for token in self.tokens(text) {
+            match token.kind {
+                // the name may continue in the next piece
+                TokenKind::Bare(_) if token.range.end == len => return (token.range.start, false),
+                // a dollar at the end may start a reference, and the closing brace of an
+                // unterminated reference may appear later
+                TokenKind::Literal => {
+                    for (idx, _) in text[token.range.clone()].match_indices('$') {
+                        let idx = token.range.start + idx;
+                        let rest = &text[idx + 1..];
+                        if rest.is_empty() {
+                            return (idx, false);
+                        }
+                        let unterminated = rest.starts_with(open)
+                            && find_closing_brace(
+                                &rest[open.len_utf8()..],
+                                self.delimiters,
+                                self.recursive,
+                            )
+                            .is_none();
+                        if unterminated {
+                            return (idx, true);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        (len, false)
+    }
+}
+
+/// Same as `Expander::stable_len()`, but for placeholders started by `introducer`.
+fn placeholders_stable_len<'a, T>(text: &str, introducer: &str, tokens: T) -> usize
+where
+    T: Iterator<Item = Token<'a>>,
+{
+    for token in tokens {
+        if let TokenKind::Literal = token.kind {
+            let literal = &text[token.range.clone()];
+            // an introducer in literal text may be terminated or doubled later
+            if let Some(idx) = literal.find(introducer) {
+                return token.range.start + idx;
+            }
+            // literal text at the end may end with the beginning of an introducer
+            let partial = (1..introducer.len())
+                .rev()
+                .filter(|&len| introducer.is_char_boundary(len))
+                .find(|&len| literal.ends_with(&introducer[..len]));
+            if let Some(len) = partial {
+                if token.range.end == text.len() {
+                    return text.len() - len;
+                }
+            }
+        }
+    }
+    text.len()
+}
+
+/// Performs the environment expansion of everything read from `reader`, writing the result to
+/// `writer`.
+///
+/// This function works like `env_with_context()`, but it does not need the whole input in
+/// memory: the input is expanded on the fly, piece by piece, while it is being read. Only the
+/// text which may still become a part of a variable reference is kept in memory until the rest of
+/// the reference arrives, so references may freely cross the boundaries of reads. The result is
+/// exactly the same as if the whole input were expanded with `env_with_context()`. Note that an
+/// unterminated `${` makes the rest of the input buffered, since its closing brace may appear
+/// anywhere later. The buffered text is only scanned again when a closing brace arrives; however,
+/// each such scan starts at the beginning of the buffer, so many closing braces which leave
+/// a reference unterminated, like in deeply nested references in recursive mode, make the time
+/// quadratic in the size of the buffered text. The same applies to every read after an
+/// unterminated placeholder, if placeholders are used.
+///
+/// The input must be valid UTF-8. Offsets of expansion errors are counted from the beginning of
+/// the stream. Tilde expansion is not performed. Use `Expander::expand_stream()` to expand streams
+/// with non-default settings.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// let mut output = Vec::new();
+/// shellexpand::expand_stream("first $A\nsecond ${A}\n".as_bytes(), &mut output, context)
+///     .unwrap();
+/// assert_eq!(output, b"first a value\nsecond a value\n");
+/// ```
+pub fn expand_stream<R, W, CO, C, E>(
+    reader: R,
+    writer: W,
+    context: C,
+) -> Result<(), ExpandStreamError<E>>
+where
+    R: Read,
+    W: Write,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    Expander::new().expand_stream(reader, writer, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, Read};
+
+    use super::{expand_stream, ExpandStreamError};
+    use {ExpandError, Expander};
+
+    /// A reader which returns at most `step` bytes at a time.
+    struct SlowReader<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl<'a> Read for SlowReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = cmp::min(cmp::min(self.step, buf.len()), self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "A" => Ok(Some("a")),
+            "LONG_NAME" => Ok(Some("long välue")),
+            "$" => Ok(Some("pid")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        }
+    }
+
+    fn stream(expander: &Expander, input: &str, step: usize) -> String {
+        let reader = SlowReader {
+            data: input.as_bytes(),
+            step,
+        };
+        let mut output = Vec::new();
+        expander
+            .expand_stream(reader, &mut output, context)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_expand_stream() {
+        let inputs = [
+            "",
+            "no references",
+            "$A",
+            "x$A",
+            "$LONG_NAME/${LONG_NAME}/$LONG_NAMEx",
+            "${A:-default}${UNKNOWN:-${A}}${UNKNOWN:+alt}",
+            "$$A $$$A $$${A}",
+            "a$",
+            "a$$",
+            "$/$ $",
+            "${A",
+            "${}${A}${",
+            "x${A x ${A} ${",
+            "ünïcödé $A ünïcödé${LONG_NAME}ü",
+            "\\$A \\\\$A \"$A\" \\\"",
+            "${A:-${UNKNOWN:-x}}}",
+            "$$A$$ $$$$A$$ $$$$$A$$ ##A## ####A##x ##LONG_NAME## <%A%> <%A#",
+            "x$$A$ <%A% ##A#",
+        ];
+        let expanders = [
+            Expander::new(),
+            Expander::new().recursive(true),
+            Expander::new().shell_escaping(true),
+            Expander::new().strip_double_quotes(true),
+            Expander::new().delimiters('[', ']'),
+            Expander::new().placeholders("$$", "$$"),
+            Expander::new().placeholders("##", "##"),
+            Expander::new().placeholders("<%", "%>"),
+        ];
+        for expander in &expanders {
+            for input in &inputs {
+                let expected = expander.env_with_context(*input, context).unwrap();
+                for &step in &[1, 2, 3, 1000] {
+                    assert_eq!(
+                        stream(expander, input, step),
+                        expected,
+                        "{:?} {:?} {}",
+                        expander,
+                        input,
+                        step
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_expand_stream_unterminated() {
+        let long = "x".repeat(200_000);
+        for input in &[
+            format!("$A ${{A{}", long),
+            format!("$A ${{A{}}} $A", long),
+            format!("ü${{{}ü", long),
+        ] {
+            let expander = Expander::new();
+            let expected = expander.env_with_context(input, context).unwrap();
+            assert_eq!(stream(&expander, input, 13), expected);
+        }
+    }
+
+    #[test]
+    fn test_expand_stream_errors() {
+        let reader = SlowReader {
+            data: "first line\nsecond $ERR".as_bytes(),
+            step: 1,
+        };
+        match expand_stream(reader, io::sink(), context) {
+            Err(ExpandStreamError::Expand(ExpandError::Lookup(e))) => {
+                assert_eq!(e.var_name, "ERR");
+                assert_eq!(e.offset, 18);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let reader = SlowReader {
+            data: b"valid \xff invalid",
+            step: 1,
+        };
+        match expand_stream(reader, io::sink(), context) {
+            Err(ExpandStreamError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
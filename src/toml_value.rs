@@ -0,0 +1,339 @@
+//! Expansion of strings inside TOML documents.
+
+use std::borrow::Cow;
+use std::env::VarError;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use toml::value::{Table, Value};
+
+use dotted::{self, Nested};
+use {full_with_context, home, LookupError};
+
+/// An error which occurred while expanding a string inside a TOML value.
+///
+/// See `full_toml_with_context()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TomlError<E> {
+    /// The path to the offending string inside the TOML value, like `server.paths[1]`.
+    ///
+    /// The path consists of table keys separated by dots and of array indices in brackets. It is
+    /// empty if the value itself is the offending string.
+    pub path: String,
+    /// The error which occurred while expanding the string.
+    pub error: LookupError<E>,
+}
+
+impl<E: fmt::Display> fmt::Display for TomlError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error expanding value '{}': {}", self.path, self.error)
+    }
+}
+
+impl<E: Error + 'static> Error for TomlError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Performs both tilde and environment expansion of every string inside a TOML value.
+///
+/// This function walks through `value` recursively and returns a new value in which all strings,
+/// including the strings inside arrays and tables, are expanded with `full_with_context()` using
+/// the provided contexts. Keys of tables are left as they are, and values of other types are
+/// copied without changes. The contexts may be called for each of the strings, which is why
+/// `home_dir` must be `FnMut` here.
+///
+/// If the expansion of some string fails, the error is returned along with the path to that
+/// string inside `value`.
+///
+/// This function is only available with the `toml` feature.
+///
+/// # Examples
+///
+/// ```
+/// let config: toml::Value = r#"
+///     name = "$USER"
+///
+///     [paths]
+///     data = ["~/data", "${CACHE:-/tmp}/data"]
+/// "#.parse().unwrap();
+///
+/// fn context(s: &str) -> Result<Option<&'static str>, std::env::VarError> {
+///     match s {
+///         "USER" => Ok(Some("john")),
+///         "CACHE" => Ok(None),
+///         _ => Err(std::env::VarError::NotPresent),
+///     }
+/// }
+///
+/// let expanded = shellexpand::full_toml_with_context(&config, || Some("/home/john"), context);
+/// let expected: toml::Value = r#"
+///     name = "john"
+///
+///     [paths]
+///     data = ["/home/john/data", "/tmp/data"]
+/// "#.parse().unwrap();
+/// assert_eq!(expanded, Ok(expected));
+///
+/// let config: toml::Value = r#"paths = { data = ["$UNKNOWN"] }"#.parse().unwrap();
+/// let err = shellexpand::full_toml_with_context(&config, || Some("/home/john"), context)
+///     .unwrap_err();
+/// assert_eq!(err.path, "paths.data[0]");
+/// assert_eq!(err.error.var_name, "UNKNOWN");
+/// ```
+pub fn full_toml_with_context<CO, C, E, P, HD>(
+    value: &Value,
+    mut home_dir: HD,
+    mut context: C,
+) -> Result<Value, TomlError<E>>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnMut() -> Option<P>,
+{
+    let mut path = String::new();
+    expand_value(value, &mut path, &mut |s| {
+        full_with_context(s, &mut home_dir, &mut context).map(|s| s.into_owned())
+    })
+}
+
+/// Performs both tilde and environment expansion of every string inside a TOML value in the
+/// default system context.
+///
+/// This function uses the same sources as `full()`, see `full_toml_with_context()` for details.
+///
+/// This function is only available with the `toml` feature.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("A", "a value");
+///
+/// let config: toml::Value = "value = '$A'".parse().unwrap();
+/// let expected: toml::Value = "value = 'a value'".parse().unwrap();
+/// assert_eq!(shellexpand::full_toml(&config), Ok(expected));
+/// ```
+pub fn full_toml(value: &Value) -> Result<Value, TomlError<VarError>> {
+    full_toml_with_context(value, home::home_dir, |s| std::env::var(s).map(Some))
+}
+
+fn expand_value<E, F>(
+    value: &Value,
+    path: &mut String,
+    expand: &mut F,
+) -> Result<Value, TomlError<E>>
+where
+    F: FnMut(&str) -> Result<String, LookupError<E>>,
+{
+    let parent_len = path.len();
+    let result = match *value {
+        Value::String(ref s) => expand(s).map(Value::String).map_err(|error| TomlError {
+            path: path.clone(),
+            error,
+        })?,
+        Value::Array(ref values) => {
+            let mut result = Vec::with_capacity(values.len());
+            for (idx, value) in values.iter().enumerate() {
+                path.push_str(&format!("[{}]", idx));
+                result.push(expand_value(value, path, expand)?);
+                path.truncate(parent_len);
+            }
+            Value::Array(result)
+        }
+        Value::Table(ref table) => {
+            let mut result = Table::new();
+            for (key, value) in table {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+                result.insert(key.clone(), expand_value(value, path, expand)?);
+                path.truncate(parent_len);
+            }
+            Value::Table(result)
+        }
+        ref other => other.clone(),
+    };
+    Ok(result)
+}
+
+/// Creates a context which looks variables up in a TOML table.
+///
+/// This is the TOML counterpart of `json_context()`: strings are used as they are, integers,
+/// floats, booleans and datetimes are converted to strings with their TOML representation, and
+/// arrays, tables and missing values are treated as unset variables. Names containing dots are
+/// paths into nested tables and arrays, like `${database.host}` or `${servers.0}`, unless the
+/// table contains a key with this exact name.
+///
+/// This function is only available with the `toml` feature.
+///
+/// # Examples
+///
+/// ```
+/// let config: toml::Table = r#"
+///     [database]
+///     host = "db.local"
+///     port = 5432
+/// "#.parse().unwrap();
+///
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors(
+///         "${database.host}:${database.port}/${database.user}",
+///         shellexpand::toml_context(&config),
+///     ),
+///     "db.local:5432/${database.user}"
+/// );
+/// ```
+pub fn toml_context<'a>(table: &'a Table) -> impl Fn(&str) -> Option<Cow<'a, str>> + 'a {
+    move |s| match *dotted::lookup(|key| table.get(key), s)? {
+        Value::String(ref value) => Some(value.as_str().into()),
+        Value::Integer(value) => Some(value.to_string().into()),
+        Value::Float(value) => Some(value.to_string().into()),
+        Value::Boolean(value) => Some(value.to_string().into()),
+        Value::Datetime(ref value) => Some(value.to_string().into()),
+        Value::Array(_) | Value::Table(_) => None,
+    }
+}
+
+impl Nested for Value {
+    fn member(&self, key: &str) -> Option<&Value> {
+        self.as_table()?.get(key)
+    }
+
+    fn element(&self, index: usize) -> Option<&Value> {
+        self.as_array()?.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::VarError;
+
+    use toml::Value;
+
+    use super::{full_toml_with_context, toml_context, TomlError};
+    use LookupError;
+    use ReferenceKind;
+
+    fn context(s: &str) -> Result<Option<&'static str>, VarError> {
+        match s {
+            "HOME" => Ok(Some("/home/user")),
+            "ERR" => Err(VarError::NotPresent),
+            _ => Ok(None),
+        }
+    }
+
+    fn home_dir() -> Option<&'static str> {
+        Some("/home/dir")
+    }
+
+    #[test]
+    fn test_nested_tables() {
+        let value: Value = r#"
+            name = "${HOME}"
+            count = 3
+
+            [dirs]
+            "${HOME}" = "key"
+            cache = "${HOME}/.cache"
+            data = ["~/data", "$UNKNOWN", 1]
+
+            [dirs.nested]
+            config = "${CONFIG:-$HOME/.config}"
+        "#
+        .parse()
+        .unwrap();
+        let expected: Value = r#"
+            name = "/home/user"
+            count = 3
+
+            [dirs]
+            "${HOME}" = "key"
+            cache = "/home/user/.cache"
+            data = ["/home/dir/data", "$UNKNOWN", 1]
+
+            [dirs.nested]
+            config = "$HOME/.config"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            full_toml_with_context(&value, home_dir, context),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn test_error_path() {
+        let value: Value = r#"
+            ok = "$HOME"
+
+            [a.b]
+            c = ["x", { d = "${ERR}" }]
+        "#
+        .parse()
+        .unwrap();
+
+        let err = full_toml_with_context(&value, home_dir, context).unwrap_err();
+        assert_eq!(
+            err,
+            TomlError {
+                path: "a.b.c[1].d".into(),
+                error: LookupError {
+                    var_name: "ERR".into(),
+                    offset: 0,
+                    kind: ReferenceKind::Braced,
+                    cause: VarError::NotPresent,
+                },
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "error expanding value 'a.b.c[1].d': error looking key 'ERR' up: environment variable \
+             not found"
+        );
+
+        let value = Value::String("$ERR".into());
+        assert_eq!(
+            full_toml_with_context(&value, home_dir, context)
+                .unwrap_err()
+                .path,
+            ""
+        );
+    }
+
+    #[test]
+    fn test_toml_context() {
+        let table: ::toml::Table = r#"
+            name = "value"
+            int = 42
+            float = 1.5
+            yes = true
+            servers = ["alpha", { name = "beta" }]
+
+            [database]
+            host = "db.local"
+            auth = { user = "admin" }
+        "#
+        .parse()
+        .unwrap();
+        let context = toml_context(&table);
+
+        assert_eq!(context("name").as_deref(), Some("value"));
+        assert_eq!(context("int").as_deref(), Some("42"));
+        assert_eq!(context("float").as_deref(), Some("1.5"));
+        assert_eq!(context("yes").as_deref(), Some("true"));
+        assert_eq!(context("database.host").as_deref(), Some("db.local"));
+        assert_eq!(context("database.auth.user").as_deref(), Some("admin"));
+        assert_eq!(context("servers.1.name").as_deref(), Some("beta"));
+        assert_eq!(context("database.missing.user"), None);
+        assert_eq!(context("database.auth"), None);
+        assert_eq!(context("missing"), None);
+    }
+}
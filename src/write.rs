@@ -0,0 +1,188 @@
+//! Environment expansion into arbitrary `fmt::Write` implementors.
+
+use std::error::Error;
+use std::fmt;
+
+use {ExpandError, Expander, State};
+
+/// An error which occurred while expanding a string into a writer with `env_write()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExpandWriteError<E> {
+    /// Writing to the writer failed.
+    Write(fmt::Error),
+    /// The expansion failed.
+    Expand(ExpandError<E>),
+}
+
+impl<E> From<fmt::Error> for ExpandWriteError<E> {
+    fn from(e: fmt::Error) -> ExpandWriteError<E> {
+        ExpandWriteError::Write(e)
+    }
+}
+
+impl<E> From<ExpandError<E>> for ExpandWriteError<E> {
+    fn from(e: ExpandError<E>) -> ExpandWriteError<E> {
+        ExpandWriteError::Expand(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for ExpandWriteError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandWriteError::Write(ref e) => write!(f, "write error: {}", e),
+            ExpandWriteError::Expand(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ExpandWriteError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExpandWriteError::Write(ref e) => Some(e),
+            ExpandWriteError::Expand(ref e) => Some(e),
+        }
+    }
+}
+
+impl Expander {
+    /// Performs the environment expansion of `input`, writing the result to `writer`.
+    ///
+    /// See `env_write()` for more details.
+    pub fn env_write<SI, W, CO, C, E>(
+        &self,
+        input: &SI,
+        writer: &mut W,
+        mut context: C,
+    ) -> Result<(), ExpandWriteError<E>>
+    where
+        SI: AsRef<str> + ?Sized,
+        W: fmt::Write + ?Sized,
+        CO: AsRef<str>,
+        C: FnMut(&str) -> Result<Option<CO>, E>,
+    {
+        self.write_env(input.as_ref(), writer, &mut context, &mut State::default())
+    }
+}
+
+/// Performs the environment expansion of `input`, writing the result to `writer`.
+///
+/// This function works like `env_with_context()`, but instead of returning a `String` it appends
+/// the result to any `fmt::Write` implementor, like a `fmt::Formatter` or a string type other
+/// than `String`. The result may be partially written if an error occurs. Use
+/// `Expander::env_write()` to expand with non-default settings.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// let mut output = String::from("expanded: ");
+/// shellexpand::env_write("$A and $B", &mut output, context).unwrap();
+/// assert_eq!(output, "expanded: a value and $B");
+/// ```
+pub fn env_write<SI, W, CO, C, E>(
+    input: &SI,
+    writer: &mut W,
+    context: C,
+) -> Result<(), ExpandWriteError<E>>
+where
+    SI: AsRef<str> + ?Sized,
+    W: fmt::Write + ?Sized,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    Expander::new().env_write(input, writer, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::{env_write, ExpandWriteError};
+    use {ExpandError, Expander};
+
+    /// A string with a fixed capacity, which fails to accept more text than fits into it.
+    struct FixedString {
+        buf: [u8; 16],
+        len: usize,
+    }
+
+    impl FixedString {
+        fn new() -> FixedString {
+            FixedString {
+                buf: [0; 16],
+                len: 0,
+            }
+        }
+
+        fn as_str(&self) -> &str {
+            std::str::from_utf8(&self.buf[..self.len]).unwrap()
+        }
+    }
+
+    impl fmt::Write for FixedString {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.buf.len() {
+                return Err(fmt::Error);
+            }
+            self.buf[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "A" => Ok(Some("ä")),
+            "LONG" => Ok(Some("a rather long value")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        }
+    }
+
+    #[test]
+    fn test_env_write() {
+        let mut output = FixedString::new();
+        env_write("x$A ${B:-b} $$", &mut output, context).unwrap();
+        assert_eq!(output.as_str(), "xä b $");
+
+        let expander = Expander::new().strip_double_quotes(true);
+        let mut output = FixedString::new();
+        expander
+            .env_write("\"$A\" \\\"", &mut output, context)
+            .unwrap();
+        assert_eq!(output.as_str(), "ä \"");
+
+        // the result matches the one of `env_with_context()`
+        let input = "$A/${A}/${B:+alt}/${C:-$A}/$NONE";
+        let mut output = String::new();
+        env_write(input, &mut output, context).unwrap();
+        assert_eq!(output, ::env_with_context(input, context).unwrap());
+    }
+
+    #[test]
+    fn test_env_write_errors() {
+        let mut output = FixedString::new();
+        assert_eq!(
+            env_write("$LONG", &mut output, context),
+            Err(ExpandWriteError::Write(fmt::Error))
+        );
+
+        let mut output = FixedString::new();
+        match env_write("ok $ERR", &mut output, context) {
+            Err(ExpandWriteError::Expand(ExpandError::Lookup(e))) => {
+                assert_eq!(e.var_name, "ERR");
+                assert_eq!(e.offset, 3);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(output.as_str(), "ok ");
+    }
+}